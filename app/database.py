@@ -1,8 +1,9 @@
 # app/database.py
+import hashlib
 import aiosqlite
 from datetime import datetime, timedelta, timezone
 
-from app.config import MSK_TZ
+from app.config import MSK_TZ, DEFAULT_QUIET_HOURS, DEFAULT_UTC_OFFSET
 
 class Database:
     """Класс для асинхронной работы с базой данных SQLite."""
@@ -38,7 +39,19 @@ class Database:
                 'has_rewarded_bonus': 'INTEGER DEFAULT 0',
                 'last_used_image_model': 'TEXT',
                 'user_instruction': 'TEXT',
-                'user_temperature': 'REAL'
+                'user_temperature': 'REAL',
+                'last_seen_changelog_id': 'INTEGER DEFAULT 0',
+                'quiet_start': f'INTEGER DEFAULT {DEFAULT_QUIET_HOURS[0]}',
+                'quiet_end': f'INTEGER DEFAULT {DEFAULT_QUIET_HOURS[1]}',
+                'utc_offset': f'INTEGER DEFAULT {DEFAULT_UTC_OFFSET}',
+                'custom_daily_limit': 'INTEGER',
+                'bonus_max_runs': 'INTEGER DEFAULT 0',
+                'is_beta_tester': 'INTEGER DEFAULT 0',
+                'last_image_size': 'TEXT',
+                'reward_revoke_at': 'TIMESTAMP',
+                'last_model_category': 'TEXT',
+                'chat_mode': 'TEXT',
+                'max_mode_instruction': 'TEXT'
             }
 
             for col, col_type in migrations.items():
@@ -50,62 +63,1459 @@ class Database:
             columns = [row[1] for row in await cursor.fetchall()]
             if 'is_max_mode' not in columns:
                 await db.execute('ALTER TABLE requests ADD COLUMN is_max_mode INTEGER DEFAULT 0')
+            if 'chat_id' not in columns:
+                await db.execute('ALTER TABLE requests ADD COLUMN chat_id INTEGER')
+            if 'is_bonus' not in columns:
+                await db.execute('ALTER TABLE requests ADD COLUMN is_bonus INTEGER DEFAULT 0')
+            for col in ('prompt_tokens', 'completion_tokens'):
+                if col not in columns:
+                    await db.execute(f'ALTER TABLE requests ADD COLUMN {col} INTEGER DEFAULT 0')
+            if 'cost' not in columns:
+                await db.execute('ALTER TABLE requests ADD COLUMN cost REAL DEFAULT 0')
+
+            # Миграции для таблицы inflight_requests
+            cursor = await db.execute('PRAGMA table_info(inflight_requests)')
+            columns = [row[1] for row in await cursor.fetchall()]
+            if 'conversation_id' not in columns:
+                await db.execute('ALTER TABLE inflight_requests ADD COLUMN conversation_id INTEGER')
+
+            # Рассылки и отложенные уведомления с вложениями
+            for table, extra_columns in (
+                ('broadcasts', {'media_type': 'TEXT', 'media_file_id': 'TEXT', 'progress_message_id': 'INTEGER'}),
+                ('pending_notifications', {'media_type': 'TEXT', 'media_file_id': 'TEXT'}),
+                ('conversations', {'pinned': 'INTEGER DEFAULT 0'}),
+            ):
+                cursor = await db.execute(f'PRAGMA table_info({table})')
+                columns = [row[1] for row in await cursor.fetchall()]
+                for col, col_type in extra_columns.items():
+                    if col not in columns:
+                        await db.execute(f'ALTER TABLE {table} ADD COLUMN {col} {col_type}')
+
+            await db.commit()
+
+    async def create_tables(self):
+        """Создает таблицы, если они не существуют."""
+        await self._execute(f'''
+            CREATE TABLE IF NOT EXISTS users (
+                user_id INTEGER PRIMARY KEY,
+                username TEXT,
+                subscription_level INTEGER DEFAULT 0,
+                subscription_end TIMESTAMP,
+                is_blocked INTEGER DEFAULT 0,
+                is_verified INTEGER DEFAULT 0,
+                has_rewarded_bonus INTEGER DEFAULT 0,
+                last_used_model TEXT,
+                last_used_image_model TEXT,
+                user_instruction TEXT,
+                user_temperature REAL,
+                last_seen_changelog_id INTEGER DEFAULT 0,
+                quiet_start INTEGER DEFAULT {DEFAULT_QUIET_HOURS[0]},
+                quiet_end INTEGER DEFAULT {DEFAULT_QUIET_HOURS[1]},
+                utc_offset INTEGER DEFAULT {DEFAULT_UTC_OFFSET},
+                custom_daily_limit INTEGER,
+                bonus_max_runs INTEGER DEFAULT 0,
+                is_beta_tester INTEGER DEFAULT 0, -- видит модели в бета-тесте
+                last_image_size TEXT, -- ключ из IMAGE_SIZES
+                reward_revoke_at TIMESTAMP, -- когда отозвать бонус за каналы, если пользователь не вернется в них
+                last_model_category TEXT, -- последняя открытая категория в меню выбора модели
+                chat_mode TEXT, -- 'chat' или 'max_mode', если пользователь в диалоге; нужен для восстановления после перезапуска
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS requests (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                model TEXT,
+                request_date DATE,
+                is_max_mode INTEGER DEFAULT 0, -- 0 for normal, 1 for max mode
+                chat_id INTEGER, -- ID группы для запросов из групп
+                is_bonus INTEGER DEFAULT 0, -- 1, если запрос оплачен разовым бонусом администратора
+                prompt_tokens INTEGER DEFAULT 0,
+                completion_tokens INTEGER DEFAULT 0,
+                cost REAL DEFAULT 0, -- стоимость в долларах по MODEL_PRICES
+                FOREIGN KEY (user_id) REFERENCES users (user_id)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS system_state (
+                key TEXT PRIMARY KEY,
+                value TEXT,
+                updated_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS group_settings (
+                chat_id INTEGER PRIMARY KEY,
+                admins_only INTEGER DEFAULT 0,
+                user_cooldown INTEGER DEFAULT 0, -- в секундах
+                daily_cap INTEGER DEFAULT 0 -- 0 = без ограничений
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS broadcasts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                admin_id INTEGER,
+                text TEXT NOT NULL,
+                status TEXT DEFAULT 'running', -- running, done
+                last_user_id INTEGER DEFAULT 0,
+                success_count INTEGER DEFAULT 0,
+                fail_count INTEGER DEFAULT 0,
+                created_at TIMESTAMP,
+                finished_at TIMESTAMP,
+                media_type TEXT, -- photo, video, animation, document; text тогда - подпись
+                media_file_id TEXT,
+                progress_message_id INTEGER -- сообщение администратору с прогрессом
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS inflight_requests (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                chat_id INTEGER,
+                kind TEXT, -- chat, max_mode
+                model TEXT,
+                prompt TEXT,
+                prompt_hash TEXT,
+                started_at TIMESTAMP,
+                notified INTEGER DEFAULT 0,
+                conversation_id INTEGER -- беседа обычного чата, которую продолжал запрос
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS file_cache (
+                file_hash TEXT NOT NULL,
+                operation TEXT NOT NULL, -- например: ocr, summary, transcription
+                result TEXT,
+                hits INTEGER DEFAULT 0,
+                created_at TIMESTAMP,
+                last_used_at TIMESTAMP,
+                PRIMARY KEY (file_hash, operation)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS pending_notifications (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                text TEXT NOT NULL,
+                reply_markup TEXT, -- JSON клавиатуры
+                send_after TIMESTAMP,
+                created_at TIMESTAMP,
+                media_type TEXT,
+                media_file_id TEXT
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS extra_requests (
+                user_id INTEGER,
+                request_date DATE,
+                amount INTEGER DEFAULT 0,
+                PRIMARY KEY (user_id, request_date)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS admin_audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                admin_id INTEGER,
+                action TEXT,
+                target_user_id INTEGER,
+                details TEXT,
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS winback (
+                user_id INTEGER PRIMARY KEY,
+                lapsed_at TEXT, -- subscription_end, для которого идет последовательность
+                stage INTEGER DEFAULT 0, -- последний отправленный этап
+                last_sent_at TIMESTAMP,
+                opted_out INTEGER DEFAULT 0
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS winback_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                stage INTEGER,
+                event TEXT, -- sent, converted, opt_out
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS promocodes (
+                code TEXT PRIMARY KEY,
+                user_id INTEGER, -- NULL - код не привязан к пользователю
+                discount_percent INTEGER,
+                source TEXT, -- откуда выдан код, например winback
+                created_at TIMESTAMP,
+                expires_at TIMESTAMP,
+                used_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS surveys (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                question TEXT NOT NULL,
+                kind TEXT, -- choice, nps, text
+                options TEXT, -- варианты ответа через перевод строки (для choice)
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS survey_responses (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                survey_id INTEGER,
+                user_id INTEGER,
+                answer TEXT,
+                created_at TIMESTAMP,
+                UNIQUE (survey_id, user_id)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS group_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chat_id INTEGER,
+                user_id INTEGER,
+                model TEXT,
+                prompt TEXT,
+                response TEXT, -- для изображений - ссылка на картинку
+                message_id INTEGER, -- сообщение бота с ответом
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS abuse_reports (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                history_id INTEGER,
+                reporter_id INTEGER,
+                status TEXT DEFAULT 'open', -- open, dismissed, deleted
+                resolved_by INTEGER,
+                created_at TIMESTAMP,
+                resolved_at TIMESTAMP,
+                UNIQUE (history_id, reporter_id)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS shared_conversations (
+                token TEXT PRIMARY KEY,
+                user_id INTEGER,
+                model TEXT,
+                messages TEXT, -- снимок беседы в JSON
+                views INTEGER DEFAULT 0,
+                created_at TIMESTAMP,
+                revoked_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS api_tokens (
+                user_id INTEGER PRIMARY KEY,
+                token_hash TEXT UNIQUE, -- хранится только SHA-256 токена
+                created_at TIMESTAMP,
+                last_used_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS webhooks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                name TEXT,
+                token_hash TEXT UNIQUE, -- хранится только SHA-256 токена из адреса
+                summarize INTEGER DEFAULT 0,
+                deliveries INTEGER DEFAULT 0,
+                created_at TIMESTAMP,
+                last_used_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS tool_servers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT UNIQUE,
+                url TEXT,
+                auth_token TEXT,
+                tools TEXT, -- описание инструментов сервера (tools/list) в JSON
+                enabled INTEGER DEFAULT 1,
+                created_at TIMESTAMP,
+                updated_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS scheduled_prompts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                prompt TEXT,
+                model TEXT,
+                weekdays TEXT, -- дни недели через запятую (0 - понедельник), NULL - каждый день
+                hour INTEGER,
+                minute INTEGER, -- время по часовому поясу пользователя
+                enabled INTEGER DEFAULT 1,
+                next_run_at TIMESTAMP,
+                last_run_at TIMESTAMP,
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS admin_confirmations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                action TEXT,
+                payload TEXT, -- JSON с параметрами действия
+                requested_by INTEGER,
+                status TEXT DEFAULT 'pending', -- pending, approved, rejected
+                confirmed_by INTEGER,
+                created_at TIMESTAMP,
+                expires_at TIMESTAMP,
+                resolved_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS model_status_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                model TEXT,
+                status TEXT, -- 'OK' или описание ошибки
+                checked_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS payments (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                level INTEGER,
+                days INTEGER,
+                amount INTEGER, -- в минимальных единицах валюты (копейки, звезды)
+                currency TEXT,
+                telegram_charge_id TEXT UNIQUE, -- повторная доставка платежа не создает новую запись
+                provider_charge_id TEXT UNIQUE,
+                metadata TEXT, -- JSON, зашифрованный при заданном DB_ENCRYPTION_KEY
+                status TEXT DEFAULT 'paid',
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS dialogue_states (
+                key TEXT PRIMARY KEY, -- bot_id:chat_id:user_id:thread_id:business_connection_id:destiny
+                state TEXT,
+                data TEXT, -- данные FSM в JSON
+                updated_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS update_journal (
+                update_id INTEGER PRIMARY KEY,
+                kind TEXT, -- payment
+                payload TEXT, -- Update в JSON
+                received_at TIMESTAMP,
+                replayed_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS conversations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                model TEXT,
+                parent_id INTEGER, -- беседа, от которой ответвлена эта
+                pinned INTEGER DEFAULT 0, -- закрепленная беседа не удаляется вместе со старыми
+                created_at TIMESTAMP,
+                updated_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                conversation_id INTEGER,
+                role TEXT, -- user или assistant
+                content TEXT,
+                message_id INTEGER, -- сообщение в Telegram (для ответа - сообщение с кнопками)
+                created_at TIMESTAMP,
+                FOREIGN KEY (conversation_id) REFERENCES conversations (id)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS settings_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                setting TEXT, -- instruction или temperature
+                value TEXT, -- прежнее значение; NULL - значение не было задано
+                changed_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS beta_feedback (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                model TEXT,
+                score INTEGER, -- 1 или -1
+                message_id INTEGER, -- ответ, под которым нажата кнопка
+                created_at TIMESTAMP,
+                UNIQUE (user_id, message_id)
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS favorites (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                model TEXT,
+                prompt TEXT, -- запрос, на который дан ответ (для подписи в списке)
+                content TEXT,
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS image_generations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                model TEXT,
+                prompt TEXT,
+                width INTEGER,
+                height INTEGER,
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS image_styles (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                name TEXT,
+                suffix TEXT, -- дописывается к промпту изображения
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS max_mode_runs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                prompt TEXT,
+                participants TEXT, -- JSON: [{"model", "answer", "duration", "error"}]
+                arbiter TEXT,
+                arbiter_output TEXT,
+                arbiter_duration REAL,
+                total_duration REAL,
+                cost REAL, -- Условная стоимость: сумма ModelInfo.cost вызванных моделей
+                status TEXT, -- ok, failed
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event TEXT NOT NULL,
+                user_id INTEGER,
+                props TEXT, -- JSON с подробностями события
+                created_at TIMESTAMP
+            )
+        ''')
+        await self._execute('CREATE INDEX IF NOT EXISTS idx_events_event_created ON events (event, created_at)')
+        await self._execute('CREATE INDEX IF NOT EXISTS idx_requests_user_model ON requests (user_id, model)')
+        await self._execute('''
+            CREATE TABLE IF NOT EXISTS changelog (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                text TEXT NOT NULL,
+                created_at TIMESTAMP
+            )
+        ''')
+
+    async def init_db(self):
+        """Инициализирует БД: создает таблицы и запускает миграции."""
+        await self.create_tables()
+        await self._run_migrations()
+
+    # Методы для работы с системным состоянием
+    async def get_system_state(self, key: str):
+        query = 'SELECT value, updated_at FROM system_state WHERE key = ?'
+        return await self._fetchone(query, (key,))
+
+    async def set_system_state(self, key: str, value: str):
+        now_utc = datetime.now(timezone.utc)
+        query = '''
+            INSERT INTO system_state (key, value, updated_at) VALUES (?, ?, ?)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at
+        '''
+        await self._execute(query, (key, value, now_utc))
+
+    # Методы для работы с настройками групп (group_settings)
+    async def get_group_settings(self, chat_id: int):
+        """Возвращает кортеж (admins_only, user_cooldown, daily_cap) с настройками по умолчанию для новых групп."""
+        result = await self._fetchone(
+            'SELECT admins_only, user_cooldown, daily_cap FROM group_settings WHERE chat_id = ?', (chat_id,)
+        )
+        return result or (0, 0, 0)
+
+    async def set_group_setting(self, chat_id: int, field: str, value: int):
+        if field not in ('admins_only', 'user_cooldown', 'daily_cap'):
+            raise ValueError(f"Unknown group setting: {field}")
+        await self._execute('INSERT OR IGNORE INTO group_settings (chat_id) VALUES (?)', (chat_id,))
+        await self._execute(f'UPDATE group_settings SET {field} = ? WHERE chat_id = ?', (value, chat_id))
+
+    # Методы для работы с рассылками (broadcasts)
+    async def create_broadcast(self, admin_id: int, text: str, media_type: str | None = None, media_file_id: str | None = None) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO broadcasts (admin_id, text, media_type, media_file_id, created_at) VALUES (?, ?, ?, ?, ?)',
+                (admin_id, text, media_type, media_file_id, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_broadcast(self, broadcast_id: int):
+        """(id, admin_id, text, status, last_user_id, success_count, fail_count, media_type, media_file_id, progress_message_id)"""
+        query = '''
+            SELECT id, admin_id, text, status, last_user_id, success_count, fail_count,
+                   media_type, media_file_id, progress_message_id
+            FROM broadcasts WHERE id = ?
+        '''
+        return await self._fetchone(query, (broadcast_id,))
+
+    async def set_broadcast_progress_message(self, broadcast_id: int, message_id: int):
+        await self._execute('UPDATE broadcasts SET progress_message_id = ? WHERE id = ?', (message_id, broadcast_id))
+
+    async def get_unfinished_broadcast_ids(self):
+        rows = await self._fetchall("SELECT id FROM broadcasts WHERE status = 'running' ORDER BY id")
+        return [row[0] for row in rows]
+
+    async def update_broadcast_progress(self, broadcast_id: int, last_user_id: int, delivered: bool):
+        counter = 'success_count' if delivered else 'fail_count'
+        await self._execute(
+            f'UPDATE broadcasts SET last_user_id = ?, {counter} = {counter} + 1 WHERE id = ?',
+            (last_user_id, broadcast_id)
+        )
+
+    async def finish_broadcast(self, broadcast_id: int):
+        await self._execute(
+            "UPDATE broadcasts SET status = 'done', finished_at = ? WHERE id = ?",
+            (datetime.now(timezone.utc), broadcast_id)
+        )
+
+    # Методы для журнала выполняющихся запросов (inflight_requests)
+    async def start_inflight_request(
+        self, user_id: int, chat_id: int, kind: str, model: str, prompt: str, conversation_id: int | None = None
+    ) -> int:
+        prompt_hash = hashlib.sha256(prompt.encode('utf-8')).hexdigest()[:16]
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''INSERT INTO inflight_requests (user_id, chat_id, kind, model, prompt, prompt_hash, started_at, conversation_id)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?)''',
+                (user_id, chat_id, kind, model, prompt, prompt_hash, datetime.now(timezone.utc), conversation_id)
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def finish_inflight_request(self, request_id: int):
+        await self._execute('DELETE FROM inflight_requests WHERE id = ?', (request_id,))
+
+    async def get_inflight_request(self, request_id: int):
+        query = 'SELECT id, user_id, chat_id, kind, model, prompt, conversation_id FROM inflight_requests WHERE id = ?'
+        return await self._fetchone(query, (request_id,))
+
+    async def get_interrupted_requests(self):
+        """Возвращает запросы, прерванные перезапуском, о которых пользователи еще не уведомлены."""
+        query = 'SELECT id, user_id, chat_id, kind, model FROM inflight_requests WHERE notified = 0 ORDER BY id'
+        return await self._fetchall(query)
+
+    async def mark_inflight_request_notified(self, request_id: int):
+        await self._execute('UPDATE inflight_requests SET notified = 1 WHERE id = ?', (request_id,))
+
+    async def delete_stale_inflight_requests(self, days: int = 1):
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        await self._execute('DELETE FROM inflight_requests WHERE notified = 1 AND started_at < ?', (threshold,))
+
+    # Методы для кэша результатов обработки файлов (file_cache)
+    async def get_file_cache(self, file_hash: str, operation: str) -> str | None:
+        result = await self._fetchone(
+            'SELECT result FROM file_cache WHERE file_hash = ? AND operation = ?', (file_hash, operation)
+        )
+        if not result:
+            return None
+        await self._execute(
+            'UPDATE file_cache SET hits = hits + 1, last_used_at = ? WHERE file_hash = ? AND operation = ?',
+            (datetime.now(timezone.utc), file_hash, operation)
+        )
+        return result[0]
+
+    async def set_file_cache(self, file_hash: str, operation: str, result: str):
+        now_utc = datetime.now(timezone.utc)
+        query = '''
+            INSERT INTO file_cache (file_hash, operation, result, created_at, last_used_at) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(file_hash, operation) DO UPDATE SET result = excluded.result, last_used_at = excluded.last_used_at
+        '''
+        await self._execute(query, (file_hash, operation, result, now_utc, now_utc))
+
+    async def delete_old_file_cache(self, days: int):
+        """Удаляет результаты, которые не запрашивались дольше days дней."""
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        await self._execute('DELETE FROM file_cache WHERE last_used_at < ?', (threshold,))
+
+    # Методы для возврата ушедших подписчиков (winback)
+    async def get_winback_candidates(self, lapsed_after: datetime, lapsed_before: datetime, inactive_since):
+        """
+        Пользователи с подпиской, истекшей в промежутке [lapsed_after, lapsed_before), которые не делали запросов
+        с inactive_since и не отказались от напоминаний.
+        Возвращает (user_id, subscription_end, winback_lapsed_at, winback_stage).
+        """
+        query = '''
+            SELECT u.user_id, u.subscription_end, w.lapsed_at, COALESCE(w.stage, 0)
+            FROM users u
+            LEFT JOIN winback w ON w.user_id = u.user_id
+            WHERE u.subscription_end >= ? AND u.subscription_end < ? AND u.is_blocked = 0
+              AND COALESCE(w.opted_out, 0) = 0
+              AND NOT EXISTS (SELECT 1 FROM requests r WHERE r.user_id = u.user_id AND r.request_date >= ?)
+        '''
+        return await self._fetchall(query, (lapsed_after.isoformat(), lapsed_before.isoformat(), inactive_since))
+
+    async def set_winback_stage(self, user_id: int, lapsed_at: str, stage: int):
+        now_utc = datetime.now(timezone.utc)
+        query = '''
+            INSERT INTO winback (user_id, lapsed_at, stage, last_sent_at) VALUES (?, ?, ?, ?)
+            ON CONFLICT(user_id) DO UPDATE SET
+                lapsed_at = excluded.lapsed_at, stage = excluded.stage, last_sent_at = excluded.last_sent_at
+        '''
+        await self._execute(query, (user_id, lapsed_at, stage, now_utc))
+        await self.add_winback_event(user_id, stage, 'sent')
+
+    async def get_winback_stage(self, user_id: int) -> int:
+        result = await self._fetchone('SELECT stage FROM winback WHERE user_id = ?', (user_id,))
+        return result[0] if result else 0
+
+    async def reset_winback_stage(self, user_id: int):
+        await self._execute('UPDATE winback SET stage = 0 WHERE user_id = ?', (user_id,))
+
+    async def set_winback_opt_out(self, user_id: int):
+        query = '''
+            INSERT INTO winback (user_id, opted_out) VALUES (?, 1)
+            ON CONFLICT(user_id) DO UPDATE SET opted_out = 1
+        '''
+        await self._execute(query, (user_id,))
+        await self.add_winback_event(user_id, await self.get_winback_stage(user_id), 'opt_out')
+
+    async def add_winback_event(self, user_id: int, stage: int, event: str):
+        await self._execute(
+            'INSERT INTO winback_events (user_id, stage, event, created_at) VALUES (?, ?, ?, ?)',
+            (user_id, stage, event, datetime.now(timezone.utc))
+        )
+
+    async def get_winback_stats(self):
+        """Возвращает {stage: {event: count}} по всем событиям win-back."""
+        rows = await self._fetchall('SELECT stage, event, COUNT(*) FROM winback_events GROUP BY stage, event')
+        stats = {}
+        for stage, event, count in rows:
+            stats.setdefault(stage, {})[event] = count
+        return stats
+
+    # Методы для промокодов (promocodes)
+    async def add_promocode(self, code: str, user_id: int | None, discount_percent: int, source: str, expires_at: datetime):
+        await self._execute(
+            '''INSERT INTO promocodes (code, user_id, discount_percent, source, created_at, expires_at)
+               VALUES (?, ?, ?, ?, ?, ?)''',
+            (code, user_id, discount_percent, source, datetime.now(timezone.utc), expires_at)
+        )
+
+    async def get_promocode(self, code: str):
+        query = 'SELECT code, user_id, discount_percent, source, expires_at, used_at FROM promocodes WHERE code = ?'
+        return await self._fetchone(query, (code.upper(),))
+
+    async def get_user_promocode(self, user_id: int, code: str | None = None):
+        """
+        Неиспользованный действующий код пользователя для оплаты: (code, discount_percent) или None.
+        Без code выбирается код с наибольшей скидкой (из равных - тот, что истекает раньше).
+        """
+        query = '''
+            SELECT code, discount_percent FROM promocodes
+            WHERE user_id = ? AND used_at IS NULL AND expires_at > ? AND (? IS NULL OR code = ?)
+            ORDER BY discount_percent DESC, expires_at LIMIT 1
+        '''
+        code = code.upper() if code else None
+        return await self._fetchone(query, (user_id, datetime.now(timezone.utc), code, code))
+
+    async def mark_promocode_used(self, code: str):
+        await self._execute('UPDATE promocodes SET used_at = ? WHERE code = ?', (datetime.now(timezone.utc), code.upper()))
+
+    async def add_promocodes(self, codes: list[tuple], source: str) -> int:
+        """Добавляет коды (code, user_id, discount_percent, expires_at), пропуская уже существующие. Возвращает число добавленных."""
+        now_utc = datetime.now(timezone.utc)
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.executemany(
+                '''INSERT OR IGNORE INTO promocodes (code, user_id, discount_percent, source, created_at, expires_at)
+                   VALUES (?, ?, ?, ?, ?, ?)''',
+                [(code, user_id, discount, source, now_utc, expires_at) for code, user_id, discount, expires_at in codes]
+            )
+            await db.commit()
+            return cursor.rowcount
+
+    async def get_unused_promocodes(self, source: str | None = None):
+        """Неиспользованные действующие коды: (code, discount_percent, expires_at, user_id, source)."""
+        query = '''
+            SELECT code, discount_percent, expires_at, user_id, source FROM promocodes
+            WHERE used_at IS NULL AND expires_at > ? AND (? IS NULL OR source = ?)
+            ORDER BY created_at, code
+        '''
+        return await self._fetchall(query, (datetime.now(timezone.utc), source, source))
+
+    # Методы для опросов (surveys, survey_responses)
+    async def create_survey(self, question: str, kind: str, options: list[str] | None = None) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO surveys (question, kind, options, created_at) VALUES (?, ?, ?, ?)',
+                (question, kind, '\n'.join(options) if options else None, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_survey(self, survey_id: int):
+        """Возвращает (id, question, kind, options_list)."""
+        result = await self._fetchone('SELECT id, question, kind, options FROM surveys WHERE id = ?', (survey_id,))
+        if not result:
+            return None
+        return result[0], result[1], result[2], result[3].split('\n') if result[3] else []
+
+    async def get_surveys(self, limit: int = 5):
+        return await self._fetchall('SELECT id, question, kind FROM surveys ORDER BY id DESC LIMIT ?', (limit,))
+
+    async def add_survey_response(self, survey_id: int, user_id: int, answer: str) -> bool:
+        """Сохраняет ответ. Возвращает False, если пользователь уже отвечал на этот опрос."""
+        if await self._fetchone('SELECT id FROM survey_responses WHERE survey_id = ? AND user_id = ?', (survey_id, user_id)):
+            return False
+        await self._execute(
+            'INSERT INTO survey_responses (survey_id, user_id, answer, created_at) VALUES (?, ?, ?, ?)',
+            (survey_id, user_id, answer, datetime.now(timezone.utc))
+        )
+        return True
+
+    async def get_survey_answer_counts(self, survey_id: int):
+        query = 'SELECT answer, COUNT(*) FROM survey_responses WHERE survey_id = ? GROUP BY answer ORDER BY COUNT(*) DESC'
+        return await self._fetchall(query, (survey_id,))
+
+    async def get_survey_text_answers(self, survey_id: int, limit: int = 10):
+        query = 'SELECT answer FROM survey_responses WHERE survey_id = ? ORDER BY id DESC LIMIT ?'
+        return [row[0] for row in await self._fetchall(query, (survey_id, limit))]
+
+    async def get_segment_user_ids(self, segment: str):
+        """Возвращает ID незаблокированных пользователей сегмента: all, free, paid, active."""
+        now_iso = datetime.now(timezone.utc).isoformat()
+        week_ago = datetime.now(MSK_TZ).date() - timedelta(days=7)
+        conditions = {
+            'all': ('1 = 1', ()),
+            'free': ('(subscription_level = 0 OR subscription_end < ?)', (now_iso,)),
+            'paid': ('subscription_level > 0 AND subscription_end >= ?', (now_iso,)),
+            'active': ('user_id IN (SELECT user_id FROM requests WHERE request_date >= ?)', (week_ago,)),
+        }
+        condition, params = conditions[segment]
+        rows = await self._fetchall(f'SELECT user_id FROM users WHERE is_blocked = 0 AND {condition} ORDER BY user_id', params)
+        return [row[0] for row in rows]
+
+    # Методы для истории ответов в группах (group_history)
+    async def add_group_history(self, chat_id: int, user_id: int, model: str, prompt: str, response: str | None = None, message_id: int | None = None) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''INSERT INTO group_history (chat_id, user_id, model, prompt, response, message_id, created_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?)''',
+                (chat_id, user_id, model, prompt, response, message_id, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def update_group_history(self, history_id: int, response: str, message_id: int):
+        await self._execute(
+            'UPDATE group_history SET response = ?, message_id = ? WHERE id = ?', (response, message_id, history_id)
+        )
+
+    # Методы для жалоб на ответы (abuse_reports)
+    async def add_abuse_report(self, history_id: int, reporter_id: int) -> bool:
+        """Добавляет жалобу. Возвращает False, если пользователь уже жаловался на этот ответ."""
+        if await self._fetchone('SELECT id FROM abuse_reports WHERE history_id = ? AND reporter_id = ?', (history_id, reporter_id)):
+            return False
+        await self._execute(
+            'INSERT INTO abuse_reports (history_id, reporter_id, created_at) VALUES (?, ?, ?)',
+            (history_id, reporter_id, datetime.now(timezone.utc))
+        )
+        return True
+
+    _REPORT_QUERY = '''
+        SELECT r.id, r.reporter_id, h.chat_id, h.user_id, h.model, h.prompt, h.response, h.message_id,
+               (SELECT COUNT(*) FROM abuse_reports r2 WHERE r2.history_id = r.history_id)
+        FROM abuse_reports r
+        JOIN group_history h ON h.id = r.history_id
+    '''
+
+    async def get_report(self, report_id: int):
+        """Возвращает (report_id, reporter_id, chat_id, author_id, model, prompt, response, message_id, reports_count)."""
+        return await self._fetchone(self._REPORT_QUERY + 'WHERE r.id = ?', (report_id,))
+
+    async def get_next_open_report(self):
+        """Возвращает самую старую открытую жалобу в том же формате, что и get_report."""
+        return await self._fetchone(self._REPORT_QUERY + "WHERE r.status = 'open' ORDER BY r.id LIMIT 1")
+
+    async def get_open_reports_count(self) -> int:
+        result = await self._fetchone("SELECT COUNT(*) FROM abuse_reports WHERE status = 'open'")
+        return result[0] if result else 0
+
+    async def resolve_report(self, report_id: int, status: str, admin_id: int):
+        """Закрывает жалобу и все остальные открытые жалобы на тот же ответ."""
+        query = '''
+            UPDATE abuse_reports SET status = ?, resolved_by = ?, resolved_at = ?
+            WHERE status = 'open' AND history_id = (SELECT history_id FROM abuse_reports WHERE id = ?)
+        '''
+        await self._execute(query, (status, admin_id, datetime.now(timezone.utc), report_id))
+
+    # Методы для ссылок на беседы (shared_conversations)
+    async def add_shared_conversation(self, token: str, user_id: int, model: str, messages: str):
+        await self._execute(
+            'INSERT INTO shared_conversations (token, user_id, model, messages, created_at) VALUES (?, ?, ?, ?, ?)',
+            (token, user_id, model, messages, datetime.now(timezone.utc))
+        )
+
+    async def get_shared_conversation(self, token: str):
+        """Возвращает (user_id, model, messages, created_at) действующей ссылки и увеличивает счетчик просмотров."""
+        result = await self._fetchone(
+            'SELECT user_id, model, messages, created_at FROM shared_conversations WHERE token = ? AND revoked_at IS NULL',
+            (token,)
+        )
+        if result:
+            await self._execute('UPDATE shared_conversations SET views = views + 1 WHERE token = ?', (token,))
+        return result
+
+    async def get_user_shared_conversations(self, user_id: int, limit: int = 10):
+        """Возвращает действующие ссылки пользователя: (token, model, views, created_at)."""
+        query = '''
+            SELECT token, model, views, created_at FROM shared_conversations
+            WHERE user_id = ? AND revoked_at IS NULL ORDER BY created_at DESC LIMIT ?
+        '''
+        return await self._fetchall(query, (user_id, limit))
+
+    async def revoke_shared_conversation(self, token: str, user_id: int) -> bool:
+        """Отзывает ссылку. Возвращает False, если ссылки нет, она чужая или уже отозвана."""
+        if not await self._fetchone(
+            'SELECT token FROM shared_conversations WHERE token = ? AND user_id = ? AND revoked_at IS NULL', (token, user_id)
+        ):
+            return False
+        await self._execute(
+            'UPDATE shared_conversations SET revoked_at = ? WHERE token = ?', (datetime.now(timezone.utc), token)
+        )
+        return True
+
+    # Методы для персональных токенов API (api_tokens)
+    async def set_api_token(self, user_id: int, token_hash: str):
+        """Сохраняет токен пользователя, заменяя предыдущий."""
+        query = '''
+            INSERT INTO api_tokens (user_id, token_hash, created_at, last_used_at) VALUES (?, ?, ?, NULL)
+            ON CONFLICT(user_id) DO UPDATE SET
+                token_hash = excluded.token_hash, created_at = excluded.created_at, last_used_at = NULL
+        '''
+        await self._execute(query, (user_id, token_hash, datetime.now(timezone.utc)))
+
+    async def get_api_token_info(self, user_id: int):
+        """Возвращает (created_at, last_used_at) или None, если токена нет."""
+        return await self._fetchone('SELECT created_at, last_used_at FROM api_tokens WHERE user_id = ?', (user_id,))
+
+    async def get_user_id_by_api_token(self, token_hash: str) -> int | None:
+        """Находит владельца токена и отмечает время использования."""
+        result = await self._fetchone('SELECT user_id FROM api_tokens WHERE token_hash = ?', (token_hash,))
+        if not result:
+            return None
+        await self._execute('UPDATE api_tokens SET last_used_at = ? WHERE user_id = ?', (datetime.now(timezone.utc), result[0]))
+        return result[0]
+
+    async def delete_api_token(self, user_id: int) -> bool:
+        if not await self._fetchone('SELECT user_id FROM api_tokens WHERE user_id = ?', (user_id,)):
+            return False
+        await self._execute('DELETE FROM api_tokens WHERE user_id = ?', (user_id,))
+        return True
+
+    # Методы для входящих вебхуков (webhooks)
+    async def add_webhook(self, user_id: int, name: str, token_hash: str) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO webhooks (user_id, name, token_hash, created_at) VALUES (?, ?, ?, ?)',
+                (user_id, name, token_hash, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_webhook_by_token(self, token_hash: str):
+        """Возвращает (id, user_id, name, summarize)."""
+        return await self._fetchone('SELECT id, user_id, name, summarize FROM webhooks WHERE token_hash = ?', (token_hash,))
+
+    async def get_user_webhooks(self, user_id: int):
+        """Возвращает список (id, name, summarize, deliveries, last_used_at)."""
+        return await self._fetchall(
+            'SELECT id, name, summarize, deliveries, last_used_at FROM webhooks WHERE user_id = ? ORDER BY id', (user_id,)
+        )
+
+    async def toggle_webhook_summary(self, webhook_id: int, user_id: int) -> bool:
+        """Переключает краткое изложение. Возвращает False, если вебхука нет или он чужой."""
+        if not await self._fetchone('SELECT id FROM webhooks WHERE id = ? AND user_id = ?', (webhook_id, user_id)):
+            return False
+        await self._execute('UPDATE webhooks SET summarize = 1 - summarize WHERE id = ?', (webhook_id,))
+        return True
+
+    async def delete_webhook(self, webhook_id: int, user_id: int) -> bool:
+        if not await self._fetchone('SELECT id FROM webhooks WHERE id = ? AND user_id = ?', (webhook_id, user_id)):
+            return False
+        await self._execute('DELETE FROM webhooks WHERE id = ?', (webhook_id,))
+        return True
 
+    async def mark_webhook_delivered(self, webhook_id: int):
+        await self._execute(
+            'UPDATE webhooks SET deliveries = deliveries + 1, last_used_at = ? WHERE id = ?',
+            (datetime.now(timezone.utc), webhook_id)
+        )
+
+    # Методы для внешних серверов инструментов (tool_servers)
+    async def save_tool_server(self, name: str, url: str, auth_token: str | None, tools: str):
+        """Добавляет сервер или обновляет адрес, токен и список инструментов существующего."""
+        now_utc = datetime.now(timezone.utc)
+        query = '''
+            INSERT INTO tool_servers (name, url, auth_token, tools, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(name) DO UPDATE SET
+                url = excluded.url, auth_token = excluded.auth_token, tools = excluded.tools, updated_at = excluded.updated_at
+        '''
+        await self._execute(query, (name, url, auth_token, tools, now_utc, now_utc))
+
+    async def get_tool_server(self, name: str):
+        """Возвращает (name, url, auth_token, tools, enabled)."""
+        return await self._fetchone('SELECT name, url, auth_token, tools, enabled FROM tool_servers WHERE name = ?', (name,))
+
+    async def get_tool_servers(self, enabled_only: bool = False):
+        """Возвращает список (name, url, auth_token, tools, enabled)."""
+        query = 'SELECT name, url, auth_token, tools, enabled FROM tool_servers'
+        if enabled_only:
+            query += ' WHERE enabled = 1'
+        return await self._fetchall(query + ' ORDER BY name')
+
+    async def set_tool_server_enabled(self, name: str, enabled: bool) -> bool:
+        if not await self._fetchone('SELECT id FROM tool_servers WHERE name = ?', (name,)):
+            return False
+        await self._execute('UPDATE tool_servers SET enabled = ? WHERE name = ?', (int(enabled), name))
+        return True
+
+    async def delete_tool_server(self, name: str) -> bool:
+        if not await self._fetchone('SELECT id FROM tool_servers WHERE name = ?', (name,)):
+            return False
+        await self._execute('DELETE FROM tool_servers WHERE name = ?', (name,))
+        return True
+
+    # Методы для запланированных запросов (scheduled_prompts)
+    async def add_scheduled_prompt(self, user_id: int, prompt: str, model: str, weekdays: str | None,
+                                   hour: int, minute: int, next_run_at: datetime) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''INSERT INTO scheduled_prompts (user_id, prompt, model, weekdays, hour, minute, next_run_at, created_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?)''',
+                (user_id, prompt, model, weekdays, hour, minute, next_run_at, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_user_scheduled_prompts(self, user_id: int):
+        """Возвращает список (id, prompt, model, weekdays, hour, minute, enabled, next_run_at)."""
+        query = '''
+            SELECT id, prompt, model, weekdays, hour, minute, enabled, next_run_at
+            FROM scheduled_prompts WHERE user_id = ? ORDER BY id
+        '''
+        return await self._fetchall(query, (user_id,))
+
+    async def get_scheduled_prompt(self, prompt_id: int, user_id: int):
+        """Возвращает (id, prompt, model, weekdays, hour, minute, enabled, next_run_at) или None, если запрос чужой."""
+        query = '''
+            SELECT id, prompt, model, weekdays, hour, minute, enabled, next_run_at
+            FROM scheduled_prompts WHERE id = ? AND user_id = ?
+        '''
+        return await self._fetchone(query, (prompt_id, user_id))
+
+    async def update_scheduled_prompt(self, prompt_id: int, prompt: str, weekdays: str | None,
+                                      hour: int, minute: int, next_run_at: datetime):
+        query = '''
+            UPDATE scheduled_prompts SET prompt = ?, weekdays = ?, hour = ?, minute = ?, next_run_at = ?, enabled = 1
+            WHERE id = ?
+        '''
+        await self._execute(query, (prompt, weekdays, hour, minute, next_run_at, prompt_id))
+
+    async def set_scheduled_prompt_enabled(self, prompt_id: int, enabled: bool, next_run_at: datetime | None):
+        await self._execute(
+            'UPDATE scheduled_prompts SET enabled = ?, next_run_at = ? WHERE id = ?',
+            (int(enabled), next_run_at, prompt_id)
+        )
+
+    async def delete_scheduled_prompt(self, prompt_id: int, user_id: int) -> bool:
+        if not await self.get_scheduled_prompt(prompt_id, user_id):
+            return False
+        await self._execute('DELETE FROM scheduled_prompts WHERE id = ?', (prompt_id,))
+        return True
+
+    async def get_due_scheduled_prompts(self, limit: int = 100):
+        """Возвращает включенные запросы, время которых наступило: (id, user_id, prompt, model, weekdays, hour, minute)."""
+        query = '''
+            SELECT id, user_id, prompt, model, weekdays, hour, minute FROM scheduled_prompts
+            WHERE enabled = 1 AND next_run_at <= ? ORDER BY next_run_at LIMIT ?
+        '''
+        return await self._fetchall(query, (datetime.now(timezone.utc), limit))
+
+    async def set_scheduled_prompt_run(self, prompt_id: int, next_run_at: datetime):
+        await self._execute(
+            'UPDATE scheduled_prompts SET last_run_at = ?, next_run_at = ? WHERE id = ?',
+            (datetime.now(timezone.utc), next_run_at, prompt_id)
+        )
+
+    # Методы для работы с историей проверок моделей (model_status_history)
+    async def add_model_status_history(self, statuses: dict):
+        """Сохраняет результаты одной проверки: {модель: статус}."""
+        now_utc = datetime.now(timezone.utc)
+        async with aiosqlite.connect(self.db_path) as db:
+            await db.executemany(
+                'INSERT INTO model_status_history (model, status, checked_at) VALUES (?, ?, ?)',
+                [(model, status, now_utc) for model, status in statuses.items()]
+            )
+            await db.commit()
+
+    async def get_recent_model_statuses(self, runs: int):
+        """Возвращает (model, status) за последние runs проверок, от новых к старым."""
+        query = '''
+            SELECT model, status FROM model_status_history
+            WHERE checked_at IN (SELECT DISTINCT checked_at FROM model_status_history ORDER BY checked_at DESC LIMIT ?)
+            ORDER BY checked_at DESC
+        '''
+        return await self._fetchall(query, (runs,))
+
+    async def delete_old_model_status_history(self, days: int):
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        await self._execute('DELETE FROM model_status_history WHERE checked_at < ?', (threshold,))
+
+    # Методы для платежей (payments)
+    async def record_payment(
+        self, user_id: int, level: int, days: int, amount: int, currency: str,
+        telegram_charge_id: str, provider_charge_id: str | None, metadata: str | None, promocode: str | None = None
+    ) -> tuple[bool, str | None]:
+        """
+        Записывает платеж и в той же транзакции продлевает подписку: при действующей подписке
+        того же уровня срок прибавляется к ее окончанию, иначе отсчитывается от текущего момента.
+        Промокод, по которому выставлен счет, отмечается использованным в той же транзакции.
+        Возвращает (новый ли платеж, subscription_end). Для уже записанного платежа подписка не меняется.
+        """
+        now_utc = datetime.now(timezone.utc)
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''INSERT OR IGNORE INTO payments
+                   (user_id, level, days, amount, currency, telegram_charge_id, provider_charge_id, metadata, created_at)
+                   VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)''',
+                (user_id, level, days, amount, currency, telegram_charge_id, provider_charge_id, metadata, now_utc)
+            )
+            async with db.execute('SELECT subscription_level, subscription_end FROM users WHERE user_id = ?', (user_id,)) as user_cursor:
+                current = await user_cursor.fetchone()
+            if cursor.rowcount == 0:
+                return False, current[1] if current else None
+
+            start = now_utc
+            if current and current[0] == level and current[1]:
+                try:
+                    start = max(now_utc, datetime.fromisoformat(current[1]))
+                except ValueError:
+                    pass
+            end_date = (start + timedelta(days=days)).isoformat()
+            await db.execute(
+                'UPDATE users SET subscription_level = ?, subscription_end = ? WHERE user_id = ?', (level, end_date, user_id)
+            )
+            if promocode:
+                await db.execute(
+                    'UPDATE promocodes SET used_at = ? WHERE code = ? AND used_at IS NULL', (now_utc, promocode.upper())
+                )
             await db.commit()
+            return True, end_date
 
-    async def create_tables(self):
-        """Создает таблицы, если они не существуют."""
-        await self._execute('''
-            CREATE TABLE IF NOT EXISTS users (
-                user_id INTEGER PRIMARY KEY,
-                username TEXT,
-                subscription_level INTEGER DEFAULT 0,
-                subscription_end TIMESTAMP,
-                is_blocked INTEGER DEFAULT 0,
-                is_verified INTEGER DEFAULT 0,
-                has_rewarded_bonus INTEGER DEFAULT 0,
-                last_used_model TEXT,
-                last_used_image_model TEXT,
-                user_instruction TEXT,
-                user_temperature REAL,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    async def get_payment_stats(self, days: int = 30) -> list:
+        """Сумма и число оплаченных платежей за период по валютам: (currency, count, amount)."""
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        query = '''
+            SELECT currency, COUNT(*), SUM(amount) FROM payments
+            WHERE status = 'paid' AND created_at >= ? GROUP BY currency
+        '''
+        return await self._fetchall(query, (threshold,))
+
+    async def get_payment(self, telegram_charge_id: str):
+        """Возвращает (id, user_id, level, days, amount, currency, status) или None."""
+        return await self._fetchone(
+            'SELECT id, user_id, level, days, amount, currency, status FROM payments WHERE telegram_charge_id = ?',
+            (telegram_charge_id,)
+        )
+
+    async def get_recent_payments(self, currency: str, limit: int = 10) -> list:
+        """Последние оплаченные платежи в валюте: (telegram_charge_id, user_id, level, amount, created_at)."""
+        query = '''
+            SELECT telegram_charge_id, user_id, level, amount, created_at FROM payments
+            WHERE currency = ? AND status = 'paid' ORDER BY id DESC LIMIT ?
+        '''
+        return await self._fetchall(query, (currency, limit))
+
+    async def refund_payment(self, telegram_charge_id: str) -> bool:
+        """
+        Помечает платеж возвращенным и в той же транзакции сокращает подписку на оплаченный срок
+        (если у пользователя все еще подписка того же уровня). Возвращает False, если платеж уже возвращен.
+        """
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                "UPDATE payments SET status = 'refunded' WHERE telegram_charge_id = ? AND status = 'paid'", (telegram_charge_id,)
             )
-        ''')
-        await self._execute('''
-            CREATE TABLE IF NOT EXISTS requests (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                user_id INTEGER,
-                model TEXT,
-                request_date DATE,
-                is_max_mode INTEGER DEFAULT 0, -- 0 for normal, 1 for max mode
-                FOREIGN KEY (user_id) REFERENCES users (user_id)
+            if cursor.rowcount == 0:
+                return False
+            async with db.execute(
+                '''SELECT p.user_id, p.level, p.days, u.subscription_level, u.subscription_end
+                   FROM payments p JOIN users u ON u.user_id = p.user_id WHERE p.telegram_charge_id = ?''',
+                (telegram_charge_id,)
+            ) as user_cursor:
+                row = await user_cursor.fetchone()
+            if row and row[3] == row[1] and row[4]:
+                try:
+                    end_date = datetime.fromisoformat(row[4]) - timedelta(days=row[2])
+                except ValueError:
+                    end_date = None
+                if end_date and end_date > datetime.now(timezone.utc):
+                    await db.execute('UPDATE users SET subscription_end = ? WHERE user_id = ?', (end_date.isoformat(), row[0]))
+                elif end_date:
+                    await db.execute('UPDATE users SET subscription_level = 0, subscription_end = NULL WHERE user_id = ?', (row[0],))
+            await db.commit()
+            return True
+
+    # Методы для хранилища состояний FSM (dialogue_states)
+    async def get_dialogue_state(self, key: str):
+        """Возвращает (state, data) или None."""
+        return await self._fetchone('SELECT state, data FROM dialogue_states WHERE key = ?', (key,))
+
+    async def set_dialogue_state(self, key: str, state: str | None):
+        query = '''
+            INSERT INTO dialogue_states (key, state, updated_at) VALUES (?, ?, ?)
+            ON CONFLICT(key) DO UPDATE SET state = excluded.state, updated_at = excluded.updated_at
+        '''
+        await self._execute(query, (key, state, datetime.now(timezone.utc)))
+
+    async def set_dialogue_data(self, key: str, data: str):
+        query = '''
+            INSERT INTO dialogue_states (key, data, updated_at) VALUES (?, ?, ?)
+            ON CONFLICT(key) DO UPDATE SET data = excluded.data, updated_at = excluded.updated_at
+        '''
+        await self._execute(query, (key, data, datetime.now(timezone.utc)))
+
+    async def delete_stale_dialogue_states(self, days: int):
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        await self._execute('DELETE FROM dialogue_states WHERE updated_at < ?', (threshold,))
+
+    # Методы для журнала пропущенных обновлений (update_journal)
+    async def add_journal_update(self, update_id: int, kind: str, payload: str) -> bool:
+        """Сохраняет обновление. Возвращает False, если оно уже есть в журнале."""
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT OR IGNORE INTO update_journal (update_id, kind, payload, received_at) VALUES (?, ?, ?, ?)',
+                (update_id, kind, payload, datetime.now(timezone.utc))
             )
-        ''')
-        await self._execute('''
-            CREATE TABLE IF NOT EXISTS system_state (
-                key TEXT PRIMARY KEY,
-                value TEXT,
-                updated_at TIMESTAMP
+            await db.commit()
+            return cursor.rowcount > 0
+
+    async def get_pending_journal_updates(self):
+        """Возвращает необработанные обновления: (update_id, kind, payload)."""
+        query = 'SELECT update_id, kind, payload FROM update_journal WHERE replayed_at IS NULL ORDER BY update_id'
+        return await self._fetchall(query)
+
+    async def get_pending_journal_counts(self) -> dict:
+        query = 'SELECT kind, COUNT(*) FROM update_journal WHERE replayed_at IS NULL GROUP BY kind'
+        return dict(await self._fetchall(query))
+
+    async def mark_journal_update_replayed(self, update_id: int):
+        await self._execute(
+            'UPDATE update_journal SET replayed_at = ? WHERE update_id = ?', (datetime.now(timezone.utc), update_id)
+        )
+
+    # Методы для работы с беседами чата (conversations, messages)
+    async def create_conversation(self, user_id: int, model: str | None, parent_id: int | None = None) -> int:
+        now_utc = datetime.now(timezone.utc)
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO conversations (user_id, model, parent_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?)',
+                (user_id, model, parent_id, now_utc, now_utc)
             )
-        ''')
+            await db.commit()
+            return cursor.lastrowid
 
-    async def init_db(self):
-        """Инициализирует БД: создает таблицы и запускает миграции."""
-        await self.create_tables()
-        await self._run_migrations()
+    async def get_conversation(self, conversation_id: int, user_id: int):
+        """Возвращает (id, model, parent_id, updated_at) беседы пользователя или None."""
+        query = 'SELECT id, model, parent_id, updated_at FROM conversations WHERE id = ? AND user_id = ?'
+        return await self._fetchone(query, (conversation_id, user_id))
 
-    # Методы для работы с системным состоянием
-    async def get_system_state(self, key: str):
-        query = 'SELECT value, updated_at FROM system_state WHERE key = ?'
-        return await self._fetchone(query, (key,))
+    async def get_latest_conversation(self, user_id: int):
+        query = 'SELECT id, model, parent_id, updated_at FROM conversations WHERE user_id = ? ORDER BY updated_at DESC, id DESC LIMIT 1'
+        return await self._fetchone(query, (user_id,))
 
-    async def set_system_state(self, key: str, value: str):
+    async def add_conversation_messages(self, conversation_id: int, messages: list):
+        """Добавляет сообщения ({"role", "content", "message_id"}) в конец беседы."""
         now_utc = datetime.now(timezone.utc)
+        async with aiosqlite.connect(self.db_path) as db:
+            await db.executemany(
+                'INSERT INTO messages (conversation_id, role, content, message_id, created_at) VALUES (?, ?, ?, ?, ?)',
+                [(conversation_id, m['role'], m['content'], m.get('message_id'), now_utc) for m in messages]
+            )
+            await db.execute('UPDATE conversations SET updated_at = ? WHERE id = ?', (now_utc, conversation_id))
+            await db.commit()
+
+    async def get_conversation_messages(self, conversation_id: int, limit: int, up_to_id: int | None = None):
+        """Последние limit сообщений беседы (до сообщения up_to_id включительно): (role, content, message_id), от старых к новым."""
         query = '''
-            INSERT INTO system_state (key, value, updated_at) VALUES (?, ?, ?)
-            ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at
+            SELECT role, content, message_id FROM (
+                SELECT id, role, content, message_id FROM messages
+                WHERE conversation_id = ? AND id <= ? ORDER BY id DESC LIMIT ?
+            ) ORDER BY id
         '''
-        await self._execute(query, (key, value, now_utc))
+        return await self._fetchall(query, (conversation_id, up_to_id if up_to_id is not None else 2 ** 63 - 1, limit))
+
+    async def get_last_conversation_message(self, conversation_id: int):
+        """Последнее сообщение беседы: (id, role, content, message_id) или None."""
+        query = 'SELECT id, role, content, message_id FROM messages WHERE conversation_id = ? ORDER BY id DESC LIMIT 1'
+        return await self._fetchone(query, (conversation_id,))
+
+    async def update_conversation_message(self, row_id: int, content: str, message_id: int):
+        await self._execute('UPDATE messages SET content = ?, message_id = ? WHERE id = ?', (content, message_id, row_id))
+
+    async def get_conversation_tail(self, conversation_id: int, limit: int):
+        """Последние limit сообщений беседы без текста: (id, role, message_id), от новых к старым."""
+        query = 'SELECT id, role, message_id FROM messages WHERE conversation_id = ? ORDER BY id DESC LIMIT ?'
+        return await self._fetchall(query, (conversation_id, limit))
+
+    async def truncate_conversation(self, conversation_id: int, from_id: int):
+        """Удаляет из беседы сообщение from_id и все сообщения после него."""
+        await self._execute('DELETE FROM messages WHERE conversation_id = ? AND id >= ?', (conversation_id, from_id))
+
+    async def find_conversation_message(self, user_id: int, message_id: int, preferred_conversation_id: int | None = None):
+        """Ищет сообщение беседы по message_id в Telegram, в первую очередь в preferred_conversation_id: (conversation_id, id)."""
+        query = '''
+            SELECT m.conversation_id, m.id FROM messages m JOIN conversations c ON c.id = m.conversation_id
+            WHERE c.user_id = ? AND m.message_id = ?
+            ORDER BY m.conversation_id = ? DESC, m.id DESC LIMIT 1
+        '''
+        return await self._fetchone(query, (user_id, message_id, preferred_conversation_id))
+
+    async def delete_old_conversations(self, user_id: int, keep_count: int, keep_ids: tuple = ()):
+        """
+        Оставляет keep_count последних бесед пользователя (и беседы keep_ids), удаляя остальные вместе с сообщениями.
+        Закрепленные беседы не удаляются и в keep_count не считаются.
+        """
+        rows = await self._fetchall(
+            'SELECT id FROM conversations WHERE user_id = ? AND pinned = 0 ORDER BY updated_at DESC, id DESC', (user_id,)
+        )
+        stale_ids = [row[0] for row in rows[keep_count:] if row[0] not in keep_ids]
+        if not stale_ids:
+            return
+        placeholders = ','.join('?' * len(stale_ids))
+        async with aiosqlite.connect(self.db_path) as db:
+            await db.execute(f'DELETE FROM messages WHERE conversation_id IN ({placeholders})', stale_ids)
+            await db.execute(f'DELETE FROM conversations WHERE id IN ({placeholders})', stale_ids)
+            await db.commit()
+
+    async def set_conversation_pinned(self, conversation_id: int, user_id: int, pinned: bool, max_pinned: int) -> bool:
+        """Закрепляет или открепляет беседу. Возвращает False, если закреплено уже max_pinned бесед."""
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''
+                UPDATE conversations SET pinned = ? WHERE id = ? AND user_id = ?
+                AND (? = 0 OR pinned = 1 OR (SELECT COUNT(*) FROM conversations WHERE user_id = ? AND pinned = 1) < ?)
+                ''',
+                (int(pinned), conversation_id, user_id, int(pinned), user_id, max_pinned)
+            )
+            await db.commit()
+            return cursor.rowcount > 0
+
+    async def is_conversation_pinned(self, conversation_id: int, user_id: int) -> bool:
+        result = await self._fetchone('SELECT pinned FROM conversations WHERE id = ? AND user_id = ?', (conversation_id, user_id))
+        return bool(result and result[0])
+
+    async def get_pinned_conversations(self, user_id: int):
+        """Закрепленные беседы: (id, model, первый запрос), сначала недавние."""
+        query = '''
+            SELECT c.id, c.model, (
+                SELECT content FROM messages WHERE conversation_id = c.id AND role = 'user' ORDER BY id LIMIT 1
+            ) FROM conversations c
+            WHERE c.user_id = ? AND c.pinned = 1 ORDER BY c.updated_at DESC, c.id DESC
+        '''
+        return await self._fetchall(query, (user_id,))
+
+    # Методы для сгенерированных изображений (image_generations)
+    async def add_image_generation(self, user_id: int, model: str, prompt: str, width: int, height: int) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO image_generations (user_id, model, prompt, width, height, created_at) VALUES (?, ?, ?, ?, ?, ?)',
+                (user_id, model, prompt, width, height, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_image_generation(self, generation_id: int, user_id: int):
+        """Возвращает (model, prompt, width, height) или None."""
+        return await self._fetchone(
+            'SELECT model, prompt, width, height FROM image_generations WHERE id = ? AND user_id = ?', (generation_id, user_id)
+        )
+
+    # Методы для стилей изображений (image_styles)
+    async def add_image_style(self, user_id: int, name: str, suffix: str):
+        await self._execute(
+            'INSERT INTO image_styles (user_id, name, suffix, created_at) VALUES (?, ?, ?, ?)',
+            (user_id, name, suffix, datetime.now(timezone.utc))
+        )
+
+    async def get_image_styles(self, user_id: int):
+        """Возвращает список (id, name, suffix) в порядке добавления."""
+        return await self._fetchall('SELECT id, name, suffix FROM image_styles WHERE user_id = ? ORDER BY id', (user_id,))
+
+    async def get_image_style(self, style_id: int, user_id: int):
+        """Возвращает (name, suffix) или None, если стиля нет или он чужой."""
+        return await self._fetchone('SELECT name, suffix FROM image_styles WHERE id = ? AND user_id = ?', (style_id, user_id))
+
+    async def delete_image_style(self, style_id: int, user_id: int) -> bool:
+        if not await self.get_image_style(style_id, user_id):
+            return False
+        await self._execute('DELETE FROM image_styles WHERE id = ?', (style_id,))
+        return True
+
+    # Методы для запусков Max Mode (max_mode_runs)
+    async def add_max_mode_run(
+        self, user_id: int, prompt: str, participants: str, arbiter: str, arbiter_output: str | None,
+        arbiter_duration: float | None, total_duration: float, cost: float, status: str
+    ) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''
+                INSERT INTO max_mode_runs (user_id, prompt, participants, arbiter, arbiter_output, arbiter_duration,
+                                           total_duration, cost, status, created_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ''',
+                (user_id, prompt, participants, arbiter, arbiter_output, arbiter_duration,
+                 total_duration, cost, status, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_max_mode_run(self, run_id: int):
+        """Возвращает (id, user_id, prompt, participants, arbiter, arbiter_output, arbiter_duration, total_duration, cost, status, created_at)."""
+        return await self._fetchone(
+            '''
+            SELECT id, user_id, prompt, participants, arbiter, arbiter_output, arbiter_duration,
+                   total_duration, cost, status, created_at
+            FROM max_mode_runs WHERE id = ?
+            ''', (run_id,)
+        )
+
+    async def get_max_mode_runs(self, user_id: int | None = None, limit: int = 10):
+        """Последние запуски (id, user_id, prompt, total_duration, status, created_at), всех или одного пользователя."""
+        if user_id is None:
+            return await self._fetchall(
+                'SELECT id, user_id, prompt, total_duration, status, created_at FROM max_mode_runs ORDER BY id DESC LIMIT ?',
+                (limit,)
+            )
+        return await self._fetchall(
+            '''
+            SELECT id, user_id, prompt, total_duration, status, created_at FROM max_mode_runs
+            WHERE user_id = ? ORDER BY id DESC LIMIT ?
+            ''', (user_id, limit)
+        )
+
+    async def delete_old_max_mode_runs(self, days: int):
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        await self._execute('DELETE FROM max_mode_runs WHERE created_at < ?', (threshold,))
+
+    # Методы для событий аналитики (events)
+    async def add_events(self, events: list):
+        """Записывает пачку событий [(event, user_id, props, created_at)] одной транзакцией."""
+        async with aiosqlite.connect(self.db_path) as db:
+            await db.executemany('INSERT INTO events (event, user_id, props, created_at) VALUES (?, ?, ?, ?)', events)
+            await db.commit()
+
+    async def get_event_counts(self, days: int, limit: int) -> list:
+        """Самые частые события за days дней: [(event, количество, уникальных пользователей)]."""
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        query = '''
+            SELECT event, COUNT(*), COUNT(DISTINCT user_id) FROM events
+            WHERE created_at >= ? GROUP BY event ORDER BY COUNT(DISTINCT user_id) DESC, COUNT(*) DESC LIMIT ?
+        '''
+        return await self._fetchall(query, (threshold, limit))
+
+    async def get_first_event_times(self, events: list, days: int) -> list:
+        """Когда каждый пользователь впервые за days дней выполнил каждое из событий: [(user_id, event, время)]."""
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        placeholders = ', '.join('?' * len(events))
+        query = f'''
+            SELECT user_id, event, MIN(created_at) FROM events
+            WHERE event IN ({placeholders}) AND created_at >= ? AND user_id IS NOT NULL
+            GROUP BY user_id, event
+        '''
+        return await self._fetchall(query, (*events, threshold))
+
+    async def delete_old_events(self, days: int):
+        threshold = datetime.now(timezone.utc) - timedelta(days=days)
+        await self._execute('DELETE FROM events WHERE created_at < ?', (threshold,))
+
+    # Методы для избранных ответов (favorites)
+    async def add_favorite(self, user_id: int, model: str | None, prompt: str, content: str, max_favorites: int) -> int | None:
+        """Сохраняет ответ в избранное. Возвращает id записи или None, если избранное заполнено."""
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''
+                INSERT INTO favorites (user_id, model, prompt, content, created_at)
+                SELECT ?, ?, ?, ?, ? WHERE (SELECT COUNT(*) FROM favorites WHERE user_id = ?) < ?
+                ''',
+                (user_id, model, prompt, content, datetime.now(timezone.utc), user_id, max_favorites)
+            )
+            await db.commit()
+            return cursor.lastrowid if cursor.rowcount else None
+
+    async def has_favorite(self, user_id: int, content: str) -> bool:
+        return await self._fetchone('SELECT 1 FROM favorites WHERE user_id = ? AND content = ?', (user_id, content)) is not None
+
+    async def get_favorites(self, user_id: int):
+        """Избранные ответы: (id, model, prompt, created_at), сначала новые."""
+        return await self._fetchall(
+            'SELECT id, model, prompt, created_at FROM favorites WHERE user_id = ? ORDER BY id DESC', (user_id,)
+        )
+
+    async def get_favorite(self, favorite_id: int, user_id: int):
+        """Возвращает (model, prompt, content) или None."""
+        return await self._fetchone(
+            'SELECT model, prompt, content FROM favorites WHERE id = ? AND user_id = ?', (favorite_id, user_id)
+        )
+
+    async def delete_favorite(self, favorite_id: int, user_id: int) -> bool:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute('DELETE FROM favorites WHERE id = ? AND user_id = ?', (favorite_id, user_id))
+            await db.commit()
+            return cursor.rowcount > 0
+
+    # Методы для работы со списком изменений (changelog)
+    async def add_changelog_entry(self, text: str) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO changelog (text, created_at) VALUES (?, ?)',
+                (text, datetime.now(timezone.utc))
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def delete_changelog_entry(self, entry_id: int) -> bool:
+        if not await self._fetchone('SELECT id FROM changelog WHERE id = ?', (entry_id,)):
+            return False
+        await self._execute('DELETE FROM changelog WHERE id = ?', (entry_id,))
+        return True
+
+    async def get_changelog(self, limit: int = 5):
+        return await self._fetchall('SELECT id, text, created_at FROM changelog ORDER BY id DESC LIMIT ?', (limit,))
+
+    async def get_changelog_since(self, entry_id: int, limit: int = 5):
+        """Записи новее entry_id (не больше limit последних) в порядке добавления."""
+        query = 'SELECT id, text FROM changelog WHERE id > ? ORDER BY id DESC LIMIT ?'
+        return list(reversed(await self._fetchall(query, (entry_id, limit))))
+
+    async def get_latest_changelog_id(self) -> int:
+        result = await self._fetchone('SELECT MAX(id) FROM changelog')
+        return result[0] if result and result[0] else 0
+
+    async def has_unseen_changelog(self, user_id: int) -> bool:
+        query = '''
+            SELECT 1 FROM changelog
+            WHERE id > (SELECT COALESCE(last_seen_changelog_id, 0) FROM users WHERE user_id = ?)
+            LIMIT 1
+        '''
+        return await self._fetchone(query, (user_id,)) is not None
+
+    async def set_last_seen_changelog_id(self, user_id: int, entry_id: int):
+        await self._execute('UPDATE users SET last_seen_changelog_id = ? WHERE user_id = ?', (entry_id, user_id))
 
     # Методы для работы с пользователями (users)
     async def add_user(self, user_id, username):
@@ -115,9 +1525,10 @@ class Database:
                  await self._execute('UPDATE users SET username = ? WHERE user_id = ?', (username.lower() if username else None, user_id))
             return False
         else:
+            # Новому пользователю прошлые изменения не показываются как новые
             await self._execute(
-                'INSERT INTO users (user_id, username, created_at) VALUES (?, ?, ?)',
-                (user_id, username.lower() if username else None, datetime.now(timezone.utc))
+                'INSERT INTO users (user_id, username, created_at, last_seen_changelog_id) VALUES (?, ?, ?, ?)',
+                (user_id, username.lower() if username else None, datetime.now(timezone.utc), await self.get_latest_changelog_id())
             )
             return True
 
@@ -128,7 +1539,7 @@ class Database:
         query = '''
             SELECT user_id, username, subscription_level, subscription_end, is_blocked,
                    last_used_model, created_at, is_verified, has_rewarded_bonus,
-                   last_used_image_model, user_instruction, user_temperature
+                   last_used_image_model, user_instruction, user_temperature, last_image_size
             FROM users
             WHERE user_id = ?
         '''
@@ -151,12 +1562,98 @@ class Database:
     async def set_last_used_image_model(self, user_id, model_name):
         await self._execute('UPDATE users SET last_used_image_model = ? WHERE user_id = ?', (model_name, user_id))
 
+    async def set_last_image_size(self, user_id: int, size: str):
+        await self._execute('UPDATE users SET last_image_size = ? WHERE user_id = ?', (size, user_id))
+
+    async def get_last_model_category(self, user_id: int) -> str | None:
+        result = await self._fetchone('SELECT last_model_category FROM users WHERE user_id = ?', (user_id,))
+        return result[0] if result else None
+
+    async def set_last_model_category(self, user_id: int, category: str):
+        await self._execute('UPDATE users SET last_model_category = ? WHERE user_id = ?', (category, user_id))
+
+    async def get_chat_mode(self, user_id: int) -> str | None:
+        result = await self._fetchone('SELECT chat_mode FROM users WHERE user_id = ?', (user_id,))
+        return result[0] if result else None
+
+    async def set_chat_mode(self, user_id: int, mode: str | None):
+        await self._execute('UPDATE users SET chat_mode = ? WHERE user_id = ?', (mode, user_id))
+
+    async def get_max_mode_instruction(self, user_id: int) -> str | None:
+        result = await self._fetchone('SELECT max_mode_instruction FROM users WHERE user_id = ?', (user_id,))
+        return result[0] if result else None
+
+    async def set_max_mode_instruction(self, user_id: int, mode: str | None):
+        await self._execute('UPDATE users SET max_mode_instruction = ? WHERE user_id = ?', (mode, user_id))
+
     async def set_user_instruction(self, user_id, instruction):
         await self._execute('UPDATE users SET user_instruction = ? WHERE user_id = ?', (instruction, user_id))
 
     async def set_user_temperature(self, user_id, temperature):
         await self._execute('UPDATE users SET user_temperature = ? WHERE user_id = ?', (temperature, user_id))
 
+    # Методы для истории настроек (settings_history)
+    async def add_settings_history(self, user_id: int, setting: str, value: str | None, keep_count: int):
+        """Запоминает прежнее значение настройки, оставляя у пользователя keep_count последних записей."""
+        await self._execute(
+            'INSERT INTO settings_history (user_id, setting, value, changed_at) VALUES (?, ?, ?, ?)',
+            (user_id, setting, value, datetime.now(timezone.utc))
+        )
+        await self._execute('''
+            DELETE FROM settings_history WHERE user_id = ? AND id NOT IN (
+                SELECT id FROM settings_history WHERE user_id = ? ORDER BY id DESC LIMIT ?
+            )
+        ''', (user_id, user_id, keep_count))
+
+    async def has_settings_history(self, user_id: int) -> bool:
+        return await self._fetchone('SELECT 1 FROM settings_history WHERE user_id = ? LIMIT 1', (user_id,)) is not None
+
+    async def pop_settings_history(self, user_id: int):
+        """Извлекает (и удаляет) последнюю запись истории: (setting, value) или None."""
+        async with aiosqlite.connect(self.db_path) as db:
+            async with db.execute(
+                'SELECT id, setting, value FROM settings_history WHERE user_id = ? ORDER BY id DESC LIMIT 1', (user_id,)
+            ) as cursor:
+                row = await cursor.fetchone()
+            if not row:
+                return None
+            cursor = await db.execute('DELETE FROM settings_history WHERE id = ?', (row[0],))
+            await db.commit()
+            # Двойное нажатие кнопки не должно вернуть сразу две записи
+            return (row[1], row[2]) if cursor.rowcount else None
+
+    # Методы для тихих часов и отложенных уведомлений
+    async def get_notification_settings(self, user_id):
+        """Возвращает (quiet_start, quiet_end, utc_offset). quiet_start = None — тихие часы отключены."""
+        return await self._fetchone('SELECT quiet_start, quiet_end, utc_offset FROM users WHERE user_id = ?', (user_id,))
+
+    async def set_quiet_hours(self, user_id, start: int | None, end: int | None):
+        await self._execute('UPDATE users SET quiet_start = ?, quiet_end = ? WHERE user_id = ?', (start, end, user_id))
+
+    async def set_utc_offset(self, user_id, offset: int):
+        await self._execute('UPDATE users SET utc_offset = ? WHERE user_id = ?', (offset, user_id))
+
+    async def add_pending_notification(
+        self, user_id: int, text: str, reply_markup: str | None, send_after: datetime, media: tuple[str, str] | None = None
+    ):
+        media_type, media_file_id = media or (None, None)
+        await self._execute(
+            '''INSERT INTO pending_notifications (user_id, text, reply_markup, send_after, created_at, media_type, media_file_id)
+               VALUES (?, ?, ?, ?, ?, ?, ?)''',
+            (user_id, text, reply_markup, send_after, datetime.now(timezone.utc), media_type, media_file_id)
+        )
+
+    async def get_due_notifications(self, limit: int = 200):
+        """(id, user_id, text, reply_markup, media_type, media_file_id)"""
+        query = '''
+            SELECT id, user_id, text, reply_markup, media_type, media_file_id FROM pending_notifications
+            WHERE send_after <= ? ORDER BY id LIMIT ?
+        '''
+        return await self._fetchall(query, (datetime.now(timezone.utc), limit))
+
+    async def delete_pending_notification(self, notification_id: int):
+        await self._execute('DELETE FROM pending_notifications WHERE id = ?', (notification_id,))
+
     async def block_user(self, user_id, block=True):
         await self._execute('UPDATE users SET is_blocked = ? WHERE user_id = ?', (1 if block else 0, user_id))
 
@@ -164,12 +1661,42 @@ class Database:
         await self._execute('UPDATE users SET is_verified = ? WHERE user_id = ?', (1 if status else 0, user_id))
 
     async def set_reward_bonus(self, user_id):
-        await self._execute('UPDATE users SET has_rewarded_bonus = 1 WHERE user_id = ?', (user_id,))
+        await self._execute('UPDATE users SET has_rewarded_bonus = 1, reward_revoke_at = NULL WHERE user_id = ?', (user_id,))
+
+    async def get_rewarded_users(self):
+        """Получившие бонус за каналы: (user_id, reward_revoke_at)."""
+        return await self._fetchall('SELECT user_id, reward_revoke_at FROM users WHERE has_rewarded_bonus = 1')
+
+    async def get_reward_revoke_at(self, user_id: int):
+        result = await self._fetchone('SELECT reward_revoke_at FROM users WHERE user_id = ?', (user_id,))
+        return result[0] if result else None
+
+    async def set_reward_revoke_at(self, user_id: int, revoke_at: datetime | None):
+        await self._execute('UPDATE users SET reward_revoke_at = ? WHERE user_id = ?', (revoke_at, user_id))
+
+    async def revoke_reward_bonus(self, user_id: int):
+        await self._execute('UPDATE users SET has_rewarded_bonus = 0, reward_revoke_at = NULL WHERE user_id = ?', (user_id,))
 
     async def get_all_user_ids(self):
         rows = await self._fetchall('SELECT user_id FROM users')
         return [row[0] for row in rows]
 
+    async def get_user_ids_after(self, last_user_id: int, limit: int = 100):
+        rows = await self._fetchall(
+            'SELECT user_id FROM users WHERE user_id > ? ORDER BY user_id LIMIT ?', (last_user_id, limit)
+        )
+        return [row[0] for row in rows]
+
+    async def get_recently_active_user_ids(self, days: int = 1, limit: int = 500):
+        """Возвращает ID пользователей, делавших запросы за последние days дней, начиная с самых активных."""
+        since = datetime.now(MSK_TZ).date() - timedelta(days=days)
+        query = '''
+            SELECT user_id FROM requests WHERE request_date >= ?
+            GROUP BY user_id ORDER BY COUNT(*) DESC LIMIT ?
+        '''
+        rows = await self._fetchall(query, (since, limit))
+        return [row[0] for row in rows]
+
     async def get_users_paginated(self, page: int = 1, page_size: int = 1):
         offset = (page - 1) * page_size
         query = 'SELECT user_id FROM users ORDER BY created_at DESC LIMIT ? OFFSET ?'
@@ -198,10 +1725,229 @@ class Database:
         )
         return result[0] if result else 0
 
-    async def add_request(self, user_id, model, is_max_mode=False):
-        """Добавляет запись о новом запросе."""
+    async def get_user_tokens_today(self, user_id: int, is_max_mode: bool, default_tokens: int) -> int:
+        """
+        Сумма токенов обычных или Max Mode запросов за сегодня. Запросы без данных о токенах
+        (генерация изображений, ответы без usage) считаются за default_tokens.
+        """
+        today = datetime.now(MSK_TZ).date()
+        result = await self._fetchone(
+            '''
+            SELECT COALESCE(SUM(CASE WHEN prompt_tokens + completion_tokens > 0
+                                     THEN prompt_tokens + completion_tokens ELSE ? END), 0)
+            FROM requests WHERE user_id = ? AND request_date = ? AND is_max_mode = ?
+            ''',
+            (default_tokens, user_id, today, 1 if is_max_mode else 0)
+        )
+        return result[0] if result else 0
+
+    async def get_public_stats(self):
+        """Сводка за сегодня (МСК): (запросов, пользователей с запросами, самая популярная модель или None)."""
+        today = datetime.now(MSK_TZ).date()
+        totals = await self._fetchone(
+            'SELECT COUNT(*), COUNT(DISTINCT user_id) FROM requests WHERE request_date = ?', (today,)
+        )
+        top_model = await self._fetchone('''
+            SELECT model FROM requests WHERE request_date = ? AND is_max_mode = 0
+            GROUP BY model ORDER BY COUNT(*) DESC, model LIMIT 1
+        ''', (today,))
+        return totals[0], totals[1], top_model[0] if top_model else None
+
+    # Методы для ручной корректировки лимитов
+    async def get_quota_overrides(self, user_id: int):
+        """Возвращает (custom_daily_limit, bonus_max_runs, extra_requests_today, bonus_max_runs_used_today)."""
+        today = datetime.now(MSK_TZ).date()
+        query = '''
+            SELECT u.custom_daily_limit, u.bonus_max_runs, COALESCE(e.amount, 0),
+                   (SELECT COUNT(*) FROM requests r
+                    WHERE r.user_id = u.user_id AND r.request_date = ? AND r.is_max_mode = 1 AND r.is_bonus = 1)
+            FROM users u
+            LEFT JOIN extra_requests e ON e.user_id = u.user_id AND e.request_date = ?
+            WHERE u.user_id = ?
+        '''
+        return await self._fetchone(query, (today, today, user_id))
+
+    async def set_custom_daily_limit(self, user_id: int, limit: int | None):
+        await self._execute('UPDATE users SET custom_daily_limit = ? WHERE user_id = ?', (limit, user_id))
+
+    async def add_extra_requests_today(self, user_id: int, amount: int):
+        today = datetime.now(MSK_TZ).date()
+        query = '''
+            INSERT INTO extra_requests (user_id, request_date, amount) VALUES (?, ?, ?)
+            ON CONFLICT(user_id, request_date) DO UPDATE SET amount = amount + excluded.amount
+        '''
+        await self._execute(query, (user_id, today, amount))
+
+    async def add_bonus_max_runs(self, user_id: int, amount: int):
+        await self._execute('UPDATE users SET bonus_max_runs = bonus_max_runs + ? WHERE user_id = ?', (amount, user_id))
+
+    async def verify_new_user(self, user_id: int, welcome_max_runs: int) -> bool:
+        """
+        Отмечает пользователя проверенным и в том же запросе выдает пробные запуски Max Mode.
+        Возвращает False, если пользователь уже был проверен (тогда бонус не выдается повторно).
+        """
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'UPDATE users SET is_verified = 1, bonus_max_runs = COALESCE(bonus_max_runs, 0) + ? WHERE user_id = ? AND is_verified = 0',
+                (welcome_max_runs, user_id)
+            )
+            await db.commit()
+            return cursor.rowcount > 0
+
+    async def consume_bonus_max_run(self, user_id: int):
+        await self._execute(
+            'UPDATE users SET bonus_max_runs = bonus_max_runs - 1 WHERE user_id = ? AND bonus_max_runs > 0', (user_id,)
+        )
+
+    # Методы для бета-тестирования моделей (users.is_beta_tester, beta_feedback)
+    async def set_beta_tester(self, user_id: int, is_tester: bool):
+        await self._execute('UPDATE users SET is_beta_tester = ? WHERE user_id = ?', (1 if is_tester else 0, user_id))
+
+    async def is_beta_tester(self, user_id: int) -> bool:
+        result = await self._fetchone('SELECT is_beta_tester FROM users WHERE user_id = ?', (user_id,))
+        return bool(result and result[0])
+
+    async def get_beta_testers(self) -> list:
+        """Возвращает [(user_id, username)]."""
+        return await self._fetchall('SELECT user_id, username FROM users WHERE is_beta_tester = 1 ORDER BY user_id')
+
+    async def save_beta_feedback(self, user_id: int, model: str, score: int, message_id: int):
+        await self._execute('''
+            INSERT INTO beta_feedback (user_id, model, score, message_id, created_at) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(user_id, message_id) DO UPDATE SET score = excluded.score, created_at = excluded.created_at
+        ''', (user_id, model, score, message_id, datetime.now(timezone.utc)))
+
+    async def get_beta_model_stats(self, model: str) -> tuple:
+        """
+        Метрики бета-модели отдельно от общей статистики:
+        (запросов тестировщиков, тестировщиков с запросами, 👍, 👎, проверок с ошибкой, всего проверок).
+        """
+        usage = await self._fetchone('''
+            SELECT COUNT(*), COUNT(DISTINCT r.user_id) FROM requests r
+            JOIN users u ON u.user_id = r.user_id
+            WHERE r.model = ? AND u.is_beta_tester = 1
+        ''', (model,))
+        feedback = await self._fetchone('''
+            SELECT COALESCE(SUM(score = 1), 0), COALESCE(SUM(score = -1), 0) FROM beta_feedback WHERE model = ?
+        ''', (model,))
+        checks = await self._fetchone('''
+            SELECT COALESCE(SUM(status != 'OK'), 0), COUNT(*) FROM model_status_history WHERE model = ?
+        ''', (model,))
+        return (*usage, *feedback, *checks)
+
+    # Методы для журнала действий администраторов (admin_audit_log)
+    async def add_audit_log(self, admin_id: int, action: str, target_user_id: int | None = None, details: str | None = None):
+        await self._execute(
+            'INSERT INTO admin_audit_log (admin_id, action, target_user_id, details, created_at) VALUES (?, ?, ?, ?, ?)',
+            (admin_id, action, target_user_id, details, datetime.now(timezone.utc))
+        )
+
+    # Методы для подтверждения опасных действий вторым администратором (admin_confirmations)
+    async def create_admin_confirmation(self, action: str, payload: str, requested_by: int, expires_at: datetime) -> int:
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                'INSERT INTO admin_confirmations (action, payload, requested_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?)',
+                (action, payload, requested_by, datetime.now(timezone.utc), expires_at)
+            )
+            await db.commit()
+            return cursor.lastrowid
+
+    async def get_admin_confirmation(self, confirmation_id: int):
+        """Возвращает (id, action, payload, requested_by, status, expires_at)."""
+        query = 'SELECT id, action, payload, requested_by, status, expires_at FROM admin_confirmations WHERE id = ?'
+        return await self._fetchone(query, (confirmation_id,))
+
+    async def resolve_admin_confirmation(self, confirmation_id: int, status: str, admin_id: int) -> bool:
+        """Закрывает ожидающий и не просроченный запрос чужого администратора. False, если закрыть нельзя."""
+        now_utc = datetime.now(timezone.utc)
+        async with aiosqlite.connect(self.db_path) as db:
+            cursor = await db.execute(
+                '''UPDATE admin_confirmations SET status = ?, confirmed_by = ?, resolved_at = ?
+                   WHERE id = ? AND status = 'pending' AND requested_by != ? AND expires_at > ?''',
+                (status, admin_id, now_utc, confirmation_id, admin_id, now_utc)
+            )
+            await db.commit()
+            return cursor.rowcount > 0
+
+    async def get_audit_log(self, target_user_id: int | None = None, limit: int = 10):
+        if target_user_id is None:
+            query = 'SELECT admin_id, action, target_user_id, details, created_at FROM admin_audit_log ORDER BY id DESC LIMIT ?'
+            return await self._fetchall(query, (limit,))
+        query = '''
+            SELECT admin_id, action, target_user_id, details, created_at FROM admin_audit_log
+            WHERE target_user_id = ? ORDER BY id DESC LIMIT ?
+        '''
+        return await self._fetchall(query, (target_user_id, limit))
+
+    async def get_group_requests_today(self, chat_id: int):
+        """Получает количество запросов, сделанных в группе за сегодня."""
+        today = datetime.now(MSK_TZ).date()
+        result = await self._fetchone(
+            'SELECT COUNT(*) FROM requests WHERE chat_id = ? AND request_date = ?', (chat_id, today)
+        )
+        return result[0] if result else 0
+
+    async def add_request(self, user_id, model, is_max_mode=False, chat_id=None, is_bonus=False, tokens=(0, 0, 0.0)):
+        """
+        Добавляет запись о новом запросе. chat_id указывается для запросов из групп,
+        tokens - (prompt_tokens, completion_tokens, cost) из ответа API.
+        """
         today = datetime.now(MSK_TZ).date()
+        prompt_tokens, completion_tokens, cost = tokens
         await self._execute(
-            'INSERT INTO requests (user_id, model, request_date, is_max_mode) VALUES (?, ?, ?, ?)',
-            (user_id, model, today, 1 if is_max_mode else 0)
-        )
\ No newline at end of file
+            '''
+            INSERT INTO requests (user_id, model, request_date, is_max_mode, chat_id, is_bonus, prompt_tokens, completion_tokens, cost)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ''',
+            (user_id, model, today, 1 if is_max_mode else 0, chat_id, 1 if is_bonus else 0, prompt_tokens, completion_tokens, cost)
+        )
+
+    async def get_token_usage(self, user_id: int | None = None, days: int = 1) -> tuple[int, int]:
+        """(prompt_tokens, completion_tokens) за последние days дней (МСК, включая сегодня), одного пользователя или всех."""
+        since = datetime.now(MSK_TZ).date() - timedelta(days=days - 1)
+        query = 'SELECT COALESCE(SUM(prompt_tokens), 0), COALESCE(SUM(completion_tokens), 0) FROM requests WHERE request_date >= ?'
+        params = (since,)
+        if user_id is not None:
+            query += ' AND user_id = ?'
+            params += (user_id,)
+        return await self._fetchone(query, params)
+
+    async def get_model_usage(self, user_id: int, limit: int = 10) -> list:
+        """Обычные (не Max Mode) запросы пользователя по моделям за все время: [(model, запросов)], частые первыми."""
+        query = '''
+            SELECT model, COUNT(*) FROM requests WHERE user_id = ? AND is_max_mode = 0
+            GROUP BY model ORDER BY COUNT(*) DESC, model LIMIT ?
+        '''
+        return await self._fetchall(query, (user_id, limit))
+
+    async def get_spend_by_model(self, user_id: int | None = None, days: int | None = None) -> list:
+        """
+        Расходы по моделям: [(model, запросов, prompt_tokens, completion_tokens, cost)], дорогие первыми.
+        days=None - за все время.
+        """
+        query = '''
+            SELECT model, COUNT(*), COALESCE(SUM(prompt_tokens), 0), COALESCE(SUM(completion_tokens), 0), COALESCE(SUM(cost), 0)
+            FROM requests WHERE 1 = 1
+        '''
+        params = ()
+        if user_id is not None:
+            query += ' AND user_id = ?'
+            params += (user_id,)
+        if days is not None:
+            query += ' AND request_date >= ?'
+            params += (datetime.now(MSK_TZ).date() - timedelta(days=days - 1),)
+        query += ' GROUP BY model ORDER BY SUM(cost) DESC, COUNT(*) DESC'
+        return await self._fetchall(query, params)
+
+    async def get_top_spenders(self, days: int | None = None, limit: int = 10) -> list:
+        """Пользователи с наибольшими расходами: [(user_id, username, запросов, cost)]. days=None - за все время."""
+        query = '''
+            SELECT r.user_id, u.username, COUNT(*), COALESCE(SUM(r.cost), 0)
+            FROM requests r LEFT JOIN users u ON u.user_id = r.user_id
+        '''
+        params = ()
+        if days is not None:
+            query += ' WHERE r.request_date >= ?'
+            params += (datetime.now(MSK_TZ).date() - timedelta(days=days - 1),)
+        query += ' GROUP BY r.user_id ORDER BY SUM(r.cost) DESC LIMIT ?'
+        return await self._fetchall(query, params + (limit,))
\ No newline at end of file
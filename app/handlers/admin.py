@@ -1,32 +1,71 @@
 # app/handlers/admin.py
 
-import asyncio
+import html
+import json
 import logging
+import time
 from datetime import datetime, timezone
 
-from aiogram import F, Router, Bot
-from aiogram.filters import BaseFilter, StateFilter
+from aiogram import F, Router, Bot, Dispatcher
+from aiogram.filters import BaseFilter, StateFilter, Command, CommandObject
 from aiogram.fsm.context import FSMContext
-from aiogram.types import Message, CallbackQuery
+from aiogram.types import Message, CallbackQuery, BufferedInputFile
 from aiogram.utils.markdown import hcode
 from aiogram.exceptions import TelegramForbiddenError, TelegramBadRequest
 
 from app.database import Database
-from app.config import ADMIN_IDS, MSK_TZ
+from app.config import (
+    ADMIN_IDS, MSK_TZ, ANNOUNCEMENT_BANNER_MAX_LENGTH, BETA_MODELS, PROMO_IMPORT_MAX_BYTES, CHAOS_MAX_MINUTES,
+    ANALYTICS_FUNNELS, ANALYTICS_REPORT_DAYS, ANALYTICS_TOP_EVENTS, ANALYTICS_SURVEYS, PLAN_NAMES,
+    get_model_display_name
+)
 from app.states import Admin as AdminState
 # --- ИЗМЕНЕНИЕ: импортируем новые классы ---
-from app.keyboards.callbacks import Menu, AdminMenu, AdminUserAction, AdminUserBrowse
+from app.keyboards.callbacks import (
+    Menu, AdminMenu, AdminUserAction, AdminUserBrowse, ModerationAction, AdminConfirmation, AdminBroadcast
+)
 from app.keyboards.inline import (
     get_admin_menu, get_admin_users_menu, get_user_card_menu, 
-    get_user_browse_menu, get_back_to_admin_menu
+    get_user_browse_menu, get_back_to_admin_menu, get_moderation_menu, get_broadcast_preview_menu
 )
 from app.services.user_service import (
-    get_user_id_from_input, invalidate_user_cache, get_user_limits
+    get_user_id_from_input, invalidate_user_cache, get_user_limits, get_usage_today
+)
+from app.services.system_service import (
+    LOG_LEVELS, set_log_level, get_log_levels, set_announcement_banner, get_announcement_text, get_announcement_banner
+)
+from app.services.broadcast_service import (
+    MEDIA_NAMES, schedule_broadcast, extract_broadcast_content, get_content_media
+)
+from app.services.notification_service import send_content
+from app.services.winback_service import mark_winback_conversion
+from app.services.admin_report_service import collect_admin_stats, format_stats_html, send_admin_report
+from app.services.survey_service import SEGMENTS, send_survey, format_survey_results
+from app.services.prompt_suite_service import run_prompt_suite, load_suite
+from app.services.crypto_service import decrypt_field
+from app.services.confirmation_service import (
+    ACTION_NAMES, is_two_person_rule_active, request_confirmation, resolve_confirmation
+)
+from app.services.payment_service import format_amount, refund_star_payment
+from app.services.text_service import TELEGRAM_MESSAGE_LIMIT, split_text, split_message, format_token_usage, format_cost, format_quota
+from app.services.ai_service import CHAOS_MODES, set_chaos, get_chaos
+from app.services.analytics_service import flush_events, get_funnel, format_funnel
+from app.services.format_service import format_date, format_datetime
+from app.services.promocode_service import import_promocodes, export_promocodes_csv
+from app.services.update_journal_service import UPDATE_KINDS, replay_journal
+from app.services.trace_service import get_trace, normalize_request_id
+from app.services.tool_service import (
+    SERVER_NAME_RE, ToolServerError, register_tool_server, set_tool_server_enabled, delete_tool_server
 )
 
 logger = logging.getLogger(__name__)
 router = Router()
 
+BROADCAST_PROMPT = (
+    "Отправьте сообщение для рассылки: текст или фото, видео, GIF, документ с подписью. "
+    "Форматирование сохранится. Перед отправкой всем пользователям рассылка придет вам как тестовая."
+)
+
 # --- Фильтр для проверки прав администратора ---
 class IsAdmin(BaseFilter):
     async def __call__(self, event: Message | CallbackQuery) -> bool:
@@ -43,24 +82,22 @@ async def format_user_card(user_id: int, db: Database) -> tuple[str, AdminUserAc
         return f"Пользователь с ID {user_id} не найден в базе.", None
         
     (uid, uname, s_level, s_end, blocked, last_model, created, verified, 
-     rewarded, last_image_model, user_instr, user_temp) = details
+     rewarded, last_image_model, user_instr, user_temp, _) = details
      
-    plan_name = {0: "Free", 1: "Standard", 2: "Premium", 3: "Max"}[s_level]
+    plan_name = PLAN_NAMES[s_level]
     if s_level == 0 and rewarded:
         plan_name = "Free (Бонусный)"
         
     s_end_str = "N/A"
     if s_end and s_level > 0:
         try:
-            s_end_dt = datetime.fromisoformat(s_end).astimezone(MSK_TZ)
-            s_end_str = s_end_dt.strftime('%Y-%m-%d %H:%M')
+            s_end_str = format_datetime(s_end)
         except (ValueError, TypeError): pass
     
-    created_dt = datetime.fromisoformat(created).astimezone(MSK_TZ)
-    created_str = created_dt.strftime('%Y-%m-%d %H:%M')
+    created_str = format_datetime(created)
     
-    requests_today = await db.get_user_requests_today(uid)
-    max_requests_today = await db.get_user_requests_today(uid, is_max_mode=True)
+    used_today = await get_usage_today(uid, db)
+    max_used_today = await get_usage_today(uid, db, is_max_mode=True)
     daily_limit, max_limit = await get_user_limits(uid, db)
     
     text = [
@@ -70,10 +107,18 @@ async def format_user_card(user_id: int, db: Database) -> tuple[str, AdminUserAc
         f"<b>Статус:</b> {'❌ Заблокирован' if blocked else '✅ Активен'}",
         f"<b>Верификация:</b> {'✅ Пройдена' if verified else '❌ Не пройдена'}",
         f"<b>План:</b> {plan_name} (до {s_end_str})" if s_level > 0 else f"<b>План:</b> {plan_name}",
-        f"<b>Запросы сегодня:</b> {requests_today}/{daily_limit if daily_limit != float('inf') else '∞'}",
+        f"<b>Израсходовано сегодня:</b> {format_quota(used_today)} / {format_quota(daily_limit)}",
     ]
-    if s_level == 3:
-        text.append(f"<b>Max запросы сегодня:</b> {max_requests_today}/{max_limit if max_limit != float('inf') else '∞'}")
+    if s_level == 3 or max_limit > 0:
+        text.append(f"<b>Max Mode сегодня:</b> {format_quota(max_used_today)} / {format_quota(max_limit)}")
+
+    custom_daily_limit, bonus_max_runs, extra_today, _ = await db.get_quota_overrides(uid)
+    if custom_daily_limit is not None:
+        text.append(f"<b>Свой дневной лимит:</b> {format_quota(custom_daily_limit)}")
+    if extra_today:
+        text.append(f"<b>Доп. запросы сегодня:</b> +{extra_today}")
+    if bonus_max_runs:
+        text.append(f"<b>Разовые запуски Max Mode:</b> {bonus_max_runs}")
     
     text.extend([
         f"<b>Последняя модель:</b> {hcode(last_model or 'N/A')}",
@@ -83,6 +128,25 @@ async def format_user_card(user_id: int, db: Database) -> tuple[str, AdminUserAc
     keyboard = get_user_card_menu(user_id=uid, is_blocked=bool(blocked))
     return "\n".join(text), keyboard
 
+async def format_next_report(db: Database):
+    """Возвращает текст и клавиатуру для самой старой открытой жалобы."""
+    report = await db.get_next_open_report()
+    if not report:
+        return "✅ Открытых жалоб нет.", get_back_to_admin_menu()
+
+    report_id, reporter_id, chat_id, author_id, model, prompt, response, _, reports_count = report
+    open_count = await db.get_open_reports_count()
+    text = (
+        f"<b>⚠️ Жалоба #{report_id}</b> (в очереди: {open_count})\n\n"
+        f"<b>Группа:</b> {hcode(str(chat_id))}\n"
+        f"<b>Автор запроса:</b> {hcode(str(author_id))}\n"
+        f"<b>Пожаловался:</b> {hcode(str(reporter_id))} (всего жалоб на ответ: {reports_count})\n"
+        f"<b>Модель:</b> {hcode(model)}\n\n"
+        f"<b>Запрос:</b>\n{hcode((prompt or '')[:1000])}\n\n"
+        f"<b>Ответ:</b>\n{hcode((response or '')[:2000])}"
+    )
+    return text, get_moderation_menu(report_id)
+
 # --- Основные меню админ-панели ---
 @router.callback_query(Menu.filter(F.action == 'admin'))
 async def admin_main_menu(callback: CallbackQuery):
@@ -119,6 +183,52 @@ async def find_user_process(message: Message, state: FSMContext, db: Database):
     await message.answer(card_text, reply_markup=card_keyboard)
 
 # --- Действия из карточки пользователя ---
+# Ручная корректировка лимитов: действие -> текст запроса значения у администратора
+QUOTA_ACTIONS = {
+    'extra_today': "Сколько дополнительных запросов выдать пользователю {user_id} на сегодня?",
+    'custom_limit': "Отправьте персональный дневной лимит для пользователя {user_id}. Чтобы вернуть лимит тарифа, отправьте `-` (минус).",
+    'bonus_max': "Сколько разовых запусков Max Mode выдать пользователю {user_id}?"
+}
+
+@router.callback_query(AdminUserAction.filter(F.action.in_(QUOTA_ACTIONS)))
+async def quota_action_start(callback: CallbackQuery, callback_data: AdminUserAction, state: FSMContext):
+    await callback.answer()
+    await state.set_state(AdminState.waiting_for_quota_value)
+    await state.update_data(quota_user_id=callback_data.user_id, quota_action=callback_data.action)
+    await callback.message.edit_text(QUOTA_ACTIONS[callback_data.action].format(user_id=callback_data.user_id))
+
+@router.message(AdminState.waiting_for_quota_value)
+async def quota_action_process(message: Message, state: FSMContext, db: Database):
+    data = await state.get_data()
+    await state.clear()
+    user_id, action = data['quota_user_id'], data['quota_action']
+    value_str = message.text.strip()
+
+    try:
+        value = None if action == 'custom_limit' and value_str == '-' else int(value_str)
+        if value is not None and value <= 0:
+            raise ValueError
+    except ValueError:
+        await message.answer("Неверное значение. Нужно целое положительное число.", reply_markup=get_back_to_admin_menu())
+        return
+
+    if action == 'extra_today':
+        await db.add_extra_requests_today(user_id, value)
+        result_text = f"Пользователю {user_id} выдано +{value} запросов на сегодня."
+    elif action == 'custom_limit':
+        await db.set_custom_daily_limit(user_id, value)
+        result_text = (f"Для пользователя {user_id} установлен дневной лимит {value}." if value is not None
+                       else f"Для пользователя {user_id} восстановлен лимит тарифа.")
+    else:
+        await db.add_bonus_max_runs(user_id, value)
+        result_text = f"Пользователю {user_id} выдано разовых запусков Max Mode: {value}."
+
+    await db.add_audit_log(message.from_user.id, action, user_id, value_str)
+    logger.info(f"Admin {message.from_user.id} applied {action}={value_str} to user {user_id}")
+    card_text, card_keyboard = await format_user_card(user_id, db)
+    await message.answer(f"✅ {result_text}")
+    await message.answer(card_text, reply_markup=card_keyboard)
+
 @router.callback_query(AdminUserAction.filter())
 async def handle_user_action(callback: CallbackQuery, callback_data: AdminUserAction, db: Database, cache: dict, bot: Bot):
     user_id = callback_data.user_id
@@ -134,6 +244,7 @@ async def handle_user_action(callback: CallbackQuery, callback_data: AdminUserAc
         action_func, success_msg = actions[action]
         await action_func(user_id)
         invalidate_user_cache(user_id, cache)
+        await db.add_audit_log(callback.from_user.id, action, user_id)
         await callback.answer(success_msg, show_alert=True)
         
         # Обновляем карточку
@@ -205,6 +316,8 @@ async def grant_sub_process(message: Message, state: FSMContext, db: Database, c
         if user_id:
             await db.update_subscription(user_id, level, days=days)
             invalidate_user_cache(user_id, cache)
+            await db.add_audit_log(message.from_user.id, 'grant', user_id, f"level={level} days={days}")
+            await mark_winback_conversion(db, user_id)
             await message.answer(f'Подписка уровня {level} на {days} дней выдана пользователю {target_input}.', reply_markup=get_back_to_admin_menu())
             logger.info(f"Admin {message.from_user.id} granted level {level} for {days} days to user {user_id}")
         else:
@@ -218,33 +331,783 @@ async def admin_main_actions(callback: CallbackQuery, callback_data: AdminMenu,
     action = callback_data.action
     if action == 'stats':
         await callback.answer()
-        total_users = await db.get_user_count()
-        stats = await db.get_subscription_stats()
-        text = (f'<b>📊 Статистика:</b>\n\nВсего пользователей: {total_users}\n'
-                f' • Free: {stats.get(0, 0)}\n • Standard: {stats.get(1, 0)}\n'
-                f' • Premium: {stats.get(2, 0)}\n • Max: {stats.get(3, 0)}')
+        text = format_stats_html("📊 Статистика:", await collect_admin_stats(db, cache))
+        text += "\n\nРасходы по моделям: /spend. Отправить ежедневный отчет сейчас: /dailyreport"
         await callback.message.edit_text(text, reply_markup=get_back_to_admin_menu())
+    elif action == 'analytics':
+        await callback.answer()
+        await flush_events(db, cache) # Чтобы в отчет попали последние события
+        funnels = [
+            format_funnel(name, steps, await get_funnel(db, steps, ANALYTICS_REPORT_DAYS))
+            for name, steps in ANALYTICS_FUNNELS.items()
+        ]
+        event_lines = "\n".join(
+            f' • {event}: {users} польз. ({count} раз)'
+            for event, count, users in await db.get_event_counts(ANALYTICS_REPORT_DAYS, ANALYTICS_TOP_EVENTS)
+        ) or ' • событий нет'
+        survey_results = '\n\n'.join([
+            await format_survey_results(db, survey_id) for survey_id, _, _ in await db.get_surveys(limit=ANALYTICS_SURVEYS)
+        ]) or ' • опросов нет'
+        text = (f'<b>📈 Аналитика за {ANALYTICS_REPORT_DAYS} дней</b>\n\n<b>Воронки:</b>\n' + '\n\n'.join(funnels)
+                + f'\n\n<b>Частые события:</b>\n{event_lines}\n\n<b>Последние опросы:</b>\n{survey_results}')
+        # Ответы опросов со свободным текстом могут не поместиться в одно сообщение
+        first_chunk, *other_chunks = split_message(text)
+        await callback.message.edit_text(first_chunk, reply_markup=get_back_to_admin_menu())
+        for chunk in other_chunks:
+            await callback.message.answer(chunk)
     elif action == 'report':
         await callback.answer()
         report_text = cache.get("model_status", {}).get("last_report", "Отчет еще не был сгенерирован.")
         await callback.message.edit_text(report_text, reply_markup=get_back_to_admin_menu())
+    elif action == 'reports':
+        await callback.answer()
+        text, keyboard = await format_next_report(db)
+        await callback.message.edit_text(text, reply_markup=keyboard)
+    elif action == 'surveys':
+        await callback.answer()
+        surveys = await db.get_surveys(limit=5)
+        if surveys:
+            results = [await format_survey_results(db, survey_id) for survey_id, _, _ in surveys]
+            text = '<b>📋 Последние опросы:</b>\n\n' + '\n\n'.join(results)
+        else:
+            text = '<b>📋 Опросов пока нет.</b>'
+        text += ('\n\nСоздать: <code>/survey_add ВОПРОС | ВАРИАНТ | ВАРИАНТ</code>\n'
+                 'NPS: <code>/survey_add nps ВОПРОС</code>, свободный ответ: <code>/survey_add ВОПРОС</code>\n'
+                 f'Отправить: <code>/survey_send ID СЕГМЕНТ</code> ({", ".join(SEGMENTS)})')
+        await callback.message.edit_text(text, reply_markup=get_back_to_admin_menu())
     elif action == 'broadcast':
         await callback.answer()
         await state.set_state(AdminState.waiting_for_broadcast)
-        await callback.message.edit_text("Введите текст для рассылки. Он будет отправлен всем пользователям.")
+        await callback.message.edit_text(BROADCAST_PROMPT)
 
 @router.message(AdminState.waiting_for_broadcast)
 async def broadcast_process(message: Message, state: FSMContext, db: Database, bot: Bot):
+    content = extract_broadcast_content(message)
+    if not content:
+        await message.answer("❌ Такое сообщение разослать нельзя. Отправьте текст или фото, видео, GIF, документ.")
+        return
+
+    await state.set_state(AdminState.waiting_for_broadcast_confirm)
+    await state.update_data(broadcast=content, broadcast_tested=False)
+    await message.answer(
+        f"Сообщение получат все пользователи ({await db.get_user_count()}).\n\n"
+        "Сначала отправьте рассылку себе: она придет точно так же, как пользователям. "
+        "Отправить всем можно будет после проверки тестового сообщения.",
+        reply_markup=get_broadcast_preview_menu()
+    )
+
+async def send_broadcast_test(callback: CallbackQuery, content: dict, state: FSMContext, bot: Bot):
+    """Тестовая отправка рассылки администратору, который ее готовит, тем же способом, что и пользователям."""
+    await callback.message.edit_reply_markup(reply_markup=None)
+    try:
+        await send_content(bot, callback.from_user.id, content['text'], media=get_content_media(content))
+    except TelegramBadRequest as e:
+        # Например, подпись длиннее лимита Telegram: такую рассылку не получит никто
+        logger.warning(f"Broadcast test delivery to admin {callback.from_user.id} failed: {e}")
+        await callback.message.answer(
+            f"❌ Telegram не принял сообщение: {html.escape(e.message)}\n\nИсправьте рассылку и отправьте тест еще раз.",
+            reply_markup=get_broadcast_preview_menu()
+        )
+        return
+    await state.update_data(broadcast_tested=True)
+    await callback.message.answer(
+        "☝️ Так рассылку увидят пользователи. Проверьте текст, форматирование и вложение. Все верно?",
+        reply_markup=get_broadcast_preview_menu(tested=True)
+    )
+
+@router.callback_query(AdminBroadcast.filter(), StateFilter(AdminState.waiting_for_broadcast_confirm))
+async def broadcast_preview_handler(callback: CallbackQuery, callback_data: AdminBroadcast, state: FSMContext, db: Database, bot: Bot, scheduler):
+    data = await state.get_data()
+    content = data.get('broadcast')
+    if callback_data.action == 'send' and content and not data.get('broadcast_tested'):
+        await callback.answer("Сначала отправьте тестовое сообщение себе.", show_alert=True)
+        return
+    await callback.answer()
+    if callback_data.action == 'edit':
+        await state.set_state(AdminState.waiting_for_broadcast)
+        await callback.message.edit_text(BROADCAST_PROMPT)
+        return
+    if callback_data.action == 'test' and content:
+        await send_broadcast_test(callback, content, state, bot)
+        return
+
     await state.clear()
-    await message.answer("Начинаю рассылку...")
-    user_ids = await db.get_all_user_ids()
-    success_count, fail_count = 0, 0
-    for user_id in user_ids:
+    if callback_data.action == 'cancel' or not content:
+        await callback.message.edit_text("Рассылка отменена.", reply_markup=get_back_to_admin_menu())
+        return
+
+    if is_two_person_rule_active():
+        media = get_content_media(content)
+        attachment = f" (вложение: {MEDIA_NAMES[media[0]]})" if media else ""
+        confirmation_id = await request_confirmation(
+            bot, db, 'broadcast', content, callback.from_user.id,
+            f"Текст рассылки{attachment}:\n{html.escape(content['text'][:1000])}"
+        )
+        await callback.message.edit_text(
+            f"🔐 Рассылка будет запущена после подтверждения другим администратором (запрос #{confirmation_id}).",
+            reply_markup=get_back_to_admin_menu()
+        )
+        return
+
+    result = await execute_confirmed_action('broadcast', content, callback.from_user.id, db, bot, scheduler)
+    await callback.message.edit_text(result, reply_markup=get_back_to_admin_menu())
+
+@router.callback_query(AdminBroadcast.filter())
+async def broadcast_preview_expired(callback: CallbackQuery):
+    await callback.answer("Предпросмотр устарел. Начните рассылку заново.", show_alert=True)
+
+async def execute_confirmed_action(action: str, payload: dict, requested_by: int, db: Database, bot: Bot, scheduler) -> str:
+    """Выполняет опасное действие (сразу или после подтверждения) и возвращает текст о результате."""
+    if action == 'broadcast':
+        broadcast_id = await db.create_broadcast(requested_by, payload['text'], payload.get('media_type'), payload.get('media_file_id'))
+        schedule_broadcast(scheduler, bot, db, broadcast_id)
+        logger.info(f"Admin {requested_by} started broadcast #{broadcast_id}")
+        return f"Рассылка #{broadcast_id} запущена. Прогресс будет обновляться, отчет придет по завершении."
+
+    survey_id, segment = payload['survey_id'], payload['segment']
+    scheduler.add_job(
+        send_survey, args=(bot, db, survey_id, segment, requested_by),
+        id=f"survey_{survey_id}_{segment}", replace_existing=True
+    )
+    logger.info(f"Admin {requested_by} sent survey #{survey_id} to segment '{segment}'")
+    return f"Опрос #{survey_id} отправляется: {SEGMENTS[segment]}. Отчет придет по завершении."
+
+@router.callback_query(AdminConfirmation.filter())
+async def admin_confirmation_handler(callback: CallbackQuery, callback_data: AdminConfirmation, db: Database, bot: Bot, scheduler):
+    admin_id = callback.from_user.id
+    result, confirmation = await resolve_confirmation(db, callback_data.confirmation_id, admin_id, callback_data.action == 'approve')
+    errors = {
+        'not_found': "Запрос не найден.",
+        'own': "Свой запрос должен подтвердить другой администратор.",
+        'expired': "Время на подтверждение истекло. Пусть администратор повторит действие.",
+        'resolved': "Запрос уже рассмотрен другим администратором.",
+    }
+    if result in errors:
+        await callback.answer(errors[result], show_alert=True)
+        return
+
+    confirmation_id, action, payload, requested_by, _, _ = confirmation
+    if result == 'approved':
+        outcome = await execute_confirmed_action(action, json.loads(payload), requested_by, db, bot, scheduler)
+        verdict = f"✅ Подтверждено администратором {hcode(admin_id)}."
+    else:
+        outcome = "Действие не выполнено."
+        verdict = f"❌ Отклонено администратором {hcode(admin_id)}."
+    await callback.answer()
+    await callback.message.edit_text(f"{callback.message.html_text}\n\n{verdict}")
+    try:
+        await bot.send_message(requested_by, f"🔐 Запрос #{confirmation_id} ({ACTION_NAMES[action]}): {verdict}\n{outcome}")
+    except Exception as e:
+        logger.warning(f"Failed to notify admin {requested_by} about confirmation #{confirmation_id}: {e}")
+
+# --- Управление уровнем логирования ---
+@router.message(Command('loglevel'))
+async def loglevel_handler(message: Message, command: CommandObject):
+    args = command.args.split() if command.args else []
+    if not args:
+        current = "\n".join(f" • {hcode(name)}: {level}" for name, level in get_log_levels().items())
+        await message.answer(
+            f"<b>Текущие уровни логирования:</b>\n{current}\n\n"
+            f"Формат: <code>/loglevel LEVEL [MODULE]</code>\n"
+            f"Уровни: {', '.join(LOG_LEVELS)}"
+        )
+        return
+
+    level_name = args[0]
+    target = args[1] if len(args) > 1 else None
+    if not set_log_level(level_name, target):
+        await message.answer(f"Неизвестный уровень {hcode(level_name)}. Доступные: {', '.join(LOG_LEVELS)}")
+        return
+
+    logger.info(f"Admin {message.from_user.id} set log level {level_name} for {target or 'root'}")
+    await message.answer(f"✅ Уровень логирования для {hcode(target or 'root')} установлен: <b>{level_name.upper()}</b>")
+
+# --- Объявление в главном меню ---
+@router.message(Command('banner'))
+async def banner_handler(message: Message, command: CommandObject, db: Database):
+    if not command.args:
+        current = await get_announcement_text(db)
+        await message.answer(
+            f"<b>Объявление в главном меню:</b>\n{hcode(current) if current else 'не задано'}\n\n"
+            "Задать: <code>/banner ТЕКСТ</code>\n"
+            "Убрать: <code>/banner -</code>"
+        )
+        return
+
+    text = command.args.strip()
+    if text == '-':
+        await set_announcement_banner(db, None)
+        await db.add_audit_log(message.from_user.id, 'banner_clear', None)
+        logger.info(f"Admin {message.from_user.id} cleared the announcement banner")
+        await message.answer("✅ Объявление убрано из главного меню.")
+        return
+    if len(text) > ANNOUNCEMENT_BANNER_MAX_LENGTH:
+        await message.answer(f"❌ Объявление должно быть не длиннее {ANNOUNCEMENT_BANNER_MAX_LENGTH} символов.")
+        return
+
+    await set_announcement_banner(db, text)
+    await db.add_audit_log(message.from_user.id, 'banner_set', None, text[:200])
+    logger.info(f"Admin {message.from_user.id} set the announcement banner")
+    await message.answer(f"✅ Объявление будет показываться над главным меню, пока его не уберут:\n\n{await get_announcement_banner(db)}")
+
+# --- Бета-тестирование моделей ---
+@router.message(Command('beta'))
+async def beta_handler(message: Message, command: CommandObject, db: Database, cache: dict):
+    args = command.args.split() if command.args else []
+    if len(args) == 2 and args[0] in ('add', 'del'):
+        user_id = await get_user_id_from_input(args[1], db)
+        if not user_id or not await db.get_user(user_id):
+            await message.answer(f"Пользователь {hcode(args[1])} не найден.")
+            return
+        is_tester = args[0] == 'add'
+        await db.set_beta_tester(user_id, is_tester)
+        invalidate_user_cache(user_id, cache)
+        await db.add_audit_log(message.from_user.id, 'beta_add' if is_tester else 'beta_del', user_id)
+        logger.info(f"Admin {message.from_user.id} {'added' if is_tester else 'removed'} beta tester {user_id}")
+        await message.answer(f"✅ Пользователь {hcode(user_id)} {'теперь' if is_tester else 'больше не'} бета-тестировщик.")
+        return
+    if args:
+        await message.answer("Формат: <code>/beta</code>, <code>/beta add ID/@username</code> или <code>/beta del ID/@username</code>")
+        return
+
+    testers = await db.get_beta_testers()
+    lines = [f"<b>🧪 Бета-тестирование</b>\n\nТестировщиков: {len(testers)}"]
+    if testers:
+        lines.append(", ".join(f"@{username}" if username else hcode(user_id) for user_id, username in testers[:30]))
+    if not BETA_MODELS:
+        lines.append("\nМоделей в бета-тесте нет. Модель попадает в бету флагом beta=True в ее описании (ModelInfo).")
+    for model in sorted(BETA_MODELS):
+        requests_count, active_testers, likes, dislikes, failed_checks, total_checks = await db.get_beta_model_stats(model)
+        lines.append(
+            f"\n<b>{get_model_display_name(model)}</b> ({hcode(model)})\n"
+            f" • Запросов тестировщиков: {requests_count} (от {active_testers} чел.)\n"
+            f" • Оценки: 👍 {likes} / 👎 {dislikes}\n"
+            f" • Проверок с ошибкой: {failed_checks} из {total_checks}"
+        )
+    lines.append("\nДобавить: <code>/beta add ID/@username</code>\nУбрать: <code>/beta del ID/@username</code>")
+    await message.answer("\n".join(lines))
+
+# --- Редактирование списка изменений ---
+@router.message(Command('changelog_add'))
+async def changelog_add_handler(message: Message, command: CommandObject, db: Database):
+    if not command.args:
+        await message.answer("Формат: <code>/changelog_add ТЕКСТ</code>")
+        return
+    entry_id = await db.add_changelog_entry(command.args.strip())
+    logger.info(f"Admin {message.from_user.id} added changelog entry #{entry_id}")
+    await message.answer(f"✅ Запись #{entry_id} добавлена в список изменений.")
+
+@router.message(Command('changelog_del'))
+async def changelog_del_handler(message: Message, command: CommandObject, db: Database):
+    try:
+        entry_id = int(command.args.strip())
+    except (AttributeError, ValueError):
+        await message.answer("Формат: <code>/changelog_del ID</code>")
+        return
+    if await db.delete_changelog_entry(entry_id):
+        logger.info(f"Admin {message.from_user.id} deleted changelog entry #{entry_id}")
+        await message.answer(f"🗑️ Запись #{entry_id} удалена.")
+    else:
+        await message.answer(f"Запись #{entry_id} не найдена.")
+
+
+# --- Проверка промокодов ---
+@router.message(Command('promo'))
+async def promo_handler(message: Message, command: CommandObject, db: Database):
+    args = command.args.split() if command.args else []
+    if not args:
+        await message.answer("Формат: <code>/promo КОД [use]</code>\n<code>use</code> — отметить код использованным.")
+        return
+
+    promo = await db.get_promocode(args[0])
+    if not promo:
+        await message.answer(f"Промокод {hcode(args[0])} не найден.")
+        return
+
+    code, user_id, discount, source, expires_at, used_at = promo
+    expired = datetime.fromisoformat(str(expires_at)) < datetime.now(timezone.utc)
+    if len(args) > 1 and args[1].lower() == 'use':
+        if used_at or expired:
+            await message.answer(f"Промокод {hcode(code)} уже использован или истек.")
+            return
+        await db.mark_promocode_used(code)
+        await db.add_audit_log(message.from_user.id, 'promo_use', user_id, code)
+        if user_id and source == 'winback':
+            await mark_winback_conversion(db, user_id)
+        await message.answer(f"✅ Промокод {hcode(code)} отмечен как использованный.")
+        return
+
+    status = "использован" if used_at else ("истек" if expired else "активен")
+    await message.answer(
+        f"<b>Промокод</b> {hcode(code)}\n"
+        f"Скидка: {discount}%\n"
+        f"Пользователь: {hcode(str(user_id)) if user_id else 'любой'}\n"
+        f"Источник: {source}\n"
+        f"Статус: {status}"
+    )
+
+@router.message(Command('promo_import'))
+async def promo_import_handler(message: Message, command: CommandObject, db: Database, bot: Bot):
+    """Загрузка кодов из CSV: файл с подписью /promo_import [ИСТОЧНИК] или ответ этой командой на файл."""
+    document = message.document or (message.reply_to_message.document if message.reply_to_message else None)
+    if not document:
+        await message.answer(
+            "Отправьте CSV-файл с подписью <code>/promo_import [ИСТОЧНИК]</code> "
+            "или ответьте этой командой на сообщение с файлом.\n\n"
+            "Столбцы: <code>code,discount_percent,expires_at[,user_id]</code>, дата - ГГГГ-ММ-ДД или ДД.ММ.ГГГГ. "
+            "Источник (например, имя партнера) по умолчанию - <code>import</code>."
+        )
+        return
+    if document.file_size and document.file_size > PROMO_IMPORT_MAX_BYTES:
+        await message.answer(f"Файл слишком большой (максимум {PROMO_IMPORT_MAX_BYTES // 1024} КБ).")
+        return
+
+    source = (command.args or 'import').split()[0][:32]
+    try:
+        text = (await bot.download(document)).read().decode('utf-8-sig')
+    except UnicodeDecodeError:
+        await message.answer("Файл должен быть в кодировке UTF-8.")
+        return
+
+    added, duplicates, errors = await import_promocodes(db, text, source)
+    await db.add_audit_log(message.from_user.id, 'promo_import', None, f"source={source} added={added}")
+    logger.info(f"Admin {message.from_user.id} imported {added} promocodes (source {source})")
+    lines = [
+        f"📥 <b>Загрузка промокодов</b> (источник {hcode(source)})",
+        f"Добавлено: {added}",
+        f"Уже были в базе: {duplicates}",
+        f"Ошибок: {len(errors)}",
+    ]
+    if errors:
+        lines.append("")
+        lines.extend(f"• {html.escape(error)}" for error in errors[:15])
+        if len(errors) > 15:
+            lines.append(f"…и еще {len(errors) - 15}")
+    await message.answer("\n".join(lines))
+
+@router.message(Command('promo_export'))
+async def promo_export_handler(message: Message, command: CommandObject, db: Database):
+    """Выгрузка неиспользованных действующих кодов в CSV, при необходимости только одного источника."""
+    source = command.args.split()[0] if command.args else None
+    content, count = await export_promocodes_csv(db, source)
+    if not count:
+        await message.answer("Неиспользованных действующих промокодов нет." if not source else
+                             f"Неиспользованных действующих промокодов с источником {hcode(source)} нет.")
+        return
+    filename = f"promocodes_{source or 'all'}_{datetime.now(MSK_TZ).strftime('%Y%m%d')}.csv"
+    await message.answer_document(
+        BufferedInputFile(content, filename=filename),
+        caption=f"📤 Неиспользованные промокоды: {count}" + (f" (источник {hcode(source)})" if source else "")
+    )
+
+
+# --- Опросы ---
+@router.message(Command('survey_add'))
+async def survey_add_handler(message: Message, command: CommandObject, db: Database):
+    if not command.args:
+        await message.answer(
+            "Формат:\n<code>/survey_add ВОПРОС | ВАРИАНТ | ВАРИАНТ</code> — выбор из вариантов\n"
+            "<code>/survey_add nps ВОПРОС</code> — оценка от 0 до 10\n"
+            "<code>/survey_add ВОПРОС</code> — свободный ответ"
+        )
+        return
+
+    args = command.args.strip()
+    if args.lower().startswith('nps '):
+        kind, question, options = 'nps', args[4:].strip(), None
+    else:
+        parts = [part.strip() for part in args.split('|') if part.strip()]
+        question, options = parts[0], parts[1:]
+        if len(options) == 1:
+            await message.answer("Для опроса с вариантами нужно минимум два варианта ответа.")
+            return
+        kind = 'choice' if options else 'text'
+
+    survey_id = await db.create_survey(question, kind, options)
+    logger.info(f"Admin {message.from_user.id} created survey #{survey_id} ({kind})")
+    await message.answer(f"✅ Опрос #{survey_id} создан. Отправить: <code>/survey_send {survey_id} all</code>")
+
+@router.message(Command('survey_send'))
+async def survey_send_handler(message: Message, command: CommandObject, db: Database, bot: Bot, scheduler):
+    args = command.args.split() if command.args else []
+    try:
+        survey_id, segment = int(args[0]), args[1] if len(args) > 1 else 'all'
+    except (IndexError, ValueError):
+        await message.answer(f"Формат: <code>/survey_send ID [СЕГМЕНТ]</code>\nСегменты: {', '.join(SEGMENTS)}")
+        return
+    if segment not in SEGMENTS:
+        await message.answer(f"Неизвестный сегмент {hcode(segment)}. Доступные: {', '.join(SEGMENTS)}")
+        return
+    survey = await db.get_survey(survey_id)
+    if not survey:
+        await message.answer(f"Опрос #{survey_id} не найден.")
+        return
+
+    payload = {"survey_id": survey_id, "segment": segment}
+    if is_two_person_rule_active():
+        confirmation_id = await request_confirmation(
+            bot, db, 'survey_send', payload, message.from_user.id,
+            f"Опрос #{survey_id}: {html.escape(survey[1])}\nПолучатели: {SEGMENTS[segment]}"
+        )
+        await message.answer(f"🔐 Опрос будет отправлен после подтверждения другим администратором (запрос #{confirmation_id}).")
+        return
+    await message.answer(await execute_confirmed_action('survey_send', payload, message.from_user.id, db, bot, scheduler))
+
+
+# --- Модерация жалоб ---
+@router.callback_query(ModerationAction.filter())
+async def moderation_action_handler(callback: CallbackQuery, callback_data: ModerationAction, db: Database, bot: Bot):
+    report = await db.get_report(callback_data.report_id)
+    if callback_data.action == 'delete' and report:
+        chat_id, message_id = report[2], report[7]
+        try:
+            await bot.delete_message(chat_id, message_id)
+        except TelegramBadRequest as e:
+            logger.warning(f"Could not delete reported message {message_id} in chat {chat_id}: {e}")
+    status = 'deleted' if callback_data.action == 'delete' else 'dismissed'
+    await db.resolve_report(callback_data.report_id, status, callback.from_user.id)
+    await db.add_audit_log(callback.from_user.id, f'report_{status}', report[3] if report else None, str(callback_data.report_id))
+    await callback.answer("Ответ удален." if status == 'deleted' else "Жалоба отклонена.")
+
+    text, keyboard = await format_next_report(db)
+    try:
+        await callback.message.edit_text(text, reply_markup=keyboard)
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in moderation_action_handler: {e}")
+
+
+# --- Проверка модели набором тестовых промптов ---
+@router.message(Command('promptsuite'))
+async def prompt_suite_handler(message: Message, command: CommandObject, ai_client):
+    args = command.args.split() if command.args else []
+    categories = sorted({case.category for case in load_suite()})
+    if not args:
+        await message.answer(
+            "Формат: <code>/promptsuite МОДЕЛЬ [КАТЕГОРИЯ]</code>\n"
+            f"Категории: {', '.join(categories)}"
+        )
+        return
+
+    model, category = args[0], args[1] if len(args) > 1 else None
+    if category and category not in categories:
+        await message.answer(f"Неизвестная категория {hcode(category)}. Доступные: {', '.join(categories)}")
+        return
+
+    status_msg = await message.answer(f"Прогоняю тестовые промпты на {hcode(model)}... ⏳")
+    results = await run_prompt_suite(ai_client, model, category)
+    passed = sum(result.passed for result in results)
+    logger.info(f"Admin {message.from_user.id} ran prompt suite on {model}: {passed}/{len(results)}")
+
+    lines = [f"<b>🧪 {hcode(model)}: пройдено {passed} из {len(results)}</b>\n"]
+    for result in results:
+        icon = '✅' if result.passed else '❌'
+        lines.append(f"{icon} <b>{result.case.category}/{result.case.name}</b>")
+        if not result.passed:
+            lines.extend(f"   • {hcode(problem)}" for problem in result.problems)
+            lines.append(f"   Ответ: {hcode(result.response[:200] or '—')}")
+    first_chunk, *other_chunks = split_message("\n".join(lines))
+    await status_msg.edit_text(first_chunk)
+    for chunk in other_chunks:
+        await message.answer(chunk)
+
+
+# --- Имитация сбоев моделей ---
+@router.message(Command('chaos'))
+async def chaos_handler(message: Message, command: CommandObject, db: Database, cache: dict):
+    """Скрытая команда: имитация сбоев провайдера для проверки резервных моделей и сообщений об ошибках."""
+    args = command.args.split() if command.args else []
+    if not args:
+        chaos = get_chaos(cache)
+        if chaos:
+            minutes_left = max(0, CHAOS_MAX_MINUTES - int((time.time() - chaos['since']) // 60))
+            status = f"включена: {chaos['percent']}% запросов, {CHAOS_MODES[chaos['mode']]}, выключится через ~{minutes_left} мин."
+        else:
+            status = "выключена"
+        await message.answer(
+            f"💥 Имитация сбоев провайдера {status}\n\n"
+            "Включить: <code>/chaos ПРОЦЕНТ [error|timeout]</code>\n"
+            "Выключить: <code>/chaos off</code>\n"
+            f"Режим выключается сам через {CHAOS_MAX_MINUTES} мин. и после перезапуска."
+        )
+        return
+
+    if args[0].lower() == 'off':
+        set_chaos(cache, 0, 'error')
+        await db.add_audit_log(message.from_user.id, 'chaos_off', None)
+        logger.warning(f"Admin {message.from_user.id} disabled chaos mode")
+        await message.answer("✅ Имитация сбоев выключена.")
+        return
+
+    mode = args[1].lower() if len(args) > 1 else 'error'
+    try:
+        percent = int(args[0].rstrip('%'))
+    except ValueError:
+        percent = -1
+    if not 1 <= percent <= 100 or mode not in CHAOS_MODES:
+        await message.answer("Формат: <code>/chaos ПРОЦЕНТ [error|timeout]</code>, процент - от 1 до 100.")
+        return
+
+    set_chaos(cache, percent, mode)
+    await db.add_audit_log(message.from_user.id, 'chaos_on', None, f"{percent}% {mode}")
+    logger.warning(f"Admin {message.from_user.id} enabled chaos mode: {percent}% {mode}")
+    await message.answer(
+        f"💥 Имитация сбоев включена: {percent}% запросов к моделям завершатся ошибкой ({CHAOS_MODES[mode]}).\n"
+        f"Выключится сама через {CHAOS_MAX_MINUTES} мин.; выключить сейчас - <code>/chaos off</code>."
+    )
+
+# --- Разбор запусков Max Mode ---
+# Сколько символов каждого ответа показывать при разборе запуска
+MAX_MODE_RUN_PREVIEW = 1500
+
+def _format_run_time(created_at: str) -> str:
+    return format_datetime(created_at, with_year=False)
+
+def _shorten(text: str | None) -> str:
+    text = text or '—'
+    return text if len(text) <= MAX_MODE_RUN_PREVIEW else text[:MAX_MODE_RUN_PREVIEW] + '…'
+
+@router.message(Command('maxruns'))
+async def max_mode_runs_handler(message: Message, command: CommandObject, db: Database):
+    user_id = None
+    if command.args:
+        user_id = await get_user_id_from_input(command.args.strip(), db)
+        if not user_id:
+            await message.answer("Пользователь не найден. Формат: <code>/maxruns [ID или @username]</code>")
+            return
+    runs = await db.get_max_mode_runs(user_id)
+    if not runs:
+        await message.answer("Запусков Max Mode пока нет.")
+        return
+    lines = ["<b>🚀 Последние запуски Max Mode</b>\n"]
+    for run_id, run_user_id, prompt, total_duration, status, created_at in runs:
+        icon = '✅' if status == 'ok' else '❌'
+        lines.append(
+            f"{icon} <code>#{run_id}</code> {_format_run_time(created_at)}, пользователь {hcode(run_user_id)}, "
+            f"{total_duration:.1f} сек.\n   {html.escape(prompt[:80])}"
+        )
+    lines.append("\nПодробнее: <code>/maxrun НОМЕР</code>")
+    await message.answer("\n".join(lines))
+
+@router.message(Command('maxrun'))
+async def max_mode_run_handler(message: Message, command: CommandObject, db: Database):
+    """Показывает запуск Max Mode целиком: ответы участников, итог арбитра, время и стоимость."""
+    run_id = (command.args or '').strip().lstrip('#')
+    run = await db.get_max_mode_run(int(run_id)) if run_id.isdigit() else None
+    if not run:
+        await message.answer("Запуск не найден. Формат: <code>/maxrun НОМЕР</code> (номер указан под ответом Max Mode).")
+        return
+
+    (run_id, user_id, prompt, participants_json, arbiter, arbiter_output, arbiter_duration,
+     total_duration, cost, status, created_at) = run
+    parts = [
+        f"<b>🚀 Запуск Max Mode #{run_id}</b> ({'успешно' if status == 'ok' else 'ошибка'})\n"
+        f"Пользователь: {hcode(user_id)}, {_format_run_time(created_at)}\n"
+        f"Общее время: {total_duration:.2f} сек. | Условная стоимость: {cost:g}\n\n"
+        f"<b>Запрос:</b>\n{html.escape(_shorten(prompt))}"
+    ]
+    for participant in json.loads(participants_json):
+        icon = '❌' if participant['error'] else '✅'
+        parts.append(
+            f"{icon} <b>{html.escape(participant['model'])}</b> ({participant['duration']:.2f} сек.)\n"
+            f"{html.escape(_shorten(participant['error'] or participant['answer']))}"
+        )
+    arbiter_time = f"{arbiter_duration:.2f} сек." if arbiter_duration is not None else "не вызывался"
+    parts.append(f"⚖️ <b>Арбитр {html.escape(arbiter)}</b> ({arbiter_time})\n{html.escape(_shorten(arbiter_output))}")
+    logger.info(f"Admin {message.from_user.id} inspected Max Mode run #{run_id}")
+
+    for chunk in split_text("\n\n".join(parts), TELEGRAM_MESSAGE_LIMIT):
+        await message.answer(chunk)
+
+
+# --- Ежедневный отчет ---
+@router.message(Command('dailyreport'))
+async def daily_report_handler(message: Message, db: Database, bot: Bot, cache: dict):
+    """Отправляет ежедневный отчет вне расписания, например чтобы проверить настройки SMTP."""
+    await send_admin_report(bot, db, cache)
+    logger.info(f"Admin {message.from_user.id} sent the daily report manually")
+    await message.answer("✅ Отчет отправлен администраторам (и на почту, если она настроена).")
+
+
+# --- След запроса по коду ошибки ---
+@router.message(Command('trace'))
+async def trace_handler(message: Message, command: CommandObject):
+    """Показывает строки лога запроса по коду, который пользователь видел в сообщении об ошибке."""
+    if not command.args:
+        await message.answer("Формат: <code>/trace КОД</code> (код указан в сообщении об ошибке у пользователя).")
+        return
+    request_id = normalize_request_id(command.args)
+    lines = await get_trace(request_id)
+    if not lines:
+        await message.answer(f"Запрос {hcode(request_id)} не найден ни в памяти, ни в файле лога.")
+        return
+    logger.info(f"Admin {message.from_user.id} inspected trace of request {request_id}")
+    text = f"<b>🔎 Запрос {hcode(request_id)}</b> ({len(lines)} строк лога)\n\n" + html.escape("\n".join(lines))
+    for chunk in split_text(text, TELEGRAM_MESSAGE_LIMIT):
+        await message.answer(chunk)
+
+
+# --- Расходы на модели ---
+@router.message(Command('spend'))
+async def spend_handler(message: Message, command: CommandObject, db: Database):
+    """Накопленные расходы по моделям (всего или одного пользователя) и самые затратные пользователи."""
+    user_id = None
+    if command.args:
+        user_id = await get_user_id_from_input(command.args.strip(), db)
+        if not user_id:
+            await message.answer("Пользователь не найден. Формат: <code>/spend [ID или @username]</code>")
+            return
+    by_model = await db.get_spend_by_model(user_id)
+    if not by_model:
+        await message.answer("Запросов пока нет.")
+        return
+
+    title = f"пользователя {hcode(user_id)}" if user_id else "всех пользователей"
+    lines = [f"<b>💵 Расходы {title} за все время</b>\n"]
+    for model, count, prompt_tokens, completion_tokens, cost in by_model:
+        lines.append(
+            f" • <b>{html.escape(model)}</b>: {format_cost(cost)}, {count} запр.\n"
+            f"   {format_token_usage(prompt_tokens, completion_tokens)}"
+        )
+    lines.append(f"\nИтого: <b>{format_cost(sum(row[4] for row in by_model))}</b>")
+    if not user_id:
+        lines.append("\n<b>Самые затратные пользователи:</b>")
+        for spender_id, username, count, cost in await db.get_top_spenders():
+            name = f"@{html.escape(username)}" if username else hcode(spender_id)
+            lines.append(f" • {name}: {format_cost(cost)}, {count} запр.")
+    for chunk in split_text("\n".join(lines), TELEGRAM_MESSAGE_LIMIT):
+        await message.answer(chunk)
+
+
+# --- Внешние серверы инструментов ---
+@router.message(Command('tools'))
+async def tool_servers_handler(message: Message, db: Database):
+    servers = await db.get_tool_servers()
+    if not servers:
+        await message.answer(
+            "Серверы инструментов не подключены.\n\n"
+            "Подключить: <code>/tool_add ИМЯ URL [ТОКЕН]</code>\n"
+            "Управление: <code>/tool ИМЯ refresh|on|off|del</code>"
+        )
+        return
+    lines = ["<b>🧰 Серверы инструментов</b>\n"]
+    for name, url, _, tools_json, enabled in servers:
+        tool_names = [html.escape(tool['name']) for tool in json.loads(tools_json or '[]')]
+        lines.append(f"{'✅' if enabled else '⏸'} <b>{name}</b> - {hcode(url)}\n   {', '.join(tool_names) or 'нет инструментов'}")
+    lines.append("\nУправление: <code>/tool ИМЯ refresh|on|off|del</code>")
+    for chunk in split_message("\n".join(lines)):
+        await message.answer(chunk, disable_web_page_preview=True)
+
+@router.message(Command('tool_add'))
+async def tool_add_handler(message: Message, command: CommandObject, db: Database, cache: dict):
+    args = command.args.split() if command.args else []
+    if len(args) not in (2, 3) or not SERVER_NAME_RE.match(args[0]) or not args[1].startswith(('http://', 'https://')):
+        await message.answer(
+            "Формат: <code>/tool_add ИМЯ URL [ТОКЕН]</code>\n"
+            "Имя - латиница в нижнем регистре, цифры и дефис (до 20 символов). "
+            "Сервер должен отвечать на JSON-RPC методы <code>tools/list</code> и <code>tools/call</code>."
+        )
+        return
+
+    name, url = args[0], args[1]
+    auth_token = args[2] if len(args) == 3 else None
+    try:
+        tools = await register_tool_server(db, cache, name, url, auth_token)
+    except ToolServerError as e:
+        await message.answer(f"❌ Не удалось получить список инструментов: {hcode(str(e))}")
+        return
+    await db.add_audit_log(message.from_user.id, 'tool_server_add', None, f"{name} {url}")
+    logger.info(f"Admin {message.from_user.id} registered tool server {name} ({len(tools)} tools)")
+    await message.answer(
+        f"✅ Сервер <b>{name}</b> подключен. Инструменты: {', '.join(hcode(tool['name']) for tool in tools)}\n"
+        "Они будут предложены моделям с поддержкой инструментов."
+    )
+
+@router.message(Command('tool'))
+async def tool_manage_handler(message: Message, command: CommandObject, db: Database, cache: dict):
+    args = command.args.split() if command.args else []
+    if len(args) != 2 or args[1] not in ('refresh', 'on', 'off', 'del'):
+        await message.answer("Формат: <code>/tool ИМЯ refresh|on|off|del</code>")
+        return
+
+    name, action = args
+    server = await db.get_tool_server(name)
+    if not server:
+        await message.answer(f"Сервер {hcode(name)} не найден.")
+        return
+
+    if action == 'refresh':
         try:
-            await bot.send_message(user_id, message.text)
-            success_count += 1
-        except Exception:
-            fail_count += 1
-        await asyncio.sleep(0.1)
-    completion_text = f"✅ Рассылка завершена.\n\nУспешно: {success_count}\nНеудачно: {fail_count}"
-    await message.answer(completion_text, reply_markup=get_back_to_admin_menu())
\ No newline at end of file
+            tools = await register_tool_server(db, cache, name, server[1], decrypt_field(server[2]))
+        except ToolServerError as e:
+            await message.answer(f"❌ Не удалось обновить список инструментов: {hcode(str(e))}")
+            return
+        result_text = f"🔄 Список инструментов обновлен: {', '.join(hcode(tool['name']) for tool in tools)}"
+    elif action == 'del':
+        await delete_tool_server(db, cache, name)
+        result_text = f"🗑 Сервер <b>{name}</b> удален."
+    else:
+        await set_tool_server_enabled(db, cache, name, action == 'on')
+        result_text = f"{'✅ Сервер включен' if action == 'on' else '⏸ Сервер отключен'}: <b>{name}</b>."
+
+    await db.add_audit_log(message.from_user.id, f'tool_server_{action}', None, name)
+    logger.info(f"Admin {message.from_user.id} applied '{action}' to tool server {name}")
+    await message.answer(result_text)
+
+
+# --- Обновления, пропущенные во время простоя ---
+@router.message(Command('replay'))
+async def replay_handler(message: Message, command: CommandObject, db: Database, bot: Bot, dispatcher: Dispatcher):
+    counts = await db.get_pending_journal_counts()
+    if not counts:
+        await message.answer("Необработанных обновлений в журнале нет.")
+        return
+    if command.args != 'run':
+        lines = "\n".join(f" • {UPDATE_KINDS.get(kind, kind)}: {count}" for kind, count in counts.items())
+        await message.answer(
+            f"<b>📥 Пропущенные во время простоя обновления:</b>\n{lines}\n\n"
+            "Обработать: <code>/replay run</code>"
+        )
+        return
+
+    replayed, failed = await replay_journal(bot, dispatcher, db)
+    await db.add_audit_log(message.from_user.id, 'replay_updates', None, f"replayed={replayed} failed={failed}")
+    logger.info(f"Admin {message.from_user.id} replayed {replayed} journal updates ({failed} failed)")
+    text = f"✅ Обработано обновлений: {replayed}."
+    if failed:
+        text += f"\n❌ С ошибкой: {failed} (останутся в журнале, подробности в логе)."
+    await message.answer(text)
+
+
+# --- Возврат платежей в Telegram Stars ---
+@router.message(Command('refund'))
+async def refund_handler(message: Message, command: CommandObject, db: Database, bot: Bot, cache: dict):
+    if not command.args:
+        payments = await db.get_recent_payments('XTR')
+        lines = "\n".join(
+            f" • {hcode(charge_id)} - {hcode(user_id)}, уровень {level}, {format_amount(amount, 'XTR')}, "
+            f"{format_date(created_at)}"
+            for charge_id, user_id, level, amount, created_at in payments
+        ) or " Оплат звездами пока нет."
+        await message.answer(
+            f"<b>⭐ Последние оплаты звездами:</b>\n{lines}\n\n"
+            "Вернуть: <code>/refund CHARGE_ID</code>"
+        )
+        return
+
+    charge_id = command.args.strip()
+    payment, error = await refund_star_payment(bot, db, cache, charge_id)
+    if error:
+        await message.answer(f"❌ {error}")
+        return
+    _, user_id, level, days, amount, _, _ = payment
+    await db.add_audit_log(message.from_user.id, 'refund_stars', user_id, f"{charge_id} amount={amount}")
+    logger.info(f"Admin {message.from_user.id} refunded star payment {charge_id} of user {user_id}")
+    await message.answer(
+        f"✅ Возвращено {format_amount(amount, 'XTR')} пользователю {hcode(user_id)}. "
+        f"Подписка уровня {level} сокращена на {days} дн."
+    )
+    try:
+        await bot.send_message(user_id, f"Платеж {format_amount(amount, 'XTR')} возвращен, оплаченный им срок подписки отменен.")
+    except Exception as e:
+        logger.warning(f"Could not notify user {user_id} about refund: {e}")
@@ -1,32 +1,64 @@
 # app/handlers/chat.py
 # Обработчики для логики чата (обычного и Max Mode).
 
+import html
 import logging
 import time
 import asyncio
 
+import aiohttp
 from aiogram import F, Router, Bot
 from aiogram.fsm.context import FSMContext
-from aiogram.types import Message, CallbackQuery
+from aiogram.types import Message, CallbackQuery, InlineKeyboardMarkup
 from aiogram.utils.markdown import hcode
-from aiogram.exceptions import TelegramBadRequest
+from aiogram.exceptions import TelegramBadRequest, TelegramRetryAfter
 from openai import APIError
 
 from app.database import Database
-from app.config import MODEL_CATEGORIES, MODELS, MAX_MODE_PARTICIPANTS, DEFAULT_TEMPERATURE, MAX_MODE_ARBITER
+from app.config import (
+    MODEL_CATEGORIES, MAX_MODE_PARTICIPANTS, DEFAULT_TEMPERATURE, MAX_MODE_ARBITER,
+    MODEL_INFO, CAPABILITY_NAMES, LONG_ANSWER_MAX_MESSAGES, BETA_MODELS,
+    MAX_PINNED_CONVERSATIONS, MAX_FAVORITES, DOCUMENT_EXTENSIONS, DOCUMENT_MAX_SIZE, QUOTA_MODE, MAX_MODE_CONFIRM, USD_TO_RUB,
+    get_model_display_name, model_supports
+)
 from app.states import Chat, MaxMode
-from app.keyboards.callbacks import Menu, Chat as ChatCallback, ModelCategory, SelectTextModel, MaxMode as MaxModeCallback
+from app.keyboards.callbacks import (
+    Menu, Chat as ChatCallback, ModelCategory, SelectTextModel, MaxMode as MaxModeCallback, RetryRequest,
+    ModelDetails, Conversation, Favorite, SwitchModel, BetaFeedback, Regenerate
+)
 from app.keyboards.inline import (
-    get_model_categories_menu, get_models_menu, get_chat_menu, get_max_mode_activation_menu, get_main_menu
+    get_model_categories_menu, get_models_menu, get_chat_menu, get_chat_models_menu, get_max_mode_activation_menu, get_main_menu,
+    get_model_details_menu, get_capable_models_menu, get_answer_menu, get_conversation_switch_menu, get_share_menu,
+    get_long_answer_menu, get_outage_banner_menu, get_favorites_menu, get_favorite_menu, get_max_mode_confirm_menu,
+    get_regenerate_models_menu
 )
 from app.services.user_service import (
-    get_user_level, get_user_limits, check_authentication, invalidate_user_cache, get_user_details_cached
+    get_user_level, get_user_limits, get_usage_today, check_authentication, invalidate_user_cache, get_user_details_cached, add_max_mode_request,
+    get_user_accessible_models, get_favorite_model, get_stream_interval
 )
 from app.services.system_service import (
-    is_model_available, are_max_mode_models_available, set_model_failed_in_cache
+    is_model_available, are_max_mode_models_available, get_model_statuses
 )
-from app.services.ai_service import get_simple_response, get_max_mode_response
-from .subscription import show_reward_offer
+from app.services.ai_service import get_simple_response, get_max_mode_response, estimate_max_mode_cost
+from app.services.model_service import get_fallback_chain, can_answer
+from app.services.limit_message_service import build_limit_message
+from .common import build_main_menu_text
+from app.services.abuse_service import check_prompt_abuse, get_spam_block_message
+from app.services.conversation_service import (
+    get_history, get_active_history, append_messages, start_conversation, switch_conversation, branch_conversation,
+    find_message, find_last_prompt, resume_last_conversation, to_api_messages, set_pinned, build_image_content
+)
+from app.services.file_service import FileIntakeError, receive_file, read_as_data_url, extract_file
+from app.services.message_buffer_service import collect_media_group, get_media_group_caption, collect_prompt_parts
+from app.services.prompt_service import PromptRejected, prepare_prompt
+from app.services.document_service import is_supported_document, extract_document_text, summarize_document
+from app.services.share_service import create_share_link
+from app.services.telegraph_service import TelegraphError, publish_page
+from app.services.text_service import TELEGRAM_MESSAGE_LIMIT, split_message, format_quota
+from app.services.analytics_service import track
+from app.services.format_service import format_price
+from app.services.trace_service import format_error_code
+from app.services.markdown_service import markdown_to_html, html_to_plain
 
 logger = logging.getLogger(__name__)
 router = Router()
@@ -48,38 +80,132 @@ async def animate_waiting(message: Message, text: str = "Думаю"):
         except Exception:
             break
 
-async def send_limit_reached_message(message: Message, db: Database):
-    user_id = message.from_user.id
+def make_stream_updater(msg: Message, animation_task: asyncio.Task, interval: float):
+    """
+    Возвращает функцию для on_partial: показывает генерируемый ответ в сообщении-заглушке
+    не чаще раза в interval секунд. Промежуточный текст отправляется без разметки,
+    так как оборванный фрагмент может содержать незакрытые теги.
+    """
+    last_edit = 0.0
+
+    async def update(text: str):
+        nonlocal last_edit
+        now = time.monotonic()
+        if now - last_edit < interval or not text.strip():
+            return
+        last_edit = now
+        animation_task.cancel()
+        try:
+            await msg.edit_text(text[:TELEGRAM_MESSAGE_LIMIT - 2] + " ▌", parse_mode=None)
+        except (TelegramBadRequest, TelegramRetryAfter) as e:
+            logger.debug(f"Skipped streaming update for message {msg.message_id}: {e}")
+
+    return update
+
+# Сколько символов слишком длинного ответа показывать до публикации
+LONG_ANSWER_PREVIEW_SIZE = 1000
+
+async def _send_html(send, text: str, **kwargs):
+    """Отправляет (или редактирует) сообщение с HTML; если Telegram не разобрал разметку, - простым текстом."""
+    try:
+        return await send(text, **kwargs)
+    except TelegramBadRequest as e:
+        if "can't parse entities" not in e.message:
+            raise
+        logger.warning(f"Telegram rejected answer markup, sending as plain text: {e.message}")
+        return await send(html_to_plain(text), parse_mode=None, **kwargs)
+
+async def send_chunks(msg: Message, chunks: list[str], footer: str = "", reply_markup=None) -> Message:
+    """
+    Показывает части ответа (split_message) по порядку: первая - вместо сообщения-заглушки msg, остальные - новыми
+    сообщениями. Подпись footer и кнопки добавляются к последней части. Возвращает последнее сообщение.
+    """
+    chunks = chunks or [""]
+    if len(chunks) == 1:
+        await _send_html(msg.edit_text, chunks[0] + footer, reply_markup=reply_markup)
+        return msg
+    await _send_html(msg.edit_text, chunks[0])
+    for chunk in chunks[1:-1]:
+        await _send_html(msg.answer, chunk)
+    return await _send_html(msg.answer, chunks[-1] + footer, reply_markup=reply_markup)
+
+async def deliver_answer(msg: Message, response_text: str, footer: str, beta_model: str | None = None) -> int:
+    """
+    Показывает ответ модели вместо сообщения-заглушки msg. Markdown ответа переводится в HTML Telegram.
+    Длинный ответ делится на несколько сообщений, а слишком длинный сокращается с предложением опубликовать его в Telegraph.
+    Под ответом бета-модели (beta_model) добавляются кнопки оценки для тестировщика.
+    Возвращает message_id сообщения с кнопками под ответом.
+    """
+    response_html = markdown_to_html(response_text)
+    chunks = split_message(response_html, TELEGRAM_MESSAGE_LIMIT - len(footer))
+    if len(chunks) > LONG_ANSWER_MAX_MESSAGES:
+        preview = split_message(response_html, LONG_ANSWER_PREVIEW_SIZE)[0]
+        await _send_html(
+            msg.edit_text,
+            f"{preview}\n\n…\n\n📄 Ответ очень длинный (около {len(chunks)} сообщений). "
+            "Опубликуйте его в Telegra.ph или получите целиком сообщениями." + footer,
+            reply_markup=get_long_answer_menu(beta_model=beta_model)
+        )
+        return msg.message_id
+
+    last_message = await send_chunks(msg, chunks, footer, get_answer_menu(beta_model))
+    return last_message.message_id
+
+def get_answer_beta_model(markup: InlineKeyboardMarkup | None) -> str | None:
+    """Бета-модель, для которой под ответом есть кнопки оценки, - чтобы пересобрать кнопки с теми же аргументами."""
+    for row in markup.inline_keyboard if markup else []:
+        for button in row:
+            if button.callback_data and button.callback_data.startswith(f"{BetaFeedback.__prefix__}:"):
+                return BetaFeedback.unpack(button.callback_data).model_name
+    return None
+
+def get_fallback_note(requested_model: str, model: str) -> str:
+    """Пометка в подписи к ответу, если вместо выбранной модели ответила запасная."""
+    return f" (вместо {requested_model}, которая не ответила)" if model != requested_model else ""
+
+def get_model_error_text(model: str, cache: dict) -> str:
+    """Сообщение об ошибке модели; если после сбоев подряд модель отключена, предлагаем выбрать другую."""
+    if not is_model_available(model, cache):
+        return (f"😥 Модель <b>{model}</b> временно недоступна (ошибка сервера).\n\n"
+                "Она автоматически отключена на несколько минут. Пожалуйста, выберите другую модель." + format_error_code())
+    return f"😥 Модель <b>{model}</b> не ответила (ошибка сервера). Попробуйте отправить запрос еще раз." + format_error_code()
+
+async def send_limit_reached_message(message: Message, db: Database, user_id: int | None = None):
+    user_id = user_id or message.from_user.id
     details = await get_user_details_cached(user_id, db, message.bot.get('cache'))
     has_bonus = details[8] if details else False
     user_level = await get_user_level(user_id, db)
+    daily_limit, _ = await get_user_limits(user_id, db)
 
-    if user_level == 0 and not has_bonus:
-        await show_reward_offer(message)
-    else:
-        await message.answer('Достигнут дневной лимит запросов. Попробуйте снова завтра или рассмотрите улучшение подписки.')
+    text, reply_markup = build_limit_message(user_level, daily_limit, has_bonus)
+    await message.answer(text, reply_markup=reply_markup, disable_web_page_preview=True)
 
 # --- Обработчики выбора модели ---
 @router.callback_query(Menu.filter(F.action == 'models'))
-async def list_model_categories(callback: CallbackQuery, state: FSMContext, db: Database, bot: Bot):
+async def list_model_categories(callback: CallbackQuery, state: FSMContext, db: Database, bot: Bot, cache: dict):
     await callback.answer()
     if not await check_authentication(callback.from_user, db, state, bot):
         return
 
-    user_level = await get_user_level(callback.from_user.id, db)
-    accessible_models = set(MODELS['free'])
-    if user_level >= 1: accessible_models.update(MODELS['standard'])
-    if user_level >= 2: accessible_models.update(MODELS['premium'])
+    accessible_models = await get_user_accessible_models(callback.from_user.id, db)
 
     available_categories = [
         cat for cat, models_in_cat in MODEL_CATEGORIES.items()
         if any(m in accessible_models for m in models_in_cat)
     ]
+    last_category = await db.get_last_model_category(callback.from_user.id)
+    # Если модель еще не выбрана (например, после сброса), предлагаем самую используемую
+    details = await get_user_details_cached(callback.from_user.id, db, cache)
+    favorite_model = None
+    if details and not details[5]:
+        favorite_model = await get_favorite_model(callback.from_user.id, db, accessible_models)
+        if favorite_model and not is_model_available(favorite_model, cache):
+            favorite_model = None
 
     try:
         await callback.message.edit_text(
             'Выберите категорию:',
-            reply_markup=get_model_categories_menu(available_categories)
+            reply_markup=get_model_categories_menu(available_categories, last_category, favorite_model)
         )
     except TelegramBadRequest as e:
         if "message is not modified" not in e.message:
@@ -89,23 +215,48 @@ async def list_model_categories(callback: CallbackQuery, state: FSMContext, db:
 async def list_models_in_category(callback: CallbackQuery, callback_data: ModelCategory, db: Database, cache: dict):
     await callback.answer()
     category = callback_data.name
-    user_level = await get_user_level(callback.from_user.id, db)
-
-    accessible_models = set(MODELS['free'])
-    if user_level >= 1: accessible_models.update(MODELS['standard'])
-    if user_level >= 2: accessible_models.update(MODELS['premium'])
+    accessible_models = await get_user_accessible_models(callback.from_user.id, db)
 
     category_models = [m for m in MODEL_CATEGORIES.get(category, []) if m in accessible_models]
+    if callback_data.page == 0 and category in MODEL_CATEGORIES:
+        await db.set_last_model_category(callback.from_user.id, category)
 
     try:
         await callback.message.edit_text(
             f'Модели в категории "{category}":',
-            reply_markup=get_models_menu(category, category_models, cache['model_status'].get('statuses', {}))
+            reply_markup=get_models_menu(
+                category, category_models, get_model_statuses(cache), callback_data.page
+            )
         )
     except TelegramBadRequest as e:
         if "message is not modified" not in e.message:
             logger.error(f"Error in list_models_in_category: {e}")
 
+@router.callback_query(ModelDetails.filter())
+async def show_model_details(callback: CallbackQuery, callback_data: ModelDetails, cache: dict):
+    await callback.answer()
+    model = callback_data.model_name
+    info = MODEL_INFO.get(model)
+    category = next((cat for cat, models in MODEL_CATEGORIES.items() if model in models), None)
+
+    text = f"<b>{get_model_display_name(model)}</b>\n{hcode(model)}\n\n"
+    if info:
+        text += f"{info.description}\n\n"
+        if info.strengths:
+            text += f"<b>Сильные стороны:</b> {', '.join(info.strengths)}\n"
+        capabilities = [name for capability, name in CAPABILITY_NAMES.items() if getattr(info, capability)]
+        text += f"<b>Возможности:</b> {', '.join(capabilities) or 'только текст'}\n"
+        text += f"<b>Контекст:</b> до {info.max_context // 1000}K токенов"
+    is_ok = is_model_available(model, cache)
+    if not is_ok:
+        text += "\n\n⚠️ Модель сейчас недоступна."
+
+    try:
+        await callback.message.edit_text(text, reply_markup=get_model_details_menu(model, category, is_ok))
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in show_model_details: {e}")
+
 @router.callback_query(SelectTextModel.filter(F.status == "failed"))
 async def select_failed_model(callback: CallbackQuery):
     await callback.answer("⚠️ Эта модель сейчас недоступна. Выберите другую.", show_alert=True)
@@ -113,99 +264,719 @@ async def select_failed_model(callback: CallbackQuery):
 @router.callback_query(SelectTextModel.filter(F.status == "ok"))
 async def select_model_handler(callback: CallbackQuery, callback_data: SelectTextModel, state: FSMContext, db: Database, cache: dict):
     await callback.answer()
+    await activate_text_model(callback.message, callback.from_user.id, callback_data.model_name, state, db, cache)
+
+@router.callback_query(SwitchModel.filter())
+async def switch_model_handler(callback: CallbackQuery, callback_data: SwitchModel, state: FSMContext, db: Database, cache: dict):
+    """Переводит пользователя на другую модель, сохраняя текущую беседу."""
+    user_id = callback.from_user.id
+    model = callback_data.model_name
+    if model not in await get_user_accessible_models(user_id, db):
+        await callback.answer("Эта модель недоступна на вашем тарифе.", show_alert=True)
+        return
+    if not is_model_available(model, cache):
+        await callback.answer("⚠️ Эта модель сейчас тоже недоступна. Выберите другую в меню моделей.", show_alert=True)
+        return
+
+    previous_model = (await state.get_data()).get('model')
+    await db.set_last_used_model(user_id, model)
+    invalidate_user_cache(user_id, cache)
+    track(cache, 'model_switch', user_id, {'from': previous_model, 'to': model, 'source': callback_data.source})
+    await state.set_state(Chat.in_progress)
+    await state.update_data(model=model, outage_notice=None)
+    await callback.answer(f"Теперь вам отвечает {get_model_display_name(model)}.")
+    logger.info(f"User {user_id} switched from {previous_model} to {model} keeping the conversation ({callback_data.source})")
+    if callback_data.source == 'chat':
+        await callback.message.edit_text(
+            f"🔁 Модель: <b>{get_model_display_name(model)}</b>\n"
+            "Беседа продолжается: новая модель увидит всю историю. Отправьте запрос.",
+            reply_markup=get_chat_menu()
+        )
+    else:
+        await callback.message.edit_reply_markup(reply_markup=None)
+
+@router.callback_query(ChatCallback.filter(F.action.in_({'change_model', 'change_model_back'})))
+async def change_model_handler(callback: CallbackQuery, callback_data: ChatCallback, state: FSMContext, db: Database, cache: dict):
+    """Выбор модели, которая продолжит текущую беседу (например, чтобы сравнить ответы разных моделей)."""
+    if callback_data.action == 'change_model_back':
+        await callback.answer()
+        await callback.message.edit_text('Меню диалога:', reply_markup=get_chat_menu())
+        return
     user_id = callback.from_user.id
+    model = (await state.get_data()).get('model')
+    if not model:
+        await callback.answer("Беседа не начата. Выберите модель в меню моделей.", show_alert=True)
+        return
+    accessible_models = await get_user_accessible_models(user_id, db)
+    models = sorted(m for m in accessible_models if m != model and is_model_available(m, cache))
+    if not models:
+        await callback.answer("Других доступных моделей сейчас нет.", show_alert=True)
+        return
+    await callback.answer()
+    try:
+        await callback.message.edit_text(
+            f"Сейчас отвечает <b>{get_model_display_name(model)}</b>.\n"
+            "Выберите модель, которая продолжит беседу - история сохранится.",
+            reply_markup=get_chat_models_menu(models)
+        )
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in change_model_handler: {e}")
+
+async def activate_text_model(message: Message, user_id: int, model: str, state: FSMContext, db: Database, cache: dict, intro: str = ''):
+    """Проверяет доступ пользователя и начинает чат с выбранной моделью, редактируя сообщение бота."""
     details = await get_user_details_cached(user_id, db, cache)
 
     if details and details[4]:
-        await callback.message.edit_text('Ваш доступ к моделям заблокирован')
+        await message.edit_text('Ваш доступ к моделям заблокирован')
         return
 
     daily_limit, _ = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=False)
+    used_today = await get_usage_today(user_id, db)
 
-    if requests_today >= daily_limit:
-        await send_limit_reached_message(callback.message, db)
+    if used_today >= daily_limit:
+        await send_limit_reached_message(message, db, user_id)
         return
 
-    model = callback_data.model_name
     await db.set_last_used_model(user_id, model)
     invalidate_user_cache(user_id, cache)
+    track(cache, 'model_switch', user_id, {'from': details[5] if details else None, 'to': model})
 
     await state.set_state(Chat.in_progress)
-    await state.update_data(model=model, history=[])
-    await callback.message.edit_text(f'Выбрана модель: <b>{model}</b>\nОтправьте ваш запрос.\n\nДля вызова меню используйте /menu')
+    await state.update_data(model=model)
+    await start_conversation(state)
+    await message.edit_text(f'{intro}Выбрана модель: <b>{get_model_display_name(model)}</b>\nОтправьте ваш запрос.\n\nДля вызова меню используйте /menu')
 
 # --- Обработчики обычного чата ---
 @router.callback_query(ChatCallback.filter(F.action == 'new'))
 async def new_chat_handler(callback: CallbackQuery, state: FSMContext):
     await callback.answer("Начат новый диалог. Контекст очищен.")
-    await state.update_data(history=[])
+    await start_conversation(state)
     model = (await state.get_data()).get('model', 'Не выбрана')
     await callback.message.edit_text(f'<b>Модель: {model}</b>\nОтправьте ваш запрос.')
 
+@router.callback_query(ChatCallback.filter(F.action == 'branch'))
+async def branch_chat_handler(callback: CallbackQuery, state: FSMContext, db: Database):
+    """Создает новую беседу с историей до ответа, под которым нажата кнопка."""
+    data = await state.get_data()
+    if not data.get('model'):
+        await callback.answer("Эта беседа больше недоступна. Выберите модель и начните новую.", show_alert=True)
+        return
+    branch = await branch_conversation(db, state, callback.from_user.id, callback.message.message_id)
+    if not branch:
+        await callback.answer("Этот ответ уже не входит в контекст беседы, ответвиться от него нельзя.", show_alert=True)
+        return
+
+    source_id, _, messages_count = branch
+    await callback.answer()
+    await state.set_state(Chat.in_progress)
+    await callback.message.answer(
+        "🌿 Создана новая ветка беседы от этого ответа "
+        f"(сообщений в контексте: {messages_count}).\n"
+        "Отправьте запрос, чтобы продолжить с этого места. Исходная беседа сохранена.",
+        reply_markup=get_conversation_switch_menu(source_id, '↩️ Вернуться к исходной беседе')
+    )
+
+@router.callback_query(BetaFeedback.filter())
+async def beta_feedback_handler(callback: CallbackQuery, callback_data: BetaFeedback, db: Database):
+    """Оценка тестировщиком ответа бета-модели. Повторное нажатие меняет оценку."""
+    await db.save_beta_feedback(callback.from_user.id, callback_data.model_name, callback_data.score, callback.message.message_id)
+    await callback.answer("Спасибо за оценку!" if callback_data.score > 0 else "Спасибо, учтем при доработке модели.")
+
+@router.callback_query(ChatCallback.filter(F.action.in_({'telegraph', 'expand'})))
+async def long_answer_handler(callback: CallbackQuery, callback_data: ChatCallback, state: FSMContext, db: Database):
+    """Публикует слишком длинный ответ в Telegraph или присылает его целиком сообщениями."""
+    found = await find_message(db, callback.from_user.id, await state.get_data(), callback.message.message_id)
+    if not found:
+        await callback.answer("Этот ответ уже не входит в сохраненные беседы.", show_alert=True)
+        return
+    _, history = found
+    answer = history[-1]['content']
+
+    if callback_data.action == 'expand':
+        await callback.answer()
+        beta_model = get_answer_beta_model(callback.message.reply_markup)
+        await callback.message.edit_reply_markup(reply_markup=get_answer_menu(beta_model))
+        for chunk in split_message(markdown_to_html(answer)):
+            await _send_html(callback.message.answer, chunk)
+        return
+
+    prompt = history[-2]['content'] if len(history) > 1 and history[-2]['role'] == 'user' else ''
+    try:
+        url = await publish_page(db, prompt.split('\n')[0][:100] or "Ответ MiniArima", answer)
+    except (TelegraphError, aiohttp.ClientError, asyncio.TimeoutError) as e:
+        logger.error(f"Telegraph publishing failed for user {callback.from_user.id}: {e}")
+        await callback.answer("Не удалось опубликовать ответ. Попробуйте позже или получите его сообщениями.", show_alert=True)
+        return
+
+    await callback.answer("Ответ опубликован.")
+    logger.info(f"User {callback.from_user.id} published a long answer to Telegraph")
+    await callback.message.edit_reply_markup(
+        reply_markup=get_long_answer_menu(url, get_answer_beta_model(callback.message.reply_markup))
+    )
+    await callback.message.reply(f"📄 Ответ опубликован: {url}")
+
+async def _get_regenerable_turn(db: Database, data: dict, message_id: int) -> tuple[int, list] | None:
+    """
+    Если message_id - последний ответ активной беседы, возвращает (id этого ответа в БД, история до него).
+    Перегенерировать можно только последний ответ: более ранние уже продолжены следующими запросами.
+    """
+    conversation_id = data.get('conversation_id')
+    last = await db.get_last_conversation_message(conversation_id) if conversation_id is not None else None
+    if not data.get('model') or not last or last[1] != 'assistant' or last[3] != message_id:
+        return None
+    history = (await get_history(db, conversation_id))[:-1]
+    return (last[0], history) if history and history[-1]['role'] == 'user' else None
+
+def _get_saved_answer_markup(data: dict, message_id: int) -> InlineKeyboardMarkup:
+    """Кнопки ответа, сохраненные перед показом выбора модели; для старых сообщений - обычные кнопки ответа."""
+    saved = data.get('regenerate_markup')
+    if saved and saved['message_id'] == message_id:
+        return InlineKeyboardMarkup.model_validate(saved['markup'])
+    return get_answer_menu()
+
+@router.callback_query(ChatCallback.filter(F.action.in_({'regenerate_pick', 'regenerate_back'})))
+async def regenerate_pick_handler(callback: CallbackQuery, callback_data: ChatCallback, state: FSMContext, db: Database, cache: dict):
+    """
+    Показывает под ответом модели, которыми можно ответить заново, или возвращает кнопки, с которыми ответ был
+    отправлен. Эти кнопки на время выбора сохраняются в состоянии: у сообщения их уже не будет.
+    """
+    data = await state.get_data()
+    if callback_data.action == 'regenerate_back':
+        await callback.answer()
+        await callback.message.edit_reply_markup(reply_markup=_get_saved_answer_markup(data, callback.message.message_id))
+        return
+    user_id = callback.from_user.id
+    if not await _get_regenerable_turn(db, data, callback.message.message_id):
+        await callback.answer("Перегенерировать можно только последний ответ текущей беседы.", show_alert=True)
+        return
+    accessible_models = await get_user_accessible_models(user_id, db)
+    models = sorted(m for m in accessible_models if m != data['model'] and is_model_available(m, cache))
+    if not models:
+        await callback.answer("Других доступных моделей сейчас нет.", show_alert=True)
+        return
+    await callback.answer()
+    if callback.message.reply_markup:
+        await state.update_data(regenerate_markup={
+            'message_id': callback.message.message_id,
+            'markup': callback.message.reply_markup.model_dump(mode='json', exclude_none=True)
+        })
+    await callback.message.edit_reply_markup(reply_markup=get_regenerate_models_menu(models))
+
+@router.callback_query(Regenerate.filter())
+async def regenerate_handler(callback: CallbackQuery, callback_data: Regenerate, state: FSMContext, db: Database, ai_client, cache: dict):
+    """
+    Заново отвечает на последний запрос беседы той же или выбранной моделью.
+    Новый ответ заменяет прежний в истории беседы; модель беседы не меняется.
+    """
+    user_id = callback.from_user.id
+    data = await state.get_data()
+    turn = await _get_regenerable_turn(db, data, callback.message.message_id)
+    if not turn:
+        await callback.answer("Перегенерировать можно только последний ответ текущей беседы.", show_alert=True)
+        return
+    answer_row_id, history = turn
+
+    model = callback_data.model_name or data['model']
+    accessible_models = await get_user_accessible_models(user_id, db)
+    if model not in accessible_models:
+        await callback.answer("Эта модель недоступна на вашем тарифе.", show_alert=True)
+        return
+    if not can_answer(model, accessible_models, cache):
+        await callback.answer("⚠️ Эта модель сейчас недоступна. Выберите другую.", show_alert=True)
+        return
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        await callback.answer()
+        await send_limit_reached_message(callback.message, db, user_id)
+        return
+
+    await callback.answer()
+    # Кнопки убираются сразу, чтобы повторное нажатие не запустило вторую генерацию
+    await callback.message.edit_reply_markup(reply_markup=None)
+    msg = await callback.message.answer('Думаю... ⏳')
+    animation_task = asyncio.create_task(animate_waiting(msg))
+    try:
+        response_text, duration, usage = await get_simple_response(ai_client, model, to_api_messages(history), user_id, db, cache)
+        animation_task.cancel()
+        requested_model, model = model, usage.model or model
+        await db.add_request(user_id, model, is_max_mode=False, tokens=usage.tokens)
+        footer = f"\n\n---\nМодель: {model}{get_fallback_note(requested_model, model)} | 🔄 Заново | Время: {duration:.2f} сек."
+        answer_message_id = await deliver_answer(msg, response_text, footer, model if model in BETA_MODELS else None)
+        await db.update_conversation_message(answer_row_id, response_text, answer_message_id)
+        track(cache, 'answer_regenerated', user_id, {'model': model, 'same_model': not callback_data.model_name})
+        logger.info(f"User {user_id} regenerated an answer with model {model}")
+        return
+    except (APIError, RuntimeError) as e:
+        animation_task.cancel()
+        logger.error(f"Regenerate API error for user {user_id} with model {model}: {e}")
+        track(cache, 'error_shown', user_id, {'kind': 'model_error', 'model': model, 'source': 'regenerate'})
+        await msg.edit_text(get_model_error_text(model, cache))
+    except Exception as e:
+        animation_task.cancel()
+        logger.error(f"Generic regenerate error for user {user_id} with model {model}: {e}", exc_info=True)
+        track(cache, 'error_shown', user_id, {'kind': 'unexpected', 'source': 'regenerate'})
+        await msg.edit_text(f'Произошла непредвиденная ошибка: {html.escape(str(e))}' + format_error_code())
+    # Прежний ответ остается в беседе, поэтому его кнопки возвращаются. callback.message - снимок сообщения
+    # до нажатия: при выборе модели в нем меню моделей, иначе - исходные кнопки ответа
+    if callback_data.model_name:
+        markup = _get_saved_answer_markup(data, callback.message.message_id)
+    else:
+        markup = callback.message.reply_markup
+    await callback.message.edit_reply_markup(reply_markup=markup)
+
+@router.callback_query(ChatCallback.filter(F.action == 'share'))
+async def share_chat_handler(callback: CallbackQuery, state: FSMContext, db: Database, bot: Bot):
+    """Публикует снимок текущей беседы и присылает ссылку на него."""
+    data = await state.get_data()
+    history = await get_active_history(db, data)
+    if not history:
+        await callback.answer("В беседе пока нет сообщений, делиться нечем.", show_alert=True)
+        return
+
+    await callback.answer()
+    token, link = await create_share_link(bot, db, callback.from_user.id, data.get('model'), history)
+    logger.info(f"User {callback.from_user.id} shared a conversation ({len(history)} messages)")
+    await callback.message.answer(
+        "🔗 <b>Ссылка на беседу</b>\n\n"
+        f"{link}\n\n"
+        "Открывший ссылку увидит беседу в ее текущем виде, только для чтения. "
+        "Новые сообщения в ссылку не попадут. Отозвать ее можно кнопкой ниже или в настройках.",
+        reply_markup=get_share_menu(token)
+    )
+
+@router.callback_query(ChatCallback.filter(F.action == 'pin'))
+async def pin_chat_handler(callback: CallbackQuery, state: FSMContext, db: Database):
+    """Закрепляет текущую беседу: она не удалится вместе со старыми и будет доступна в избранном."""
+    user_id = callback.from_user.id
+    conversation_id = (await state.get_data()).get('conversation_id')
+    if conversation_id is None or not await db.get_conversation(conversation_id, user_id):
+        await callback.answer("В беседе пока нет сообщений, закреплять нечего.", show_alert=True)
+        return
+    if await db.is_conversation_pinned(conversation_id, user_id):
+        await callback.answer("Беседа уже закреплена. Открепить ее можно в разделе «⭐ Избранное».", show_alert=True)
+        return
+    if not await set_pinned(db, user_id, conversation_id, True):
+        await callback.answer(
+            f"Можно закрепить не больше {MAX_PINNED_CONVERSATIONS} бесед. Открепите одну из них в разделе «⭐ Избранное».",
+            show_alert=True
+        )
+        return
+    await callback.answer("📌 Беседа закреплена. Она доступна в разделе «⭐ Избранное».")
+
+@router.callback_query(ChatCallback.filter(F.action == 'favorite'))
+async def favorite_answer_handler(callback: CallbackQuery, state: FSMContext, db: Database):
+    """Сохраняет ответ, под которым нажата кнопка, в избранное."""
+    user_id = callback.from_user.id
+    found = await find_message(db, user_id, await state.get_data(), callback.message.message_id)
+    if not found:
+        await callback.answer("Этот ответ уже не входит в сохраненные беседы.", show_alert=True)
+        return
+    conversation_id, history = found
+    answer = history[-1]['content']
+    if await db.has_favorite(user_id, answer):
+        await callback.answer("Этот ответ уже в избранном.")
+        return
+
+    prompt = history[-2]['content'] if len(history) > 1 and history[-2]['role'] == 'user' else ''
+    conversation = await db.get_conversation(conversation_id, user_id)
+    model = conversation[1] if conversation else None
+    if not await db.add_favorite(user_id, model, prompt.split('\n')[0][:100], answer, MAX_FAVORITES):
+        await callback.answer(f"В избранном уже {MAX_FAVORITES} ответов. Удалите ненужные, чтобы сохранить новые.", show_alert=True)
+        return
+    await callback.answer("⭐ Ответ сохранен в избранное.")
+
+async def show_favorites(message: Message, user_id: int, db: Database):
+    pinned = await db.get_pinned_conversations(user_id)
+    favorites = await db.get_favorites(user_id)
+    text = (
+        "⭐ <b>Избранное</b>\n\n"
+        f"📌 Закрепленные беседы: {len(pinned)} из {MAX_PINNED_CONVERSATIONS}. "
+        "Они не удаляются вместе со старыми; нажмите, чтобы продолжить беседу.\n"
+        f"⭐ Сохраненные ответы: {len(favorites)} из {MAX_FAVORITES}. Нажмите, чтобы получить ответ снова."
+    )
+    if not pinned and not favorites:
+        text += (
+            "\n\nЗдесь пока пусто. Сохранить ответ можно кнопкой «⭐ В избранное» под ним, "
+            "закрепить беседу - кнопкой «📌 Закрепить беседу» в меню чата."
+        )
+    try:
+        await message.edit_text(text, reply_markup=get_favorites_menu(pinned, favorites))
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in show_favorites: {e}")
+
+@router.callback_query(Menu.filter(F.action == 'favorites'))
+async def favorites_menu_handler(callback: CallbackQuery, state: FSMContext, db: Database, bot: Bot):
+    await callback.answer()
+    if not await check_authentication(callback.from_user, db, state, bot):
+        return
+    await show_favorites(callback.message, callback.from_user.id, db)
+
+@router.callback_query(Favorite.filter(F.action == 'show'))
+async def show_favorite_handler(callback: CallbackQuery, callback_data: Favorite, db: Database):
+    """Присылает сохраненный ответ заново."""
+    favorite = await db.get_favorite(callback_data.favorite_id, callback.from_user.id)
+    if not favorite:
+        await callback.answer("Этого ответа уже нет в избранном.", show_alert=True)
+        return
+    model, _, content = favorite
+    await callback.answer()
+    footer = f"\n\n---\n⭐ Из избранного | Модель: {model or 'неизвестна'}"
+    chunks = split_message(markdown_to_html(content), TELEGRAM_MESSAGE_LIMIT - len(footer)) or [""]
+    chunks[-1] += footer
+    for chunk in chunks[:-1]:
+        await _send_html(callback.message.answer, chunk)
+    await _send_html(callback.message.answer, chunks[-1], reply_markup=get_favorite_menu(callback_data.favorite_id))
+
+@router.callback_query(Favorite.filter(F.action == 'delete'))
+async def delete_favorite_handler(callback: CallbackQuery, callback_data: Favorite, db: Database):
+    if not await db.delete_favorite(callback_data.favorite_id, callback.from_user.id):
+        await callback.answer("Этого ответа уже нет в избранном.", show_alert=True)
+        return
+    await callback.answer("Ответ удален из избранного.")
+    await callback.message.edit_reply_markup(reply_markup=None)
+
+@router.callback_query(Conversation.filter(F.action == 'unpin'))
+async def unpin_conversation_handler(callback: CallbackQuery, callback_data: Conversation, db: Database):
+    await set_pinned(db, callback.from_user.id, callback_data.conversation_id, False)
+    await callback.answer("Беседа откреплена.")
+    await show_favorites(callback.message, callback.from_user.id, db)
+
+@router.callback_query(Conversation.filter(F.action == 'open'))
+async def open_pinned_conversation_handler(callback: CallbackQuery, callback_data: Conversation, state: FSMContext, db: Database, cache: dict):
+    """Продолжает закрепленную беседу с той моделью, с которой она велась (если модель доступна)."""
+    user_id = callback.from_user.id
+    conversation = await db.get_conversation(callback_data.conversation_id, user_id)
+    if not conversation:
+        await callback.answer("Эта беседа больше недоступна.", show_alert=True)
+        return
+
+    model = conversation[1]
+    accessible_models = await get_user_accessible_models(user_id, db)
+    if model not in accessible_models or not is_model_available(model, cache):
+        model = (await state.get_data()).get('model')
+        if not model:
+            await callback.answer("Модель этой беседы сейчас недоступна. Выберите модель, а затем откройте беседу снова.", show_alert=True)
+            return
+
+    await switch_conversation(db, state, user_id, callback_data.conversation_id)
+    await state.set_state(Chat.in_progress)
+    await state.update_data(model=model)
+    await callback.answer()
+    await callback.message.edit_text(
+        f"📌 Беседа открыта. Модель: <b>{get_model_display_name(model)}</b>\n"
+        "Следующий запрос продолжит ее с последнего ответа.",
+        reply_markup=get_chat_menu()
+    )
+
+@router.callback_query(Conversation.filter(F.action == 'switch'))
+async def switch_conversation_handler(callback: CallbackQuery, callback_data: Conversation, state: FSMContext, db: Database):
+    """Переключает активную беседу; кнопка в сообщении меняется на обратный переход."""
+    previous_id = (await state.get_data()).get('conversation_id')
+    if not await switch_conversation(db, state, callback.from_user.id, callback_data.conversation_id):
+        await callback.answer("Эта беседа больше недоступна.", show_alert=True)
+        return
+
+    await callback.answer()
+    await state.set_state(Chat.in_progress)
+    text = "↩️ Беседа переключена. Следующий запрос продолжит ее с последнего ответа."
+    reply_markup = None
+    if previous_id and previous_id != callback_data.conversation_id:
+        reply_markup = get_conversation_switch_menu(previous_id, '🌿 Перейти в другую ветку')
+    try:
+        await callback.message.edit_text(text, reply_markup=reply_markup)
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in switch_conversation_handler: {e}")
+
+# Вопрос к фото без подписи
+DEFAULT_IMAGE_PROMPT = "Что изображено на этой картинке?"
+
+@router.message(Chat.in_progress, F.photo)
+async def handle_chat_photo(message: Message, state: FSMContext, db: Database, ai_client, cache: dict, bot: Bot):
+    """
+    Фото в чате: модель с поддержкой изображений получает его вместе с подписью (вопросом).
+    Альбом обрабатывается одним запросом со всеми фото. Если текущая модель не понимает изображения, предлагаем подходящие.
+    """
+    user_id = message.from_user.id
+    parts = [message]
+    if message.media_group_id:
+        parts = await collect_media_group(message, cache)
+        if parts is None: # Эту часть альбома обработает первая
+            return
+    model = (await state.get_data()).get('model')
+    if model_supports(model, 'vision'):
+        prompt = get_media_group_caption(parts) or DEFAULT_IMAGE_PROMPT
+        image_urls = []
+        try:
+            for part in parts:
+                async with receive_file(part, user_id, bot, db) as incoming:
+                    image_urls.append(await read_as_data_url(incoming))
+        except FileIntakeError as e:
+            await message.answer(str(e))
+            return
+        await process_chat_prompt(parts[0], user_id, prompt, state, db, ai_client, cache, image_urls=image_urls)
+        return
+
+    capable_models = sorted(
+        m for m in await get_user_accessible_models(message.from_user.id, db)
+        if model_supports(m, 'vision') and is_model_available(m, cache)
+    )
+    if not capable_models:
+        await message.answer(f"Модель <b>{get_model_display_name(model)}</b> не понимает изображения, а подходящих моделей на вашем тарифе сейчас нет.")
+        return
+    await message.answer(
+        f"Модель <b>{get_model_display_name(model)}</b> не понимает изображения.\n"
+        "Выберите модель с поддержкой изображений и отправьте фото еще раз:",
+        reply_markup=get_capable_models_menu(capable_models)
+    )
+
+@router.message(Chat.in_progress, F.document)
+async def handle_chat_document(message: Message, state: FSMContext, db: Database, ai_client, cache: dict, bot: Bot):
+    """Документ в чате (.txt, .md, .pdf): текущая модель кратко излагает его содержимое."""
+    user_id = message.from_user.id
+    details = await get_user_details_cached(user_id, db, cache)
+    if details and details[4]:
+        await message.answer('Ваш доступ к моделям заблокирован администратором.')
+        return
+
+    incoming = extract_file(message)
+    if not is_supported_document(incoming):
+        await message.answer(f"📄 Для краткого изложения поддерживаются документы {', '.join(DOCUMENT_EXTENSIONS)}.")
+        return
+    if incoming.size and incoming.size > DOCUMENT_MAX_SIZE:
+        await message.answer(
+            f"Документ слишком большой: {incoming.size / 1024 / 1024:.1f} МБ. "
+            f"Можно отправить документ до {DOCUMENT_MAX_SIZE // 1024 // 1024} МБ."
+        )
+        return
+
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        await state.clear()
+        await send_limit_reached_message(message, db, user_id)
+        return
+    model = (await state.get_data()).get('model')
+    accessible_models = await get_user_accessible_models(user_id, db)
+    if not can_answer(model, accessible_models, cache):
+        await message.answer(f"😥 Ваша текущая модель <b>{model}</b> сейчас недоступна.", reply_markup=get_chat_menu())
+        return
+
+    file_name = incoming.file_name
+    msg = await message.answer('Читаю документ... ⏳')
+    animation_task = asyncio.create_task(animate_waiting(msg, "Читаю документ"))
+    try:
+        async with receive_file(message, user_id, bot, db) as incoming:
+            text = await extract_document_text(incoming, db)
+        summary, duration, usage = await summarize_document(ai_client, model, file_name, text, user_id, db, cache)
+        animation_task.cancel()
+        requested_model, model = model, usage.model or model
+        await db.add_request(user_id, model, is_max_mode=False, tokens=usage.tokens)
+        footer = f"\n\n---\nМодель: {model}{get_fallback_note(requested_model, model)} | Документ: {html.escape(file_name)} | Время: {duration:.2f} сек."
+        answer_message_id = await deliver_answer(msg, summary, footer, model if model in BETA_MODELS else None)
+        # В беседу попадает только резюме, чтобы по документу можно было задавать вопросы дальше
+        await append_messages(db, state, user_id, [
+            {"role": "user", "content": f"📄 [Документ «{file_name}»] Кратко изложи документ.", "message_id": message.message_id},
+            {"role": "assistant", "content": summary, "message_id": answer_message_id}
+        ], model)
+    except FileIntakeError as e:
+        animation_task.cancel()
+        await msg.edit_text(html.escape(str(e)))
+    except (APIError, RuntimeError) as e:
+        animation_task.cancel()
+        logger.error(f"Document summary error for user {user_id} with model {model}: {e}")
+        track(cache, 'error_shown', user_id, {'kind': 'model_error', 'model': model, 'source': 'document'})
+        await msg.edit_text(get_model_error_text(model, cache))
+    except Exception as e:
+        animation_task.cancel()
+        logger.error(f"Generic document summary error for user {user_id}: {e}", exc_info=True)
+        await msg.edit_text(f'Произошла непредвиденная ошибка: {html.escape(str(e))}' + format_error_code())
+
+async def resume_chat(message: Message, state: FSMContext, db: Database, ai_client, cache: dict):
+    """
+    Вопрос вне чата (см. handlers/intent.py): продолжаем последнюю сохраненную беседу с последней выбранной моделью.
+    Если пользователь был в диалоге до перезапуска бота, режим восстанавливает ChatModeMiddleware.
+    """
+    user_id = message.from_user.id
+    details = await get_user_details_cached(user_id, db, cache)
+    if not details:
+        return
+    accessible_models = await get_user_accessible_models(user_id, db)
+    model = details[5] or await get_favorite_model(user_id, db, accessible_models)
+    if not model or model not in accessible_models:
+        await message.answer("Выберите модель, чтобы начать чат.", reply_markup=await get_main_menu(user_id, db))
+        return
+
+    await state.set_state(Chat.in_progress)
+    await state.update_data(model=model)
+    await resume_last_conversation(db, state, user_id)
+    await process_chat_prompt(message, user_id, message.text, state, db, ai_client, cache)
+
 @router.message(Chat.in_progress)
 async def handle_chat_message(message: Message, state: FSMContext, db: Database, ai_client, cache: dict):
+    """
+    Запрос в чате. Текст, отправленный несколькими сообщениями подряд, собирается в один запрос;
+    ответ приходит на последнее сообщение, и в истории беседы запрос привязан к нему.
+    """
+    if not message.text:
+        await process_chat_prompt(message, message.from_user.id, message.text, state, db, ai_client, cache)
+        return
+    parts = await collect_prompt_parts(message, cache)
+    if parts is None: # Эта часть вошла в запрос, который отправит обработчик первой части
+        return
+    if len(parts) > 1:
+        logger.info(f"User {message.from_user.id} sent a prompt in {len(parts)} parts, sending them as one request")
+    prompt = "\n".join(part.text for part in parts if part.text)
+    await process_chat_prompt(parts[-1], message.from_user.id, prompt, state, db, ai_client, cache)
+
+@router.edited_message(Chat.in_progress, F.text)
+async def handle_edited_prompt(message: Message, state: FSMContext, db: Database, ai_client, cache: dict, bot: Bot):
+    """
+    Пользователь исправил свой последний запрос: беседа откатывается к нему, и модель отвечает на новый текст.
+    Ответ на прежний текст остается в чате, но из истории беседы удаляется.
+    """
     user_id = message.from_user.id
+    found = await find_last_prompt(db, await state.get_data(), message.message_id)
+    if not found:
+        await message.reply(
+            "✏️ Изменить можно только последний запрос текущей беседы. "
+            "Чтобы продолжить с более раннего места, нажмите «🌿 Ответвить» под нужным ответом."
+        )
+        return
+    prompt_row_id, answer_message_id = found
+    # Прежний ответ больше не продолжает беседу: его кнопки (ответвить, перегенерировать) убираются
+    try:
+        await bot.edit_message_reply_markup(chat_id=message.chat.id, message_id=answer_message_id, reply_markup=None)
+    except TelegramBadRequest:
+        pass
+    track(cache, 'prompt_edited', user_id)
+    logger.info(f"User {user_id} edited the last prompt, regenerating the answer")
+    await process_chat_prompt(message, user_id, message.text, state, db, ai_client, cache, replace_from=prompt_row_id)
+
+async def process_chat_prompt(
+    message: Message, user_id: int, prompt: str, state: FSMContext, db: Database, ai_client, cache: dict,
+    image_urls: list[str] | None = None, replace_from: int | None = None
+):
+    """
+    Обрабатывает запрос в обычном чате. Ответ отправляется в чат сообщения message.
+    image_urls (data URL фото) передаются модели вместе с prompt; в истории беседы
+    изображения не хранятся, остается только отметка о них и текст запроса.
+    replace_from - id сообщения активной беседы, которое заменяет этот запрос (исправленный запрос):
+    модель видит историю до него, а после успешного ответа оно и все следующие сообщения удаляются.
+    """
     details = await get_user_details_cached(user_id, db, cache)
 
     if details and details[4]:
         await message.answer('Ваш доступ к моделям заблокирован администратором.')
         return
 
+    try:
+        prepared = await prepare_prompt(prompt, message, user_id, db)
+    except PromptRejected as e:
+        await message.answer(str(e))
+        return
+    prompt = prepared.text
+
+    spam_reason = await check_prompt_abuse(user_id, prompt, cache, ai_client)
+    if spam_reason:
+        await message.answer(get_spam_block_message(spam_reason))
+        return
+
     daily_limit, _ = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=False)
+    used_today = await get_usage_today(user_id, db)
 
-    if requests_today >= daily_limit:
+    if used_today >= daily_limit:
         await state.clear()
-        await send_limit_reached_message(message, db)
+        await send_limit_reached_message(message, db, user_id)
         return
 
     user_data = await state.get_data()
     model = user_data.get('model')
-    history = user_data.get('history', [])
+    if replace_from is not None:
+        history = (await get_history(db, user_data['conversation_id'], up_to_id=replace_from))[:-1]
+    else:
+        history = await get_active_history(db, user_data)
 
-    if not is_model_available(model, cache):
-        await message.answer(
-            f"😥 Ваша текущая модель <b>{model}</b> сейчас недоступна.\n\n"
-            "Пожалуйста, выберите другую модель.",
-            reply_markup=get_chat_menu()
-        )
-        return
+    if is_model_available(model, cache):
+        if user_data.get('outage_notice'):
+            await state.update_data(outage_notice=None)
+    else:
+        # Модель отключена автоматическим выключателем: временно отвечает замена
+        accessible_models = await get_user_accessible_models(user_id, db)
+        fallback_chain = get_fallback_chain(model, accessible_models, cache, 'vision' if image_urls else None)
+        fallback = fallback_chain[0] if fallback_chain else None
+        if not fallback:
+            await message.answer(
+                f"😥 Ваша текущая модель <b>{model}</b> сейчас недоступна.\n\n"
+                "Пожалуйста, выберите другую модель.",
+                reply_markup=get_chat_menu()
+            )
+            return
+        # Баннер показывается один раз за время недоступности модели
+        if user_data.get('outage_notice') != model:
+            await message.answer(
+                f"⚠️ Модель <b>{get_model_display_name(model)}</b> недоступна, "
+                f"временно отвечает <b>{get_model_display_name(fallback)}</b>.\n"
+                "Когда модель снова заработает, ответы вернутся к ней автоматически.",
+                reply_markup=get_outage_banner_menu(fallback)
+            )
+            await state.update_data(outage_notice=model)
+        model = fallback
 
     # --- ИЗМЕНЕНИЕ: Отправляем сообщение сразу с первым кадром анимации ---
     msg = await message.answer('Думаю... ⏳')
     animation_task = asyncio.create_task(animate_waiting(msg))
-    history.append({"role": "user", "content": message.text})
+    if image_urls:
+        mark = "📷 [Изображение]" if len(image_urls) == 1 else f"📷 [Изображения: {len(image_urls)}]"
+        content = f"{mark} {prompt}"
+    else:
+        content = prompt
+    user_entry = {"role": "user", "content": content, "message_id": message.message_id}
+    history.append(user_entry)
+    api_messages = prepared.system_messages() + to_api_messages(history)
+    if image_urls:
+        api_messages[-1]["content"] = build_image_content(prompt, image_urls)
+    journal_id = await db.start_inflight_request(user_id, message.chat.id, 'chat', model, prompt, user_data.get('conversation_id'))
 
     try:
-        response_text, duration = await get_simple_response(ai_client, model, history, user_id, db, cache)
+        stream_interval = get_stream_interval(await get_user_level(user_id, db))
+        on_partial = make_stream_updater(msg, animation_task, stream_interval) if stream_interval else None
+        response_text, duration, usage = await get_simple_response(
+            ai_client, model, api_messages, user_id, db, cache, on_partial=on_partial
+        )
         animation_task.cancel()
-        history.append({"role": "assistant", "content": response_text})
-        await state.update_data(history=history[-10:])
-        await db.add_request(user_id, model, is_max_mode=False)
+        requested_model, model = model, usage.model or model
+        await db.add_request(user_id, model, is_max_mode=False, tokens=usage.tokens)
         temp = details[11] if details and details[11] is not None else DEFAULT_TEMPERATURE
-        footer = f"\n\n---\nМодель: {model} | t: {temp:.1f} | Время: {duration:.2f} сек."
-        await msg.edit_text(response_text + footer)
+        footer = f"\n\n---\nМодель: {model}{get_fallback_note(requested_model, model)} | t: {temp:.1f} | Время: {duration:.2f} сек."
+        answer_message_id = await deliver_answer(msg, response_text, footer, model if model in BETA_MODELS else None)
+        if replace_from is not None:
+            await db.truncate_conversation(user_data['conversation_id'], replace_from)
+        await append_messages(db, state, user_id, [
+            user_entry, {"role": "assistant", "content": response_text, "message_id": answer_message_id}
+        ], model)
     except (APIError, RuntimeError) as e:
         animation_task.cancel()
-        set_model_failed_in_cache(model, cache)
-        history.pop()
-        await state.update_data(history=history)
         logger.error(f"Chat Error for user {user_id} with model {model}: {e}")
-        await msg.edit_text(f"😥 Модель <b>{model}</b> временно недоступна (ошибка сервера).\n\nОна автоматически отключена. Пожалуйста, выберите другую модель.")
+        track(cache, 'error_shown', user_id, {'kind': 'model_error', 'model': model, 'source': 'chat'})
+        await msg.edit_text(get_model_error_text(model, cache))
     except Exception as e:
         animation_task.cancel()
-        history.pop()
-        await state.update_data(history=history)
         logger.error(f"Generic Chat Error for user {user_id} with model {model}: {e}", exc_info=True)
-        await msg.edit_text(f'Произошла непредвиденная ошибка: {e}')
+        track(cache, 'error_shown', user_id, {'kind': 'unexpected', 'source': 'chat'})
+        await msg.edit_text(f'Произошла непредвиденная ошибка: {html.escape(str(e))}' + format_error_code())
+    finally:
+        await db.finish_inflight_request(journal_id)
 
 # --- Обработчики Max Mode ---
 @router.callback_query(Menu.filter(F.action == 'max_mode'))
 async def max_mode_intro(callback: CallbackQuery, db: Database, cache: dict):
     await callback.answer()
     user_id = callback.from_user.id
-    if await get_user_level(user_id, db) != 3:
+    _, max_mode_limit = await get_user_limits(user_id, db)
+    # Пользователи без уровня Max могут попасть сюда с разовыми запусками, выданными администратором
+    if await get_user_level(user_id, db) != 3 and max_mode_limit <= 0:
         await callback.answer("🚀 Max Mode доступен только для подписчиков уровня Max.", show_alert=True)
         return
 
@@ -213,17 +984,21 @@ async def max_mode_intro(callback: CallbackQuery, db: Database, cache: dict):
         await callback.answer("К сожалению, одна или несколько моделей для Max Mode сейчас недоступны. Попробуйте позже.", show_alert=True)
         return
 
-    _, max_mode_limit = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=True)
+    used_today = await get_usage_today(user_id, db, is_max_mode=True)
+    overrides = await db.get_quota_overrides(user_id)
+    bonus_runs = overrides[1] if overrides and overrides[1] else 0
     models_list_str = "\n".join(f"  • {hcode(m)}" for m in MAX_MODE_PARTICIPANTS)
     text = (
         "<b>🚀 Режим Max Mode</b>\n\n"
         "Это специальный режим, в котором ваш запрос обрабатывается "
         "сразу несколькими ведущими моделями для достижения максимального качества ответа.\n\n"
         f"<b>Модели-участники:</b>\n{models_list_str}\n\n"
-        f"<b>Лимит:</b> {requests_today} / {max_mode_limit} запросов в день.\n"
-        "Один запрос в этом режиме списывает одну единицу лимита Max Mode."
+        f"<b>Лимит:</b> {used_today} / {format_quota(max_mode_limit)} в день.\n"
+        + ("Запрос в этом режиме списывает токены всех моделей-участников и арбитра."
+           if QUOTA_MODE == 'tokens' else "Один запрос в этом режиме списывает одну единицу лимита Max Mode.")
     )
+    if bonus_runs:
+        text += f"\n\n🎁 <b>Разовых запусков:</b> {bonus_runs}. Они расходуются первыми, до лимита по тарифу."
     await callback.message.edit_text(text, reply_markup=get_max_mode_activation_menu())
 
 @router.callback_query(MaxModeCallback.filter(F.action == "activate"))
@@ -235,9 +1010,9 @@ async def activate_max_mode(callback: CallbackQuery, state: FSMContext, db: Data
         return
 
     _, max_mode_limit = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=True)
+    used_today = await get_usage_today(user_id, db, is_max_mode=True)
 
-    if requests_today >= max_mode_limit:
+    if used_today >= max_mode_limit:
         await callback.answer("Достигнут дневной лимит запросов в Max Mode.", show_alert=True)
         return
 
@@ -253,22 +1028,84 @@ async def exit_max_mode(callback: CallbackQuery, state: FSMContext, db: Database
     await callback.answer("Вы вышли из Max Mode.")
     await state.clear()
     await callback.message.edit_text(
-        'Главное меню:',
+        await build_main_menu_text(db),
         reply_markup=await get_main_menu(callback.from_user.id, db)
     )
 
 @router.message(MaxMode.in_progress)
 async def handle_max_mode_message(message: Message, state: FSMContext, db: Database, ai_client, cache: dict):
-    user_id = message.from_user.id
+    if MAX_MODE_CONFIRM:
+        await ask_max_mode_confirmation(message, message.from_user.id, message.text, state, db)
+    else:
+        await process_max_mode_prompt(message, message.from_user.id, message.text, state, db, ai_client, cache)
+
+async def ask_max_mode_confirmation(message: Message, user_id: int, prompt: str | None, state: FSMContext, db: Database):
+    """Показывает оценку стоимости запуска Max Mode и запоминает запрос до подтверждения."""
+    if not prompt:
+        await message.answer("В Max Mode можно отправить только текстовый запрос.")
+        return
+    _, max_mode_limit = await get_user_limits(user_id, db)
+    used_today = await get_usage_today(user_id, db, is_max_mode=True)
+    if used_today >= max_mode_limit:
+        await message.answer("Достигнут дневной лимит запросов в Max Mode. Режим автоматически отключен.")
+        await state.clear()
+        return
+
+    estimate = estimate_max_mode_cost(prompt)
+    text = (
+        "<b>🚀 Запуск Max Mode</b>\n\n"
+        f"~{estimate.models} моделей ({len(MAX_MODE_PARTICIPANTS)} участников и арбитр), "
+        f"примерно {format_price(round(estimate.cost * USD_TO_RUB, 2))} внутренней стоимости.\n"
+    )
+    if max_mode_limit != float('inf'):
+        if QUOTA_MODE == 'tokens':
+            text += f"Будет списано около {format_quota(estimate.tokens)} из оставшихся {format_quota(max_mode_limit - used_today)}.\n"
+        else:
+            text += f"Это {used_today + 1} из {max_mode_limit} дневных запусков.\n"
+    await state.update_data(max_mode_prompt=prompt)
+    await message.answer(text + "\nЗапустить?", reply_markup=get_max_mode_confirm_menu())
+
+@router.callback_query(MaxMode.in_progress, MaxModeCallback.filter(F.action == "confirm"))
+async def confirm_max_mode_run(callback: CallbackQuery, state: FSMContext, db: Database, ai_client, cache: dict):
+    data = await state.get_data()
+    prompt = data.get('max_mode_prompt')
+    if not prompt:
+        await callback.answer("Этот запрос уже запущен или отменен.", show_alert=True)
+        return
+    await callback.answer()
+    await state.update_data(max_mode_prompt=None)
+    await callback.message.edit_reply_markup(reply_markup=None)
+    await process_max_mode_prompt(callback.message, callback.from_user.id, prompt, state, db, ai_client, cache)
+
+@router.callback_query(MaxModeCallback.filter(F.action == "cancel"))
+async def cancel_max_mode_run(callback: CallbackQuery, state: FSMContext):
+    await callback.answer("Запуск отменен.")
+    await state.update_data(max_mode_prompt=None)
+    await callback.message.edit_text("Запуск Max Mode отменен. Отправьте новый запрос или используйте /menu для выхода.")
+
+async def process_max_mode_prompt(message: Message, user_id: int, prompt: str, state: FSMContext, db: Database, ai_client, cache: dict):
+    """Обрабатывает запрос в Max Mode. Ответ отправляется в чат сообщения message."""
+    try:
+        prepared = await prepare_prompt(prompt, message, user_id, db)
+    except PromptRejected as e:
+        await message.answer(str(e))
+        return
+    prompt = prepared.text
+
+    spam_reason = await check_prompt_abuse(user_id, prompt, cache, ai_client)
+    if spam_reason:
+        await message.answer(get_spam_block_message(spam_reason))
+        return
+
     if not are_max_mode_models_available(cache):
         await message.answer("К сожалению, одна или несколько моделей для Max Mode стали недоступны. Режим автоматически отключен.")
         await state.clear()
         return
 
     _, max_mode_limit = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=True)
+    used_today = await get_usage_today(user_id, db, is_max_mode=True)
 
-    if requests_today >= max_mode_limit:
+    if used_today >= max_mode_limit:
         await message.answer("Достигнут дневной лимит запросов в Max Mode. Режим автоматически отключен.")
         await state.clear()
         return
@@ -276,25 +1113,54 @@ async def handle_max_mode_message(message: Message, state: FSMContext, db: Datab
     # --- ИЗМЕНЕНИЕ: То же самое для Max Mode ---
     msg = await message.answer("Обработка несколькими моделями... ⏳")
     animation_task = asyncio.create_task(animate_waiting(msg, text="Обработка несколькими моделями"))
+    journal_id = await db.start_inflight_request(user_id, message.chat.id, 'max_mode', MAX_MODE_ARBITER, prompt)
 
     try:
-        response_text, duration = await get_max_mode_response(ai_client, message.text, user_id, db, cache)
+        response_text, duration, run_id, usage = await get_max_mode_response(ai_client, prepared.with_notes(), user_id, db, cache)
         animation_task.cancel()
-        await db.add_request(user_id, "max_mode_ensemble", is_max_mode=True)
+        await add_max_mode_request(user_id, db, tokens=usage.tokens)
         participants_str = ", ".join(f"{hcode(m)}" for m in MAX_MODE_PARTICIPANTS)
         footer = (
             f"\n\n"
             f"--- 🚀 Max Mode ---\n"
             f"<b>Участники:</b> {participants_str}\n"
             f"<b>Арбитр:</b> {hcode(MAX_MODE_ARBITER)}\n"
-            f"<b>Время:</b> {duration:.2f} сек."
+            f"<b>Время:</b> {duration:.2f} сек. | <b>Запуск:</b> #{run_id}"
         )
-        await msg.edit_text(response_text + footer)
+        await send_chunks(msg, split_message(markdown_to_html(response_text), TELEGRAM_MESSAGE_LIMIT - len(footer)), footer)
     except RuntimeError as e:
         animation_task.cancel()
         logger.error(f"Max Mode runtime error for user {user_id}: {e}")
-        await msg.edit_text(f"😥 <b>Произошла ошибка в Max Mode:</b>\n{e}")
+        await msg.edit_text(f"😥 <b>Произошла ошибка в Max Mode:</b>\n{e}" + format_error_code())
     except Exception as e:
         animation_task.cancel()
         logger.error(f"Generic Max Mode error for user {user_id}: {e}", exc_info=True)
-        await msg.edit_text(f"😥 Произошла непредвиденная ошибка в Max Mode: {e}")
\ No newline at end of file
+        await msg.edit_text(f"😥 Произошла непредвиденная ошибка в Max Mode: {html.escape(str(e))}" + format_error_code())
+    finally:
+        await db.finish_inflight_request(journal_id)
+
+# --- Повтор прерванных запросов ---
+@router.callback_query(RetryRequest.filter())
+async def retry_interrupted_request(callback: CallbackQuery, callback_data: RetryRequest, state: FSMContext, db: Database, ai_client, cache: dict):
+    user_id = callback.from_user.id
+    request = await db.get_inflight_request(callback_data.request_id)
+    if not request or request[1] != user_id:
+        await callback.answer("Этот запрос уже недоступен для повтора.", show_alert=True)
+        return
+
+    await callback.answer()
+    request_id, _, _, kind, model, prompt, conversation_id = request
+    await db.finish_inflight_request(request_id)
+    await callback.message.edit_reply_markup(reply_markup=None)
+    logger.info(f"User {user_id} retries interrupted {kind} request {request_id}")
+
+    if kind == 'max_mode':
+        await state.set_state(MaxMode.in_progress)
+        await process_max_mode_prompt(callback.message, user_id, prompt, state, db, ai_client, cache)
+    else:
+        await state.set_state(Chat.in_progress)
+        await state.update_data(model=model)
+        # Запрос повторяется в той же беседе, с ее историей; новая начинается, только если беседы уже нет
+        if conversation_id is None or not await switch_conversation(db, state, user_id, conversation_id):
+            await start_conversation(state)
+        await process_chat_prompt(callback.message, user_id, prompt, state, db, ai_client, cache)
\ No newline at end of file
@@ -6,30 +6,46 @@ import random
 from datetime import datetime
 
 from aiogram import F, Router, Bot
-from aiogram.filters import Command, StateFilter
+from aiogram.filters import Command, CommandObject, StateFilter
 from aiogram.fsm.context import FSMContext
 from aiogram.types import Message, CallbackQuery
 from aiogram.utils.markdown import hcode
 from aiogram.exceptions import TelegramBadRequest
 
 from app.database import Database
-from app.config import ADMIN_IDS, CAPTCHA_VARIANTS, MSK_TZ, LIMITS, REWARD_LIMIT
+from app.config import (
+    ADMIN_IDS, CAPTCHA_VARIANTS, MSK_TZ, QUOTA_LIMITS, QUOTA_REWARD_LIMIT, WELCOME_MAX_MODE_RUNS, PLAN_NAMES,
+    get_model_display_name
+)
 from app.states import Captcha, Chat, MaxMode
 from app.keyboards.inline import get_main_menu, get_chat_menu
 from app.keyboards.callbacks import Menu, Chat as ChatCallback
-from app.services.user_service import invalidate_user_cache, check_authentication, get_user_level
+from app.services.user_service import (
+    invalidate_user_cache, check_authentication, get_user_level, get_user_details_cached, get_favorite_models,
+    get_utc_offset
+)
+from app.services.system_service import get_update_banner, get_announcement_banner
+from app.services.share_service import SHARE_PREFIX, render_shared_conversation
+from app.services.text_service import format_quota, format_token_usage
+from app.services.analytics_service import track
+from app.services.format_service import format_date
 
 logger = logging.getLogger(__name__)
 router = Router()
 
+async def build_main_menu_text(db: Database, text: str = 'Главное меню:') -> str:
+    """Добавляет к тексту главного меню активные баннеры."""
+    return await get_announcement_banner(db) + await get_update_banner(db) + text
+
 # --- Обработчики команд ---
 @router.message(Command('start'), F.chat.type == "private")
-async def start_handler(message: Message, state: FSMContext, db: Database, bot: Bot, cache: dict):
+async def start_handler(message: Message, state: FSMContext, db: Database, bot: Bot, cache: dict, command: CommandObject | None = None):
     await state.clear()
     user = message.from_user
     
     # Проверяем, есть ли пользователь в БД, и добавляем, если нет
     user_in_db = await db.get_user(user.id)
+    track(cache, 'start', user.id, {'new': not user_in_db})
     if not user_in_db:
         is_new = await db.add_user(user.id, user.username)
         if is_new:
@@ -43,6 +59,10 @@ async def start_handler(message: Message, state: FSMContext, db: Database, bot:
             await db.set_user_verified(user.id, False)
             invalidate_user_cache(user.id, cache)
 
+    # Ссылка на чужую беседу: показываем ее до проверки, просмотр не расходует запросы
+    if command and command.args and command.args.startswith(SHARE_PREFIX):
+        await show_shared_conversation(message, db, command.args.removeprefix(SHARE_PREFIX))
+
     # Проверяем верификацию (капчу)
     if not await check_authentication(user, db, state, bot):
         return
@@ -55,10 +75,19 @@ async def start_handler(message: Message, state: FSMContext, db: Database, bot:
 
     current_time_msk = datetime.now(MSK_TZ).strftime("%H:%M МСК")
     await message.answer(
-        f'Привет, это MiniArima!\n\nТекущее время: <b>{current_time_msk}</b>\n\nВыберите действие:',
+        await build_main_menu_text(db, f'Привет, это MiniArima!\n\nТекущее время: <b>{current_time_msk}</b>\n\nВыберите действие:'),
         reply_markup=await get_main_menu(user.id, db)
     )
 
+async def show_shared_conversation(message: Message, db: Database, token: str):
+    parts = await render_shared_conversation(db, token)
+    if not parts:
+        await message.answer("🔗 Ссылка на беседу недействительна: владелец ее отозвал или она введена с ошибкой.")
+        return
+    logger.info(f"User {message.from_user.id} opened a shared conversation")
+    for part in parts:
+        await message.answer(part)
+
 # --- ИЗМЕНЕННЫЙ ХЕНДЛЕР /menu ---
 @router.message(Command('menu'), F.chat.type == "private")
 async def menu_handler(message: Message, state: FSMContext, db: Database, bot: Bot, cache: dict):
@@ -86,7 +115,7 @@ async def menu_handler(message: Message, state: FSMContext, db: Database, bot: B
     else:
         await state.clear()
         await message.answer(
-            'Главное меню:',
+            await build_main_menu_text(db),
             reply_markup=await get_main_menu(message.from_user.id, db)
         )
 
@@ -98,7 +127,7 @@ async def back_to_main_menu(callback: CallbackQuery, state: FSMContext, db: Data
     await state.clear()
     try:
         await callback.message.edit_text(
-            'Главное меню:',
+            await build_main_menu_text(db),
             reply_markup=await get_main_menu(callback.from_user.id, db)
         )
     except TelegramBadRequest as e:
@@ -112,12 +141,14 @@ async def help_handler(callback: CallbackQuery, db: Database):
         f'<b>ℹ️ Справка</b>\n\n'
         f'<b>Доступные команды:</b>\n'
         f'<code>/start</code> - главное меню\n'
-        f'<code>/menu</code> - меню в любой момент\n\n'
-        f'<b>Лимиты запросов в день:</b>\n'
-        f' • <b>Free:</b> {LIMITS[0]["daily"]} (или {REWARD_LIMIT} с бонусом)\n'
-        f' • <b>Standard:</b> {LIMITS[1]["daily"]}\n'
-        f' • <b>Premium:</b> {LIMITS[2]["daily"]}\n'
-        f' • <b>Max:</b> {LIMITS[3]["daily"]} обычных + {LIMITS[3]["max_mode"]} Max Mode'
+        f'<code>/menu</code> - меню в любой момент\n'
+        f'<code>/whatsnew</code> - что нового в боте\n'
+        f'<code>/stats</code> - статистика бота\n\n'
+        f'<b>Дневные лимиты:</b>\n'
+        f' • <b>Free:</b> {format_quota(QUOTA_LIMITS[0]["daily"])} (или {format_quota(QUOTA_REWARD_LIMIT)} с бонусом)\n'
+        f' • <b>Standard:</b> {format_quota(QUOTA_LIMITS[1]["daily"])}\n'
+        f' • <b>Premium:</b> {format_quota(QUOTA_LIMITS[2]["daily"])}\n'
+        f' • <b>Max:</b> {format_quota(QUOTA_LIMITS[3]["daily"])} + {format_quota(QUOTA_LIMITS[3]["max_mode"])} на Max Mode'
     )
     try:
         await callback.message.edit_text(text, reply_markup=await get_main_menu(callback.from_user.id, db))
@@ -125,6 +156,97 @@ async def help_handler(callback: CallbackQuery, db: Database):
         if "message is not modified" not in e.message:
             raise
 
+# --- Список изменений ---
+async def format_changelog(db: Database, show_ids: bool = False) -> str:
+    entries = await db.get_changelog(limit=5)
+    if not entries:
+        return '<b>🆕 Что нового</b>\n\nПока здесь пусто.'
+    lines = ['<b>🆕 Что нового</b>']
+    for entry_id, text, created_at in entries:
+        date_str = format_date(created_at)
+        prefix = f'#{entry_id} · ' if show_ids else ''
+        lines.append(f'\n<b>{prefix}{date_str}</b>\n{text}')
+    return '\n'.join(lines)
+
+@router.message(Command('whatsnew'), F.chat.type == "private")
+async def whatsnew_command_handler(message: Message, db: Database):
+    user_id = message.from_user.id
+    await message.answer(await format_changelog(db, show_ids=user_id in ADMIN_IDS))
+    await db.set_last_seen_changelog_id(user_id, await db.get_latest_changelog_id())
+
+async def get_public_stats(db: Database, cache: dict) -> dict:
+    """Общая статистика без персональных данных. Хранится в кэше несколько минут."""
+    stats_cache = cache["public_stats"]
+    if 'stats' not in stats_cache:
+        requests_today, active_today, top_model = await db.get_public_stats()
+        stats_cache['stats'] = {
+            "total_users": await db.get_user_count(),
+            "requests_today": requests_today,
+            "active_today": active_today,
+            "top_model": top_model,
+        }
+    return stats_cache['stats']
+
+@router.message(Command('stats'), F.chat.type == "private")
+async def stats_command_handler(message: Message, db: Database, cache: dict):
+    stats = await get_public_stats(db, cache)
+    text = (
+        "<b>📊 MiniArima сегодня</b>\n\n"
+        f"👥 Пользователей: <b>{stats['total_users']}</b>\n"
+        f"🟢 Активны сегодня: <b>{stats['active_today']}</b>\n"
+        f"💬 Запросов за сегодня: <b>{stats['requests_today']}</b>\n"
+    )
+    if stats['top_model']:
+        text += f"🏆 Самая популярная модель: <b>{get_model_display_name(stats['top_model'])}</b>\n"
+    text += "\n<i>Данные обновляются раз в несколько минут.</i>"
+    await message.answer(text)
+
+@router.message(Command('profile'), F.chat.type == "private")
+async def profile_command_handler(message: Message, state: FSMContext, db: Database, cache: dict, bot: Bot):
+    """Профиль: тариф, дата регистрации, текущая и самые используемые модели."""
+    if not await check_authentication(message.from_user, db, state, bot):
+        return
+    user_id = message.from_user.id
+    details = await get_user_details_cached(user_id, db, cache)
+    if not details:
+        return
+    utc_offset = await get_utc_offset(db, user_id)
+    user_level = await get_user_level(user_id, db)
+
+    text = f"<b>👤 Ваш профиль</b>\n\nТариф: <b>{PLAN_NAMES[user_level]}</b>"
+    if user_level > 0 and details[3] and user_id not in ADMIN_IDS:
+        text += f" (до {format_date(details[3], utc_offset)})"
+    if details[6]:
+        text += f"\nС нами с {format_date(details[6], utc_offset)}"
+    if details[5]:
+        text += f"\nТекущая модель: <b>{get_model_display_name(details[5])}</b>"
+
+    usage = await get_favorite_models(user_id, db, limit=100)
+    if usage:
+        total = sum(count for _, count in usage)
+        lines = [
+            f" {index}. {get_model_display_name(model)} - {count} ({count * 100 // total}%)"
+            for index, (model, count) in enumerate(usage[:3], start=1)
+        ]
+        text += f"\n\n<b>⭐ Любимая модель:</b> {get_model_display_name(usage[0][0])}\n"
+        text += "Чаще всего вы спрашиваете:\n" + "\n".join(lines)
+    else:
+        text += "\n\nВы еще не задавали вопросов моделям."
+    text += f"\n\nТокены за 30 дней: {format_token_usage(*await db.get_token_usage(user_id, days=30))}"
+    await message.answer(text)
+
+@router.callback_query(Menu.filter(F.action == 'whatsnew'))
+async def whatsnew_callback_handler(callback: CallbackQuery, db: Database):
+    await callback.answer()
+    user_id = callback.from_user.id
+    text = await format_changelog(db, show_ids=user_id in ADMIN_IDS)
+    await db.set_last_seen_changelog_id(user_id, await db.get_latest_changelog_id())
+    try:
+        await callback.message.edit_text(text, reply_markup=await get_main_menu(user_id, db))
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in whatsnew_callback_handler: {e}")
+
 # --- Обработчики Капчи ---
 @router.message(Captcha.waiting_for_answer)
 async def process_captcha(message: Message, state: FSMContext, db: Database, cache: dict):
@@ -132,13 +254,16 @@ async def process_captcha(message: Message, state: FSMContext, db: Database, cac
     correct_answer = user_data.get('captcha_answer')
 
     if message.text and message.text.strip().lower() == correct_answer.lower():
-        await db.set_user_verified(message.from_user.id, True)
+        is_new = await db.verify_new_user(message.from_user.id, WELCOME_MAX_MODE_RUNS)
         invalidate_user_cache(message.from_user.id, cache)
         await state.clear()
-        await message.answer(
-            "✅ Верно! Добро пожаловать.",
-            reply_markup=await get_main_menu(message.from_user.id, db)
-        )
+        text = "✅ Верно! Добро пожаловать."
+        if is_new and WELCOME_MAX_MODE_RUNS > 0:
+            text += (
+                f"\n\n🎁 Подарок новичкам - пробные запуски Max Mode: <b>{WELCOME_MAX_MODE_RUNS}</b>. "
+                "В этом режиме на запрос отвечают сразу несколько ведущих моделей. Попробуйте в меню «🚀 Max Mode»."
+            )
+        await message.answer(text, reply_markup=await get_main_menu(message.from_user.id, db))
         logger.info(f"User {message.from_user.id} passed captcha.")
     else:
         await message.answer("❌ Неверно. Попробуйте еще раз.")
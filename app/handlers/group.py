@@ -1,24 +1,38 @@
 # app/handlers/group.py
 # Обработчики для сообщений в группах
 
+import html
 import logging
 import asyncio
-import aiohttp
+import re
 import time
 
 from aiogram import F, Router, Bot
-from aiogram.types import Message
+from aiogram.filters import Command, CommandObject
+from aiogram.types import Message, CallbackQuery, BufferedInputFile
 from aiogram.utils.markdown import hcode
 
 from app.database import Database
 from app.config import (
     GROUP_TEXT_TRIGGER, GROUP_IMAGE_TRIGGER, DEFAULT_TEXT_MODEL, 
-    DEFAULT_IMAGE_MODEL, API_URL, API_KEY
+    DEFAULT_IMAGE_MODEL, GROUP_MAX_COOLDOWN
 )
-from app.services.user_service import get_user_details_cached, get_user_limits
-from app.services.system_service import is_model_available, set_model_failed_in_cache
-from app.services.ai_service import get_simple_response
-from .chat import animate_waiting # Импортируем анимацию из соседнего модуля
+from app.services.user_service import (
+    get_user_details_cached, get_user_limits, get_usage_today, get_user_level, get_user_accessible_models
+)
+from app.services.system_service import is_model_available, record_model_failure, record_model_success
+from app.services.ai_service import get_simple_response, generate_image
+from app.services.model_service import can_answer
+from app.services.image_queue_service import ImageQueueFull, group_image_queue
+from app.services.trace_service import format_error_code
+from app.services.abuse_service import check_prompt_abuse
+from app.services.prompt_service import PromptRejected, prepare_prompt
+from app.services.limit_message_service import format_reset_countdown
+from app.keyboards.callbacks import ReportOutput
+from app.keyboards.inline import get_report_menu
+from app.services.text_service import TELEGRAM_MESSAGE_LIMIT, split_message
+from app.services.markdown_service import markdown_to_html
+from .chat import animate_waiting, send_chunks # Импортируем анимацию из соседнего модуля
 
 logger = logging.getLogger(__name__)
 router = Router()
@@ -26,8 +40,99 @@ router = Router()
 # Фильтр, чтобы хендлеры работали только в группах и супергруппах
 IS_GROUP = F.chat.type.in_({'group', 'supergroup'})
 
+def starts_with_trigger(trigger: str):
+    """Сообщение начинается с триггера как с отдельного слова: «.text вопрос», но не «.textbook»."""
+    return F.text.regexp(rf'^{re.escape(trigger)}(?:\s|$)')
+
+async def is_group_admin(message: Message) -> bool:
+    member = await message.bot.get_chat_member(message.chat.id, message.from_user.id)
+    return member.status in ('administrator', 'creator')
+
+async def check_group_restrictions(message: Message, db: Database, cache: dict) -> bool:
+    """
+    Проверяет настройки группы: доступ только для админов, задержку между
+    запросами участника и дневной лимит группы. Возвращает True, если запрос можно выполнить.
+    Задержка отсчитывается от запроса, отправленного модели (см. start_group_cooldown).
+    """
+    admins_only, user_cooldown, daily_cap = await db.get_group_settings(message.chat.id)
+
+    if admins_only and not await is_group_admin(message):
+        return False # Молча игнорируем обычных участников
+
+    cooldowns = cache.get("group_cooldowns")
+    cooldown_key = (message.chat.id, message.from_user.id)
+    if user_cooldown and cooldowns is not None and cooldown_key in cooldowns:
+        remaining = int(user_cooldown - (time.monotonic() - cooldowns[cooldown_key]))
+        if remaining > 0:
+            try:
+                await message.reply(f"Подождите {remaining} сек. перед следующим запросом.", disable_notification=True)
+            except Exception:
+                pass
+            return False
+
+    if daily_cap and await db.get_group_requests_today(message.chat.id) >= daily_cap:
+        try:
+            await message.reply("Дневной лимит запросов для этой группы исчерпан.", disable_notification=True)
+        except Exception:
+            pass
+        return False
+    return True
+
+def start_group_cooldown(message: Message, cache: dict):
+    """Отмечает время запроса участника: вызывается, когда запрос прошел все проверки и уходит модели."""
+    cooldowns = cache.get("group_cooldowns")
+    if cooldowns is not None:
+        cooldowns[(message.chat.id, message.from_user.id)] = time.monotonic()
+
+# --- Настройки группы (/groupconfig) ---
+@router.message(IS_GROUP, Command('groupconfig'))
+async def group_config_handler(message: Message, command: CommandObject, db: Database):
+    if not await is_group_admin(message):
+        return
+
+    args = command.args.split() if command.args else []
+    chat_id = message.chat.id
+
+    if len(args) == 2:
+        option, value = args[0].lower(), args[1].lower()
+        try:
+            if option == 'admins' and value in ('on', 'off'):
+                await db.set_group_setting(chat_id, 'admins_only', 1 if value == 'on' else 0)
+            elif option == 'cooldown' and 0 <= int(value) <= GROUP_MAX_COOLDOWN:
+                await db.set_group_setting(chat_id, 'user_cooldown', int(value))
+            elif option == 'cap' and int(value) >= 0:
+                await db.set_group_setting(chat_id, 'daily_cap', int(value))
+            else:
+                raise ValueError
+            logger.info(f"Group {chat_id} setting '{option}' changed to '{value}' by {message.from_user.id}")
+        except ValueError:
+            await message.reply("Неверное значение. Отправьте /groupconfig без параметров, чтобы увидеть справку.")
+            return
+
+    admins_only, user_cooldown, daily_cap = await db.get_group_settings(chat_id)
+    requests_today = await db.get_group_requests_today(chat_id)
+    await message.reply(
+        "<b>⚙️ Настройки группы</b>\n\n"
+        f"Только для админов: <b>{'да' if admins_only else 'нет'}</b>\n"
+        f"Задержка между запросами участника: <b>{user_cooldown} сек.</b>\n"
+        f"Дневной лимит группы: <b>{daily_cap or '∞'}</b> (сегодня: {requests_today})\n\n"
+        "<b>Изменить:</b>\n"
+        "<code>/groupconfig admins on|off</code>\n"
+        f"<code>/groupconfig cooldown СЕК</code> (0–{GROUP_MAX_COOLDOWN})\n"
+        "<code>/groupconfig cap ЧИСЛО</code> (0 — без лимита)"
+    )
+
+# --- Жалобы на ответы бота ---
+@router.callback_query(ReportOutput.filter())
+async def report_output_handler(callback: CallbackQuery, callback_data: ReportOutput, db: Database):
+    if await db.add_abuse_report(callback_data.history_id, callback.from_user.id):
+        logger.info(f"User {callback.from_user.id} reported group output #{callback_data.history_id}")
+        await callback.answer("Жалоба отправлена администраторам. Спасибо!", show_alert=True)
+    else:
+        await callback.answer("Вы уже пожаловались на этот ответ.", show_alert=True)
+
 # --- Обработчик для текстовых запросов (.text) ---
-@router.message(IS_GROUP, F.text.startswith(GROUP_TEXT_TRIGGER))
+@router.message(IS_GROUP, starts_with_trigger(GROUP_TEXT_TRIGGER))
 async def handle_group_text_trigger(message: Message, db: Database, ai_client, cache: dict):
     prompt = message.text[len(GROUP_TEXT_TRIGGER):].strip()
     if not prompt:
@@ -42,45 +147,69 @@ async def handle_group_text_trigger(message: Message, db: Database, ai_client, c
     if user_details[4]: # Заблокирован
         return
 
+    if not await check_group_restrictions(message, db, cache):
+        return
+
+    try:
+        prepared = await prepare_prompt(prompt, message, user_id, db)
+    except PromptRejected as e:
+        try:
+            await message.reply(str(e), disable_notification=True)
+        except Exception:
+            pass
+        return
+    prompt = prepared.text
+
+    # Спам в группах молча игнорируем, чтобы не засорять чат
+    if await check_prompt_abuse(user_id, prompt, cache, ai_client):
+        return
+
     # Проверка лимитов
     daily_limit, _ = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=False)
-    if requests_today >= daily_limit:
+    used_today = await get_usage_today(user_id, db)
+    if used_today >= daily_limit:
         try:
-            await message.reply("У вас закончились лимиты на сегодня.", disable_notification=True)
+            await message.reply(f"У вас закончились лимиты на сегодня. {format_reset_countdown()}", disable_notification=True)
         except Exception:
             pass
         return
 
     model_to_use = user_details[5] or DEFAULT_TEXT_MODEL
-    if not is_model_available(model_to_use, cache):
+    accessible_models = await get_user_accessible_models(user_id, db)
+    if not can_answer(model_to_use, accessible_models, cache):
         try:
             await message.reply(f"Модель {hcode(model_to_use)} сейчас недоступна.", disable_notification=True)
         except Exception:
             pass
         return
 
+    start_group_cooldown(message, cache)
     # --- ИЗМЕНЕНИЕ: Отправляем сообщение сразу с первым кадром анимации ---
     msg = await message.reply('Думаю над ответом... ⏳', disable_notification=True)
     animation_task = asyncio.create_task(animate_waiting(msg))
 
     try:
-        response_text, duration = await get_simple_response(
-            ai_client, model_to_use, [{"role": "user", "content": prompt}], user_id, db, cache
+        response_text, duration, usage = await get_simple_response(
+            ai_client, model_to_use, prepared.system_messages() + [{"role": "user", "content": prompt}], user_id, db, cache
         )
         animation_task.cancel()
-        await db.add_request(user_id, model_to_use, is_max_mode=False)
+        model_to_use = usage.model or model_to_use # Могла ответить запасная модель
+        await db.add_request(user_id, model_to_use, is_max_mode=False, chat_id=message.chat.id, tokens=usage.tokens)
         footer = f"\n\n---\nМодель: {hcode(model_to_use)} | Время: {duration:.2f} сек."
-        await msg.edit_text(response_text + footer)
+        history_id = await db.add_group_history(message.chat.id, user_id, model_to_use, prompt, response_text, msg.message_id)
+        await send_chunks(
+            msg, split_message(markdown_to_html(response_text), TELEGRAM_MESSAGE_LIMIT - len(footer)), footer,
+            get_report_menu(history_id)
+        )
     except Exception as e:
         animation_task.cancel()
         logger.error(f"Group text handler error for user {user_id}: {e}")
-        await msg.edit_text("Произошла ошибка при обработке запроса.")
+        await msg.edit_text("Произошла ошибка при обработке запроса." + format_error_code())
 
 
 # --- Обработчик для генерации изображений (.image) ---
-@router.message(IS_GROUP, F.text.startswith(GROUP_IMAGE_TRIGGER))
-async def handle_group_image_trigger(message: Message, db: Database, cache: dict):
+@router.message(IS_GROUP, starts_with_trigger(GROUP_IMAGE_TRIGGER))
+async def handle_group_image_trigger(message: Message, db: Database, ai_client, cache: dict):
     prompt = message.text[len(GROUP_IMAGE_TRIGGER):].strip()
     if not prompt:
         return
@@ -95,17 +224,22 @@ async def handle_group_image_trigger(message: Message, db: Database, cache: dict
         return
         
     # Проверяем уровень подписки для генерации изображений
-    from app.services.user_service import get_user_level
     user_level = await get_user_level(user_id, db)
     if user_level < 2:
         return # Молча игнорируем, если нет нужного уровня
 
+    if not await check_group_restrictions(message, db, cache):
+        return
+
+    if await check_prompt_abuse(user_id, prompt, cache):
+        return
+
     # Проверка лимитов
     daily_limit, _ = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=False)
-    if requests_today >= daily_limit:
+    used_today = await get_usage_today(user_id, db)
+    if used_today >= daily_limit:
         try:
-            await message.reply("У вас закончились лимиты на сегодня.", disable_notification=True)
+            await message.reply(f"У вас закончились лимиты на сегодня. {format_reset_countdown()}", disable_notification=True)
         except Exception:
             pass
         return
@@ -118,38 +252,45 @@ async def handle_group_image_trigger(message: Message, db: Database, cache: dict
             pass
         return
 
-    # --- ИЗМЕНЕНИЕ: Отправляем сообщение сразу с первым кадром анимации ---
-    msg = await message.reply('Творю... ⏳', disable_notification=True)
-    animation_task = asyncio.create_task(animate_waiting(msg, text="Творю"))
-    
-    start_time = time.time()
+    # Генерации группы выполняются по очереди; пока запрос ждет, участник видит свой номер в очереди
+    try:
+        async with group_image_queue(cache, message.chat.id) as ticket:
+            start_group_cooldown(message, cache)
+            waiting_text = f"⏳ Ваш запрос №{ticket.position} в очереди" if ticket.position else 'Творю... ⏳'
+            msg = await message.reply(waiting_text, disable_notification=True)
+            await ticket.wait_turn()
+            if ticket.position:
+                await msg.edit_text('Творю... ⏳')
+            animation_task = asyncio.create_task(animate_waiting(msg, text="Творю"))
 
-    async with aiohttp.ClientSession() as session:
-        url = f"{API_URL}/images/generations"
-        headers = {"Authorization": f"Bearer {API_KEY}", "Content-Type": "application/json"}
-        payload = {"model": model_to_use, "prompt": prompt, "height": 1024, "width": 1024, "response_format": "url"}
-        try:
-            async with session.post(url, headers=headers, json=payload, timeout=180) as response:
+            try:
+                image_bytes, duration = await generate_image(ai_client, model_to_use, prompt)
+            except Exception as e:
                 animation_task.cancel()
-                if response.status == 200:
-                    duration = time.time() - start_time
-                    data = await response.json()
-                    image_url = data['data'][0]['url']
-                    await db.add_request(user_id, model_to_use, is_max_mode=False)
-                    await msg.delete()
-                    
-                    caption_text = (
-                        f"<b>Модель:</b> {hcode(model_to_use)}\n"
-                        f"<b>Время:</b> {duration:.2f} сек.\n\n"
-                        f"<b>Промпт:</b> {hcode(prompt)}"
-                    )
-                    await message.reply_photo(photo=image_url, caption=caption_text)
-                else:
-                    set_model_failed_in_cache(model_to_use, cache)
-                    error_text = await response.text()
-                    await msg.edit_text(f"😥 Произошла ошибка при генерации.\n<b>Статус:</b> {response.status}\n<b>Ответ:</b> {error_text}")
-        except Exception as e:
-            animation_task.cancel()
-            set_model_failed_in_cache(model_to_use, cache)
-            logger.error(f"Group image generation failed for user {user_id} with model {model_to_use}. Error: {e}", exc_info=True)
-            await msg.edit_text(f"😥 Критическая ошибка: {e}", parse_mode=None)
\ No newline at end of file
+                record_model_failure(model_to_use, cache)
+                logger.error(f"Group image generation failed for user {user_id} with model {model_to_use}. Error: {e}", exc_info=True)
+                await msg.edit_text(f"😥 Произошла ошибка при генерации: {html.escape(str(e))}" + format_error_code())
+                return
+    except ImageQueueFull:
+        try:
+            await message.reply("В этой группе уже много запросов на изображения. Попробуйте чуть позже.", disable_notification=True)
+        except Exception:
+            pass
+        return
+
+    animation_task.cancel()
+    record_model_success(model_to_use, cache)
+    await db.add_request(user_id, model_to_use, is_max_mode=False, chat_id=message.chat.id)
+    await msg.delete()
+
+    caption_text = (
+        f"<b>Модель:</b> {hcode(model_to_use)}\n"
+        f"<b>Время:</b> {duration:.2f} сек.\n\n"
+        f"<b>Промпт:</b> {hcode(prompt)}"
+    )
+    history_id = await db.add_group_history(message.chat.id, user_id, model_to_use, prompt)
+    photo_msg = await message.reply_photo(
+        photo=BufferedInputFile(image_bytes, filename="image.png"), caption=caption_text, reply_markup=get_report_menu(history_id)
+    )
+    # Вместо ответа сохраняется file_id фото: изображение приходит байтами, ссылки на него нет
+    await db.update_group_history(history_id, photo_msg.photo[-1].file_id, photo_msg.message_id)
\ No newline at end of file
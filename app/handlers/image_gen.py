@@ -1,29 +1,46 @@
 # app/handlers/image_gen.py
 # Обработчики для генерации изображений.
 
+import html
 import logging
 import asyncio
-import aiohttp
-import time
 
 from aiogram import F, Router, Bot
 from aiogram.fsm.context import FSMContext
-from aiogram.types import Message, CallbackQuery
+from aiogram.types import Message, CallbackQuery, BufferedInputFile
 from aiogram.utils.markdown import hcode
 from aiogram.exceptions import TelegramBadRequest
 
 from app.database import Database
-from app.config import IMAGE_MODELS, API_URL, API_KEY
+from app.config import IMAGE_MODELS, IMAGE_SIZES, DEFAULT_IMAGE_SIZE, IMAGE_STYLES_PER_USER, IMAGE_STYLE_MAX_LENGTH
 from app.states import ImageGen as ImageGenState
-from app.keyboards.callbacks import Menu, SelectImageModel
-from app.keyboards.inline import get_image_models_menu, get_main_menu
-from app.services.user_service import get_user_level, get_user_limits, check_authentication, invalidate_user_cache
-from app.services.system_service import is_model_available, set_model_failed_in_cache
+from app.keyboards.callbacks import Menu, SelectImageModel, ImageSize, ImageGenAction, ImageStyleAction
+from app.keyboards.inline import (
+    get_image_models_menu, get_image_result_menu, get_main_menu, get_image_styles_menu, get_image_style_picker
+)
+from app.services.ai_service import generate_image, paraphrase_image_prompt
+from app.services.image_queue_service import image_generation_slot
+from app.services.trace_service import format_error_code
+from app.services.user_service import (
+    get_user_level, get_user_limits, get_usage_today, check_authentication, invalidate_user_cache, get_user_details_cached
+)
+from app.services.system_service import (
+    is_model_available, record_model_failure, record_model_success, get_model_statuses
+)
 from .chat import animate_waiting, send_limit_reached_message
 
 logger = logging.getLogger(__name__)
 router = Router()
 
+def get_image_size(user_details) -> str:
+    """Выбранный пользователем размер изображения (ключ из IMAGE_SIZES)."""
+    size = user_details[12] if user_details else None
+    return size if size in IMAGE_SIZES else DEFAULT_IMAGE_SIZE
+
+async def build_image_models_menu(user_id: int, db: Database, cache: dict):
+    user_details = await get_user_details_cached(user_id, db, cache)
+    return get_image_models_menu(IMAGE_MODELS, get_model_statuses(cache), get_image_size(user_details))
+
 @router.callback_query(Menu.filter(F.action == 'image_gen'))
 async def start_image_gen_handler(callback: CallbackQuery, state: FSMContext, db: Database, cache: dict, bot: Bot):
     if not await check_authentication(callback.from_user, db, state, bot):
@@ -37,21 +54,39 @@ async def start_image_gen_handler(callback: CallbackQuery, state: FSMContext, db
     
     await callback.answer()
     await state.set_state(ImageGenState.waiting_for_model)
+    await state.update_data(pending_image_prompt=None)
     try:
         await callback.message.edit_text(
-            "Выберите модель для генерации изображения:",
-            reply_markup=get_image_models_menu(IMAGE_MODELS, cache['model_status'].get('statuses', {}))
+            "Выберите размер и модель для генерации изображения:",
+            reply_markup=await build_image_models_menu(callback.from_user.id, db, cache)
         )
     except TelegramBadRequest as e:
         if "message is not modified" not in e.message:
             logger.error(f"Error in start_image_gen_handler: {e}")
 
+@router.callback_query(ImageSize.filter(), ImageGenState.waiting_for_model)
+async def select_image_size_handler(callback: CallbackQuery, callback_data: ImageSize, db: Database, cache: dict):
+    if callback_data.size not in IMAGE_SIZES:
+        await callback.answer("Неизвестный размер.", show_alert=True)
+        return
+    await db.set_last_image_size(callback.from_user.id, callback_data.size)
+    invalidate_user_cache(callback.from_user.id, cache)
+    _, width, height = IMAGE_SIZES[callback_data.size]
+    await callback.answer(f"Размер: {width}×{height}")
+    try:
+        await callback.message.edit_reply_markup(reply_markup=await build_image_models_menu(callback.from_user.id, db, cache))
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in select_image_size_handler: {e}")
+
 @router.callback_query(SelectImageModel.filter(F.status == "failed"))
 async def select_failed_image_model(callback: CallbackQuery):
     await callback.answer("⚠️ Эта модель сейчас недоступна. Выберите другую.", show_alert=True)
 
 @router.callback_query(SelectImageModel.filter(F.status == "ok"), ImageGenState.waiting_for_model)
-async def select_image_model_handler(callback: CallbackQuery, callback_data: SelectImageModel, state: FSMContext, db: Database, cache: dict):
+async def select_image_model_handler(
+    callback: CallbackQuery, callback_data: SelectImageModel, state: FSMContext, db: Database, ai_client, cache: dict
+):
     await callback.answer()
     await db.set_last_used_image_model(callback.from_user.id, callback_data.model_name)
     invalidate_user_cache(callback.from_user.id, cache)
@@ -59,10 +94,71 @@ async def select_image_model_handler(callback: CallbackQuery, callback_data: Sel
     await state.update_data(image_model=callback_data.model_name)
     await state.set_state(ImageGenState.waiting_for_prompt)
 
+    # Промпт уже получен из сообщения вне меню (см. start_image_request): сразу генерируем
+    prompt = (await state.get_data()).get('pending_image_prompt')
+    if prompt:
+        await state.update_data(pending_image_prompt=None)
+        await callback.message.edit_text(f"Выбрана модель: <b>{callback_data.model_name}</b>.")
+        await start_image_generation(callback.message, callback.from_user.id, callback_data.model_name, prompt, state, db, ai_client, cache)
+        return
     await callback.message.edit_text(f"Выбрана модель: <b>{callback_data.model_name}</b>.\n\nТеперь отправьте мне текстовый промпт.")
 
+async def start_image_request(message: Message, user_id: int, prompt: str, state: FSMContext, db: Database, ai_client, cache: dict):
+    """
+    Генерация по сообщению вне меню (например, «нарисуй кота»): с последней выбранной моделью,
+    а если она не выбрана или недоступна - после выбора модели. Пустой промпт запрашивается отдельно.
+    """
+    if await get_user_level(user_id, db) < 2:
+        await message.answer("🎨 Генерация изображений доступна только для подписчиков Premium и Max.")
+        return
+    user_details = await get_user_details_cached(user_id, db, cache)
+    model = user_details[9] if user_details else None
+    if model not in IMAGE_MODELS or not is_model_available(model, cache):
+        await state.set_state(ImageGenState.waiting_for_model)
+        await state.update_data(pending_image_prompt=prompt or None)
+        await message.answer(
+            "Выберите размер и модель для генерации изображения:",
+            reply_markup=await build_image_models_menu(user_id, db, cache)
+        )
+        return
+
+    await state.update_data(image_model=model)
+    if not prompt:
+        await state.set_state(ImageGenState.waiting_for_prompt)
+        await message.answer(f"Модель: <b>{model}</b>.\n\nЧто нарисовать? Отправьте текстовый промпт.")
+        return
+    await start_image_generation(message, user_id, model, prompt, state, db, ai_client, cache)
+
+@router.callback_query(ImageGenAction.filter(F.action == 'again'))
+async def generate_again_handler(callback: CallbackQuery, state: FSMContext, db: Database, cache: dict, bot: Bot):
+    """Повторная генерация: ждем новый промпт для последней использованной модели."""
+    user_id = callback.from_user.id
+    if not await check_authentication(callback.from_user, db, state, bot):
+        await callback.answer("Сначала пройдите проверку.", show_alert=True)
+        return
+    if await get_user_level(user_id, db) < 2:
+        await callback.answer("🎨 Генерация изображений доступна только для подписчиков Premium и Max.", show_alert=True)
+        return
+
+    user_details = await get_user_details_cached(user_id, db, cache)
+    model = user_details[9] if user_details else None
+    if model not in IMAGE_MODELS or not is_model_available(model, cache):
+        # Модель больше недоступна - предлагаем выбрать другую
+        await callback.answer()
+        await state.set_state(ImageGenState.waiting_for_model)
+        await callback.message.answer(
+            "Выберите размер и модель для генерации изображения:",
+            reply_markup=await build_image_models_menu(user_id, db, cache)
+        )
+        return
+
+    await callback.answer()
+    await state.update_data(image_model=model)
+    await state.set_state(ImageGenState.waiting_for_prompt)
+    await callback.message.answer(f"Модель: <b>{model}</b>.\n\nОтправьте новый текстовый промпт.")
+
 @router.message(ImageGenState.waiting_for_prompt)
-async def generate_image_handler(message: Message, state: FSMContext, db: Database, cache: dict):
+async def generate_image_handler(message: Message, state: FSMContext, db: Database, ai_client, cache: dict):
     user_id = message.from_user.id
     user_data = await state.get_data()
     model = user_data.get('image_model')
@@ -81,46 +177,194 @@ async def generate_image_handler(message: Message, state: FSMContext, db: Databa
         await state.clear()
         return
 
+    prompt = message.text
+    if not prompt:
+        await message.answer("Пожалуйста, отправьте промпт текстом.")
+        return
+    await start_image_generation(message, user_id, model, prompt, state, db, ai_client, cache)
+
+async def start_image_generation(message: Message, user_id: int, model: str, prompt: str, state: FSMContext, db: Database, ai_client, cache: dict):
+    """Проверяет лимит и генерирует изображение; если у пользователя есть стили, сначала предлагает выбрать стиль."""
     daily_limit, _ = await get_user_limits(user_id, db)
-    requests_today = await db.get_user_requests_today(user_id, is_max_mode=False)
+    used_today = await get_usage_today(user_id, db)
 
-    if requests_today >= daily_limit:
+    if used_today >= daily_limit:
         await state.clear()
-        await send_limit_reached_message(message, db)
+        await send_limit_reached_message(message, db, user_id)
         return
 
-    prompt = message.text
+    styles = await db.get_image_styles(user_id)
+    if styles:
+        # Генерация начнется после выбора стиля (см. pick_image_style_handler)
+        await state.update_data(image_prompt=prompt)
+        await state.set_state(ImageGenState.waiting_for_style)
+        await message.answer("Выберите стиль изображения:", reply_markup=get_image_style_picker(styles))
+        return
+    await state.clear()
+
+    _, width, height = IMAGE_SIZES[get_image_size(await get_user_details_cached(user_id, db, cache))]
+    await run_image_generation(message, user_id, model, prompt, width, height, db, ai_client, cache)
+
+# --- Стили изображений ---
+def apply_image_style(prompt: str, suffix: str) -> str:
+    return f"{prompt.rstrip(' .,')}, {suffix}"
+
+@router.callback_query(ImageStyleAction.filter(F.action.in_({'pick', 'none'})), ImageGenState.waiting_for_style)
+async def pick_image_style_handler(
+    callback: CallbackQuery, callback_data: ImageStyleAction, state: FSMContext, db: Database, ai_client, cache: dict
+):
+    user_id = callback.from_user.id
+    data = await state.get_data()
+    model, prompt = data.get('image_model'), data.get('image_prompt')
+    style_name = "без стиля"
+    if callback_data.action == 'pick':
+        style = await db.get_image_style(callback_data.style_id, user_id)
+        if not style:
+            await callback.answer("Этот стиль уже удален. Выберите другой.", show_alert=True)
+            return
+        style_name, suffix = style
+        prompt = apply_image_style(prompt, suffix)
+    await state.clear()
+
+    if not is_model_available(model, cache):
+        await callback.answer(f"⚠️ Модель {model} сейчас недоступна. Выберите другую.", show_alert=True)
+        return
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        await callback.answer()
+        await send_limit_reached_message(callback.message, db, user_id)
+        return
+
+    await callback.answer()
+    await callback.message.edit_text(f"Стиль: <b>{html.escape(style_name)}</b>")
+    _, width, height = IMAGE_SIZES[get_image_size(await get_user_details_cached(user_id, db, cache))]
+    await run_image_generation(callback.message, user_id, model, prompt, width, height, db, ai_client, cache)
+
+@router.callback_query(ImageStyleAction.filter(F.action.in_({'pick', 'none'})))
+async def stale_image_style_handler(callback: CallbackQuery):
+    await callback.answer("Этот запрос уже обработан. Отправьте новый промпт.", show_alert=True)
+
+async def show_image_styles(message: Message, user_id: int, db: Database, edit: bool = True):
+    styles = await db.get_image_styles(user_id)
+    text = (
+        "<b>🖌 Стили изображений</b>\n\n"
+        "Стиль - текст, который дописывается к промпту, например «акварель, мягкий свет». "
+        "Если стили есть, после промпта бот предложит выбрать один из них.\n\n"
+    )
+    if styles:
+        text += "\n".join(
+            f"{number}. <b>{html.escape(name)}</b>: {html.escape(suffix)}" for number, (_, name, suffix) in enumerate(styles, 1)
+        )
+    else:
+        text += "Стилей пока нет."
+    reply_markup = get_image_styles_menu(
+        [(number, style[0]) for number, style in enumerate(styles, 1)], can_add=len(styles) < IMAGE_STYLES_PER_USER
+    )
+    if edit:
+        await message.edit_text(text, reply_markup=reply_markup)
+    else:
+        await message.answer(text, reply_markup=reply_markup)
+
+@router.callback_query(ImageStyleAction.filter(F.action == 'list'))
+async def image_styles_handler(callback: CallbackQuery, state: FSMContext, db: Database):
+    if await get_user_level(callback.from_user.id, db) < 2:
+        await callback.answer("🎨 Генерация изображений доступна только для подписчиков Premium и Max.", show_alert=True)
+        return
+    await callback.answer()
+    await state.clear()
+    await show_image_styles(callback.message, callback.from_user.id, db)
+
+@router.callback_query(ImageStyleAction.filter(F.action == 'add'))
+async def image_style_add_start(callback: CallbackQuery, state: FSMContext, db: Database):
+    if len(await db.get_image_styles(callback.from_user.id)) >= IMAGE_STYLES_PER_USER:
+        await callback.answer(f"Можно сохранить не больше {IMAGE_STYLES_PER_USER} стилей.", show_alert=True)
+        return
+    await callback.answer()
+    await state.set_state(ImageGenState.waiting_for_style_text)
+    await callback.message.edit_text(
+        "Отправьте стиль в формате <code>Название: текст стиля</code>, например:\n"
+        "<code>Акварель: в стиле акварели, мягкий свет, пастельные тона</code>"
+    )
+
+@router.message(ImageGenState.waiting_for_style_text)
+async def image_style_add_process(message: Message, state: FSMContext, db: Database):
+    name, _, suffix = (message.text or '').partition(':')
+    name, suffix = name.strip(), suffix.strip()
+    if not name or not suffix or len(name) > 30 or len(suffix) > IMAGE_STYLE_MAX_LENGTH:
+        await message.answer(
+            f"❌ Нужны название (до 30 символов) и текст стиля (до {IMAGE_STYLE_MAX_LENGTH} символов) через двоеточие. Попробуйте снова."
+        )
+        return
     await state.clear()
+    await db.add_image_style(message.from_user.id, name, suffix)
+    logger.info(f"User {message.from_user.id} added an image style")
+    await show_image_styles(message, message.from_user.id, db, edit=False)
 
+@router.callback_query(ImageStyleAction.filter(F.action == 'delete'))
+async def image_style_delete_handler(callback: CallbackQuery, callback_data: ImageStyleAction, db: Database):
+    if not await db.delete_image_style(callback_data.style_id, callback.from_user.id):
+        await callback.answer("Этот стиль уже удален.", show_alert=True)
+    else:
+        await callback.answer()
+    await show_image_styles(callback.message, callback.from_user.id, db)
+
+@router.callback_query(ImageGenAction.filter(F.action.in_({'regenerate', 'variation'})))
+async def regenerate_image_handler(callback: CallbackQuery, callback_data: ImageGenAction, db: Database, ai_client, cache: dict):
+    """Повторяет генерацию с тем же промптом или с промптом, переформулированным моделью."""
+    user_id = callback.from_user.id
+    if await get_user_level(user_id, db) < 2:
+        await callback.answer("🎨 Генерация изображений доступна только для подписчиков Premium и Max.", show_alert=True)
+        return
+    generation = await db.get_image_generation(callback_data.generation_id, user_id)
+    if not generation:
+        await callback.answer("Этот запрос больше недоступен. Отправьте новый промпт.", show_alert=True)
+        return
+    model, prompt, width, height = generation
+    if not is_model_available(model, cache):
+        await callback.answer(f"⚠️ Модель {model} сейчас недоступна. Выберите другую.", show_alert=True)
+        return
+
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        await callback.answer()
+        await send_limit_reached_message(callback.message, db, user_id)
+        return
+
+    await callback.answer()
+    if callback_data.action == 'variation':
+        try:
+            prompt = await paraphrase_image_prompt(ai_client, prompt)
+        except Exception as e:
+            logger.warning(f"Image prompt paraphrase failed for user {user_id}: {e}")
+            await callback.message.answer("😥 Не удалось придумать вариацию промпта. Попробуйте позже.")
+            return
+    await run_image_generation(callback.message, user_id, model, prompt, width, height, db, ai_client, cache)
+
+async def run_image_generation(message: Message, user_id: int, model: str, prompt: str, width: int, height: int, db: Database, ai_client, cache: dict):
+    """Генерирует изображение и присылает его в чат message. Лимит и доступность модели проверяются заранее."""
     # --- ИЗМЕНЕНИЕ: Отправляем сообщение сразу с первым кадром анимации ---
     msg = await message.answer("Творю... ⏳")
     animation_task = asyncio.create_task(animate_waiting(msg, text="Творю"))
-    
-    start_time = time.time()
 
-    async with aiohttp.ClientSession() as session:
-        url = f"{API_URL}/images/generations"
-        headers = {"Authorization": f"Bearer {API_KEY}", "Content-Type": "application/json"}
-        payload = {"model": model, "prompt": prompt, "height": 1024, "width": 1024, "response_format": "url"}
-        try:
-            async with session.post(url, headers=headers, json=payload, timeout=180) as response:
-                animation_task.cancel()
-                if response.status == 200:
-                    duration = time.time() - start_time
-                    data = await response.json()
-                    image_url = data['data'][0]['url']
-                    await db.add_request(user_id, model, is_max_mode=False)
-                    await msg.delete()
-                    await message.answer_photo(
-                        photo=image_url, 
-                        caption=f"✅ Готово!\n\n<b>Модель:</b> {hcode(model)}\n<b>Время:</b> {duration:.2f} сек.\n<b>Промпт:</b> {hcode(prompt)}"
-                    )
-                else:
-                    set_model_failed_in_cache(model, cache)
-                    error_text = await response.text()
-                    await msg.edit_text(f"😥 Произошла ошибка при генерации.\n<b>Статус:</b> {response.status}\n<b>Ответ:</b> {error_text}")
-        except Exception as e:
-            animation_task.cancel()
-            set_model_failed_in_cache(model, cache)
-            logger.error(f"Image generation failed for user {user_id} with model {model}. Error: {e}", exc_info=True)
-            await msg.edit_text(f"😥 Критическая ошибка: {e}", parse_mode=None)
\ No newline at end of file
+    try:
+        async with image_generation_slot(cache):
+            image_bytes, duration = await generate_image(ai_client, model, prompt, width, height)
+    except Exception as e:
+        animation_task.cancel()
+        record_model_failure(model, cache)
+        logger.error(f"Image generation failed for user {user_id} with model {model}. Error: {e}", exc_info=True)
+        await msg.edit_text(f"😥 Произошла ошибка при генерации.\n<b>Ответ:</b> {html.escape(str(e))}" + format_error_code())
+        return
+
+    animation_task.cancel()
+    record_model_success(model, cache)
+    await db.add_request(user_id, model, is_max_mode=False)
+    generation_id = await db.add_image_generation(user_id, model, prompt, width, height)
+    await msg.delete()
+    # Подпись к фото ограничена 1024 символами, поэтому длинный промпт показывается не целиком
+    shown_prompt = prompt if len(prompt) <= 700 else prompt[:700] + '…'
+    await message.answer_photo(
+        photo=BufferedInputFile(image_bytes, filename="image.png"),
+        caption=f"✅ Готово!\n\n<b>Модель:</b> {hcode(model)}\n<b>Размер:</b> {width}×{height}\n<b>Время:</b> {duration:.2f} сек.\n<b>Промпт:</b> {hcode(shown_prompt)}",
+        reply_markup=get_image_result_menu(generation_id)
+    )
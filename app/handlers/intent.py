@@ -0,0 +1,58 @@
+# app/handlers/intent.py
+# Сообщения вне меню. Вопрос продолжает последнюю беседу, «нарисуй …» запускает генерацию изображения,
+# «сколько осталось?» показывает остаток лимита (см. intent_service). Роутер подключается перед
+# роутером основных команд: его обработчик нераспознанных сообщений отвечает только на остальное (стикеры, файлы).
+
+import logging
+
+from aiogram import F, Router, Bot
+from aiogram.filters import StateFilter
+from aiogram.fsm.context import FSMContext
+from aiogram.types import Message
+
+from app.database import Database
+from app.config import INTENT_DETECTION
+from app.services.analytics_service import track
+from app.services.intent_service import detect_intent, INTENT_CHAT, INTENT_IMAGE, INTENT_LIMITS
+from app.services.limit_message_service import format_reset_countdown
+from app.services.text_service import format_quota
+from app.services.user_service import check_authentication, get_user_level, get_user_limits, get_usage_today
+from .chat import resume_chat
+from .image_gen import start_image_request
+
+logger = logging.getLogger(__name__)
+router = Router()
+
+
+async def answer_limits(message: Message, user_id: int, db: Database):
+    daily_limit, max_mode_limit = await get_user_limits(user_id, db)
+    used_today = await get_usage_today(user_id, db)
+    text = (
+        f"📊 Сегодня осталось: <b>{format_quota(max(daily_limit - used_today, 0))}</b>\n"
+        f" • Обычный режим: {format_quota(used_today)} / {format_quota(daily_limit)}"
+    )
+    if max_mode_limit:
+        max_used_today = await get_usage_today(user_id, db, is_max_mode=True)
+        text += f"\n • Max Mode: {format_quota(max_used_today)} / {format_quota(max_mode_limit)}"
+    await message.answer(f"{text}\n\n{format_reset_countdown()}")
+
+@router.message(StateFilter(None), F.text, ~F.text.startswith('/'))
+async def free_text_handler(message: Message, state: FSMContext, db: Database, ai_client, cache: dict, bot: Bot):
+    """Текст вне меню: определяем, что хочет пользователь, и ведем его туда без кнопок."""
+    if not await check_authentication(message.from_user, db, state, bot):
+        return
+    user_id = message.from_user.id
+    intent, prompt = detect_intent(message.text) if INTENT_DETECTION else (INTENT_CHAT, message.text)
+    # Генерация изображений доступна с Premium: на младших тарифах просьба нарисовать уходит модели в чат
+    if intent == INTENT_IMAGE and await get_user_level(user_id, db) < 2:
+        intent = INTENT_CHAT
+    if intent != INTENT_CHAT:
+        track(cache, 'intent', user_id, {'kind': intent})
+        logger.info(f"Free text from user {user_id} recognized as '{intent}'")
+
+    if intent == INTENT_IMAGE:
+        await start_image_request(message, user_id, prompt, state, db, ai_client, cache)
+    elif intent == INTENT_LIMITS:
+        await answer_limits(message, user_id, db)
+    else:
+        await resume_chat(message, state, db, ai_client, cache)
@@ -0,0 +1,80 @@
+# app/handlers/model_wizard.py
+# Мастер подбора модели: несколько быстрых вопросов и рекомендация из доступных моделей.
+
+import logging
+
+from aiogram import F, Router
+from aiogram.fsm.context import FSMContext
+from aiogram.types import CallbackQuery
+
+from app.database import Database
+from app.config import MODEL_INFO, get_model_display_name
+from app.states import ModelWizard
+from app.keyboards.callbacks import Menu, WizardAnswer
+from app.keyboards.inline import get_model_wizard_menu
+from app.services.user_service import get_user_accessible_models
+from app.services.model_service import recommend_model
+from .chat import activate_text_model
+
+logger = logging.getLogger(__name__)
+router = Router()
+
+@router.callback_query(Menu.filter(F.action == 'model_wizard'))
+async def start_model_wizard(callback: CallbackQuery, state: FSMContext):
+    await callback.answer()
+    await state.set_state(ModelWizard.waiting_for_priority)
+    await callback.message.edit_text(
+        "<b>🧭 Подбор модели</b>\n\nШаг 1 из 3. Что для вас важнее?",
+        reply_markup=get_model_wizard_menu('priority')
+    )
+
+@router.callback_query(WizardAnswer.filter(F.step == 'priority'), ModelWizard.waiting_for_priority)
+async def wizard_priority_answer(callback: CallbackQuery, callback_data: WizardAnswer, state: FSMContext):
+    await callback.answer()
+    await state.update_data(wizard_priority=callback_data.value)
+    await state.set_state(ModelWizard.waiting_for_task)
+    await callback.message.edit_text(
+        "<b>🧭 Подбор модели</b>\n\nШаг 2 из 3. Для каких задач нужна модель?",
+        reply_markup=get_model_wizard_menu('task')
+    )
+
+@router.callback_query(WizardAnswer.filter(F.step == 'task'), ModelWizard.waiting_for_task)
+async def wizard_task_answer(callback: CallbackQuery, callback_data: WizardAnswer, state: FSMContext):
+    await callback.answer()
+    await state.update_data(wizard_task=callback_data.value)
+    await state.set_state(ModelWizard.waiting_for_budget)
+    await callback.message.edit_text(
+        "<b>🧭 Подбор модели</b>\n\nШаг 3 из 3. Предпочитаете экономичные модели?",
+        reply_markup=get_model_wizard_menu('budget')
+    )
+
+@router.callback_query(WizardAnswer.filter(F.step == 'budget'), ModelWizard.waiting_for_budget)
+async def wizard_budget_answer(callback: CallbackQuery, callback_data: WizardAnswer, state: FSMContext, db: Database, cache: dict):
+    await callback.answer()
+    user_id = callback.from_user.id
+    answers = await state.get_data()
+
+    model = recommend_model(
+        await get_user_accessible_models(user_id, db),
+        answers.get('wizard_priority', 'balance'),
+        answers.get('wizard_task', 'general'),
+        callback_data.value,
+        cache
+    )
+    await state.clear()
+
+    if not model:
+        await callback.message.edit_text(
+            "😥 Сейчас нет доступных моделей. Попробуйте позже.",
+            reply_markup=get_model_wizard_menu('priority')
+        )
+        return
+
+    logger.info(f"Model wizard recommended {model} to user {user_id}")
+    info = MODEL_INFO[model]
+    intro = f"🧭 Рекомендуем: <b>{get_model_display_name(model)}</b>\n{info.description}\n\n"
+    await activate_text_model(callback.message, user_id, model, state, db, cache, intro=intro)
+
+@router.callback_query(WizardAnswer.filter())
+async def wizard_stale_answer(callback: CallbackQuery):
+    await callback.answer("Мастер подбора устарел. Запустите его заново.", show_alert=True)
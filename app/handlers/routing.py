@@ -0,0 +1,46 @@
+# app/handlers/routing.py
+# Разведение обновлений по типу чата. Роутеры личных сообщений получают только личные чаты,
+# групповой роутер - только группы и супергруппы. Посты каналов и сообщения, отправленные
+# от имени чата (анонимные администраторы группы, пользователи, пишущие от имени канала,
+# автопересылка постов в группу обсуждения), обрабатываются здесь явно: по ним нельзя
+# определить пользователя, поэтому ни доступ, ни лимиты для них проверить нельзя.
+
+import logging
+
+from aiogram import F, Router
+from aiogram.types import Message
+
+from app.config import GROUP_TEXT_TRIGGER, GROUP_IMAGE_TRIGGER
+
+logger = logging.getLogger(__name__)
+router = Router(name="routing")
+
+PRIVATE_CHAT_TYPES = {'private'}
+GROUP_CHAT_TYPES = {'group', 'supergroup'}
+
+
+def build_chat_type_router(name: str, chat_types: set, *routers: Router) -> Router:
+    """Объединяет роутеры под общим фильтром: сообщения и нажатия кнопок только из чатов указанных типов."""
+    chat_type_router = Router(name=name)
+    chat_type_router.message.filter(F.chat.type.in_(chat_types))
+    chat_type_router.callback_query.filter(F.message.chat.type.in_(chat_types))
+    chat_type_router.include_routers(*routers)
+    return chat_type_router
+
+@router.channel_post()
+async def ignore_channel_post(message: Message):
+    """В каналах бот не работает: посты игнорируются (например, если бота добавили администратором канала)."""
+    logger.debug(f"Ignored post in channel {message.chat.id}")
+
+@router.message(F.chat.type.in_(GROUP_CHAT_TYPES), F.sender_chat)
+async def sender_chat_message(message: Message):
+    """Сообщение от имени чата. На обращение к боту отвечаем подсказкой, остальное игнорируем."""
+    if message.is_automatic_forward or not message.text:
+        return
+    if message.text.startswith((GROUP_TEXT_TRIGGER, GROUP_IMAGE_TRIGGER, '/')):
+        logger.info(f"Ignored request on behalf of chat {message.sender_chat.id} in group {message.chat.id}")
+        await message.reply(
+            "Бот не отвечает на сообщения, отправленные от имени группы или канала: "
+            "по ним нельзя проверить доступ и лимиты. Отправьте запрос от своего имени.",
+            disable_notification=True
+        )
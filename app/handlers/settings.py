@@ -1,6 +1,7 @@
 # app/handlers/settings.py
 # Обработчики для меню настроек пользователя.
 
+import html
 import logging
 
 from aiogram import F, Router, Bot
@@ -10,15 +11,42 @@ from aiogram.utils.markdown import hcode
 from aiogram.exceptions import TelegramBadRequest
 
 from app.database import Database
-from app.config import DEFAULT_TEMPERATURE
+from app.config import (
+    DEFAULT_TEMPERATURE, SETTINGS_HISTORY_SIZE, DEFAULT_QUIET_HOURS, DEFAULT_UTC_OFFSET, API_TOKEN_MIN_LEVEL, PUBLIC_API_URL,
+    WEBHOOKS_PER_USER, WEBHOOK_RATE_LIMIT, DEFAULT_TEXT_MODEL, SCHEDULED_PROMPTS_MIN_LEVEL, SCHEDULED_PROMPTS_PER_USER,
+    SCHEDULED_PROMPT_MAX_LENGTH, MAX_MODE_INSTRUCTION_MODES
+)
 from app.states import Settings as SettingsState
-from app.keyboards.callbacks import Menu, Settings as SettingsCallback
-from app.keyboards.inline import get_settings_menu, get_main_menu
-from app.services.user_service import check_authentication, get_user_details_cached, invalidate_user_cache
+from app.keyboards.callbacks import Menu, Settings as SettingsCallback, SharedLink, WebhookAction, ScheduledPromptAction
+from app.keyboards.inline import (
+    get_settings_menu, get_main_menu, get_shared_links_menu, get_api_token_menu, get_webhooks_menu,
+    get_scheduled_prompts_menu
+)
+from app.services.user_service import (
+    check_authentication, get_user_details_cached, invalidate_user_cache, get_user_level, get_user_accessible_models,
+    get_utc_offset
+)
+from app.services.share_service import get_share_link
+from app.services.ai_service import get_max_mode_instruction
+from app.services.format_service import format_datetime
+from app.services.analytics_service import track
+from app.services.token_service import issue_api_token
+from app.services.webhook_service import create_webhook
+from app.services.scheduled_prompt_service import (
+    parse_schedule, format_schedule, compute_next_run, weekdays_to_str
+)
 
 logger = logging.getLogger(__name__)
 router = Router()
 
+def format_quiet_hours(quiet_start, quiet_end) -> str:
+    if quiet_start is None or quiet_end is None or quiet_start == quiet_end:
+        return "Отключены"
+    return f"{quiet_start:02d}:00–{quiet_end:02d}:00"
+
+def format_utc_offset(utc_offset) -> str:
+    return f"UTC{(utc_offset or 0):+d}"
+
 @router.callback_query(Menu.filter(F.action == 'settings'))
 async def settings_menu_handler(callback: CallbackQuery, state: FSMContext, db: Database, cache: dict, bot: Bot):
     if not await check_authentication(callback.from_user, db, state, bot):
@@ -27,24 +55,81 @@ async def settings_menu_handler(callback: CallbackQuery, state: FSMContext, db:
     
     await callback.answer()
     await state.clear()
+    await show_settings_menu(callback.message, callback.from_user.id, db, cache)
 
-    user_details = await get_user_details_cached(callback.from_user.id, db, cache)
+async def show_settings_menu(message: Message, user_id: int, db: Database, cache: dict):
+    user_details = await get_user_details_cached(user_id, db, cache)
     instruction = user_details[10] if user_details and user_details[10] else "Не задана"
     temperature = user_details[11] if user_details and user_details[11] is not None else DEFAULT_TEMPERATURE
+    quiet_start, quiet_end, utc_offset = await db.get_notification_settings(user_id) or (*DEFAULT_QUIET_HOURS, DEFAULT_UTC_OFFSET)
+    can_revert = await db.has_settings_history(user_id)
+    max_mode_instruction = MAX_MODE_INSTRUCTION_MODES[await get_max_mode_instruction(db, user_id)]
 
     text = (
         "<b>⚙️ Настройки</b>\n\n"
         "Здесь вы можете настроить поведение модели под себя.\n\n"
         f"<b>Текущая инструкция:</b>\n{hcode(instruction)}\n\n"
-        f"<b>Текущая температура:</b> {hcode(str(temperature))}\n\n"
+        f"<b>Текущая температура:</b> {hcode(str(temperature))}\n"
+        f"<b>Инструкция для участников Max Mode:</b> {hcode(max_mode_instruction)}\n\n"
+        f"<b>Тихие часы:</b> {hcode(format_quiet_hours(quiet_start, quiet_end))}\n"
+        f"<b>Часовой пояс:</b> {hcode(format_utc_offset(utc_offset))}\n\n"
         "<b>Инструкция</b> - это системное сообщение, которое будет направлять модель в каждом запросе. "
-        "<b>Температура</b> (от 0.0 до 2.0) контролирует случайность ответа: низкие значения делают ответ более предсказуемым, высокие - более креативным."
+        "<b>Температура</b> (от 0.0 до 2.0) контролирует случайность ответа: низкие значения делают ответ более предсказуемым, высокие - более креативным.\n"
+        "<b>Инструкция в Max Mode</b> - передавать ли инструкцию каждой модели-участнику (это увеличивает расход токенов). "
+        "Модель-арбитр всегда получает ее целиком.\n"
+        "<b>Тихие часы</b> - время, когда бот не присылает рассылки и напоминания: они придут утром."
     )
+    if can_revert:
+        text += "\n\n<b>↩️ Вернуть предыдущее</b> отменяет последнее изменение инструкции или температуры."
     try:
-        await callback.message.edit_text(text, reply_markup=get_settings_menu())
+        await message.edit_text(text, reply_markup=get_settings_menu(can_revert))
     except TelegramBadRequest as e:
         if "message is not modified" not in e.message:
-            logger.error(f"Error in settings_menu_handler: {e}")
+            logger.error(f"Error in show_settings_menu: {e}")
+
+async def save_model_setting(db: Database, cache: dict, user_id: int, setting: str, value):
+    """Меняет инструкцию или температуру, запоминая прежнее значение для кнопки «Вернуть предыдущее»."""
+    user_details = await get_user_details_cached(user_id, db, cache)
+    previous = user_details[10 if setting == 'instruction' else 11] if user_details else None
+    if previous != value:
+        await db.add_settings_history(user_id, setting, None if previous is None else str(previous), SETTINGS_HISTORY_SIZE)
+    if setting == 'instruction':
+        await db.set_user_instruction(user_id, value)
+    else:
+        await db.set_user_temperature(user_id, value)
+    invalidate_user_cache(user_id, cache)
+    track(cache, 'settings_change', user_id, {'setting': setting})
+
+@router.callback_query(SettingsCallback.filter(F.action == "revert"))
+async def settings_revert_handler(callback: CallbackQuery, db: Database, cache: dict):
+    user_id = callback.from_user.id
+    entry = await db.pop_settings_history(user_id)
+    if not entry:
+        await callback.answer("Нечего возвращать: история изменений пуста.", show_alert=True)
+        return
+
+    setting, value = entry
+    if setting == 'instruction':
+        await db.set_user_instruction(user_id, value)
+        await callback.answer("↩️ Возвращена предыдущая инструкция." if value else "↩️ Инструкция снова не задана.")
+    else:
+        temperature = float(value) if value is not None else None
+        await db.set_user_temperature(user_id, temperature)
+        await callback.answer(f"↩️ Температура возвращена: {temperature if temperature is not None else DEFAULT_TEMPERATURE}.")
+    invalidate_user_cache(user_id, cache)
+    await show_settings_menu(callback.message, user_id, db, cache)
+
+# --- Инструкция в Max Mode ---
+@router.callback_query(SettingsCallback.filter(F.action == "max_instruction"))
+async def settings_max_instruction_handler(callback: CallbackQuery, db: Database, cache: dict):
+    """Переключает режим по кругу: целиком -> сокращенно -> не передавать."""
+    user_id = callback.from_user.id
+    modes = list(MAX_MODE_INSTRUCTION_MODES)
+    mode = modes[(modes.index(await get_max_mode_instruction(db, user_id)) + 1) % len(modes)]
+    await db.set_max_mode_instruction(user_id, mode)
+    track(cache, 'settings_change', user_id, {'setting': 'max_instruction'})
+    await callback.answer(f"Инструкция для участников Max Mode: {MAX_MODE_INSTRUCTION_MODES[mode]}.")
+    await show_settings_menu(callback.message, user_id, db, cache)
 
 # --- Инструкция ---
 @router.callback_query(SettingsCallback.filter(F.action == "instruction"))
@@ -64,13 +149,12 @@ async def settings_instruction_process(message: Message, state: FSMContext, db:
         return
 
     if instruction == "-":
-        await db.set_user_instruction(message.from_user.id, None)
+        await save_model_setting(db, cache, message.from_user.id, 'instruction', None)
         await message.answer("✅ Ваша персональная инструкция удалена.")
     else:
-        await db.set_user_instruction(message.from_user.id, instruction)
+        await save_model_setting(db, cache, message.from_user.id, 'instruction', instruction)
         await message.answer(f"✅ Ваша персональная инструкция обновлена:\n\n{hcode(instruction)}")
 
-    invalidate_user_cache(message.from_user.id, cache)
     await message.answer("Возвращаю в главное меню...", reply_markup=await get_main_menu(message.from_user.id, db))
 
 # --- Температура ---
@@ -86,13 +170,13 @@ async def settings_temperature_process(message: Message, state: FSMContext, db:
     temp_str = message.text.strip().replace(',', '.')
 
     if temp_str == "-":
-        await db.set_user_temperature(message.from_user.id, None)
+        await save_model_setting(db, cache, message.from_user.id, 'temperature', None)
         await message.answer(f"✅ Температура сброшена к значению по умолчанию ({DEFAULT_TEMPERATURE}).")
     else:
         try:
             temperature = float(temp_str)
             if 0.0 <= temperature <= 2.0:
-                await db.set_user_temperature(message.from_user.id, temperature)
+                await save_model_setting(db, cache, message.from_user.id, 'temperature', temperature)
                 await message.answer(f"✅ Температура установлена на {temperature}.")
             else:
                 await message.answer("❌ Ошибка. Температура должна быть в диапазоне от 0.0 до 2.0. Попробуйте снова.")
@@ -103,5 +187,405 @@ async def settings_temperature_process(message: Message, state: FSMContext, db:
             await state.set_state(SettingsState.waiting_for_temperature)
             return
 
-    invalidate_user_cache(message.from_user.id, cache)
-    await message.answer("Возвращаю в главное меню...", reply_markup=await get_main_menu(message.from_user.id, db))
\ No newline at end of file
+    await message.answer("Возвращаю в главное меню...", reply_markup=await get_main_menu(message.from_user.id, db))
+
+# --- Тихие часы ---
+@router.callback_query(SettingsCallback.filter(F.action == "quiet_hours"))
+async def settings_quiet_hours_start(callback: CallbackQuery, state: FSMContext):
+    await callback.answer()
+    await state.set_state(SettingsState.waiting_for_quiet_hours)
+    await callback.message.edit_text(
+        "Отправьте тихие часы в формате `23-8` (с 23:00 до 08:00 по вашему времени). "
+        "Чтобы отключить тихие часы, отправьте `-` (минус)."
+    )
+
+@router.message(SettingsState.waiting_for_quiet_hours)
+async def settings_quiet_hours_process(message: Message, state: FSMContext, db: Database):
+    await state.clear()
+    value = message.text.strip()
+
+    if value == "-":
+        await db.set_quiet_hours(message.from_user.id, None, None)
+        await message.answer("✅ Тихие часы отключены. Уведомления будут приходить в любое время.")
+    else:
+        try:
+            start_str, end_str = value.replace('–', '-').split('-')
+            quiet_start, quiet_end = int(start_str), int(end_str)
+            if not (0 <= quiet_start <= 23 and 0 <= quiet_end <= 23) or quiet_start == quiet_end:
+                raise ValueError
+        except ValueError:
+            await message.answer("❌ Ошибка. Укажите два разных часа от 0 до 23 через дефис, например `23-8`. Попробуйте снова.")
+            await state.set_state(SettingsState.waiting_for_quiet_hours)
+            return
+        await db.set_quiet_hours(message.from_user.id, quiet_start, quiet_end)
+        await message.answer(f"✅ Тихие часы установлены: {format_quiet_hours(quiet_start, quiet_end)}.")
+
+    await message.answer("Возвращаю в главное меню...", reply_markup=await get_main_menu(message.from_user.id, db))
+
+# --- Часовой пояс ---
+@router.callback_query(SettingsCallback.filter(F.action == "timezone"))
+async def settings_timezone_start(callback: CallbackQuery, state: FSMContext):
+    await callback.answer()
+    await state.set_state(SettingsState.waiting_for_utc_offset)
+    await callback.message.edit_text(
+        "Отправьте ваш часовой пояс как смещение от UTC, например `+3` для Москвы или `-5` для Нью-Йорка."
+    )
+
+@router.message(SettingsState.waiting_for_utc_offset)
+async def settings_timezone_process(message: Message, state: FSMContext, db: Database):
+    await state.clear()
+    value = message.text.strip().upper().removeprefix('UTC')
+
+    try:
+        utc_offset = int(value)
+        if not -12 <= utc_offset <= 14:
+            raise ValueError
+    except ValueError:
+        await message.answer("❌ Ошибка. Введите целое число от -12 до +14 (например, +3). Попробуйте снова.")
+        await state.set_state(SettingsState.waiting_for_utc_offset)
+        return
+
+    await db.set_utc_offset(message.from_user.id, utc_offset)
+    await message.answer(f"✅ Часовой пояс установлен: {format_utc_offset(utc_offset)}.")
+    await message.answer("Возвращаю в главное меню...", reply_markup=await get_main_menu(message.from_user.id, db))
+
+# --- Ссылки на беседы ---
+async def show_shared_links(callback: CallbackQuery, db: Database, bot: Bot):
+    links = await db.get_user_shared_conversations(callback.from_user.id)
+    if not links:
+        text = "<b>🔗 Мои ссылки на беседы</b>\n\nДействующих ссылок нет. Поделиться беседой можно из меню диалога (/menu во время чата)."
+    else:
+        utc_offset = await get_utc_offset(db, callback.from_user.id)
+        lines = []
+        for number, (token, model, views, created_at) in enumerate(links, 1):
+            created_str = format_datetime(created_at, utc_offset)
+            lines.append(f"{number}. {created_str}, {hcode(model)}, просмотров: {views}\n{await get_share_link(bot, token)}")
+        text = "<b>🔗 Мои ссылки на беседы</b>\n\n" + "\n\n".join(lines)
+    try:
+        await callback.message.edit_text(
+            text, reply_markup=get_shared_links_menu([(n, link[0]) for n, link in enumerate(links, 1)]),
+            disable_web_page_preview=True
+        )
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in show_shared_links: {e}")
+
+@router.callback_query(SettingsCallback.filter(F.action == "shares"))
+async def settings_shares_handler(callback: CallbackQuery, db: Database, bot: Bot):
+    await callback.answer()
+    await show_shared_links(callback, db, bot)
+
+@router.callback_query(SharedLink.filter(F.action.in_({'revoke', 'revoke_list'})))
+async def revoke_shared_link_handler(callback: CallbackQuery, callback_data: SharedLink, db: Database, bot: Bot):
+    if not await db.revoke_shared_conversation(callback_data.token, callback.from_user.id):
+        await callback.answer("Ссылка уже отозвана.", show_alert=True)
+        return
+
+    await callback.answer("Ссылка отозвана.")
+    logger.info(f"User {callback.from_user.id} revoked a shared conversation link")
+    if callback_data.action == 'revoke_list':
+        await show_shared_links(callback, db, bot)
+    else:
+        await callback.message.edit_text("🚫 Ссылка на беседу отозвана. Открыть ее больше нельзя.")
+
+# --- Доступ к API ---
+def format_msk_time(value) -> str:
+    return f"{format_datetime(value)} МСК" if value else "никогда"
+
+@router.callback_query(SettingsCallback.filter(F.action == "api"))
+async def settings_api_handler(callback: CallbackQuery, db: Database):
+    if await get_user_level(callback.from_user.id, db) < API_TOKEN_MIN_LEVEL:
+        await callback.answer("🔑 Доступ к API есть только у подписчиков уровня Max.", show_alert=True)
+        return
+
+    await callback.answer()
+    token_info = await db.get_api_token_info(callback.from_user.id)
+    if token_info:
+        created_at, last_used_at = token_info
+        status = f"выпущен {format_msk_time(created_at)} МСК, последний запрос: {format_msk_time(last_used_at)}"
+    else:
+        status = "не выпущен"
+    text = (
+        "<b>🔑 Доступ к API</b>\n\n"
+        "Персональный токен позволяет обращаться к моделям из своих скриптов и приложений. "
+        "Запросы расходуют ваш дневной лимит, как и сообщения в боте.\n\n"
+        f"<b>Токен:</b> {status}\n"
+        f"<b>Адрес:</b> {hcode(f'POST {PUBLIC_API_URL}/v1/user/chat')}"
+    )
+    try:
+        await callback.message.edit_text(text, reply_markup=get_api_token_menu(bool(token_info)))
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in settings_api_handler: {e}")
+
+@router.callback_query(SettingsCallback.filter(F.action == "api_issue"))
+async def settings_api_issue_handler(callback: CallbackQuery, db: Database):
+    if await get_user_level(callback.from_user.id, db) < API_TOKEN_MIN_LEVEL:
+        await callback.answer("🔑 Доступ к API есть только у подписчиков уровня Max.", show_alert=True)
+        return
+
+    await callback.answer()
+    token = await issue_api_token(db, callback.from_user.id)
+    logger.info(f"User {callback.from_user.id} issued a new API token")
+    example = (
+        f"curl -X POST {PUBLIC_API_URL}/v1/user/chat \\\n"
+        f"  -H 'Authorization: Bearer {token}' \\\n"
+        "  -H 'Content-Type: application/json' \\\n"
+        "  -d '{\"prompt\": \"Привет!\"}'"
+    )
+    await callback.message.edit_text(
+        "<b>🔑 Новый токен API</b>\n\n"
+        f"{hcode(token)}\n\n"
+        "Сохраните его: токен показывается только один раз. Предыдущий токен больше не действует.\n\n"
+        f"<b>Пример запроса:</b>\n<pre>{example}</pre>\n\n"
+        "Необязательные поля: <code>model</code> (по умолчанию - последняя выбранная модель) и "
+        "<code>messages</code> вместо <code>prompt</code> для передачи истории.",
+        reply_markup=get_api_token_menu(True)
+    )
+
+@router.callback_query(SettingsCallback.filter(F.action == "api_revoke"))
+async def settings_api_revoke_handler(callback: CallbackQuery, db: Database):
+    if not await db.delete_api_token(callback.from_user.id):
+        await callback.answer("Токен уже отозван.", show_alert=True)
+        return
+    await callback.answer("Токен отозван.")
+    logger.info(f"User {callback.from_user.id} revoked the API token")
+    await callback.message.edit_text(
+        "🚫 Токен API отозван. Запросы с ним больше не принимаются.",
+        reply_markup=get_api_token_menu(False)
+    )
+
+# --- Вебхуки-уведомления ---
+async def show_webhooks(message: Message, user_id: int, db: Database, edit: bool = True):
+    webhooks = await db.get_user_webhooks(user_id)
+    text = (
+        "<b>🔔 Вебхуки-уведомления</b>\n\n"
+        "Отправьте POST-запрос на адрес вебхука (например, из CI или системы мониторинга) - "
+        "бот перешлет его содержимое вам. Модель может кратко изложить уведомление, "
+        "это расходует один запрос из дневного лимита.\n"
+        f"Не больше {WEBHOOK_RATE_LIMIT} уведомлений в минуту на вебхук.\n\n"
+    )
+    if webhooks:
+        text += "\n".join(
+            f"{number}. <b>{html.escape(name)}</b> - доставлено: {deliveries}, последнее: {format_msk_time(last_used_at)}"
+            for number, (_, name, _, deliveries, last_used_at) in enumerate(webhooks, 1)
+        )
+    else:
+        text += "Вебхуков пока нет."
+    reply_markup = get_webhooks_menu(
+        [(number, webhook[0], webhook[2]) for number, webhook in enumerate(webhooks, 1)],
+        can_add=len(webhooks) < WEBHOOKS_PER_USER
+    )
+    if not edit:
+        await message.answer(text, reply_markup=reply_markup)
+        return
+    try:
+        await message.edit_text(text, reply_markup=reply_markup)
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in show_webhooks: {e}")
+
+@router.callback_query(SettingsCallback.filter(F.action == "webhooks"))
+async def settings_webhooks_handler(callback: CallbackQuery, db: Database):
+    await callback.answer()
+    await show_webhooks(callback.message, callback.from_user.id, db)
+
+@router.callback_query(SettingsCallback.filter(F.action == "webhook_add"))
+async def settings_webhook_add_start(callback: CallbackQuery, state: FSMContext, db: Database):
+    if len(await db.get_user_webhooks(callback.from_user.id)) >= WEBHOOKS_PER_USER:
+        await callback.answer(f"Можно создать не больше {WEBHOOKS_PER_USER} вебхуков.", show_alert=True)
+        return
+    await callback.answer()
+    await state.set_state(SettingsState.waiting_for_webhook_name)
+    await callback.message.edit_text("Отправьте название вебхука (до 50 символов), например <code>CI</code> или <code>Мониторинг сервера</code>.")
+
+@router.message(SettingsState.waiting_for_webhook_name)
+async def settings_webhook_add_process(message: Message, state: FSMContext, db: Database):
+    name = (message.text or '').strip()
+    if not name or len(name) > 50:
+        await message.answer("❌ Название должно быть от 1 до 50 символов. Попробуйте снова.")
+        return
+    await state.clear()
+
+    url = await create_webhook(db, message.from_user.id, name)
+    logger.info(f"User {message.from_user.id} created a webhook")
+    await message.answer(
+        f"✅ Вебхук <b>{html.escape(name)}</b> создан.\n\n"
+        f"<b>Адрес:</b>\n{hcode(url)}\n\n"
+        "Сохраните его: адрес показывается только один раз. Тело запроса - текст или JSON "
+        "(поле <code>text</code> будет показано как обычный текст).\n\n"
+        f"<b>Пример:</b>\n<pre>curl -X POST {url} -d 'Сборка упала'</pre>"
+    )
+    await show_webhooks(message, message.from_user.id, db, edit=False)
+
+@router.callback_query(WebhookAction.filter())
+async def webhook_action_handler(callback: CallbackQuery, callback_data: WebhookAction, db: Database):
+    user_id = callback.from_user.id
+    if callback_data.action == 'summary':
+        changed = await db.toggle_webhook_summary(callback_data.webhook_id, user_id)
+    else:
+        changed = await db.delete_webhook(callback_data.webhook_id, user_id)
+        if changed:
+            logger.info(f"User {user_id} deleted webhook #{callback_data.webhook_id}")
+    if not changed:
+        await callback.answer("Этот вебхук уже удален.", show_alert=True)
+    else:
+        await callback.answer()
+    await show_webhooks(callback.message, user_id, db)
+
+# --- Запланированные запросы ---
+SCHEDULE_HELP = (
+    "Примеры расписания:\n"
+    "<code>ежедневно 9:00</code>\n"
+    "<code>будни 8:30</code> или <code>выходные 11:00</code>\n"
+    "<code>пн 9:00</code> или <code>пн,ср,пт 18:00</code>\n\n"
+    "Время указывается в вашем часовом поясе (его можно изменить в настройках)."
+)
+
+async def show_scheduled_prompts(message: Message, user_id: int, db: Database, edit: bool = True):
+    prompts = await db.get_user_scheduled_prompts(user_id)
+    utc_offset = await get_utc_offset(db, user_id)
+    text = (
+        "<b>⏰ Запланированные запросы</b>\n\n"
+        "Бот сам отправит запрос модели по расписанию и пришлет ответ. "
+        "Каждый запуск расходует один запрос из дневного лимита.\n\n"
+    )
+    if prompts:
+        lines = []
+        for number, (_, prompt, model, weekdays, hour, minute, enabled, next_run_at) in enumerate(prompts, 1):
+            status = (
+                f"следующий запуск {format_datetime(next_run_at, utc_offset, with_year=False)}"
+                if enabled and next_run_at else "на паузе"
+            )
+            preview = prompt if len(prompt) <= 100 else prompt[:100] + "…"
+            lines.append(f"{number}. <b>{format_schedule(weekdays, hour, minute)}</b>, {hcode(model)}, {status}\n{html.escape(preview)}")
+        text += "\n\n".join(lines)
+    else:
+        text += "Запланированных запросов пока нет."
+    reply_markup = get_scheduled_prompts_menu(
+        [(number, item[0], item[6]) for number, item in enumerate(prompts, 1)],
+        can_add=len(prompts) < SCHEDULED_PROMPTS_PER_USER
+    )
+    if not edit:
+        await message.answer(text, reply_markup=reply_markup)
+        return
+    try:
+        await message.edit_text(text, reply_markup=reply_markup)
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in show_scheduled_prompts: {e}")
+
+@router.callback_query(SettingsCallback.filter(F.action == "scheduled"))
+async def settings_scheduled_handler(callback: CallbackQuery, db: Database):
+    if await get_user_level(callback.from_user.id, db) < SCHEDULED_PROMPTS_MIN_LEVEL:
+        await callback.answer("⏰ Запланированные запросы доступны с тарифа Premium.", show_alert=True)
+        return
+    await callback.answer()
+    await show_scheduled_prompts(callback.message, callback.from_user.id, db)
+
+@router.callback_query(SettingsCallback.filter(F.action == "scheduled_add"))
+async def settings_scheduled_add_start(callback: CallbackQuery, state: FSMContext, db: Database):
+    user_id = callback.from_user.id
+    if await get_user_level(user_id, db) < SCHEDULED_PROMPTS_MIN_LEVEL:
+        await callback.answer("⏰ Запланированные запросы доступны с тарифа Premium.", show_alert=True)
+        return
+    if len(await db.get_user_scheduled_prompts(user_id)) >= SCHEDULED_PROMPTS_PER_USER:
+        await callback.answer(f"Можно запланировать не больше {SCHEDULED_PROMPTS_PER_USER} запросов.", show_alert=True)
+        return
+    await callback.answer()
+    await state.set_state(SettingsState.waiting_for_scheduled_prompt)
+    await state.update_data(scheduled_prompt_id=None)
+    await callback.message.edit_text(
+        f"Отправьте текст запроса (до {SCHEDULED_PROMPT_MAX_LENGTH} символов), например: "
+        "<i>Составь план недели: что важно успеть и как распределить задачи.</i>"
+    )
+
+@router.callback_query(ScheduledPromptAction.filter())
+async def scheduled_prompt_action_handler(callback: CallbackQuery, callback_data: ScheduledPromptAction, state: FSMContext, db: Database):
+    user_id = callback.from_user.id
+    scheduled = await db.get_scheduled_prompt(callback_data.prompt_id, user_id)
+    if not scheduled:
+        await callback.answer("Этот запрос уже удален.", show_alert=True)
+        await show_scheduled_prompts(callback.message, user_id, db)
+        return
+    prompt_id, prompt, _, weekdays, hour, minute, enabled, _ = scheduled
+
+    if callback_data.action == 'edit':
+        await callback.answer()
+        await state.set_state(SettingsState.waiting_for_scheduled_prompt)
+        await state.update_data(scheduled_prompt_id=prompt_id)
+        await callback.message.edit_text(
+            f"<b>Текущий запрос:</b>\n{html.escape(prompt)}\n\n"
+            "Отправьте новый текст запроса или <code>-</code>, чтобы оставить текущий."
+        )
+        return
+
+    if callback_data.action == 'delete':
+        await db.delete_scheduled_prompt(prompt_id, user_id)
+        await callback.answer("Запрос удален.")
+    elif enabled:
+        await db.set_scheduled_prompt_enabled(prompt_id, False, None)
+        await callback.answer("Запрос поставлен на паузу.")
+    else:
+        if await get_user_level(user_id, db) < SCHEDULED_PROMPTS_MIN_LEVEL:
+            await callback.answer("⏰ Запланированные запросы доступны с тарифа Premium.", show_alert=True)
+            return
+        next_run_at = compute_next_run(weekdays, hour, minute, await get_utc_offset(db, user_id))
+        await db.set_scheduled_prompt_enabled(prompt_id, True, next_run_at)
+        await callback.answer("Запрос снова активен.")
+    await show_scheduled_prompts(callback.message, user_id, db)
+
+@router.message(SettingsState.waiting_for_scheduled_prompt)
+async def settings_scheduled_prompt_process(message: Message, state: FSMContext):
+    prompt = (message.text or '').strip()
+    is_editing = (await state.get_data()).get('scheduled_prompt_id') is not None
+    if is_editing and prompt == '-':
+        prompt = None
+    elif not prompt or len(prompt) > SCHEDULED_PROMPT_MAX_LENGTH:
+        await message.answer(f"❌ Текст запроса должен быть от 1 до {SCHEDULED_PROMPT_MAX_LENGTH} символов. Попробуйте снова.")
+        return
+
+    await state.update_data(scheduled_prompt_text=prompt)
+    await state.set_state(SettingsState.waiting_for_schedule)
+    keep_hint = "\n\nОтправьте <code>-</code>, чтобы оставить текущее расписание." if is_editing else ""
+    await message.answer(f"Когда выполнять запрос?\n\n{SCHEDULE_HELP}{keep_hint}")
+
+@router.message(SettingsState.waiting_for_schedule)
+async def settings_schedule_process(message: Message, state: FSMContext, db: Database, cache: dict):
+    user_id = message.from_user.id
+    data = await state.get_data()
+    prompt_id, prompt = data.get('scheduled_prompt_id'), data.get('scheduled_prompt_text')
+    current = await db.get_scheduled_prompt(prompt_id, user_id) if prompt_id is not None else None
+    if prompt_id is not None and not current:
+        await state.clear()
+        await message.answer("Этот запрос уже удален.")
+        return
+
+    value = (message.text or '').strip()
+    if current and value == '-':
+        weekdays, hour, minute = current[3], current[4], current[5]
+    else:
+        try:
+            parsed_weekdays, hour, minute = parse_schedule(value)
+        except ValueError:
+            await message.answer(f"❌ Не удалось разобрать расписание.\n\n{SCHEDULE_HELP}")
+            return
+        weekdays = weekdays_to_str(parsed_weekdays)
+    await state.clear()
+
+    next_run_at = compute_next_run(weekdays, hour, minute, await get_utc_offset(db, user_id))
+    if current:
+        await db.update_scheduled_prompt(prompt_id, prompt or current[1], weekdays, hour, minute, next_run_at)
+        logger.info(f"User {user_id} updated scheduled prompt #{prompt_id}")
+        await message.answer(f"✅ Запрос обновлен: {format_schedule(weekdays, hour, minute)}.")
+    else:
+        details = await get_user_details_cached(user_id, db, cache)
+        accessible_models = await get_user_accessible_models(user_id, db)
+        model = details[5] if details and details[5] in accessible_models else DEFAULT_TEXT_MODEL
+        prompt_id = await db.add_scheduled_prompt(user_id, prompt, model, weekdays, hour, minute, next_run_at)
+        logger.info(f"User {user_id} created scheduled prompt #{prompt_id}")
+        await message.answer(
+            f"✅ Запрос запланирован: {format_schedule(weekdays, hour, minute)}.\n"
+            f"Модель: <b>{model}</b> (последняя выбранная)."
+        )
+    await show_scheduled_prompts(message, user_id, db, edit=False)
@@ -6,35 +6,36 @@ from datetime import datetime, timezone
 
 from aiogram import F, Router, Bot
 from aiogram.fsm.context import FSMContext
-from aiogram.types import Message, CallbackQuery
+from aiogram.types import Message, CallbackQuery, LabeledPrice, PreCheckoutQuery
 from aiogram.utils.markdown import hcode
 from aiogram.exceptions import TelegramBadRequest
 
 from app.database import Database
-from app.config import ADMIN_IDS, REWARD_CHANNELS, REWARD_LIMIT, LIMITS, PRICES, MODELS
-from app.keyboards.callbacks import Menu, SubscriptionDetails, Reward
+from app.config import (
+    ADMIN_IDS, REWARD_CHANNELS, QUOTA_REWARD_LIMIT, QUOTA_LIMITS, PRICES, MODELS, SUBSCRIPTION_DAYS,
+    PAYMENT_PROVIDER_TOKEN, STARS_PAYMENTS_ENABLED, PLAN_NAMES, SUPPORT_CONTACT
+)
+from app.keyboards.callbacks import Menu, SubscriptionDetails, Reward, Winback, BuySubscription
 from app.keyboards.inline import (
-    get_subscription_menu, get_subscription_details_menu, get_reward_menu, get_main_menu
+    get_subscription_menu, get_subscription_details_menu, get_subscription_compare_menu, get_main_menu
 )
 from app.services.user_service import (
-    get_user_level, get_user_limits, check_authentication, get_user_details_cached, invalidate_user_cache
+    get_user_level, get_user_limits, get_usage_today, check_authentication, get_user_details_cached, invalidate_user_cache,
+    get_utc_offset
+)
+from app.services.reward_service import get_missing_channels
+from app.services.plan_service import format_plans_table
+from app.services.payment_service import (
+    build_invoice_payload, get_expected_amount, get_user_discount, validate_pre_checkout, process_successful_payment,
+    PAYMENT_NEW, PAYMENT_UNKNOWN
 )
+from app.services.text_service import format_token_usage, format_quota
+from app.services.analytics_service import track
+from app.services.format_service import format_price, format_date, format_duration
 
 logger = logging.getLogger(__name__)
 router = Router()
 
-async def show_reward_offer(message: Message):
-    if not REWARD_CHANNELS:
-        await message.answer('Достигнут дневной лимит запросов.')
-        return
-
-    text = (
-        "<b>Закончились бесплатные запросы!</b>\n\n"
-        f"Хотите получить больше? Подпишитесь на наши каналы и получите "
-        f"<b>{REWARD_LIMIT} запросов в день</b> вместо {LIMITS[0]['daily']}!\n"
-    )
-    await message.answer(text, reply_markup=get_reward_menu(REWARD_CHANNELS), disable_web_page_preview=True)
-
 @router.callback_query(Menu.filter(F.action == 'subscription'))
 async def subscription_menu_handler(callback: CallbackQuery, state: FSMContext, db: Database, cache: dict, bot: Bot):
     if not await check_authentication(callback.from_user, db, state, bot):
@@ -44,6 +45,8 @@ async def subscription_menu_handler(callback: CallbackQuery, state: FSMContext,
     await callback.answer()
     user_id = callback.from_user.id
     user_level = await get_user_level(user_id, db)
+    # Администратору продлевать нечего
+    menu_level = 0 if user_id in ADMIN_IDS else user_level
 
     if user_id in ADMIN_IDS:
         text = (
@@ -52,20 +55,22 @@ async def subscription_menu_handler(callback: CallbackQuery, state: FSMContext,
             "Вам доступен весь функционал."
         )
     else:
-        requests_today = await db.get_user_requests_today(user_id)
-        max_requests_today = await db.get_user_requests_today(user_id, is_max_mode=True)
+        used_today = await get_usage_today(user_id, db)
+        max_used_today = await get_usage_today(user_id, db, is_max_mode=True)
         daily_limit, max_mode_limit = await get_user_limits(user_id, db)
         details = await get_user_details_cached(user_id, db, cache)
         has_bonus = details[8] if details else False
-        plan_name = {0: "Free", 1: "Standard", 2: "Premium", 3: "Max"}[user_level]
+        plan_name = PLAN_NAMES[user_level]
         if user_level == 0 and has_bonus:
             plan_name = "Free (Бонусный)"
 
         text = f'<b>⭐ Ваш план: {plan_name}</b>\n\n'
         text += f'Использовано сегодня:\n'
-        text += f' • Обычные запросы: {requests_today} / {daily_limit}\n'
+        text += f' • Обычный режим: {format_quota(used_today)} / {format_quota(daily_limit)}\n'
         if user_level == 3:
-            text += f' • Max Mode запросы: {max_requests_today} / {max_mode_limit}\n'
+            text += f' • Max Mode: {format_quota(max_used_today)} / {format_quota(max_mode_limit)}\n'
+        text += f'\nТокены сегодня: {format_token_usage(*await db.get_token_usage(user_id))}\n'
+        text += f'Токены за 30 дней: {format_token_usage(*await db.get_token_usage(user_id, days=30))}\n'
 
         sub_end_str = details[3] if details else None
         if sub_end_str and user_level > 0:
@@ -73,22 +78,44 @@ async def subscription_menu_handler(callback: CallbackQuery, state: FSMContext,
                 subscription_end = datetime.fromisoformat(sub_end_str)
                 if subscription_end > datetime.now(timezone.utc):
                     remaining = subscription_end - datetime.now(timezone.utc)
-                    text += f'\nДо конца подписки: {remaining.days} д {remaining.seconds // 3600} ч\n'
+                    end_str = format_date(subscription_end, await get_utc_offset(db, user_id))
+                    text += f'\nДо конца подписки: {format_duration(remaining)} (до {end_str})\n'
+                    text += f'Продление добавит {SUBSCRIPTION_DAYS} дней к текущему сроку.\n'
             except (ValueError, TypeError):
                 pass
     try:
-        await callback.message.edit_text(text, reply_markup=get_subscription_menu())
+        await callback.message.edit_text(text, reply_markup=get_subscription_menu(menu_level))
     except TelegramBadRequest as e:
         if "message is not modified" not in e.message:
             logger.error(f"Error in subscription_menu_handler: {e}")
 
+def format_plans_comparison(current_level: int) -> str:
+    text = format_plans_table(current_level)
+    if current_level > 0:
+        text += (
+            "\n\nПри переходе на другой тариф новая подписка начинается с момента оплаты; "
+            "продление текущего тарифа прибавляет срок к действующей подписке."
+        )
+    return text
+
+@router.callback_query(Menu.filter(F.action == 'subscription_compare'))
+async def subscription_compare_handler(callback: CallbackQuery, db: Database):
+    await callback.answer()
+    user_id = callback.from_user.id
+    user_level = 0 if user_id in ADMIN_IDS else await get_user_level(user_id, db)
+    try:
+        await callback.message.edit_text(format_plans_comparison(user_level), reply_markup=get_subscription_compare_menu(user_level))
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in subscription_compare_handler: {e}")
+
 @router.callback_query(SubscriptionDetails.filter())
 async def subscription_details_handler(callback: CallbackQuery, callback_data: SubscriptionDetails):
     await callback.answer()
     level = callback_data.level
-    plan_name = {1: "Standard", 2: "Premium", 3: "Max"}[level]
+    plan_name = PLAN_NAMES[level]
     price = PRICES[level]
-    limits = LIMITS[level]
+    limits = QUOTA_LIMITS[level]
 
     accessible_models_levels = ['free']
     if level >= 1: accessible_models_levels.append('standard')
@@ -99,12 +126,12 @@ async def subscription_details_handler(callback: CallbackQuery, callback_data: S
 
     text_html = (
         f"<b>Подписка «{plan_name}»</b>\n\n"
-        f"<b>Цена:</b> {price}₽ / месяц\n"
+        f"<b>Цена:</b> {format_price(price)} / месяц\n"
         f"<b>Лимиты:</b>\n"
-        f" • {limits['daily']} обычных запросов в день\n"
+        f" • {format_quota(limits['daily'])} в день на обычные запросы\n"
     )
     if limits['max_mode'] > 0:
-        text_html += f" • {limits['max_mode']} Max Mode запросов в день\n"
+        text_html += f" • {format_quota(limits['max_mode'])} в день на Max Mode\n"
     text_html += f"\n<b>Доступ к моделям:</b>\n<pre>{models_text_html}</pre>"
 
     try:
@@ -120,19 +147,97 @@ async def check_reward_subscription_handler(callback: CallbackQuery, db: Databas
         return await callback.answer("Бонусная программа временно неактивна.", show_alert=True)
 
     try:
-        for channel in REWARD_CHANNELS:
-            member = await callback.bot.get_chat_member(chat_id=channel['id'], user_id=user_id)
-            if member.status not in ['member', 'administrator', 'creator']:
-                await callback.answer(f"Вы не подписаны на канал {channel['name']}. Пожалуйста, проверьте подписки.", show_alert=True)
-                return
+        missing = await get_missing_channels(callback.bot, user_id)
+        if missing:
+            await callback.answer(f"Вы не подписаны на канал {missing[0]['name']}. Пожалуйста, проверьте подписки.", show_alert=True)
+            return
 
+        # Пользователь вернулся в каналы после предупреждения об отписке
+        was_pending = await db.get_reward_revoke_at(user_id) is not None
         await db.set_reward_bonus(user_id)
         invalidate_user_cache(user_id, cache)
-        await callback.answer("Бонус получен!", show_alert=True)
+        await callback.answer("Бонус сохранен!" if was_pending else "Бонус получен!", show_alert=True)
         await callback.message.edit_text(
-            f"🎉 Отлично! Ваш дневной лимит увеличен до <b>{REWARD_LIMIT}</b> запросов. Можете продолжать.",
+            "✅ Спасибо, что остались с нами! Бонусный лимит сохранен." if was_pending else
+            f"🎉 Отлично! Ваш дневной лимит увеличен до <b>{format_quota(QUOTA_REWARD_LIMIT)}</b>. Можете продолжать.",
             reply_markup=await get_main_menu(user_id, db)
         )
     except Exception as e:
         logger.error(f"Error checking reward subscription for {user_id}: {e}")
-        await callback.answer("Не удалось выполнить проверку. Возможно, вы не подписаны на все каналы или возникла ошибка. Попробуйте позже.", show_alert=True)
\ No newline at end of file
+        await callback.answer("Не удалось выполнить проверку. Возможно, вы не подписаны на все каналы или возникла ошибка. Попробуйте позже.", show_alert=True)
+
+@router.callback_query(Winback.filter(F.action == "opt_out"))
+async def winback_opt_out_handler(callback: CallbackQuery, db: Database):
+    await db.set_winback_opt_out(callback.from_user.id)
+    await callback.answer("Хорошо, больше не будем напоминать.", show_alert=True)
+    try:
+        await callback.message.edit_reply_markup(reply_markup=None)
+    except TelegramBadRequest as e:
+        if "message is not modified" not in e.message:
+            logger.error(f"Error in winback_opt_out_handler: {e}")
+
+@router.callback_query(BuySubscription.filter())
+async def buy_subscription_handler(callback: CallbackQuery, callback_data: BuySubscription, db: Database, bot: Bot, cache: dict):
+    """Выставляет счет на оплату подписки через Telegram Payments (в рублях или в Telegram Stars)."""
+    level, currency = callback_data.level, callback_data.currency
+    user_id = callback.from_user.id
+    # Для звезд платежный провайдер не нужен: provider_token передается пустым
+    provider_token = "" if currency == 'XTR' else PAYMENT_PROVIDER_TOKEN
+    available = STARS_PAYMENTS_ENABLED if currency == 'XTR' else bool(PAYMENT_PROVIDER_TOKEN)
+    if not available or level not in PRICES or get_expected_amount(level, currency) is None:
+        await callback.answer("Оплата сейчас недоступна.", show_alert=True)
+        return
+    user_level = await get_user_level(user_id, db)
+    if user_level > level:
+        await callback.answer("У вас уже действует подписка более высокого уровня.", show_alert=True)
+        return
+
+    await callback.answer()
+    plan_name = PLAN_NAMES[level]
+    if user_level == level:
+        description = f"Продление тарифа {plan_name} на {SUBSCRIPTION_DAYS} дней: срок прибавится к действующей подписке."
+    else:
+        description = f"Доступ к тарифу {plan_name} на {SUBSCRIPTION_DAYS} дней: {format_quota(QUOTA_LIMITS[level]['daily'])} в день."
+    # Персональный промокод (например, скидка за возвращение) применяется сам, называть его не нужно
+    promocode, discount_percent = await get_user_discount(db, user_id) or (None, 0)
+    label = f"{plan_name}, {SUBSCRIPTION_DAYS} дней"
+    if promocode:
+        description += f" Скидка {discount_percent}% по промокоду {promocode}."
+        label += f", скидка {discount_percent}%"
+    await bot.send_invoice(
+        chat_id=callback.message.chat.id,
+        title=f"Подписка «{plan_name}»",
+        description=description,
+        payload=build_invoice_payload(level, SUBSCRIPTION_DAYS, promocode),
+        provider_token=provider_token,
+        currency=currency,
+        prices=[LabeledPrice(label=label, amount=get_expected_amount(level, currency, discount_percent))]
+    )
+    track(cache, 'invoice_sent', user_id, {'level': level, 'currency': currency, 'promocode': bool(promocode)})
+    logger.info(f"Sent {currency} invoice for level {level} to user {user_id}" + (f" with promocode {promocode}" if promocode else ""))
+
+@router.pre_checkout_query()
+async def pre_checkout_handler(query: PreCheckoutQuery, db: Database):
+    """Telegram ждет ответа 10 секунд, поэтому здесь только проверка счета, без тяжелых операций."""
+    error = await validate_pre_checkout(query, db)
+    if error:
+        logger.warning(f"Rejected pre-checkout from user {query.from_user.id}: payload '{query.invoice_payload}', {query.total_amount} {query.currency}")
+    await query.answer(ok=error is None, error_message=error)
+
+@router.message(F.successful_payment)
+async def successful_payment_handler(message: Message, db: Database, cache: dict, bot: Bot):
+    """Активирует оплаченную подписку. Повторная доставка того же платежа игнорируется."""
+    status, subscription_end = await process_successful_payment(bot, db, cache, message.from_user.id, message.successful_payment)
+    if status == PAYMENT_UNKNOWN:
+        await message.answer(
+            "⚠️ Оплата получена, но активировать подписку автоматически не удалось. "
+            f"Администраторы уже знают об этом и выдадут подписку или вернут деньги. Поддержка: @{SUPPORT_CONTACT}"
+        )
+        return
+    if status != PAYMENT_NEW:
+        return
+    end_str = format_date(subscription_end, await get_utc_offset(db, message.from_user.id))
+    await message.answer(
+        f"✅ Оплата получена, спасибо! Подписка активна до <b>{end_str}</b>.",
+        reply_markup=await get_main_menu(message.from_user.id, db)
+    )
@@ -0,0 +1,61 @@
+# app/handlers/survey.py
+# Ответы пользователей на опросы.
+
+import logging
+
+from aiogram import Router
+from aiogram.fsm.context import FSMContext
+from aiogram.types import Message, CallbackQuery
+
+from app.database import Database
+from app.states import Survey
+from app.keyboards.callbacks import SurveyAnswer
+from app.keyboards.inline import get_main_menu
+
+logger = logging.getLogger(__name__)
+router = Router()
+
+MAX_TEXT_ANSWER_LENGTH = 1000
+
+@router.callback_query(SurveyAnswer.filter())
+async def survey_answer_handler(callback: CallbackQuery, callback_data: SurveyAnswer, state: FSMContext, db: Database):
+    survey = await db.get_survey(callback_data.survey_id)
+    if not survey:
+        await callback.answer("Этот опрос больше не действует.", show_alert=True)
+        return
+    survey_id, _, kind, options = survey
+
+    if callback_data.option == -1:
+        await callback.answer()
+        await state.set_state(Survey.waiting_for_answer)
+        await state.update_data(survey_id=survey_id)
+        await callback.message.answer("Напишите ваш ответ одним сообщением.")
+        return
+
+    if kind == 'choice':
+        if not 0 <= callback_data.option < len(options):
+            await callback.answer("Неизвестный вариант ответа.", show_alert=True)
+            return
+        answer = options[callback_data.option]
+    else:
+        answer = str(callback_data.option)
+
+    if await db.add_survey_response(survey_id, callback.from_user.id, answer):
+        await callback.answer("Спасибо за ответ!")
+        await callback.message.edit_text(f"{callback.message.html_text}\n\n✅ Ваш ответ: {answer}")
+    else:
+        await callback.answer("Вы уже ответили на этот опрос.", show_alert=True)
+
+@router.message(Survey.waiting_for_answer)
+async def survey_text_answer_handler(message: Message, state: FSMContext, db: Database):
+    data = await state.get_data()
+    await state.clear()
+    answer = (message.text or '').strip()
+    if not answer:
+        await message.answer("Ответ должен быть текстом. Нажмите «Ответить» еще раз.")
+        return
+
+    if await db.add_survey_response(data['survey_id'], message.from_user.id, answer[:MAX_TEXT_ANSWER_LENGTH]):
+        await message.answer("✅ Спасибо за ответ!", reply_markup=await get_main_menu(message.from_user.id, db))
+    else:
+        await message.answer("Вы уже ответили на этот опрос.", reply_markup=await get_main_menu(message.from_user.id, db))
@@ -3,6 +3,8 @@
 
 from aiogram.filters.callback_data import CallbackData
 
+from app.config import PAYMENT_CURRENCY
+
 # --- Общие ---
 class Menu(CallbackData, prefix="menu"):
     action: str
@@ -13,43 +15,141 @@ class Reward(CallbackData, prefix="reward"):
 class SubscriptionDetails(CallbackData, prefix="sub_details"):
     level: int
 
+class BuySubscription(CallbackData, prefix="buy_sub"):
+    level: int
+    # PAYMENT_CURRENCY (по умолчанию) или XTR (Telegram Stars)
+    currency: str = PAYMENT_CURRENCY
+
 # --- Чат и Модели ---
 class Chat(CallbackData, prefix="chat"):
     action: str
 
+class Conversation(CallbackData, prefix="conv"):
+    # action: switch, open (закрепленная беседа из избранного), unpin
+    action: str
+    conversation_id: int
+
+class Favorite(CallbackData, prefix="fav"):
+    # action: show, delete
+    action: str
+    favorite_id: int
+
+class SharedLink(CallbackData, prefix="share"):
+    # action: revoke (из сообщения со ссылкой), revoke_list (из списка ссылок в настройках)
+    action: str
+    token: str
+
 class MaxMode(CallbackData, prefix="max_mode"):
     action: str
 
 class ModelCategory(CallbackData, prefix="cat"):
     name: str
+    page: int = 0
 
 class SelectTextModel(CallbackData, prefix="model"):
     model_name: str
     status: str
 
+class ModelDetails(CallbackData, prefix="model_info"):
+    model_name: str
+
+class BetaFeedback(CallbackData, prefix="beta_fb"):
+    # score: 1 - понравилось, -1 - не понравилось
+    model_name: str
+    score: int
+
+class Regenerate(CallbackData, prefix="regen"):
+    # Новый ответ на последний запрос беседы; пустая модель - модель беседы
+    model_name: str = ''
+
+class SwitchModel(CallbackData, prefix="switch_model"):
+    # Смена модели без начала новой беседы. source: outage (на замену недоступной), chat (из меню диалога)
+    model_name: str
+    source: str = 'outage'
+
+class WizardAnswer(CallbackData, prefix="wizard"):
+    # step: priority, task, budget
+    step: str
+    value: str
+
 class SelectImageModel(CallbackData, prefix="img_model"):
     model_name: str
     status: str
 
+class ImageSize(CallbackData, prefix="img_size"):
+    size: str # ключ из IMAGE_SIZES
+
+class ImageGenAction(CallbackData, prefix="img_gen"):
+    # action: again (новый промпт), regenerate (тот же промпт), variation (переформулированный промпт)
+    action: str
+    generation_id: int = 0
+
+class ImageStyleAction(CallbackData, prefix="img_style"):
+    # action: list, add, delete, pick (выбор стиля для промпта), none (без стиля)
+    action: str
+    style_id: int = 0
+
+class RetryRequest(CallbackData, prefix="retry"):
+    request_id: int
+
+class Winback(CallbackData, prefix="winback"):
+    # action: opt_out
+    action: str
+
+class SurveyAnswer(CallbackData, prefix="survey"):
+    # option: индекс варианта (для NPS - оценка), -1 - ответить текстом
+    survey_id: int
+    option: int
+
+class ReportOutput(CallbackData, prefix="report"):
+    history_id: int
+
 # --- Настройки ---
 class Settings(CallbackData, prefix="settings"):
     action: str
 
+class WebhookAction(CallbackData, prefix="hook"):
+    # action: summary (переключить краткое изложение), delete
+    action: str
+    webhook_id: int
+
+class ScheduledPromptAction(CallbackData, prefix="sched"):
+    # action: toggle, edit, delete
+    action: str
+    prompt_id: int
+
 # --- НОВЫЕ, БОЛЕЕ КОНКРЕТНЫЕ КЛАССЫ ДЛЯ АДМИНКИ ---
 
 # Для кнопок в главном меню админки и меню управления пользователями
 class AdminMenu(CallbackData, prefix="adm"):
     # level: 0 - главное меню, 1 - меню пользователей
-    # action: stats, users, broadcast, report, back, find_user, grant
+    # action: stats, users, broadcast, report, analytics, back, find_user, grant
     level: int
     action: str
 
 # Для действий над конкретным пользователем из его карточки
 class AdminUserAction(CallbackData, prefix="adm_user"):
-    # action: block, unblock, revoke
+    # action: block, unblock, revoke, extra_today, custom_limit, bonus_max
     user_id: int
     action: str
     
+# Для очереди жалоб на ответы бота
+class ModerationAction(CallbackData, prefix="adm_mod"):
+    # action: dismiss, delete
+    report_id: int
+    action: str
+
+# Для подтверждения опасного действия вторым администратором
+class AdminConfirmation(CallbackData, prefix="adm_confirm"):
+    # action: approve, reject
+    confirmation_id: int
+    action: str
+
+# Для предпросмотра рассылки
+class AdminBroadcast(CallbackData, prefix="adm_bc"):
+    # action: test, send, edit, cancel
+    action: str
+
 # Для постраничного просмотра пользователей
 class AdminUserBrowse(CallbackData, prefix="adm_browse"):
     page: int
\ No newline at end of file
@@ -1,33 +1,64 @@
 # app/keyboards/inline.py
 # Функции для создания инлайн-клавиатур.
 
+import asyncio
+from dataclasses import dataclass
+from functools import lru_cache
+
 from aiogram.types import InlineKeyboardMarkup, InlineKeyboardButton
 from aiogram.utils.keyboard import InlineKeyboardBuilder
 
 # --- ИЗМЕНЕНИЕ: импортируем новые классы ---
 from app.keyboards.callbacks import (
     Menu, Chat, AdminMenu, AdminUserAction, AdminUserBrowse, ModelCategory, 
-    Settings, SelectTextModel, SelectImageModel, SubscriptionDetails,
-    Reward, MaxMode
+    Settings, SelectTextModel, SelectImageModel, ImageSize, ImageGenAction, ImageStyleAction, SubscriptionDetails,
+    Reward, MaxMode, RetryRequest, ModelDetails, WizardAnswer, Winback, SurveyAnswer,
+    ReportOutput, ModerationAction, Conversation, Favorite, SharedLink, WebhookAction, ScheduledPromptAction, SwitchModel,
+    AdminConfirmation, BuySubscription, AdminBroadcast, BetaFeedback, Regenerate
+)
+from app.config import (
+    ADMIN_IDS, SUPPORT_CONTACT, SUB_CONTACT, LIMITS, PRICES, API_SERVER_ENABLED, PAYMENT_PROVIDER_TOKEN,
+    STARS_PAYMENTS_ENABLED, STAR_PRICES, IMAGE_SIZES, MODELS_PAGE_SIZE, PLAN_NAMES,
+    get_model_display_name
 )
-from app.config import ADMIN_IDS, SUPPORT_CONTACT, SUB_CONTACT, LIMITS, PRICES
 from app.services.user_service import get_user_level
+from app.services.format_service import format_price
 
 # --- Главные меню ---
 
-async def get_main_menu(user_id: int, db) -> InlineKeyboardMarkup:
-    """Формирует главное меню в зависимости от уровня подписки пользователя."""
-    builder = InlineKeyboardBuilder()
-    user_level = await get_user_level(user_id, db)
+@dataclass(frozen=True)
+class MenuContext:
+    """Все, от чего зависит главное меню. Меню - чистая функция контекста, поэтому кэшируется."""
+    tier: int
+    is_admin: bool = False
+    has_max_mode_runs: bool = False # Разовые запуски Max Mode от администратора или приветственные
+    has_unseen_changelog: bool = False
+
+async def get_menu_context(user_id: int, db) -> MenuContext:
+    user_level, overrides, has_unseen_changelog = await asyncio.gather(
+        get_user_level(user_id, db), db.get_quota_overrides(user_id), db.has_unseen_changelog(user_id)
+    )
+    return MenuContext(
+        tier=user_level,
+        is_admin=user_id in ADMIN_IDS,
+        has_max_mode_runs=bool(overrides and overrides[1] > 0),
+        has_unseen_changelog=has_unseen_changelog,
+    )
 
+@lru_cache(maxsize=64)
+def render_main_menu(context: MenuContext) -> InlineKeyboardMarkup:
+    """Собирает главное меню. Разметка неизменяемая, поэтому один экземпляр отдается всем с тем же контекстом."""
+    builder = InlineKeyboardBuilder()
     builder.row(InlineKeyboardButton(text='💬 Выбрать модель', callback_data=Menu(action='models').pack()))
 
-    if user_level == 3:
+    if context.tier == 3 or context.has_max_mode_runs:
         builder.row(InlineKeyboardButton(text='🚀 Max Mode', callback_data=Menu(action='max_mode').pack()))
 
-    if user_level >= 2:
+    if context.tier >= 2:
         builder.row(InlineKeyboardButton(text='🖼️ Создать изображение', callback_data=Menu(action='image_gen').pack()))
 
+    builder.row(InlineKeyboardButton(text='⭐ Избранное', callback_data=Menu(action='favorites').pack()))
+
     builder.row(
         InlineKeyboardButton(text='⭐ Подписка', callback_data=Menu(action='subscription').pack()),
         InlineKeyboardButton(text='⚙️ Настройки', callback_data=Menu(action='settings').pack())
@@ -36,11 +67,18 @@ async def get_main_menu(user_id: int, db) -> InlineKeyboardMarkup:
         InlineKeyboardButton(text='ℹ️ Помощь', callback_data=Menu(action='help').pack()),
         InlineKeyboardButton(text='🤝 Поддержка', url=f"https://t.me/{SUPPORT_CONTACT}")
     )
-    if user_id in ADMIN_IDS:
+    # Кнопка появляется только при наличии непросмотренных записей в списке изменений
+    if context.has_unseen_changelog:
+        builder.row(InlineKeyboardButton(text='🆕 Что нового', callback_data=Menu(action='whatsnew').pack()))
+    if context.is_admin:
         builder.row(InlineKeyboardButton(text='👑 Админ-панель', callback_data=Menu(action='admin').pack()))
 
     return builder.as_markup()
 
+async def get_main_menu(user_id: int, db) -> InlineKeyboardMarkup:
+    """Формирует главное меню в зависимости от уровня подписки пользователя."""
+    return render_main_menu(await get_menu_context(user_id, db))
+
 def get_chat_menu(is_max_mode: bool = False) -> InlineKeyboardMarkup:
     """Формирует меню во время диалога."""
     builder = InlineKeyboardBuilder()
@@ -49,11 +87,121 @@ def get_chat_menu(is_max_mode: bool = False) -> InlineKeyboardMarkup:
     else:
         builder.row(
             InlineKeyboardButton(text='🔄 Новый чат', callback_data=Chat(action='new').pack()),
-            InlineKeyboardButton(text='🔁 Сменить модель', callback_data=Menu(action='models').pack())
+            InlineKeyboardButton(text='🔁 Сменить модель', callback_data=Chat(action='change_model').pack())
+        )
+        builder.row(
+            InlineKeyboardButton(text='📌 Закрепить беседу', callback_data=Chat(action='pin').pack()),
+            InlineKeyboardButton(text='🔗 Поделиться', callback_data=Chat(action='share').pack())
         )
     builder.row(InlineKeyboardButton(text='⬅️ Главное меню', callback_data=Chat(action='back_to_main').pack()))
     return builder.as_markup()
 
+def get_chat_models_menu(models: list[str]) -> InlineKeyboardMarkup:
+    """Модели, которые могут продолжить текущую беседу. Выбор через категории начинает новый чат."""
+    builder = InlineKeyboardBuilder()
+    for model in models:
+        builder.button(text=get_model_display_name(model), callback_data=SwitchModel(model_name=model, source='chat').pack())
+    builder.adjust(2)
+    builder.row(InlineKeyboardButton(text='📂 Все модели (новый чат)', callback_data=Menu(action='models').pack()))
+    builder.row(InlineKeyboardButton(text='⬅️ Назад', callback_data=Chat(action='change_model_back').pack()))
+    return builder.as_markup()
+
+def get_answer_menu(beta_model: str | None = None) -> InlineKeyboardMarkup:
+    """Кнопки под ответом модели в обычном чате. Для бета-модели - еще и оценка ответа."""
+    builder = InlineKeyboardBuilder()
+    _add_beta_feedback_row(builder, beta_model)
+    builder.row(
+        InlineKeyboardButton(text='🌿 Ответвить', callback_data=Chat(action='branch').pack()),
+        InlineKeyboardButton(text='⭐ В избранное', callback_data=Chat(action='favorite').pack())
+    )
+    _add_regenerate_row(builder)
+    return builder.as_markup()
+
+def _add_beta_feedback_row(builder: InlineKeyboardBuilder, beta_model: str | None):
+    if beta_model:
+        builder.row(
+            InlineKeyboardButton(text='👍', callback_data=BetaFeedback(model_name=beta_model, score=1).pack()),
+            InlineKeyboardButton(text='👎', callback_data=BetaFeedback(model_name=beta_model, score=-1).pack())
+        )
+
+def _add_regenerate_row(builder: InlineKeyboardBuilder):
+    builder.row(
+        InlineKeyboardButton(text='🔄 Перегенерировать', callback_data=Regenerate().pack()),
+        InlineKeyboardButton(text='🔀 Другой моделью', callback_data=Chat(action='regenerate_pick').pack())
+    )
+
+def get_regenerate_models_menu(models: list[str]) -> InlineKeyboardMarkup:
+    """Выбор модели, которая заново ответит на последний запрос."""
+    builder = InlineKeyboardBuilder()
+    for model in models:
+        builder.button(text=get_model_display_name(model), callback_data=Regenerate(model_name=model).pack())
+    builder.adjust(2)
+    builder.row(InlineKeyboardButton(text='⬅️ Назад', callback_data=Chat(action='regenerate_back').pack()))
+    return builder.as_markup()
+
+def get_long_answer_menu(telegraph_url: str | None = None, beta_model: str | None = None) -> InlineKeyboardMarkup:
+    """
+    Кнопки под сокращенным слишком длинным ответом. После публикации ведут на страницу Telegraph.
+    Для бета-модели - еще и оценка ответа.
+    """
+    builder = InlineKeyboardBuilder()
+    _add_beta_feedback_row(builder, beta_model)
+    if telegraph_url:
+        builder.row(InlineKeyboardButton(text='📄 Открыть в Telegra.ph', url=telegraph_url))
+    else:
+        builder.row(InlineKeyboardButton(text='📄 Опубликовать в Telegra.ph', callback_data=Chat(action='telegraph').pack()))
+    builder.row(InlineKeyboardButton(text='📨 Прислать сообщениями', callback_data=Chat(action='expand').pack()))
+    builder.row(
+        InlineKeyboardButton(text='🌿 Ответвить', callback_data=Chat(action='branch').pack()),
+        InlineKeyboardButton(text='⭐ В избранное', callback_data=Chat(action='favorite').pack())
+    )
+    _add_regenerate_row(builder)
+    return builder.as_markup()
+
+def get_favorites_menu(pinned: list, favorites: list) -> InlineKeyboardMarkup:
+    """Закрепленные беседы (переход и открепление) и сохраненные ответы."""
+    builder = InlineKeyboardBuilder()
+    for conversation_id, _, first_prompt in pinned:
+        builder.row(
+            InlineKeyboardButton(
+                text=f"📌 {(first_prompt or 'Беседа')[:40]}",
+                callback_data=Conversation(action='open', conversation_id=conversation_id).pack()
+            ),
+            InlineKeyboardButton(text='✖️', callback_data=Conversation(action='unpin', conversation_id=conversation_id).pack())
+        )
+    for favorite_id, _, prompt, _ in favorites:
+        builder.row(InlineKeyboardButton(
+            text=f"⭐ {(prompt or 'Ответ')[:45]}", callback_data=Favorite(action='show', favorite_id=favorite_id).pack()
+        ))
+    builder.row(InlineKeyboardButton(text='⬅️ Назад в главное меню', callback_data=Menu(action='back_main').pack()))
+    return builder.as_markup()
+
+def get_favorite_menu(favorite_id: int) -> InlineKeyboardMarkup:
+    """Кнопка под присланным избранным ответом."""
+    builder = InlineKeyboardBuilder()
+    builder.row(InlineKeyboardButton(text='🗑 Удалить из избранного', callback_data=Favorite(action='delete', favorite_id=favorite_id).pack()))
+    return builder.as_markup()
+
+def get_share_menu(token: str) -> InlineKeyboardMarkup:
+    """Кнопка отзыва под сообщением со ссылкой на беседу."""
+    builder = InlineKeyboardBuilder()
+    builder.row(InlineKeyboardButton(text='🚫 Отозвать ссылку', callback_data=SharedLink(action='revoke', token=token).pack()))
+    return builder.as_markup()
+
+def get_conversation_switch_menu(conversation_id: int, text: str) -> InlineKeyboardMarkup:
+    """Кнопка перехода в другую беседу (например, обратно из ветки в исходную)."""
+    builder = InlineKeyboardBuilder()
+    builder.row(InlineKeyboardButton(
+        text=text, callback_data=Conversation(action='switch', conversation_id=conversation_id).pack()
+    ))
+    return builder.as_markup()
+
+
+def get_retry_request_menu(request_id: int) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    builder.button(text='🔁 Повторить запрос', callback_data=RetryRequest(request_id=request_id).pack())
+    return builder.as_markup()
+
 
 # --- Меню Max Mode ---
 
@@ -64,70 +212,250 @@ def get_max_mode_activation_menu() -> InlineKeyboardMarkup:
     builder.adjust(1)
     return builder.as_markup()
 
+def get_max_mode_confirm_menu() -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    builder.button(text="🚀 Запустить", callback_data=MaxMode(action="confirm").pack())
+    builder.button(text="✖️ Отмена", callback_data=MaxMode(action="cancel").pack())
+    builder.adjust(2)
+    return builder.as_markup()
+
 # --- Меню выбора моделей ---
 
-def get_models_menu(category: str, models: list, available_statuses: dict) -> InlineKeyboardMarkup:
+def get_models_menu(category: str, models: list, available_statuses: dict, page: int = 0) -> InlineKeyboardMarkup:
     builder = InlineKeyboardBuilder()
-    for model_name in models:
+    total_pages = max(1, -(-len(models) // MODELS_PAGE_SIZE))
+    page = min(max(page, 0), total_pages - 1)
+    for model_name in models[page * MODELS_PAGE_SIZE:(page + 1) * MODELS_PAGE_SIZE]:
         is_ok = available_statuses.get(model_name, 'OK') == 'OK'
         prefix = "" if is_ok else "⚠️ "
         status = "ok" if is_ok else "failed"
-        builder.button(
-            text=f"{prefix}{model_name}",
-            callback_data=SelectTextModel(model_name=model_name, status=status).pack()
+        builder.row(
+            InlineKeyboardButton(
+                text=f"{prefix}{get_model_display_name(model_name)}",
+                callback_data=SelectTextModel(model_name=model_name, status=status).pack()
+            ),
+            InlineKeyboardButton(text="ℹ️", callback_data=ModelDetails(model_name=model_name).pack())
         )
-    builder.button(text='⬅️ Назад к категориям', callback_data=Menu(action='models').pack())
+    if total_pages > 1:
+        buttons = []
+        if page > 0:
+            buttons.append(InlineKeyboardButton(text="⬅️", callback_data=ModelCategory(name=category, page=page - 1).pack()))
+        buttons.append(InlineKeyboardButton(text=f"{page + 1}/{total_pages}", callback_data=ModelCategory(name=category, page=page).pack()))
+        if page < total_pages - 1:
+            buttons.append(InlineKeyboardButton(text="➡️", callback_data=ModelCategory(name=category, page=page + 1).pack()))
+        builder.row(*buttons)
+    builder.row(InlineKeyboardButton(text='⬅️ Назад к категориям', callback_data=Menu(action='models').pack()))
+    return builder.as_markup()
+
+def get_outage_banner_menu(fallback_model: str) -> InlineKeyboardMarkup:
+    """Кнопка под баннером о недоступности модели: перейти на временную замену насовсем."""
+    builder = InlineKeyboardBuilder()
+    builder.button(
+        text=f'🔁 Перейти на {get_model_display_name(fallback_model)}',
+        callback_data=SwitchModel(model_name=fallback_model).pack()
+    )
+    return builder.as_markup()
+
+def get_model_details_menu(model_name: str, category: str | None, is_ok: bool) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    status = "ok" if is_ok else "failed"
+    builder.button(text='✅ Выбрать эту модель', callback_data=SelectTextModel(model_name=model_name, status=status).pack())
+    if category:
+        builder.button(text='⬅️ Назад к моделям', callback_data=ModelCategory(name=category).pack())
+    else:
+        builder.button(text='⬅️ Назад к категориям', callback_data=Menu(action='models').pack())
     builder.adjust(1)
     return builder.as_markup()
 
-def get_model_categories_menu(categories: list) -> InlineKeyboardMarkup:
+def get_capable_models_menu(models: list) -> InlineKeyboardMarkup:
+    """Список подходящих моделей для быстрого переключения (например, с поддержкой изображений)."""
     builder = InlineKeyboardBuilder()
+    for model in models:
+        builder.button(text=get_model_display_name(model), callback_data=SelectTextModel(model_name=model, status='ok').pack())
+    builder.adjust(1)
+    return builder.as_markup()
+
+def get_model_categories_menu(categories: list, last_category: str | None = None, favorite_model: str | None = None) -> InlineKeyboardMarkup:
+    """
+    Категории (вендоры) моделей. Последняя открытая пользователем категория идет первой.
+    favorite_model - модель, которую можно выбрать сразу, без перехода в категорию.
+    """
+    builder = InlineKeyboardBuilder()
+    if favorite_model:
+        builder.row(InlineKeyboardButton(
+            text=f'⭐ {get_model_display_name(favorite_model)} (вы чаще всего ее выбираете)',
+            callback_data=SelectTextModel(model_name=favorite_model, status='ok').pack()
+        ))
+    if last_category in categories:
+        builder.row(InlineKeyboardButton(text=f'📂 {last_category}', callback_data=ModelCategory(name=last_category).pack()))
     for cat in categories:
-        builder.button(text=cat, callback_data=ModelCategory(name=cat).pack())
-    builder.button(text='⬅️ Назад в главное меню', callback_data=Menu(action='back_main').pack())
-    builder.adjust(2, 2, 2, 1) 
+        if cat != last_category:
+            builder.button(text=cat, callback_data=ModelCategory(name=cat).pack())
+    builder.adjust(*([1] if last_category in categories else []), 2)
+    builder.row(InlineKeyboardButton(text='🧭 Помочь выбрать модель', callback_data=Menu(action='model_wizard').pack()))
+    builder.row(InlineKeyboardButton(text='⬅️ Назад в главное меню', callback_data=Menu(action='back_main').pack()))
+    return builder.as_markup()
+
+WIZARD_OPTIONS = {
+    'priority': [('speed', '⚡ Быстрый ответ'), ('quality', '🎯 Максимальное качество'), ('balance', '⚖️ Баланс')],
+    'task': [('coding', '💻 Программирование'), ('writing', '✍️ Тексты'), ('general', '💬 Общие вопросы')],
+    'budget': [('economy', '💰 Экономичные модели'), ('any', '💎 Не важно')],
+}
+
+def get_model_wizard_menu(step: str) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    for value, text in WIZARD_OPTIONS[step]:
+        builder.button(text=text, callback_data=WizardAnswer(step=step, value=value).pack())
+    builder.button(text='⬅️ Назад к категориям', callback_data=Menu(action='models').pack())
+    builder.adjust(1)
     return builder.as_markup()
 
-def get_image_models_menu(models: list, available_statuses: dict) -> InlineKeyboardMarkup:
+def get_image_models_menu(models: list, available_statuses: dict, selected_size: str) -> InlineKeyboardMarkup:
+    """Модели для генерации и размеры изображения (выбранный отмечен)."""
     builder = InlineKeyboardBuilder()
+    size_buttons = [
+        InlineKeyboardButton(
+            text=f"{'✅ ' if size == selected_size else ''}{title}", callback_data=ImageSize(size=size).pack()
+        )
+        for size, (title, _, _) in IMAGE_SIZES.items()
+    ]
+    for i in range(0, len(size_buttons), 2):
+        builder.row(*size_buttons[i:i + 2])
     for model_name in models:
         is_ok = available_statuses.get(model_name, 'OK') == 'OK'
         prefix = "" if is_ok else "⚠️ "
         status = "ok" if is_ok else "failed"
-        builder.button(
-            text=f"{prefix}{model_name}",
+        builder.row(InlineKeyboardButton(
+            text=f"{prefix}{get_model_display_name(model_name)}",
             callback_data=SelectImageModel(model_name=model_name, status=status).pack()
-        )
-    builder.button(text='⬅️ Назад в главное меню', callback_data=Menu(action='back_main').pack())
+        ))
+    builder.row(InlineKeyboardButton(text='🖌 Мои стили', callback_data=ImageStyleAction(action='list').pack()))
+    builder.row(InlineKeyboardButton(text='⬅️ Назад в главное меню', callback_data=Menu(action='back_main').pack()))
+    return builder.as_markup()
+
+def get_image_styles_menu(styles: list, can_add: bool) -> InlineKeyboardMarkup:
+    """Список стилей изображений пользователя. styles - [(номер, id)]."""
+    builder = InlineKeyboardBuilder()
+    delete_buttons = [
+        InlineKeyboardButton(text=f"🗑 Удалить №{number}", callback_data=ImageStyleAction(action='delete', style_id=style_id).pack())
+        for number, style_id in styles
+    ]
+    for i in range(0, len(delete_buttons), 2):
+        builder.row(*delete_buttons[i:i + 2])
+    if can_add:
+        builder.row(InlineKeyboardButton(text="➕ Новый стиль", callback_data=ImageStyleAction(action='add').pack()))
+    builder.row(InlineKeyboardButton(text="⬅️ Назад", callback_data=Menu(action='image_gen').pack()))
+    return builder.as_markup()
+
+def get_image_style_picker(styles: list) -> InlineKeyboardMarkup:
+    """Выбор стиля перед генерацией. styles - [(id, название, текст стиля)]."""
+    builder = InlineKeyboardBuilder()
+    for style_id, name, _ in styles:
+        builder.button(text=f"🖌 {name}", callback_data=ImageStyleAction(action='pick', style_id=style_id).pack())
+    builder.button(text="Без стиля", callback_data=ImageStyleAction(action='none').pack())
     builder.adjust(1)
     return builder.as_markup()
 
+def get_image_result_menu(generation_id: int) -> InlineKeyboardMarkup:
+    """Кнопки под сгенерированным изображением: повтор, вариация промпта и новый промпт для той же модели."""
+    builder = InlineKeyboardBuilder()
+    builder.row(
+        InlineKeyboardButton(text='🔄 Повторить', callback_data=ImageGenAction(action='regenerate', generation_id=generation_id).pack()),
+        InlineKeyboardButton(text='🎲 Вариация', callback_data=ImageGenAction(action='variation', generation_id=generation_id).pack())
+    )
+    builder.row(InlineKeyboardButton(text='🔁 Сгенерировать ещё', callback_data=ImageGenAction(action='again').pack()))
+    return builder.as_markup()
+
 
 # --- Меню подписок и настроек ---
 
-def get_subscription_menu() -> InlineKeyboardMarkup:
+def _add_buy_buttons(builder: InlineKeyboardBuilder, level: int, verb: str):
+    """Кнопки оплаты тарифа (verb - «Купить» или «Продлить») для каждого включенного способа оплаты."""
+    plan_name = PLAN_NAMES[level]
+    price = PRICES[level]
+    if PAYMENT_PROVIDER_TOKEN:
+        builder.button(text=f'{verb} {plan_name} - {format_price(price)}', callback_data=BuySubscription(level=level).pack())
+    if STARS_PAYMENTS_ENABLED:
+        builder.button(text=f'{verb} {plan_name} - {format_price(STAR_PRICES[level], "XTR")}', callback_data=BuySubscription(level=level, currency='XTR').pack())
+    if not PAYMENT_PROVIDER_TOKEN and not STARS_PAYMENTS_ENABLED:
+        buy_text = f"Здравствуйте, хочу {verb.lower()} подписку {plan_name}."
+        builder.button(text=f'{verb} {plan_name} - {format_price(price)}', url=f"https://t.me/{SUB_CONTACT}?text={buy_text}")
+
+def get_subscription_menu(user_level: int = 0) -> InlineKeyboardMarkup:
+    """
+    Меню подписки. На платном тарифе - продление текущего тарифа и сравнение тарифов,
+    на бесплатном - описания платных тарифов.
+    """
     builder = InlineKeyboardBuilder()
-    builder.button(text='Подробнее о Standard', callback_data=SubscriptionDetails(level=1).pack())
-    builder.button(text='Подробнее о Premium', callback_data=SubscriptionDetails(level=2).pack())
-    builder.button(text='Подробнее о Max', callback_data=SubscriptionDetails(level=3).pack())
+    if user_level > 0:
+        _add_buy_buttons(builder, user_level, 'Продлить')
+        builder.button(text='🔀 Сменить тариф', callback_data=Menu(action='subscription_compare').pack())
+    else:
+        for level in (1, 2, 3):
+            builder.button(text=f'Подробнее о {PLAN_NAMES[level]}', callback_data=SubscriptionDetails(level=level).pack())
+        builder.button(text='📊 Сравнить тарифы', callback_data=Menu(action='subscription_compare').pack())
     builder.button(text='⬅️ Назад', callback_data=Menu(action='back_main').pack())
     builder.adjust(1)
     return builder.as_markup()
 
-def get_subscription_details_menu(level: int) -> InlineKeyboardMarkup:
-    plan_name = {1: "Standard", 2: "Premium", 3: "Max"}[level]
-    price = PRICES[level]
-    buy_text = f"Здравствуйте, хочу купить подписку {plan_name}."
+def get_subscription_compare_menu(user_level: int) -> InlineKeyboardMarkup:
+    """Переход на тарифы выше текущего (на более низкий тариф можно перейти, когда закончится подписка)."""
+    builder = InlineKeyboardBuilder()
+    for level in PRICES:
+        if level > user_level:
+            builder.button(text=f'⬆️ Перейти на {PLAN_NAMES[level]}', callback_data=SubscriptionDetails(level=level).pack())
+    builder.button(text='⬅️ Назад', callback_data=Menu(action='subscription').pack())
+    builder.adjust(1)
+    return builder.as_markup()
 
+def get_subscription_details_menu(level: int) -> InlineKeyboardMarkup:
     builder = InlineKeyboardBuilder()
-    builder.button(
-        text=f'Купить {plan_name} - {price}₽',
-        url=f"https://t.me/{SUB_CONTACT}?text={buy_text}"
-    )
+    _add_buy_buttons(builder, level, 'Купить')
     builder.button(text='⬅️ Назад', callback_data=Menu(action='subscription').pack())
     builder.adjust(1)
     return builder.as_markup()
 
+def get_winback_menu() -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    builder.button(text='⭐ Подписка', callback_data=Menu(action='subscription').pack())
+    builder.button(text='🔕 Больше не напоминать', callback_data=Winback(action='opt_out').pack())
+    builder.adjust(1)
+    return builder.as_markup()
+
+def get_survey_menu(survey_id: int, kind: str, options: list) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    if kind == 'nps':
+        for score in range(11):
+            builder.button(text=str(score), callback_data=SurveyAnswer(survey_id=survey_id, option=score).pack())
+        builder.adjust(6, 5)
+    elif kind == 'choice':
+        for index, option in enumerate(options):
+            builder.button(text=option, callback_data=SurveyAnswer(survey_id=survey_id, option=index).pack())
+        builder.adjust(1)
+    else:
+        builder.button(text='✍️ Ответить', callback_data=SurveyAnswer(survey_id=survey_id, option=-1).pack())
+    return builder.as_markup()
+
+def get_report_menu(history_id: int) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    builder.button(text='⚠️ Пожаловаться', callback_data=ReportOutput(history_id=history_id).pack())
+    return builder.as_markup()
+
+def get_limit_upsell_menu(target_level: int | None, reward_channels: list | None = None) -> InlineKeyboardMarkup:
+    """Кнопки под сообщением об исчерпанном лимите: подробнее о тарифе (или все тарифы) и бонус за каналы."""
+    builder = InlineKeyboardBuilder()
+    if target_level:
+        plan_name = {1: "Standard", 2: "Premium", 3: "Max"}[target_level]
+        builder.row(InlineKeyboardButton(text=f'⭐ Подробнее о {plan_name}', callback_data=SubscriptionDetails(level=target_level).pack()))
+    else:
+        builder.row(InlineKeyboardButton(text='⭐ Выбрать подписку', callback_data=Menu(action='subscription').pack()))
+    for i, channel in enumerate(reward_channels or []):
+        url = f"https://t.me/{channel['id'].lstrip('@')}"
+        builder.row(InlineKeyboardButton(text=f"Канал {i+1}: {channel['name']}", url=url))
+    if reward_channels:
+        builder.row(InlineKeyboardButton(text="✅ Я подписался, проверить!", callback_data=Reward(action="check").pack()))
+    return builder.as_markup()
+
 def get_reward_menu(channels: list) -> InlineKeyboardMarkup:
     builder = InlineKeyboardBuilder()
     for i, channel in enumerate(channels):
@@ -136,14 +464,77 @@ def get_reward_menu(channels: list) -> InlineKeyboardMarkup:
     builder.row(InlineKeyboardButton(text="✅ Я подписался, проверить!", callback_data=Reward(action="check").pack()))
     return builder.as_markup()
 
-def get_settings_menu() -> InlineKeyboardMarkup:
+def get_settings_menu(can_revert: bool = False) -> InlineKeyboardMarkup:
     builder = InlineKeyboardBuilder()
     builder.button(text="Задать инструкцию", callback_data=Settings(action="instruction").pack())
     builder.button(text="Задать температуру", callback_data=Settings(action="temperature").pack())
+    if can_revert:
+        builder.button(text="↩️ Вернуть предыдущее", callback_data=Settings(action="revert").pack())
+    builder.button(text="🧠 Инструкция в Max Mode", callback_data=Settings(action="max_instruction").pack())
+    builder.button(text="🌙 Тихие часы", callback_data=Settings(action="quiet_hours").pack())
+    builder.button(text="🕒 Часовой пояс", callback_data=Settings(action="timezone").pack())
+    builder.button(text="🔗 Мои ссылки на беседы", callback_data=Settings(action="shares").pack())
+    builder.button(text="⏰ Запланированные запросы", callback_data=Settings(action="scheduled").pack())
+    if API_SERVER_ENABLED: # Токены и вебхуки работают только при запущенном HTTP-сервере
+        builder.button(text="🔑 Доступ к API", callback_data=Settings(action="api").pack())
+        builder.button(text="🔔 Вебхуки-уведомления", callback_data=Settings(action="webhooks").pack())
     builder.button(text="⬅️ Назад", callback_data=Menu(action="back_main").pack())
     builder.adjust(1)
     return builder.as_markup()
 
+def get_api_token_menu(has_token: bool) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    if has_token:
+        builder.button(text="🔄 Перевыпустить токен", callback_data=Settings(action="api_issue").pack())
+        builder.button(text="🚫 Отозвать токен", callback_data=Settings(action="api_revoke").pack())
+    else:
+        builder.button(text="🔑 Выпустить токен", callback_data=Settings(action="api_issue").pack())
+    builder.button(text="⬅️ Назад", callback_data=Menu(action="settings").pack())
+    builder.adjust(1)
+    return builder.as_markup()
+
+def get_webhooks_menu(webhooks: list, can_add: bool) -> InlineKeyboardMarkup:
+    """Список вебхуков пользователя. webhooks - [(номер, id, summarize)]."""
+    builder = InlineKeyboardBuilder()
+    for number, webhook_id, summarize in webhooks:
+        builder.row(
+            InlineKeyboardButton(
+                text=f"№{number}: изложение {'✅' if summarize else '❌'}",
+                callback_data=WebhookAction(action='summary', webhook_id=webhook_id).pack()
+            ),
+            InlineKeyboardButton(text=f"🗑 Удалить №{number}", callback_data=WebhookAction(action='delete', webhook_id=webhook_id).pack())
+        )
+    if can_add:
+        builder.row(InlineKeyboardButton(text="➕ Новый вебхук", callback_data=Settings(action="webhook_add").pack()))
+    builder.row(InlineKeyboardButton(text="⬅️ Назад", callback_data=Menu(action="settings").pack()))
+    return builder.as_markup()
+
+def get_scheduled_prompts_menu(prompts: list, can_add: bool) -> InlineKeyboardMarkup:
+    """Список запланированных запросов. prompts - [(номер, id, enabled)]."""
+    builder = InlineKeyboardBuilder()
+    for number, prompt_id, enabled in prompts:
+        builder.row(
+            InlineKeyboardButton(
+                text=f"{'⏸' if enabled else '▶️'} №{number}",
+                callback_data=ScheduledPromptAction(action='toggle', prompt_id=prompt_id).pack()
+            ),
+            InlineKeyboardButton(text=f"✏️ №{number}", callback_data=ScheduledPromptAction(action='edit', prompt_id=prompt_id).pack()),
+            InlineKeyboardButton(text=f"🗑 №{number}", callback_data=ScheduledPromptAction(action='delete', prompt_id=prompt_id).pack())
+        )
+    if can_add:
+        builder.row(InlineKeyboardButton(text="➕ Новый запрос", callback_data=Settings(action="scheduled_add").pack()))
+    builder.row(InlineKeyboardButton(text="⬅️ Назад", callback_data=Menu(action="settings").pack()))
+    return builder.as_markup()
+
+def get_shared_links_menu(links: list) -> InlineKeyboardMarkup:
+    """Список действующих ссылок на беседы с кнопками отзыва. links - [(номер, token)]."""
+    builder = InlineKeyboardBuilder()
+    for number, token in links:
+        builder.button(text=f"🚫 Отозвать ссылку №{number}", callback_data=SharedLink(action='revoke_list', token=token).pack())
+    builder.button(text="⬅️ Назад", callback_data=Menu(action="settings").pack())
+    builder.adjust(1)
+    return builder.as_markup()
+
 
 # --- Админ-меню (ОБНОВЛЕНО) ---
 
@@ -154,8 +545,11 @@ def get_admin_menu() -> InlineKeyboardMarkup:
     builder.button(text='👥 Пользователи', callback_data=AdminMenu(level=0, action='users').pack())
     builder.button(text='📣 Рассылка', callback_data=AdminMenu(level=0, action='broadcast').pack())
     builder.button(text='🩺 Отчёт о моделях', callback_data=AdminMenu(level=0, action='report').pack())
+    builder.button(text='📋 Опросы', callback_data=AdminMenu(level=0, action='surveys').pack())
+    builder.button(text='⚠️ Жалобы', callback_data=AdminMenu(level=0, action='reports').pack())
+    builder.button(text='📈 Аналитика', callback_data=AdminMenu(level=0, action='analytics').pack())
     builder.button(text='⬅️ Назад', callback_data=Menu(action='back_main').pack())
-    builder.adjust(2, 2, 1)
+    builder.adjust(2, 2, 2, 1, 1)
     return builder.as_markup()
 
 def get_admin_users_menu() -> InlineKeyboardMarkup:
@@ -177,11 +571,45 @@ def get_user_card_menu(user_id: int, is_blocked: bool) -> InlineKeyboardMarkup:
     
     builder.button(text=block_text, callback_data=AdminUserAction(user_id=user_id, action=block_action).pack())
     builder.button(text="🗑️ Забрать подписку", callback_data=AdminUserAction(user_id=user_id, action='revoke').pack())
+    builder.button(text="➕ Запросы на сегодня", callback_data=AdminUserAction(user_id=user_id, action='extra_today').pack())
+    builder.button(text="📏 Свой дневной лимит", callback_data=AdminUserAction(user_id=user_id, action='custom_limit').pack())
+    builder.button(text="🚀 Разовый Max Mode", callback_data=AdminUserAction(user_id=user_id, action='bonus_max').pack())
     # Эта кнопка ведет в меню управления пользователями
     builder.button(text="⬅️ К управлению", callback_data=AdminMenu(level=0, action='users').pack())
     builder.adjust(1)
     return builder.as_markup()
 
+def get_moderation_menu(report_id: int) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    builder.button(text='✅ Нарушений нет', callback_data=ModerationAction(report_id=report_id, action='dismiss').pack())
+    builder.button(text='🗑️ Удалить ответ', callback_data=ModerationAction(report_id=report_id, action='delete').pack())
+    builder.button(text='⬅️ Назад', callback_data=AdminMenu(level=1, action='back').pack())
+    builder.adjust(2, 1)
+    return builder.as_markup()
+
+def get_admin_confirmation_menu(confirmation_id: int) -> InlineKeyboardMarkup:
+    builder = InlineKeyboardBuilder()
+    builder.button(text='✅ Подтвердить', callback_data=AdminConfirmation(confirmation_id=confirmation_id, action='approve').pack())
+    builder.button(text='❌ Отклонить', callback_data=AdminConfirmation(confirmation_id=confirmation_id, action='reject').pack())
+    builder.adjust(2)
+    return builder.as_markup()
+
+def get_broadcast_preview_menu(tested: bool = False) -> InlineKeyboardMarkup:
+    """Кнопка «Отправить всем» появляется только после тестовой отправки администратору."""
+    builder = InlineKeyboardBuilder()
+    if tested:
+        builder.button(text='✅ Все верно, отправить всем', callback_data=AdminBroadcast(action='send').pack())
+        builder.button(text='🧪 Отправить тест еще раз', callback_data=AdminBroadcast(action='test').pack())
+    else:
+        builder.button(text='🧪 Отправить тест себе', callback_data=AdminBroadcast(action='test').pack())
+    builder.button(text='✏️ Изменить', callback_data=AdminBroadcast(action='edit').pack())
+    builder.button(text='❌ Отмена', callback_data=AdminBroadcast(action='cancel').pack())
+    if tested:
+        builder.adjust(1, 1, 2)
+    else:
+        builder.adjust(1, 2)
+    return builder.as_markup()
+
 def get_user_browse_menu(page: int, total_pages: int) -> InlineKeyboardMarkup:
     builder = InlineKeyboardBuilder()
     buttons = []
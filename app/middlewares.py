@@ -2,13 +2,39 @@
 from typing import Any, Awaitable, Callable, Dict
 
 from aiogram import BaseMiddleware
-from aiogram.types import TelegramObject, User
+from aiogram.types import Chat, TelegramObject, Update, User
 
 from cachetools import TTLCache
 
+from app.config import PROMPT_PARTS_WAIT_SECONDS, PROMPT_PARTS_MAX_COUNT, PROMPT_PARTS_MAX_CHARS
+from app.keyboards.callbacks import Menu
+from app.services.analytics_service import track
+from app.services.conversation_service import get_chat_mode, restore_chat_mode
+from app.services.trace_service import new_request_id
+from app.states import Chat as ChatState
+
+class RequestIdMiddleware(BaseMiddleware):
+    """
+    Присваивает каждому обновлению код запроса (trace_service): он попадает во все строки лога,
+    записанные при обработке обновления, и в сообщения об ошибках для пользователя.
+    """
+    async def __call__(
+        self,
+        handler: Callable[[TelegramObject, Dict[str, Any]], Awaitable[Any]],
+        event: TelegramObject,
+        data: Dict[str, Any],
+    ) -> Any:
+        new_request_id()
+        return await handler(event, data)
+
+
 class ThrottlingMiddleware(BaseMiddleware):
     """
     Простое middleware для защиты от флуда.
+    Текст, пришедший в чате меньше чем через PROMPT_PARTS_WAIT_SECONDS после предыдущего, не отбрасывается:
+    это часть длинного запроса (message_buffer_service). Решение принимается здесь же, по времени прошлого
+    текста, а не по буферу обработчика: части приходят почти одновременно, и буфер может быть еще не создан.
+    Когда частей или символов набирается столько, сколько вмещает буфер, сообщения снова ограничиваются как обычно.
     """
     def __init__(self, rate_limit: float = 1.0, key_prefix: str = "antiflood_"):
         # TTLCache хранит записи с определенным временем жизни (ttl)
@@ -16,6 +42,8 @@ class ThrottlingMiddleware(BaseMiddleware):
         self.cache = TTLCache(maxsize=10_000, ttl=rate_limit)
         self.rate_limit = rate_limit
         self.key_prefix = key_prefix
+        # Пользователи, от которых только что пришел текст в личном чате: сколько частей и символов подряд
+        self.recent_texts = TTLCache(maxsize=10_000, ttl=PROMPT_PARTS_WAIT_SECONDS) if PROMPT_PARTS_WAIT_SECONDS > 0 else None
 
     async def __call__(
         self,
@@ -26,16 +54,84 @@ class ThrottlingMiddleware(BaseMiddleware):
         # Пытаемся получить пользователя из данных, которые передает aiogram
         user: User | None = data.get("event_from_user")
 
-        if user:
+        # Платежи приходят сразу после подтверждения счета и не должны отбрасываться,
+        # как и части альбома или длинного запроса: Telegram присылает их отдельными сообщениями почти одновременно
+        is_payment = isinstance(event, Update) and bool(
+            event.pre_checkout_query or (event.message and event.message.successful_payment)
+        )
+        is_album_part = isinstance(event, Update) and bool(event.message and event.message.media_group_id)
+        is_prompt_part = False
+        if user and self.recent_texts is not None and isinstance(event, Update) and event.message \
+                and event.message.chat.type == 'private' and event.message.text:
+            parts, chars = self.recent_texts.get(user.id, (0, 0))
+            is_prompt_part = 0 < parts < PROMPT_PARTS_MAX_COUNT and chars < PROMPT_PARTS_MAX_CHARS
+            self.recent_texts[user.id] = (parts + 1, chars + len(event.message.text))
+        if user and not is_payment and not is_album_part:
             cache_key = f"{self.key_prefix}{user.id}"
 
             # Если ключ уже есть в кэше, значит, пользователь отправляет сообщения слишком часто
             if cache_key in self.cache:
-                # Игнорируем событие, не передавая его дальше по цепочке обработчиков
-                return
+                # Части длинного запроса пропускаются только в чате: там они собираются в один запрос
+                state = data.get("state")
+                if not is_prompt_part or state is None or await state.get_state() != ChatState.in_progress:
+                    # Игнорируем событие, не передавая его дальше по цепочке обработчиков
+                    return
             else:
                 # Если ключа нет, добавляем его в кэш. Он автоматически удалится через `rate_limit` секунд.
                 self.cache[cache_key] = None
         
         # Если все в порядке, передаем событие дальше
         return await handler(event, data)
+
+
+class ChatModeMiddleware(BaseMiddleware):
+    """
+    Запоминает в БД, находится ли пользователь в диалоге (обычный чат или Max Mode).
+    При первом обновлении от пользователя после запуска бота режим сверяется с состоянием FSM:
+    если состояние потерялось при перезапуске, пользователь возвращается в диалог,
+    и его сообщение обрабатывается как обычно, а не приводит в главное меню.
+    """
+    def __init__(self):
+        # Последний записанный в БД режим; пользователи, которых еще нет в словаре, не сверялись с момента запуска
+        self.modes: Dict[int, str | None] = {}
+
+    async def __call__(
+        self,
+        handler: Callable[[TelegramObject, Dict[str, Any]], Awaitable[Any]],
+        event: TelegramObject,
+        data: Dict[str, Any],
+    ) -> Any:
+        user: User | None = data.get("event_from_user")
+        chat: Chat | None = data.get("event_chat")
+        state, db = data.get("state"), data.get("db")
+        # Диалоги ведутся только в личных сообщениях
+        if not user or state is None or db is None or (chat and chat.type != 'private'):
+            return await handler(event, data)
+
+        if user.id not in self.modes:
+            self.modes[user.id] = await restore_chat_mode(db, state, user.id)
+        try:
+            return await handler(event, data)
+        finally:
+            mode = get_chat_mode(await state.get_state())
+            if mode != self.modes[user.id]:
+                await db.set_chat_mode(user.id, mode)
+                self.modes[user.id] = mode
+
+
+class AnalyticsMiddleware(BaseMiddleware):
+    """Отмечает открытия разделов главного меню (кнопки Menu) как события аналитики 'menu.<раздел>'."""
+    async def __call__(
+        self,
+        handler: Callable[[TelegramObject, Dict[str, Any]], Awaitable[Any]],
+        event: TelegramObject,
+        data: Dict[str, Any],
+    ) -> Any:
+        callback = event.callback_query if isinstance(event, Update) else None
+        cache = data.get("cache")
+        if callback and callback.data and callback.data.startswith(f"{Menu.__prefix__}:") and cache is not None:
+            try:
+                track(cache, f"menu.{Menu.unpack(callback.data).action}", callback.from_user.id)
+            except (TypeError, ValueError):
+                pass
+        return await handler(event, data)
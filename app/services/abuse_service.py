@@ -0,0 +1,122 @@
+# app/services/abuse_service.py
+# Легковесный классификатор спама во входящих запросах к моделям.
+# Защищает бюджет API от повторяющихся запросов, флуда и бессмысленного текста.
+
+import hashlib
+import logging
+import re
+import time
+from collections import deque
+from typing import Dict
+
+from app.config import (
+    SPAM_WINDOW_SECONDS, SPAM_REPEAT_LIMIT, SPAM_FLOOD_LIMIT,
+    SPAM_BLOCK_MINUTES, SPAM_CLASSIFIER_MODEL
+)
+from app.services.ai_service import get_structured_response
+from app.services.provider_service import ProviderRegistry
+
+logger = logging.getLogger(__name__)
+
+SPAM_REASONS = {
+    'repeat': 'Одинаковые запросы',
+    'flood': 'Флуд',
+    'gibberish': 'Бессмысленный текст',
+}
+
+_REPEATED_CHAR_RE = re.compile(r'(.)\1{9,}')
+_WORD_RE = re.compile(r'[a-zа-яё]+', re.IGNORECASE)
+_VOWELS = set('aeiouyаеёиоуыэюя')
+
+def _looks_like_gibberish(text: str) -> bool:
+    """Эвристики для текста вроде «аааааааа» или «фывапролджфывапролдж»."""
+    if len(text) < 20:
+        return False
+    if _REPEATED_CHAR_RE.search(text):
+        return True
+    letters = sum(ch.isalpha() for ch in text)
+    if letters / len(text) < 0.3 and not any(ch in text for ch in '{}();=<>'):
+        return True # Почти нет букв и это не похоже на код
+    words = _WORD_RE.findall(text.lower())
+    long_words = [w for w in words if len(w) >= 12]
+    if words and len(long_words) / len(words) > 0.5:
+        return True
+    vowelless = [w for w in words if len(w) >= 6 and not _VOWELS.intersection(w)]
+    return bool(words) and len(vowelless) / len(words) > 0.5
+
+async def _confirm_with_model(ai_client: ProviderRegistry, text: str) -> bool:
+    """
+    Спрашивает дешевую модель, является ли текст спамом. Без ясного подтверждения (ошибка, таймаут,
+    ответ без поля spam) текст спамом не считается: сбой модели не должен ограничивать пользователей.
+    """
+    try:
+        result = await get_structured_response(
+            ai_client, SPAM_CLASSIFIER_MODEL,
+            [
+                {"role": "system", "content": "Определи, является ли сообщение пользователя бессмысленным набором символов или спамом."},
+                {"role": "user", "content": text[:1000]}
+            ],
+            schema_hint='{"spam": true или false}', retries=1, max_tokens=20, timeout=15.0
+        )
+        return result.get('spam') is True
+    except Exception as e:
+        logger.warning(f"Spam classifier model {SPAM_CLASSIFIER_MODEL} failed, prompt allowed: {e}")
+        return False
+
+def _register_violation(user_id: int, reason: str, cache: Dict):
+    blocks = cache.get("spam_blocks")
+    if blocks is not None:
+        blocks[user_id] = reason
+    stats = cache.setdefault("spam_stats", {})
+    stats[reason] = stats.get(reason, 0) + 1
+    logger.warning(f"Spam detected for user {user_id}: {reason}. Rate-limited for {SPAM_BLOCK_MINUTES} min.")
+
+async def check_prompt_abuse(user_id: int, prompt: str, cache: Dict, ai_client: ProviderRegistry | None = None) -> str | None:
+    """
+    Проверяет запрос на спам. Возвращает причину ограничения или None, если запрос можно выполнять.
+    Нарушитель временно ограничивается на SPAM_BLOCK_MINUTES минут.
+    """
+    blocks = cache.get("spam_blocks")
+    if blocks is not None and user_id in blocks:
+        return blocks[user_id]
+
+    tracker = cache.get("spam_tracker")
+    if tracker is None:
+        return None
+
+    now = time.monotonic()
+    prompt_hash = hashlib.sha256(prompt.strip().lower().encode('utf-8')).hexdigest()
+    history = tracker.get(user_id) or deque()
+    while history and now - history[0][0] > SPAM_WINDOW_SECONDS:
+        history.popleft()
+    history.append((now, prompt_hash))
+    tracker[user_id] = history
+
+    recent_hashes = [h for _, h in history]
+    if len(recent_hashes) >= SPAM_REPEAT_LIMIT and len(set(recent_hashes[-SPAM_REPEAT_LIMIT:])) == 1:
+        reason = 'repeat'
+    elif len(history) > SPAM_FLOOD_LIMIT:
+        reason = 'flood'
+    # Эвристики срабатывают и на код, base64 и тексты на других языках, поэтому без подтверждения моделью не ограничиваем
+    elif SPAM_CLASSIFIER_MODEL and ai_client is not None and _looks_like_gibberish(prompt) \
+            and await _confirm_with_model(ai_client, prompt):
+        reason = 'gibberish'
+    else:
+        return None
+
+    _register_violation(user_id, reason, cache)
+    tracker.pop(user_id, None)
+    return reason
+
+def get_spam_block_message(reason: str) -> str:
+    return (
+        f"🚫 Запрос отклонен: {SPAM_REASONS.get(reason, reason).lower()}.\n"
+        f"Отправка запросов ограничена на {SPAM_BLOCK_MINUTES} мин."
+    )
+
+def get_spam_stats(cache: Dict) -> Dict[str, int]:
+    """Возвращает счетчики сработавших правил с момента запуска и число ограниченных сейчас пользователей."""
+    blocks = cache.get("spam_blocks")
+    stats = dict(cache.get("spam_stats", {}))
+    stats['active_blocks'] = len(blocks) if blocks is not None else 0
+    return stats
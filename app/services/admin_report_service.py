@@ -0,0 +1,143 @@
+# app/services/admin_report_service.py
+# Статистика бота для администраторов: экран «Статистика» в админ-панели и ежедневный отчет.
+# Ежедневный отчет приходит администраторам в Telegram, при желании - с файлом CSV или Markdown,
+# а если настроен SMTP - еще и на адреса ADMIN_REPORT_EMAILS (для тех, кого нет в чате администраторов).
+
+import asyncio
+import csv
+import html
+import io
+import logging
+import smtplib
+from datetime import datetime
+from email.message import EmailMessage
+
+from aiogram import Bot
+from aiogram.types import BufferedInputFile
+
+from app.config import (
+    ADMIN_IDS, MSK_TZ, ADMIN_REPORT_FILE, ADMIN_REPORT_EMAILS, SMTP_HOST, SMTP_PORT, SMTP_USER, SMTP_PASSWORD,
+    SMTP_FROM, SMTP_TLS, PLAN_NAMES
+)
+from app.database import Database
+from app.services.abuse_service import get_spam_stats, SPAM_REASONS
+from app.services.payment_service import format_amount
+from app.services.text_service import format_token_usage, format_cost
+from app.services.winback_service import STAGE_NAMES
+
+logger = logging.getLogger(__name__)
+
+# Раздел статистики: (значок, название, [(показатель, значение)]). Пустое значение - строка без двоеточия
+StatsSection = tuple[str, str, list[tuple[str, str]]]
+
+
+async def collect_admin_stats(db: Database, cache: dict) -> list[StatsSection]:
+    subscription_stats = await db.get_subscription_stats()
+    users = [("Всего", str(await db.get_user_count()))] + [
+        (name, str(subscription_stats.get(level, 0)))
+        for level, name in PLAN_NAMES.items()
+    ]
+
+    spam_stats = get_spam_stats(cache)
+    spam = [(name, str(spam_stats.get(key, 0))) for key, name in SPAM_REASONS.items()]
+    spam.append(("Ограничены сейчас", str(spam_stats["active_blocks"])))
+
+    winback_stats = await db.get_winback_stats()
+    winback = []
+    for stage, name in STAGE_NAMES.items():
+        events = winback_stats.get(stage, {})
+        winback.append((name, f"отправлено {events.get('sent', 0)}, вернулись {events.get('converted', 0)}, "
+                              f"отписались {events.get('opt_out', 0)}"))
+
+    payments = [
+        (currency, f"{format_amount(amount, currency)} ({count} шт.)")
+        for currency, count, amount in await db.get_payment_stats(days=30)
+    ] or [("платежей нет", "")]
+
+    tokens = [
+        ("Сегодня", format_token_usage(*await db.get_token_usage())),
+        ("За 30 дней", format_token_usage(*await db.get_token_usage(days=30))),
+        ("Стоимость за 30 дней", format_cost(sum(row[4] for row in await db.get_spend_by_model(days=30)))),
+    ]
+    return [
+        ("👥", "Пользователи", users),
+        ("🛡", "Антиспам (с момента запуска)", spam),
+        ("👋", "Возврат подписчиков", winback),
+        ("💰", "Оплаты за 30 дней", payments),
+        ("🔢", "Токены", tokens),
+    ]
+
+def format_stats_html(title: str, sections: list[StatsSection]) -> str:
+    parts = [f"<b>{title}</b>"]
+    for icon, name, rows in sections:
+        lines = "\n".join(f" • {html.escape(label)}: {html.escape(value)}" if value else f" • {html.escape(label)}"
+                          for label, value in rows)
+        parts.append(f"<b>{icon} {name}:</b>\n{lines}")
+    return "\n\n".join(parts)
+
+def format_stats_csv(sections: list[StatsSection]) -> str:
+    buffer = io.StringIO()
+    writer = csv.writer(buffer)
+    writer.writerow(["section", "metric", "value"])
+    for _, name, rows in sections:
+        writer.writerows((name, label, value) for label, value in rows)
+    return buffer.getvalue()
+
+def format_stats_markdown(title: str, sections: list[StatsSection]) -> str:
+    parts = [f"# {title}"]
+    for _, name, rows in sections:
+        table = "\n".join(f"| {label} | {value} |" for label, value in rows)
+        parts.append(f"## {name}\n\n| Показатель | Значение |\n|---|---|\n{table}")
+    return "\n\n".join(parts) + "\n"
+
+def _build_report_file(title: str, sections: list[StatsSection], date_str: str) -> tuple[str, bytes] | None:
+    """Файл отчета по ADMIN_REPORT_FILE: (имя, содержимое) или None, если файл не нужен."""
+    if ADMIN_REPORT_FILE == 'csv':
+        # BOM, чтобы Excel открыл файл в UTF-8
+        return f"report_{date_str}.csv", format_stats_csv(sections).encode('utf-8-sig')
+    if ADMIN_REPORT_FILE == 'md':
+        return f"report_{date_str}.md", format_stats_markdown(title, sections).encode('utf-8')
+    return None
+
+def _send_email(subject: str, body: str, attachment: tuple[str, bytes] | None):
+    message = EmailMessage()
+    message['Subject'] = subject
+    message['From'] = SMTP_FROM
+    message['To'] = ", ".join(ADMIN_REPORT_EMAILS)
+    message.set_content(body)
+    if attachment:
+        file_name, content = attachment
+        subtype = 'csv' if file_name.endswith('.csv') else 'markdown'
+        message.add_attachment(content, maintype='text', subtype=subtype, filename=file_name)
+    with smtplib.SMTP(SMTP_HOST, SMTP_PORT, timeout=30) as smtp:
+        if SMTP_TLS:
+            smtp.starttls()
+        if SMTP_USER:
+            smtp.login(SMTP_USER, SMTP_PASSWORD)
+        smtp.send_message(message)
+
+async def send_admin_report(bot: Bot, db: Database, cache: dict):
+    """Ежедневный отчет администраторам: сообщение, файл (ADMIN_REPORT_FILE) и письмо (ADMIN_REPORT_EMAILS)."""
+    now = datetime.now(MSK_TZ)
+    title = f"📊 Ежедневный отчет за {now.strftime('%d.%m.%Y')}"
+    sections = await collect_admin_stats(db, cache)
+    text = format_stats_html(title, sections)
+    report_file = _build_report_file(title, sections, now.strftime('%Y-%m-%d'))
+
+    for admin_id in ADMIN_IDS:
+        try:
+            await bot.send_message(admin_id, text)
+            if report_file:
+                await bot.send_document(admin_id, BufferedInputFile(report_file[1], filename=report_file[0]))
+        except Exception as e:
+            logger.warning(f"Failed to send daily report to admin {admin_id}: {e}")
+
+    if ADMIN_REPORT_EMAILS and SMTP_HOST:
+        try:
+            await asyncio.to_thread(
+                _send_email, title.removeprefix("📊 "), format_stats_markdown(title, sections), report_file
+            )
+            logger.info(f"Daily report emailed to {len(ADMIN_REPORT_EMAILS)} recipients")
+        except Exception as e:
+            logger.error(f"Failed to email daily report: {e}")
+    logger.info("Daily admin report sent")
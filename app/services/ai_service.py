@@ -1,97 +1,439 @@
 # app/services/ai_service.py
 
 import asyncio
+import json
+import random
+import re
 import time
 import logging
-from typing import Tuple, Dict, List
+from dataclasses import replace
+from typing import Awaitable, Callable, NamedTuple, Tuple, Dict, List, TypeVar
 
-from openai import AsyncOpenAI, APIError
+import aiohttp
+import httpx
+from openai import APIConnectionError, APIStatusError, APITimeoutError, InternalServerError
 from aiogram.utils.markdown import hcode
 
 from app.config import (
-    GLOBAL_SYSTEM_PROMPT, DEFAULT_TEMPERATURE, 
-    MAX_MODE_PARTICIPANTS, MAX_MODE_ARBITER
+    GLOBAL_SYSTEM_PROMPT, IMAGE_VARIATION_MODEL, CHAOS_TIMEOUT_DELAY, DEFAULT_TEMPERATURE, 
+    MAX_MODE_PARTICIPANTS, MAX_MODE_ARBITER, TOOL_MAX_ROUNDS, MODEL_INFO, MODEL_PRICES, model_supports,
+    AI_RETRY_ATTEMPTS, AI_RETRY_BASE_DELAY, AI_RETRY_MAX_DELAY, MAX_MODE_EXPECTED_ANSWER_TOKENS,
+    MAX_MODE_INSTRUCTION_MODES, DEFAULT_MAX_MODE_INSTRUCTION, MAX_MODE_INSTRUCTION_SHORT_LENGTH
 )
-from app.services.user_service import get_user_details_cached
+from app.services.provider_service import ProviderHTTPError, ProviderRegistry
+from app.services.system_service import is_model_available, record_model_failure, record_model_success
+from app.services.model_service import get_fallback_chain
+from app.services.postprocess_service import ResponseContext, postprocess_response
+from app.services.user_service import get_user_details_cached, get_user_accessible_models
+from app.services.tool_service import get_tool_definitions, call_tool
+from app.services.builtin_tool_service import format_sources
+from app.services.text_service import shorten_text, estimate_tokens
+from app.services.context_service import fit_to_context
 
 logger = logging.getLogger(__name__)
 
+CHAOS_MODES = {'error': 'ошибка 503', 'timeout': 'таймаут'}
+RETRYABLE_STATUSES = {408, 409, 429, 500, 502, 503, 504}
+
+T = TypeVar('T')
+
+
+def calculate_cost(model: str, prompt_tokens: int, completion_tokens: int) -> float:
+    """Стоимость запроса в долларах по MODEL_PRICES (0, если цена модели не задана)."""
+    input_price, output_price = MODEL_PRICES.get(model, (0, 0))
+    return (prompt_tokens * input_price + completion_tokens * output_price) / 1000
+
+
+class TokenUsage(NamedTuple):
+    """
+    Токены, потраченные на ответ (из поля usage ответа API), их стоимость в долларах
+    и модель, которая ответила (может отличаться от запрошенной, если сработала запасная).
+    """
+    prompt_tokens: int = 0
+    completion_tokens: int = 0
+    cost: float = 0.0
+    model: str | None = None
+
+    @property
+    def tokens(self) -> tuple[int, int, float]:
+        """(prompt_tokens, completion_tokens, cost) - в таком виде расход записывается в БД (db.add_request)."""
+        return self.prompt_tokens, self.completion_tokens, self.cost
+
+    @classmethod
+    def from_api(cls, usage, model: str) -> 'TokenUsage':
+        if usage is None:
+            return cls(model=model)
+        prompt_tokens, completion_tokens = usage.prompt_tokens or 0, usage.completion_tokens or 0
+        return cls(prompt_tokens, completion_tokens, calculate_cost(model, prompt_tokens, completion_tokens), model)
+
+    @classmethod
+    def total(cls, usages) -> 'TokenUsage':
+        """Суммарный расход; модель указывается, только если все ответы дала одна модель."""
+        models = {usage.model for usage in usages}
+        sums = (sum(values) for values in zip((0, 0, 0.0), *(usage.tokens for usage in usages)))
+        return cls(*sums, model=models.pop() if len(models) == 1 else None)
+
+
+def set_chaos(cache: Dict, percent: int, mode: str):
+    """Включает имитацию сбоев провайдера для percent% запросов (0 - выключить). Режим живет в кэше и истекает сам."""
+    chaos = cache["chaos"]
+    chaos.clear()
+    if percent > 0:
+        chaos['settings'] = {'percent': percent, 'mode': mode, 'since': time.time()}
+
+def get_chaos(cache: Dict) -> dict | None:
+    chaos = cache.get("chaos")
+    return chaos.get('settings') if chaos is not None else None
+
+async def _maybe_simulate_outage(model: str, cache: Dict):
+    """Если администратор включил /chaos, с заданной вероятностью выбрасывает ту же ошибку, что и сбой провайдера."""
+    chaos = get_chaos(cache)
+    if not chaos or random.randint(1, 100) > chaos['percent']:
+        return
+    logger.warning(f"Chaos mode: simulating {chaos['mode']} for model {model}")
+    # Сбой имитируется до обращения к провайдеру, поэтому запрос в исключении - заглушка
+    request = httpx.Request('POST', "http://chaos.invalid/chat/completions")
+    if chaos['mode'] == 'timeout':
+        await asyncio.sleep(CHAOS_TIMEOUT_DELAY)
+        raise APITimeoutError(request=request)
+    raise InternalServerError(
+        "Simulated provider outage (chaos mode)", response=httpx.Response(503, request=request), body=None
+    )
+
+def _is_retryable(error: Exception) -> bool:
+    """Временный сбой, после которого имеет смысл повторить запрос."""
+    if isinstance(error, (APITimeoutError, APIConnectionError, aiohttp.ClientConnectionError, asyncio.TimeoutError)):
+        return True
+    if isinstance(error, APIStatusError):
+        return error.status_code in RETRYABLE_STATUSES
+    if isinstance(error, ProviderHTTPError):
+        return error.status in RETRYABLE_STATUSES
+    return False
+
+async def with_retries(call: Callable[[], Awaitable[T]], description: str) -> T:
+    """
+    Выполняет запрос к провайдеру, повторяя его при временных сбоях до AI_RETRY_ATTEMPTS раз
+    с экспоненциальной задержкой и случайным разбросом. Остальные ошибки пробрасываются сразу.
+    """
+    for attempt in range(1, AI_RETRY_ATTEMPTS + 1):
+        try:
+            result = await call()
+        except Exception as e:
+            if attempt >= AI_RETRY_ATTEMPTS or not _is_retryable(e):
+                raise
+            delay = min(AI_RETRY_MAX_DELAY, AI_RETRY_BASE_DELAY * 2 ** (attempt - 1)) * random.uniform(0.5, 1.5)
+            logger.warning(f"{description} failed (attempt {attempt}/{AI_RETRY_ATTEMPTS}): {e!r}. Retrying in {delay:.1f}s")
+            await asyncio.sleep(delay)
+            continue
+        if attempt > 1:
+            logger.info(f"{description} succeeded after {attempt - 1} retries")
+        return result
+
 async def get_simple_response(
-    ai_client: AsyncOpenAI, 
-    model: str, 
-    messages: list, 
+    ai_client: ProviderRegistry,
+    model: str,
+    messages: list,
     user_id: int,
     db,
-    cache: Dict 
-) -> Tuple[str, float]:
+    cache: Dict,
+    on_partial: Callable[[str], Awaitable[None]] | None = None,
+    final_answer: bool = True,
+    fallback: bool = True,
+    instruction_mode: str = 'full'
+) -> Tuple[str, float, TokenUsage]:
+    """
+    Получает обычный ответ от модели. Если модель отключена после сбоев подряд или не ответила
+    из-за временного сбоя, запрос по очереди передается запасным моделям (model_service.get_fallback_chain;
+    fallback=False - только указанная модель). Какая модель ответила, указано в usage.model.
+    instruction_mode - как передать персональную инструкцию: full, short (сокращенно) или off.
+    История, которая не помещается в контекст модели, сокращается (context_service.fit_to_context).
+    Возвращает кортеж (текст_ответа, время_выполнения, потраченные токены).
     """
-    Получает обычный ответ от одной модели.
-    Возвращает кортеж (текст_ответа, время_выполнения).
-    В случае ошибки вызывает исключение.
+    models = [model]
+    if fallback:
+        # Запасная модель должна быть доступна на тарифе пользователя и уметь работать с изображениями, если они есть
+        has_images = any(isinstance(message.get('content'), list) for message in messages)
+        accessible_models = await get_user_accessible_models(user_id, db)
+        models += get_fallback_chain(model, accessible_models, cache, 'vision' if has_images else None)
+    # Отключенная модель пропускается; если не работает ни одна, пробуем запрошенную
+    candidates = [m for m in models if is_model_available(m, cache)] or [model]
+
+    for index, candidate in enumerate(candidates):
+        try:
+            return await _get_model_response(
+                ai_client, candidate, messages, user_id, db, cache, on_partial, final_answer, instruction_mode
+            )
+        except Exception as e:
+            if index == len(candidates) - 1 or not _is_retryable(e):
+                raise
+            logger.warning(f"Model {candidate} failed for user {user_id}, falling back to {candidates[index + 1]}")
+
+async def _get_model_response(
+    ai_client: ProviderRegistry,
+    model: str,
+    messages: list,
+    user_id: int,
+    db,
+    cache: Dict,
+    on_partial: Callable[[str], Awaitable[None]] | None,
+    final_answer: bool,
+    instruction_mode: str = 'full'
+) -> Tuple[str, float, TokenUsage]:
     """
+    Получает ответ от одной модели (см. get_simple_response).
+    Если передан on_partial, ответ запрашивается потоком и on_partial получает накопленный текст
+    после каждого фрагмента, уже прошедший постобработку (с инструментами поток не используется).
+    Ответ проходит постобработку (postprocess_service); final_answer=False - ответ не показывается
+    пользователю напрямую, и подпись к нему не добавляется.
+    Временные сбои провайдера повторяются (with_retries), а неудача после всех повторов
+    учитывается в счетчике сбоев модели (record_model_failure). В случае ошибки вызывает исключение.
+    """
+    context = ResponseContext(model=model, user_id=user_id, final_answer=final_answer)
+    provider = ai_client.for_model(model)
+    description = f"Request to model {model} for user {user_id}"
     start_time = time.time()
     
     user_details = await get_user_details_cached(user_id, db, cache)
-    user_instruction = user_details[10] if user_details and user_details[10] else None
+    user_instruction = user_details[10] if user_details and user_details[10] and instruction_mode != 'off' else None
+    if user_instruction and instruction_mode == 'short':
+        user_instruction = shorten_text(user_instruction, MAX_MODE_INSTRUCTION_SHORT_LENGTH)
     user_temperature = user_details[11] if user_details and user_details[11] is not None else DEFAULT_TEMPERATURE
 
     final_messages = [{"role": "system", "content": GLOBAL_SYSTEM_PROMPT}]
     if user_instruction:
         final_messages.append({"role": "system", "content": f"Дополнительная инструкция от пользователя: {user_instruction}"})
     final_messages.extend(messages)
+    final_messages = await fit_to_context(ai_client, model, final_messages, user_id, cache)
+    # Инструменты внешних серверов предлагаются только моделям, которые умеют их вызывать
+    tools = await get_tool_definitions(db, cache, user_id) if model_supports(model, 'tools') else []
+    tool_kwargs = {"tools": tools} if tools else {}
+    sources = [] # Источники, найденные инструментами (например, поиском); выводятся под ответом
     
+    async def create_completion(**kwargs):
+        await _maybe_simulate_outage(model, cache)
+        # Повторами управляет with_retries, чтобы их число было видно в логах
+        return await provider.chat(
+            model, final_messages, max_retries=0, temperature=user_temperature, timeout=120.0, **kwargs
+        )
+
     try:
         logger.debug(f"Requesting model {model} for user {user_id}")
-        response = await ai_client.chat.completions.create(
-            model=model, messages=final_messages,
-            temperature=user_temperature, timeout=120.0
-        )
+        if on_partial and not tools:
+            # Повторяется только открытие потока: после первых фрагментов пользователь уже видит ответ
+            stream = await with_retries(
+                lambda: create_completion(stream=True, stream_options={"include_usage": True}), description
+            )
+            # Промежуточный текст проходит ту же постобработку, что и готовый ответ
+            partial_context = replace(context, partial=True)
+
+            async def show_partial(text: str):
+                await on_partial(postprocess_response(text, partial_context))
+
+            response_text, duration, usage = await _read_stream(stream, model, show_partial, start_time)
+            record_model_success(model, cache)
+            return postprocess_response(response_text, context), duration, usage
+        response = await with_retries(lambda: create_completion(**tool_kwargs), description)
+        usages = [TokenUsage.from_api(response.usage, model)]
+        # Модель может вызвать инструменты несколько раз подряд; в последнем раунде вызовы запрещаются
+        rounds = 0
+        while tools and response.choices and response.choices[0].message.tool_calls and rounds < TOOL_MAX_ROUNDS:
+            rounds += 1
+            assistant_message = response.choices[0].message
+            final_messages.append(assistant_message.model_dump(exclude_none=True))
+            for tool_call in assistant_message.tool_calls:
+                result = await call_tool(db, cache, tool_call.function.name, tool_call.function.arguments, user_id, sources)
+                final_messages.append({"role": "tool", "tool_call_id": tool_call.id, "content": result})
+            if rounds == TOOL_MAX_ROUNDS:
+                tool_kwargs["tool_choice"] = "none"
+            response = await with_retries(lambda: create_completion(**tool_kwargs), description)
+            usages.append(TokenUsage.from_api(response.usage, model))
         duration = time.time() - start_time
+        usage = TokenUsage.total(usages)
+        record_model_success(model, cache)
         
         # --- ИЗМЕНЕНИЕ: Добавлена проверка на None ---
         if not response.choices or response.choices[0].message.content is None:
             logger.warning(f"Model {model} for user {user_id} returned a response with no content. Finish reason: {response.choices[0].finish_reason if response.choices else 'N/A'}")
             # Возвращаем пустую строку, чтобы избежать падений дальше по коду
-            return "", duration
+            return "", duration, usage
 
         response_text = response.choices[0].message.content
-        logger.debug(f"Model {model} for user {user_id} responded in {duration:.2f}s")
-        return response_text, duration
+        if sources:
+            response_text += format_sources(sources)
+        response_text = postprocess_response(response_text, context)
+        logger.debug(f"Model {model} for user {user_id} responded in {duration:.2f}s ({usage.prompt_tokens}+{usage.completion_tokens} tokens)")
+        return response_text, duration, usage
     except Exception as e:
+        if _is_retryable(e):
+            record_model_failure(model, cache)
         logger.error(f"Failed to get response from model {model} for user {user_id}. Error: {e}", exc_info=True)
         raise
 
-async def _get_participant_response(ai_client, model, prompt, user_id, db, cache):
-    """Внутренняя функция для безопасного получения ответа от модели-участника."""
+async def _read_stream(
+    stream, model: str, on_partial: Callable[[str], Awaitable[None]], start_time: float
+) -> Tuple[str, float, TokenUsage]:
+    """
+    Читает ответ из SSE-потока /chat/completions, передавая накопленный текст в on_partial.
+    Расход токенов приходит в последнем фрагменте потока (stream_options.include_usage).
+    """
+    parts = []
+    usage = TokenUsage(model=model)
+    async for chunk in stream:
+        if getattr(chunk, 'usage', None):
+            usage = TokenUsage.from_api(chunk.usage, model)
+        delta = chunk.choices[0].delta.content if chunk.choices else None
+        if delta:
+            parts.append(delta)
+            await on_partial(''.join(parts))
+    duration = time.time() - start_time
+    logger.debug(f"Model {model} streamed {len(parts)} chunks in {duration:.2f}s")
+    return ''.join(parts), duration, usage
+
+# --- Структурированные ответы (JSON) ---
+
+class StructuredResponseError(RuntimeError):
+    """Модель так и не вернула корректный JSON-объект."""
+
+_CODE_FENCE_RE = re.compile(r'^```(?:json)?\s*|\s*```$', re.IGNORECASE)
+
+def _parse_json_object(text: str) -> dict:
+    """Достает JSON-объект из ответа модели, в том числе обернутый в ```json ... ```."""
+    text = _CODE_FENCE_RE.sub('', text.strip())
+    start, end = text.find('{'), text.rfind('}')
+    if start == -1 or end < start:
+        raise ValueError("no JSON object in response")
+    result = json.loads(text[start:end + 1])
+    if not isinstance(result, dict):
+        raise ValueError("JSON is not an object")
+    return result
+
+async def get_structured_response(
+    ai_client: ProviderRegistry,
+    model: str,
+    messages: list,
+    schema_hint: str | None = None,
+    retries: int = 2,
+    max_tokens: int | None = None,
+    timeout: float = 60.0
+) -> dict:
+    """
+    Запрашивает у модели ответ в виде JSON-объекта для внутренних задач (классификация, извлечение данных).
+    Если модель поддерживает json_mode, передается response_format. При некорректном JSON запрос
+    повторяется до retries раз с просьбой исправить ответ. В случае неудачи вызывает StructuredResponseError.
+    """
+    system_prompt = "Отвечай только корректным JSON-объектом, без пояснений и markdown."
+    if schema_hint:
+        system_prompt += f" Формат ответа: {schema_hint}"
+    request_messages = [{"role": "system", "content": system_prompt}, *messages]
+
+    extra_params = {}
+    if model_supports(model, 'json_mode'):
+        extra_params['response_format'] = {"type": "json_object"}
+    if max_tokens:
+        extra_params['max_tokens'] = max_tokens
+
+    for attempt in range(retries + 1):
+        response = await ai_client.for_model(model).chat(
+            model, request_messages, temperature=0, timeout=timeout, **extra_params
+        )
+        text = (response.choices[0].message.content or "") if response.choices else ""
+        try:
+            return _parse_json_object(text)
+        except ValueError as e:
+            logger.warning(f"Model {model} returned invalid JSON (attempt {attempt + 1}/{retries + 1}): {e}")
+            request_messages = [
+                *request_messages,
+                {"role": "assistant", "content": text},
+                {"role": "user", "content": "Это не корректный JSON-объект. Повтори ответ строго в формате JSON."}
+            ]
+    raise StructuredResponseError(f"Model {model} failed to return valid JSON after {retries + 1} attempts")
+
+async def _get_participant_response(ai_client, model, prompt, user_id, db, cache, instruction_mode):
+    """
+    Внутренняя функция для безопасного получения ответа от модели-участника.
+    instruction_mode - режим передачи персональной инструкции (см. get_max_mode_instruction).
+    Возвращает (модель, ответ, время, ошибка или None, токены).
+    """
+    start_time = time.time()
     try:
-        response, _ = await get_simple_response(ai_client, model, [{"role": "user", "content": prompt}], user_id, db, cache)
-        return model, response
+        # Без запасных моделей: замена могла бы совпасть с другим участником
+        response, duration, usage = await get_simple_response(
+            ai_client, model, [{"role": "user", "content": prompt}], user_id, db, cache,
+            final_answer=False, fallback=False, instruction_mode=instruction_mode
+        )
+        return model, response, duration, None, usage
     except Exception as e:
         logger.warning(f"Max Mode participant {model} failed for user {user_id}. Error: {e}")
-        return model, f"ОШИБКА: Модель не смогла обработать запрос. ({type(e).__name__})"
+        error_text = f"ОШИБКА: Модель не смогла обработать запрос. ({type(e).__name__})"
+        return model, error_text, time.time() - start_time, str(e), TokenUsage()
+
+async def _save_max_mode_run(db, user_id: int, prompt: str, participant_results: list, arbiter_output: str | None,
+                             arbiter_duration: float | None, total_duration: float) -> int:
+    """Сохраняет запуск Max Mode для разбора администратором (/maxrun). Возвращает id запуска."""
+    participants = [
+        {"model": model, "answer": answer, "duration": round(duration, 2), "error": error}
+        for model, answer, duration, error, _ in participant_results
+    ]
+    called_models = [model for model, *_ in participant_results] + ([MAX_MODE_ARBITER] if arbiter_duration is not None else [])
+    cost = sum(MODEL_INFO[model].cost for model in called_models if model in MODEL_INFO)
+    return await db.add_max_mode_run(
+        user_id, prompt, json.dumps(participants, ensure_ascii=False), MAX_MODE_ARBITER, arbiter_output,
+        arbiter_duration, total_duration, cost, 'ok' if arbiter_output is not None else 'failed'
+    )
 
 
 # app/services/ai_service.py
 
 # ... (остальной код файла без изменений) ...
 
+async def get_max_mode_instruction(db, user_id: int) -> str:
+    """Режим передачи персональной инструкции участникам Max Mode (ключ MAX_MODE_INSTRUCTION_MODES)."""
+    mode = await db.get_max_mode_instruction(user_id)
+    return mode if mode in MAX_MODE_INSTRUCTION_MODES else DEFAULT_MAX_MODE_INSTRUCTION
+
+class MaxModeEstimate(NamedTuple):
+    """Оценка запуска Max Mode: сколько моделей будет вызвано, токенов и долларов по MODEL_PRICES."""
+    models: int
+    tokens: int
+    cost: float
+
+
+def estimate_max_mode_cost(prompt: str) -> MaxModeEstimate:
+    """
+    Оценивает запуск Max Mode до его начала: каждый участник получает промпт и отвечает примерно
+    MAX_MODE_EXPECTED_ANSWER_TOKENS токенами, арбитр получает промпт и все ответы.
+    """
+    prompt_tokens = estimate_tokens(GLOBAL_SYSTEM_PROMPT + prompt)
+    answer_tokens = MAX_MODE_EXPECTED_ANSWER_TOKENS
+    calls = [(model, prompt_tokens) for model in MAX_MODE_PARTICIPANTS]
+    calls.append((MAX_MODE_ARBITER, prompt_tokens + answer_tokens * len(MAX_MODE_PARTICIPANTS)))
+    return MaxModeEstimate(
+        models=len(calls),
+        tokens=sum(input_tokens + answer_tokens for _, input_tokens in calls),
+        cost=sum(calculate_cost(model, input_tokens, answer_tokens) for model, input_tokens in calls),
+    )
+
 async def get_max_mode_response(
-    ai_client: AsyncOpenAI,
+    ai_client: ProviderRegistry,
     prompt: str,
     user_id: int,
     db,
     cache: Dict
-) -> Tuple[str, float]:
+) -> Tuple[str, float, int, TokenUsage]:
     """
     Получает ответ в режиме Max Mode: опрашивает несколько моделей
     и передает их ответы модели-арбитру для финального результата.
+    Каждый запуск (в том числе неудачный) сохраняется в max_mode_runs.
+    Возвращает (ответ, время, id запуска, токены всех моделей вместе).
     """
     full_start_time = time.time()
     logger.info(f"Starting Max Mode for user {user_id}")
 
-    # 1. Параллельно опрашиваем все модели-участники
+    # 1. Параллельно опрашиваем все модели-участники.
+    # Инструкция участникам передается по настройке пользователя: каждый участник платит за нее отдельно
+    instruction_mode = await get_max_mode_instruction(db, user_id)
     tasks = [
-        _get_participant_response(ai_client, model_name, prompt, user_id, db, cache)
+        _get_participant_response(ai_client, model_name, prompt, user_id, db, cache, instruction_mode)
         for model_name in MAX_MODE_PARTICIPANTS
     ]
     
@@ -117,7 +459,7 @@ async def get_max_mode_response(
     ]
 
     successful_responses = 0
-    for model_name, response_text in participant_results:
+    for model_name, response_text, *_ in participant_results:
         safe_response_text = response_text if response_text is not None else "ОШИБКА: Модель не вернула текстовый ответ."
         meta_prompt_parts.append(f"\n**Ответ от модели ({hcode(model_name)}):**\n{safe_response_text}\n---")
         if not safe_response_text.startswith("ОШИБКА:"):
@@ -126,23 +468,65 @@ async def get_max_mode_response(
     # Проверка, есть ли хотя бы один успешный ответ
     if successful_responses == 0:
         logger.error(f"Max Mode failed for user {user_id}: all participants returned an error or empty content.")
+        await _save_max_mode_run(db, user_id, prompt, participant_results, None, None, time.time() - full_start_time)
         raise RuntimeError("К сожалению, все модели-участники не смогли дать ответ. Попробуйте позже.")
 
     meta_prompt_parts.append("\n**ТВОЙ ИТОГОВЫЙ РЕЗУЛЬТАТ (выполни ШАГ 2 и ШАГ 3):**")
     meta_prompt = "\n".join(meta_prompt_parts)
 
     # 3. Отправляем запрос арбитру
+    arbiter_start_time = time.time()
     try:
         logger.info(f"Sending meta-prompt to arbiter {MAX_MODE_ARBITER} for user {user_id}")
-        final_response_text, _ = await get_simple_response(
+        final_response_text, arbiter_duration, arbiter_usage = await get_simple_response(
             ai_client, MAX_MODE_ARBITER, [{"role": "user", "content": meta_prompt}], user_id, db, cache
         )
     except Exception as e:
         logger.error(f"Max Mode arbiter {MAX_MODE_ARBITER} failed for user {user_id}. Error: {e}")
+        await _save_max_mode_run(
+            db, user_id, prompt, participant_results, None, time.time() - arbiter_start_time, time.time() - full_start_time
+        )
         raise RuntimeError(f"Модель-арбитр ({MAX_MODE_ARBITER}) не смогла обработать ответы. Попробуйте позже.")
 
     total_duration = time.time() - full_start_time
-    logger.info(f"Max Mode for user {user_id} finished in {total_duration:.2f}s")
-    return final_response_text, total_duration
+    run_id = await _save_max_mode_run(
+        db, user_id, prompt, participant_results, final_response_text, arbiter_duration, total_duration
+    )
+    logger.info(f"Max Mode run #{run_id} for user {user_id} finished in {total_duration:.2f}s")
+    usage = TokenUsage.total([result[4] for result in participant_results] + [arbiter_usage])
+    return final_response_text, total_duration, run_id, usage
+
+async def generate_image(
+    ai_client: ProviderRegistry, model: str, prompt: str, width: int = 1024, height: int = 1024
+) -> Tuple[bytes, float]:
+    """
+    Генерирует изображение по промпту через провайдера модели.
+    Возвращает кортеж (байты_изображения, время_выполнения).
+    Временные сбои провайдера повторяются (with_retries). В случае ошибки вызывает исключение.
+    """
+    start_time = time.time()
+    provider = ai_client.for_model(model)
+    image_bytes = await with_retries(
+        lambda: provider.generate_image(model, prompt, width, height), f"Image generation with model {model}"
+    )
+    return image_bytes, time.time() - start_time
+
+async def paraphrase_image_prompt(ai_client: ProviderRegistry, prompt: str) -> str:
+    """Переформулирует промпт для генерации изображения, сохраняя сюжет. В случае ошибки вызывает исключение."""
+    response = await ai_client.for_model(IMAGE_VARIATION_MODEL).chat(
+        IMAGE_VARIATION_MODEL,
+        [
+            {"role": "system", "content": (
+                "Перепиши промпт для генерации изображения: сохрани сюжет и главный объект, "
+                "но измени детали, ракурс, освещение или стиль. Ответь только новым промптом на языке исходного."
+            )},
+            {"role": "user", "content": prompt[:2000]}
+        ],
+        temperature=1.0, max_tokens=300, timeout=30.0
+    )
+    text = (response.choices[0].message.content or "").strip() if response.choices else ""
+    if not text:
+        raise RuntimeError(f"Model {IMAGE_VARIATION_MODEL} returned an empty prompt")
+    return text
 
 # ... (остальной код файла без изменений) ...
\ No newline at end of file
@@ -0,0 +1,67 @@
+# app/services/analytics_service.py
+# Продуктовая аналитика: обработчики отмечают события (открытие меню, смена модели, покупка,
+# показанная ошибка) вызовом track, события копятся в памяти и пачками записываются
+# в таблицу events планировщиком (flush_events). По ним строятся воронки из ANALYTICS_FUNNELS.
+
+import json
+import logging
+from datetime import datetime, timezone
+
+from app.config import ANALYTICS_ENABLED, ANALYTICS_BUFFER_LIMIT
+from app.database import Database
+
+logger = logging.getLogger(__name__)
+
+
+def track(cache: dict, event: str, user_id: int | None, props: dict | None = None):
+    """Отмечает событие. Запись в БД откладывается до flush_events, поэтому вызов ничего не ждет."""
+    if not ANALYTICS_ENABLED:
+        return
+    buffer = cache["analytics_events"]
+    if len(buffer) >= ANALYTICS_BUFFER_LIMIT:
+        # БД недоступна или планировщик не успевает: аналитика не должна съедать память
+        logger.warning(f"Analytics buffer is full, event '{event}' dropped")
+        return
+    props_json = json.dumps(props, ensure_ascii=False) if props else None
+    buffer.append((event, user_id, props_json, datetime.now(timezone.utc)))
+
+async def flush_events(db: Database, cache: dict):
+    """Записывает накопленные события одной пачкой. Запускается планировщиком и при остановке бота."""
+    buffer = cache["analytics_events"]
+    if not buffer:
+        return
+    batch = buffer[:]
+    del buffer[:len(batch)]
+    try:
+        await db.add_events(batch)
+    except Exception as e:
+        logger.error(f"Failed to write {len(batch)} analytics events: {e}")
+        return
+    logger.debug(f"Flushed {len(batch)} analytics events")
+
+async def get_funnel(db: Database, steps: list, days: int) -> list[int]:
+    """
+    Сколько пользователей прошло каждый шаг воронки за days дней. Шаг засчитывается, если пользователь
+    выполнил все предыдущие шаги и впервые выполнил этот шаг не раньше предыдущего.
+    """
+    first_times: dict[int, dict] = {}
+    for user_id, event, first_at in await db.get_first_event_times(steps, days):
+        first_times.setdefault(user_id, {})[event] = first_at
+
+    counts = [0] * len(steps)
+    for times in first_times.values():
+        previous_at = None
+        for index, step in enumerate(steps):
+            step_at = times.get(step)
+            if step_at is None or (previous_at is not None and step_at < previous_at):
+                break
+            counts[index] += 1
+            previous_at = step_at
+    return counts
+
+def format_funnel(name: str, steps: list, counts: list[int]) -> str:
+    lines = [f"<b>{name}</b>"]
+    for index, (step, count) in enumerate(zip(steps, counts)):
+        conversion = f" ({count / counts[index - 1] * 100:.0f}%)" if index and counts[index - 1] else ""
+        lines.append(f" {index + 1}. {step}: {count}{conversion}")
+    return "\n".join(lines)
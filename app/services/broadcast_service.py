@@ -0,0 +1,126 @@
+# app/services/broadcast_service.py
+# Логика рассылок. Прогресс сохраняется в БД после каждого сообщения,
+# поэтому прерванная рассылка продолжается с места остановки.
+# Пользователям, у которых сейчас тихие часы, сообщение доставляется позже.
+# Рассылать можно текст или фото, видео, GIF и документ с подписью;
+# администратор видит прогресс в сообщении, которое обновляется по ходу рассылки.
+
+import asyncio
+import logging
+import time
+
+from aiogram import Bot
+from aiogram.types import Message
+
+from app.database import Database
+from app.services.notification_service import send_notification
+
+logger = logging.getLogger(__name__)
+
+# Пауза между сообщениями, чтобы не упираться в лимиты Telegram (~30 сообщений/сек)
+SEND_DELAY = 0.1
+# Как часто (в секундах) обновлять сообщение с прогрессом
+PROGRESS_UPDATE_INTERVAL = 10
+MEDIA_NAMES = {'photo': 'фото', 'video': 'видео', 'animation': 'GIF', 'document': 'документ'}
+
+
+def extract_broadcast_content(message: Message) -> dict | None:
+    """Достает из сообщения администратора текст (с форматированием) и вложение. None - тип не поддерживается."""
+    if message.photo:
+        media_type, file_id = 'photo', message.photo[-1].file_id
+    elif message.animation: # GIF приходит и как документ, поэтому проверяется раньше
+        media_type, file_id = 'animation', message.animation.file_id
+    elif message.video:
+        media_type, file_id = 'video', message.video.file_id
+    elif message.document:
+        media_type, file_id = 'document', message.document.file_id
+    elif message.text:
+        media_type, file_id = None, None
+    else:
+        return None
+    return {"text": message.html_text, "media_type": media_type, "media_file_id": file_id}
+
+def get_content_media(content: dict) -> tuple[str, str] | None:
+    return (content['media_type'], content['media_file_id']) if content.get('media_type') else None
+
+def _format_progress(broadcast_id: int, processed: int, total: int, success_count: int, fail_count: int, finished: bool = False) -> str:
+    title = f"✅ Рассылка #{broadcast_id} завершена" if finished else f"📣 Рассылка #{broadcast_id} идет"
+    percent = min(100, processed * 100 // total) if total else 100
+    return f"{title}: {processed} из {total} ({percent}%)\nУспешно: {success_count}, неудачно: {fail_count}"
+
+async def _update_progress(bot: Bot, admin_id: int, message_id: int | None, text: str):
+    if not message_id:
+        return
+    try:
+        await bot.edit_message_text(text, chat_id=admin_id, message_id=message_id)
+    except Exception as e:
+        logger.debug(f"Could not update broadcast progress message: {e}")
+
+async def run_broadcast(bot: Bot, db: Database, broadcast_id: int):
+    """Выполняет (или продолжает) рассылку, начиная с пользователя после last_user_id."""
+    broadcast = await db.get_broadcast(broadcast_id)
+    if not broadcast or broadcast[3] != 'running':
+        return
+
+    _, admin_id, text, _, last_user_id, success_count, fail_count, media_type, media_file_id, progress_message_id = broadcast
+    media = (media_type, media_file_id) if media_type else None
+    total = await db.get_user_count()
+    logger.info(f"Running broadcast #{broadcast_id} starting after user {last_user_id}")
+
+    # После перезапуска продолжаем обновлять то же сообщение с прогрессом
+    if not progress_message_id:
+        try:
+            progress_message = await bot.send_message(admin_id, _format_progress(broadcast_id, 0, total, 0, 0))
+            progress_message_id = progress_message.message_id
+            await db.set_broadcast_progress_message(broadcast_id, progress_message_id)
+        except Exception as e:
+            logger.warning(f"Failed to send broadcast progress to admin {admin_id}: {e}")
+
+    last_progress_update = time.monotonic()
+    while True:
+        user_ids = await db.get_user_ids_after(last_user_id)
+        if not user_ids:
+            break
+        for user_id in user_ids:
+            delivered = await send_notification(bot, db, user_id, text, media=media)
+            await db.update_broadcast_progress(broadcast_id, user_id, delivered)
+            if delivered:
+                success_count += 1
+            else:
+                fail_count += 1
+            last_user_id = user_id
+            if time.monotonic() - last_progress_update >= PROGRESS_UPDATE_INTERVAL:
+                last_progress_update = time.monotonic()
+                processed = success_count + fail_count
+                await _update_progress(bot, admin_id, progress_message_id, _format_progress(
+                    broadcast_id, processed, max(total, processed), success_count, fail_count
+                ))
+            await asyncio.sleep(SEND_DELAY)
+
+    await db.finish_broadcast(broadcast_id)
+    processed = success_count + fail_count
+    await _update_progress(bot, admin_id, progress_message_id, _format_progress(
+        broadcast_id, processed, processed, success_count, fail_count, finished=True
+    ))
+    logger.info(f"Broadcast #{broadcast_id} finished: {success_count} sent, {fail_count} failed")
+    try:
+        await bot.send_message(
+            admin_id,
+            f"✅ Рассылка #{broadcast_id} завершена.\n\nУспешно: {success_count}\nНеудачно: {fail_count}\n\n"
+            "Пользователи, у которых сейчас тихие часы, получат сообщение утром."
+        )
+    except Exception as e:
+        logger.warning(f"Failed to send broadcast report to admin {admin_id}: {e}")
+
+def schedule_broadcast(scheduler, bot: Bot, db: Database, broadcast_id: int):
+    """Ставит рассылку в планировщик на немедленное выполнение."""
+    scheduler.add_job(
+        run_broadcast, args=(bot, db, broadcast_id),
+        id=f"broadcast_{broadcast_id}", replace_existing=True
+    )
+
+async def resume_unfinished_broadcasts(scheduler, bot: Bot, db: Database):
+    """Возобновляет рассылки, прерванные перезапуском или падением бота."""
+    for broadcast_id in await db.get_unfinished_broadcast_ids():
+        logger.info(f"Resuming unfinished broadcast #{broadcast_id}")
+        schedule_broadcast(scheduler, bot, db, broadcast_id)
@@ -0,0 +1,162 @@
+# app/services/builtin_tool_service.py
+# Встроенные инструменты, которые бот выполняет сам, без внешних серверов.
+# Каждый инструмент - подкласс BuiltinTool с описанием параметров в формате JSON Schema;
+# включенные в BUILTIN_TOOLS инструменты предлагаются моделям вместе с инструментами серверов.
+# Инструмент может добавить источники (sources) - они выводятся под итоговым ответом.
+
+import asyncio
+import html
+import logging
+from datetime import datetime, timedelta, timezone
+
+import aiohttp
+
+from app.config import (
+    BUILTIN_TOOLS, TOOL_CALL_TIMEOUT,
+    WEB_SEARCH_PROVIDER, WEB_SEARCH_API_URL, WEB_SEARCH_API_KEY, WEB_SEARCH_RESULTS, WEB_SEARCH_MIN_LEVEL
+)
+from app.database import Database
+from app.services.network_service import create_http_session
+from app.services.user_service import get_utc_offset
+
+logger = logging.getLogger(__name__)
+
+
+class BuiltinTool:
+    """
+    Встроенный инструмент. run получает разобранные аргументы и возвращает текст для модели;
+    найденные источники (title, url) инструмент добавляет в sources.
+    """
+    name = ''
+    description = ''
+    parameters = {"type": "object", "properties": {}}
+    min_level = 0 # С какого уровня подписки инструмент доступен
+
+    def definition(self) -> dict:
+        return {
+            "type": "function",
+            "function": {"name": self.name, "description": self.description, "parameters": self.parameters},
+        }
+
+    async def run(self, arguments: dict, user_id: int, db: Database, sources: list) -> str:
+        raise NotImplementedError
+
+
+class CurrentDateTime(BuiltinTool):
+    """Текущие дата и время в часовом поясе пользователя (из настроек уведомлений)."""
+    name = 'current_datetime'
+    description = "Возвращает текущие дату, время и день недели в часовом поясе пользователя."
+
+    async def run(self, arguments, user_id, db, sources):
+        utc_offset = await get_utc_offset(db, user_id)
+        now = datetime.now(timezone(timedelta(hours=utc_offset)))
+        return f"{now.strftime('%Y-%m-%d %H:%M, %A')}, UTC{utc_offset:+d}"
+
+
+class WebSearchError(Exception):
+    """Поисковый API недоступен или вернул ошибку."""
+
+
+async def _search_searxng(session: aiohttp.ClientSession, query: str) -> list:
+    params = {"q": query, "format": "json"}
+    async with session.get(f"{WEB_SEARCH_API_URL.rstrip('/')}/search", params=params, timeout=TOOL_CALL_TIMEOUT) as response:
+        if response.status != 200:
+            raise WebSearchError(f"HTTP {response.status}")
+        data = await response.json(content_type=None)
+    return [(item.get('title', ''), item.get('url', ''), item.get('content', '')) for item in data.get('results', [])]
+
+async def _search_brave(session: aiohttp.ClientSession, query: str) -> list:
+    url = WEB_SEARCH_API_URL or 'https://api.search.brave.com/res/v1/web/search'
+    headers = {"X-Subscription-Token": WEB_SEARCH_API_KEY, "Accept": "application/json"}
+    params = {"q": query, "count": WEB_SEARCH_RESULTS}
+    async with session.get(url, headers=headers, params=params, timeout=TOOL_CALL_TIMEOUT) as response:
+        if response.status != 200:
+            raise WebSearchError(f"HTTP {response.status}")
+        data = await response.json(content_type=None)
+    results = (data.get('web') or {}).get('results', [])
+    return [(item.get('title', ''), item.get('url', ''), item.get('description', '')) for item in results]
+
+SEARCH_PROVIDERS = {'searxng': _search_searxng, 'brave': _search_brave}
+
+def is_web_search_configured() -> bool:
+    if WEB_SEARCH_PROVIDER == 'searxng':
+        return bool(WEB_SEARCH_API_URL)
+    return WEB_SEARCH_PROVIDER in SEARCH_PROVIDERS and bool(WEB_SEARCH_API_KEY)
+
+async def search_web(query: str) -> list:
+    """Возвращает до WEB_SEARCH_RESULTS результатов [(title, url, snippet)]. При ошибке - WebSearchError."""
+    search = SEARCH_PROVIDERS[WEB_SEARCH_PROVIDER]
+    try:
+        async with create_http_session(WEB_SEARCH_API_URL) as session:
+            results = await search(session, query)
+    except (aiohttp.ClientError, asyncio.TimeoutError, ValueError) as e:
+        raise WebSearchError(str(e) or type(e).__name__) from e
+    return [result for result in results if result[1]][:WEB_SEARCH_RESULTS]
+
+
+class WebSearch(BuiltinTool):
+    """Поиск в интернете. Результаты нумеруются сквозь все поиски ответа, чтобы модель ссылалась на [N]."""
+    name = 'web_search'
+    description = (
+        "Ищет в интернете актуальную информацию. Используй для свежих новостей, фактов и данных, "
+        "которых может не быть в твоих знаниях. Ссылайся на результаты по номерам [N]."
+    )
+    parameters = {
+        "type": "object",
+        "properties": {"query": {"type": "string", "description": "Поисковый запрос"}},
+        "required": ["query"],
+    }
+    min_level = WEB_SEARCH_MIN_LEVEL
+
+    async def run(self, arguments, user_id, db, sources):
+        query = str(arguments.get('query') or '').strip()
+        if not query:
+            return "Ошибка: не указан поисковый запрос."
+        try:
+            results = await search_web(query)
+        except WebSearchError as e:
+            logger.warning(f"Web search failed for user {user_id}: {e}")
+            return f"Поиск сейчас недоступен: {e}"
+        if not results:
+            return "Ничего не найдено."
+
+        lines = []
+        urls = [url for _, url in sources]
+        for title, url, snippet in results:
+            if url not in urls:
+                sources.append((title, url))
+                urls.append(url)
+            lines.append(f"[{urls.index(url) + 1}] {title}\n{url}\n{snippet}")
+        return '\n\n'.join(lines)
+
+
+def _build_registry(names: list[str]) -> dict[str, BuiltinTool]:
+    """Реестр включенных инструментов по имени; неизвестные имена пропускаются с предупреждением."""
+    available = {tool.name: tool for tool in (CurrentDateTime(), WebSearch())}
+    registry = {}
+    for name in names:
+        if name not in available:
+            logger.warning(f"Unknown built-in tool '{name}' in BUILTIN_TOOLS, skipping")
+            continue
+        if name == WebSearch.name and not is_web_search_configured():
+            logger.info("Web search is not configured (WEB_SEARCH_PROVIDER / WEB_SEARCH_API_URL / WEB_SEARCH_API_KEY), tool disabled")
+            continue
+        registry[name] = available[name]
+    return registry
+
+REGISTRY = _build_registry(BUILTIN_TOOLS)
+
+def get_builtin_tool(name: str, user_level: int) -> BuiltinTool | None:
+    tool = REGISTRY.get(name)
+    return tool if tool and user_level >= tool.min_level else None
+
+def get_builtin_definitions(user_level: int) -> list:
+    return [tool.definition() for tool in REGISTRY.values() if user_level >= tool.min_level]
+
+def format_sources(sources: list) -> str:
+    """Нумерованный список источников для вывода под ответом."""
+    lines = [
+        f'{index}. <a href="{html.escape(url)}">{html.escape(title or url)}</a>'
+        for index, (title, url) in enumerate(sources, 1)
+    ]
+    return "\n\n<b>Источники:</b>\n" + "\n".join(lines)
@@ -0,0 +1,68 @@
+# app/services/confirmation_service.py
+# Правило двух администраторов: опасные действия (рассылка всем, отправка опроса)
+# выполняются только после подтверждения другим администратором в течение
+# ADMIN_CONFIRMATION_MINUTES. Если администратор один, действия выполняются сразу.
+
+import json
+import logging
+from datetime import datetime, timedelta, timezone
+
+from aiogram import Bot
+from aiogram.utils.markdown import hcode
+
+from app.config import ADMIN_IDS, ADMIN_CONFIRMATION_MINUTES
+from app.database import Database
+from app.keyboards.inline import get_admin_confirmation_menu
+
+logger = logging.getLogger(__name__)
+
+ACTION_NAMES = {
+    'broadcast': 'рассылка всем пользователям',
+    'survey_send': 'отправка опроса',
+}
+
+
+def is_two_person_rule_active() -> bool:
+    return len(ADMIN_IDS) > 1
+
+async def request_confirmation(bot: Bot, db: Database, action: str, payload: dict, requested_by: int, description: str) -> int:
+    """Сохраняет запрос и рассылает его остальным администраторам. Возвращает номер запроса."""
+    expires_at = datetime.now(timezone.utc) + timedelta(minutes=ADMIN_CONFIRMATION_MINUTES)
+    confirmation_id = await db.create_admin_confirmation(action, json.dumps(payload, ensure_ascii=False), requested_by, expires_at)
+    await db.add_audit_log(requested_by, f'request_{action}', None, f"#{confirmation_id}")
+
+    text = (
+        f"🔐 <b>Нужно подтверждение #{confirmation_id}</b>\n\n"
+        f"Администратор {hcode(requested_by)} запрашивает: <b>{ACTION_NAMES[action]}</b>.\n"
+        f"{description}\n\n"
+        f"Запрос действует {ADMIN_CONFIRMATION_MINUTES} мин."
+    )
+    for admin_id in ADMIN_IDS:
+        if admin_id == requested_by:
+            continue
+        try:
+            await bot.send_message(admin_id, text, reply_markup=get_admin_confirmation_menu(confirmation_id))
+        except Exception as e:
+            logger.warning(f"Failed to send confirmation request #{confirmation_id} to admin {admin_id}: {e}")
+    logger.info(f"Admin {requested_by} requested confirmation #{confirmation_id} for '{action}'")
+    return confirmation_id
+
+async def resolve_confirmation(db: Database, confirmation_id: int, admin_id: int, approve: bool) -> tuple[str, tuple | None]:
+    """
+    Подтверждает или отклоняет запрос. Возвращает (результат, запрос), где результат -
+    approved, rejected, not_found, own (свой запрос), expired или resolved (уже рассмотрен).
+    Запрос: (id, action, payload, requested_by, status, expires_at).
+    """
+    status = 'approved' if approve else 'rejected'
+    if await db.resolve_admin_confirmation(confirmation_id, status, admin_id):
+        await db.add_audit_log(admin_id, f'{status}_confirmation', None, f"#{confirmation_id}")
+        return status, await db.get_admin_confirmation(confirmation_id)
+
+    confirmation = await db.get_admin_confirmation(confirmation_id)
+    if not confirmation:
+        return 'not_found', None
+    if confirmation[4] != 'pending':
+        return 'resolved', confirmation
+    if confirmation[3] == admin_id:
+        return 'own', confirmation
+    return 'expired', confirmation
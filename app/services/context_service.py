@@ -0,0 +1,133 @@
+# app/services/context_service.py
+# Управление контекстом модели. История беседы ограничена числом сообщений, но длинные сообщения
+# (документы, код) могут не поместиться в контекст модели. Тогда самые старые сообщения убираются
+# из запроса: при CONTEXT_MODE='summarize' они пересказываются дешевой моделью, и пересказ («память» беседы)
+# передается модели вместо них, при 'trim' - просто отбрасываются.
+# Память хранится в cache["context_memory"] и дополняется, когда из контекста уходят новые сообщения,
+# поэтому одни и те же сообщения не пересказываются заново на каждый запрос.
+
+import hashlib
+import logging
+from typing import Dict, List
+
+from app.config import (
+    MODEL_INFO, ModelInfo, CONTEXT_MODE, CONTEXT_SUMMARY_MODEL, CONTEXT_ANSWER_RESERVE, CONTEXT_SUMMARY_MAX_TOKENS,
+    CONTEXT_KEEP_RECENT_MESSAGES, IMAGE_CONTEXT_TOKENS
+)
+from app.services.provider_service import ProviderRegistry
+from app.services.text_service import estimate_tokens, shorten_text
+
+logger = logging.getLogger(__name__)
+
+MEMORY_PREFIX = "Краткое содержание начала беседы (ранние сообщения не поместились в контекст):\n"
+SUMMARY_PROMPT = (
+    "Кратко перескажи беседу пользователя с ассистентом: факты о пользователе, решения, договоренности "
+    "и открытые вопросы, которые понадобятся, чтобы продолжить разговор. Пиши на языке беседы, без вступлений."
+)
+ROLE_NAMES = {'user': 'Пользователь', 'assistant': 'Ассистент', 'system': 'Система'}
+SUMMARY_INPUT_CHARS = 24_000 # Сколько текста беседы передается на пересказ за один раз
+MEMORY_MAX_MESSAGES = 200 # Сколько пересказанных сообщений запоминается для дополнения памяти
+
+
+def _content_text(content) -> str:
+    if isinstance(content, list):
+        return " ".join(part.get('text', '') for part in content if part.get('type') == 'text')
+    return content or ''
+
+def count_message_tokens(message: dict) -> int:
+    """Оценка токенов сообщения; изображение считается за IMAGE_CONTEXT_TOKENS."""
+    content = message.get('content')
+    images = sum(1 for part in content if part.get('type') == 'image_url') if isinstance(content, list) else 0
+    # Несколько токенов уходит на роль и разметку сообщения
+    return estimate_tokens(_content_text(content)) + images * IMAGE_CONTEXT_TOKENS + 4
+
+def get_context_budget(model: str) -> int:
+    """Сколько токенов можно отправить модели, оставив CONTEXT_ANSWER_RESERVE на ответ."""
+    info = MODEL_INFO.get(model)
+    max_context = info.max_context if info else ModelInfo.max_context
+    return max(max_context - CONTEXT_ANSWER_RESERVE, max_context // 2)
+
+def _fingerprint(message: dict) -> str:
+    return hashlib.sha1(f"{message['role']}:{_content_text(message.get('content'))}".encode()).hexdigest()
+
+
+async def summarize_messages(ai_client: ProviderRegistry, messages: List[dict], previous_summary: str | None = None) -> str:
+    """Пересказывает сообщения (дополняя прежний пересказ, если он есть). В случае ошибки вызывает исключение."""
+    size = max(500, SUMMARY_INPUT_CHARS // len(messages))
+    transcript = "\n\n".join(
+        f"{ROLE_NAMES.get(message['role'], message['role'])}: {shorten_text(_content_text(message.get('content')), size)}"
+        for message in messages
+    )
+    if previous_summary:
+        transcript = f"Прежний пересказ:\n{previous_summary}\n\nСледующие сообщения беседы:\n{transcript}"
+    response = await ai_client.for_model(CONTEXT_SUMMARY_MODEL).chat(
+        CONTEXT_SUMMARY_MODEL,
+        [{"role": "system", "content": SUMMARY_PROMPT}, {"role": "user", "content": transcript}],
+        temperature=0.3, max_tokens=CONTEXT_SUMMARY_MAX_TOKENS, timeout=60.0
+    )
+    text = (response.choices[0].message.content or "").strip() if response.choices else ""
+    if not text:
+        raise RuntimeError(f"Model {CONTEXT_SUMMARY_MODEL} returned an empty summary")
+    return text
+
+async def _get_memory(ai_client: ProviderRegistry, removed: List[dict], user_id: int, cache: Dict) -> str:
+    """
+    Пересказ убранных из контекста сообщений. Если самое старое из них уже пересказано для этого пользователя,
+    память той же беседы дополняется только новыми сообщениями, иначе пересказ составляется заново.
+    """
+    memories = cache.get("context_memory")
+    memory = memories.get(user_id) if memories is not None else None
+    fingerprints = [_fingerprint(message) for message in removed]
+    previous_summary, new_messages = None, removed
+    if memory and fingerprints[0] in memory['covered']:
+        new_messages = [m for m, fp in zip(removed, fingerprints) if fp not in memory['covered']]
+        if not new_messages:
+            return memory['summary']
+        previous_summary = memory['summary']
+
+    summary = await summarize_messages(ai_client, new_messages, previous_summary)
+    covered = (memory['covered'] if previous_summary else []) + fingerprints
+    if memories is not None:
+        memories[user_id] = {'summary': summary, 'covered': list(dict.fromkeys(covered))[-MEMORY_MAX_MESSAGES:]}
+    logger.info(f"Context memory for user {user_id} {'extended' if previous_summary else 'created'}: {len(new_messages)} messages")
+    return summary
+
+async def fit_to_context(ai_client: ProviderRegistry, model: str, messages: List[dict], user_id: int, cache: Dict) -> List[dict]:
+    """
+    Возвращает сообщения, которые помещаются в контекст модели. Системные сообщения в начале
+    и последние CONTEXT_KEEP_RECENT_MESSAGES сообщений сохраняются всегда; более старые убираются
+    с начала беседы и, в зависимости от CONTEXT_MODE, заменяются пересказом.
+    """
+    budget = get_context_budget(model)
+    total = sum(count_message_tokens(message) for message in messages)
+    if total <= budget:
+        return messages
+
+    system_count = next((i for i, message in enumerate(messages) if message['role'] != 'system'), len(messages))
+    head, body = messages[:system_count], messages[system_count:]
+    available = budget - sum(count_message_tokens(message) for message in head)
+    if CONTEXT_MODE == 'summarize':
+        available -= CONTEXT_SUMMARY_MAX_TOKENS + estimate_tokens(MEMORY_PREFIX)
+
+    body_tokens = sum(count_message_tokens(message) for message in body)
+    dropped, keep_from = 0, max(0, len(body) - max(1, CONTEXT_KEEP_RECENT_MESSAGES))
+    while dropped < keep_from and body_tokens > available:
+        body_tokens -= count_message_tokens(body[dropped])
+        dropped += 1
+    # Беседа в запросе должна начинаться с сообщения пользователя
+    while dropped < len(body) - 1 and body[dropped]['role'] != 'user':
+        dropped += 1
+    if not dropped:
+        logger.warning(f"Recent messages for user {user_id} do not fit the context of {model} ({total} > {budget} tokens)")
+        return messages
+
+    removed, kept = body[:dropped], body[dropped:]
+    logger.info(f"History for user {user_id} exceeds the context of {model} ({total} > {budget} tokens): "
+                f"{len(removed)} old messages removed ({CONTEXT_MODE})")
+    if CONTEXT_MODE == 'summarize':
+        try:
+            memory = await _get_memory(ai_client, removed, user_id, cache)
+            return head + [{"role": "system", "content": MEMORY_PREFIX + memory}] + kept
+        except Exception as e:
+            logger.warning(f"Could not summarize old messages for user {user_id}, dropping them instead: {e}")
+    return head + kept
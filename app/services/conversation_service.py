@@ -0,0 +1,158 @@
+# app/services/conversation_service.py
+# Беседы обычного чата. Хранятся в БД (таблицы conversations и messages), поэтому
+# переживают перезапуск бота; в данных FSM остается только id активной беседы.
+# Новая беседа создается в БД вместе с первым сообщением. У пользователя может быть
+# несколько бесед (например, ветки, ответвленные от старых ответов), одна из них активна.
+# Каждое сообщение истории привязано к message_id сообщения в Telegram,
+# чтобы по нажатию кнопки под ответом можно было найти место в беседе.
+# Режим диалога (обычный чат или Max Mode) тоже сохраняется в БД, чтобы после перезапуска
+# с потерей состояния FSM пользователь вернулся в диалог (см. restore_chat_mode).
+
+import logging
+
+from aiogram.fsm.context import FSMContext
+
+from app.config import MAX_PINNED_CONVERSATIONS
+from app.database import Database
+from app.services.user_service import get_user_accessible_models
+from app.states import Chat, MaxMode
+
+logger = logging.getLogger(__name__)
+
+# Сколько последних сообщений беседы отправляется модели
+MAX_HISTORY_MESSAGES = 10
+# Сколько бесед хранится у пользователя; самые старые удаляются вместе с сообщениями
+MAX_CONVERSATIONS = 20
+# Режимы диалога (значения users.chat_mode) и соответствующие им состояния FSM
+CHAT_MODES = {'chat': Chat.in_progress, 'max_mode': MaxMode.in_progress}
+
+
+async def get_history(db: Database, conversation_id: int, up_to_id: int | None = None) -> list:
+    """Последние MAX_HISTORY_MESSAGES сообщений беседы (до сообщения с id up_to_id включительно)."""
+    rows = await db.get_conversation_messages(conversation_id, MAX_HISTORY_MESSAGES, up_to_id)
+    return [{"role": role, "content": content, "message_id": message_id} for role, content, message_id in rows]
+
+async def get_active_history(db: Database, data: dict) -> list:
+    """Возвращает историю активной беседы."""
+    conversation_id = data.get('conversation_id')
+    return await get_history(db, conversation_id) if conversation_id is not None else []
+
+async def _create_conversation(db: Database, user_id: int, model: str | None, parent_id: int | None = None) -> int:
+    conversation_id = await db.create_conversation(user_id, model, parent_id)
+    await db.delete_old_conversations(user_id, MAX_CONVERSATIONS, keep_ids=(conversation_id, parent_id))
+    return conversation_id
+
+async def append_messages(db: Database, state: FSMContext, user_id: int, messages: list, model: str | None) -> int:
+    """Добавляет сообщения в активную беседу, при необходимости создавая ее. Возвращает id беседы."""
+    conversation_id = (await state.get_data()).get('conversation_id')
+    if conversation_id is None or not await db.get_conversation(conversation_id, user_id):
+        conversation_id = await _create_conversation(db, user_id, model)
+        await state.update_data(conversation_id=conversation_id)
+    await db.add_conversation_messages(conversation_id, messages)
+    return conversation_id
+
+async def start_conversation(state: FSMContext):
+    """Начинает новую беседу: она будет создана в БД с первым сообщением."""
+    await state.update_data(conversation_id=None)
+
+async def resume_last_conversation(db: Database, state: FSMContext, user_id: int) -> int | None:
+    """Делает активной последнюю беседу пользователя (например, после перезапуска бота)."""
+    conversation = await db.get_latest_conversation(user_id)
+    conversation_id = conversation[0] if conversation else None
+    await state.update_data(conversation_id=conversation_id)
+    return conversation_id
+
+async def switch_conversation(db: Database, state: FSMContext, user_id: int, conversation_id: int) -> bool:
+    """Делает беседу активной. Возвращает False, если такой беседы уже нет."""
+    if not await db.get_conversation(conversation_id, user_id):
+        return False
+    await state.update_data(conversation_id=conversation_id)
+    return True
+
+async def find_message(db: Database, user_id: int, data: dict, message_id: int) -> tuple[int, list] | None:
+    """
+    Ищет сообщение с указанным message_id: сначала в активной беседе, затем в остальных.
+    Возвращает (id беседы, история до этого сообщения включительно) или None.
+    """
+    found = await db.find_conversation_message(user_id, message_id, data.get('conversation_id'))
+    if not found:
+        return None
+    conversation_id, row_id = found
+    return conversation_id, await get_history(db, conversation_id, up_to_id=row_id)
+
+async def find_last_prompt(db: Database, data: dict, message_id: int) -> tuple[int, int] | None:
+    """
+    Если message_id - последний запрос активной беседы (за ним только ответ модели),
+    возвращает (id запроса в БД, message_id ответа). Иначе None.
+    """
+    conversation_id = data.get('conversation_id')
+    if conversation_id is None:
+        return None
+    tail = await db.get_conversation_tail(conversation_id, 2)
+    if len(tail) < 2:
+        return None
+    (_, answer_role, answer_message_id), (prompt_id, prompt_role, prompt_message_id) = tail
+    if answer_role != 'assistant' or prompt_role != 'user' or prompt_message_id != message_id:
+        return None
+    return prompt_id, answer_message_id
+
+async def branch_conversation(db: Database, state: FSMContext, user_id: int, message_id: int) -> tuple[int, int, int] | None:
+    """
+    Создает новую беседу с историей до сообщения message_id включительно и делает ее активной.
+    Исходная беседа не меняется. Возвращает (id исходной беседы, id новой, число сообщений)
+    или None, если сообщение уже не входит ни в одну беседу.
+    """
+    data = await state.get_data()
+    found = await find_message(db, user_id, data, message_id)
+    if not found:
+        return None
+    source_id, history = found
+    new_id = await _create_conversation(db, user_id, data.get('model'), parent_id=source_id)
+    await db.add_conversation_messages(new_id, history)
+    await state.update_data(conversation_id=new_id)
+    return source_id, new_id, len(history)
+
+async def set_pinned(db: Database, user_id: int, conversation_id: int, pinned: bool) -> bool:
+    """Закрепляет (открепляет) беседу. Возвращает False, если уже закреплено MAX_PINNED_CONVERSATIONS бесед."""
+    return await db.set_conversation_pinned(conversation_id, user_id, pinned, MAX_PINNED_CONVERSATIONS)
+
+def get_chat_mode(state_name: str | None) -> str | None:
+    """Режим диалога для состояния FSM ('chat', 'max_mode') или None, если пользователь не в диалоге."""
+    return next((mode for mode, chat_state in CHAT_MODES.items() if state_name == chat_state), None)
+
+async def restore_chat_mode(db: Database, state: FSMContext, user_id: int) -> str | None:
+    """
+    Сверяет состояние FSM с сохраненным режимом диалога. Если состояние потеряно при перезапуске,
+    а пользователь был в диалоге, возвращает его в чат (с последней моделью и беседой) или в Max Mode.
+    Возвращает режим, который теперь записан в БД.
+    """
+    current_state = await state.get_state()
+    saved_mode = await db.get_chat_mode(user_id)
+    if current_state is not None or saved_mode is None:
+        return saved_mode
+
+    if saved_mode == 'max_mode':
+        await state.set_state(MaxMode.in_progress)
+    else:
+        details = await db.get_user_details(user_id)
+        model = details[5] if details else None
+        accessible = await get_user_accessible_models(user_id, db)
+        if model not in accessible:
+            # Модель стала недоступна (например, закончилась подписка) - возвращать некуда
+            await db.set_chat_mode(user_id, None)
+            return None
+        await state.set_state(Chat.in_progress)
+        await state.update_data(model=model)
+        await resume_last_conversation(db, state, user_id)
+    logger.info(f"Restored {saved_mode} mode for user {user_id} after restart")
+    return saved_mode
+
+def build_image_content(text: str, image_urls: list[str]) -> list:
+    """Мультимодальное содержимое сообщения: текст и изображения (для моделей с поддержкой vision)."""
+    return [{"type": "text", "text": text}] + [
+        {"type": "image_url", "image_url": {"url": image_url}} for image_url in image_urls
+    ]
+
+def to_api_messages(history: list) -> list:
+    """Убирает из истории служебные поля, оставляя только то, что ожидает API модели."""
+    return [{"role": item["role"], "content": item["content"]} for item in history]
@@ -0,0 +1,37 @@
+# app/services/crypto_service.py
+# Шифрование чувствительных полей БД (платежные данные, токены серверов инструментов).
+# Включается ключом DB_ENCRYPTION_KEY. Зашифрованные значения помечаются префиксом,
+# поэтому поля, записанные до включения шифрования, читаются как есть.
+
+import logging
+
+from cryptography.fernet import Fernet, InvalidToken
+
+from app.config import DB_ENCRYPTION_KEY
+
+logger = logging.getLogger(__name__)
+
+ENCRYPTED_PREFIX = 'enc:'
+
+# Неверный ключ должен остановить запуск, а не всплыть при первой записи
+_fernet = Fernet(DB_ENCRYPTION_KEY.encode()) if DB_ENCRYPTION_KEY else None
+
+
+def encrypt_field(value: str | None) -> str | None:
+    """Шифрует значение перед записью в БД. Без ключа возвращает его без изменений."""
+    if value is None or _fernet is None:
+        return value
+    return ENCRYPTED_PREFIX + _fernet.encrypt(value.encode()).decode()
+
+def decrypt_field(value: str | None) -> str | None:
+    """Расшифровывает значение из БД. Незашифрованные значения возвращаются как есть."""
+    if value is None or not value.startswith(ENCRYPTED_PREFIX):
+        return value
+    if _fernet is None:
+        logger.error("Encrypted DB field found, but DB_ENCRYPTION_KEY is not set")
+        return None
+    try:
+        return _fernet.decrypt(value[len(ENCRYPTED_PREFIX):].encode()).decode()
+    except InvalidToken:
+        logger.error("Failed to decrypt DB field: DB_ENCRYPTION_KEY does not match")
+        return None
@@ -0,0 +1,104 @@
+# app/services/document_service.py
+# Краткое изложение документов (.txt, .md, .pdf), присланных в чат.
+# Текст извлекается один раз на файл (кэш file_cache), длинный текст делится на части:
+# каждая часть излагается отдельно, затем изложения сводятся в итоговое резюме.
+
+import asyncio
+import logging
+import os
+import time
+
+from pypdf import PdfReader
+from pypdf.errors import PyPdfError
+
+from app.config import DOCUMENT_EXTENSIONS, DOCUMENT_CHUNK_CHARS, DOCUMENT_MAX_CHUNKS
+from app.database import Database
+from app.services.ai_service import TokenUsage, get_simple_response
+from app.services.file_service import FileIntakeError, IncomingFile, process_with_cache
+from app.services.text_service import split_text
+from app.services.format_service import format_number
+
+logger = logging.getLogger(__name__)
+
+SUMMARY_FORMAT = (
+    "Ответ оформи так:\n"
+    "Кратко: 2-3 предложения о сути документа;\n"
+    "Основные пункты: 3-7 пунктов списком;\n"
+    "Выводы: что из документа следует и что важно не упустить.\n"
+    "Пиши на языке документа, без вступлений и без HTML-разметки."
+)
+
+
+def get_extension(incoming: IncomingFile) -> str:
+    return os.path.splitext(incoming.file_name or '')[1].lower()
+
+def is_supported_document(incoming: IncomingFile) -> bool:
+    return incoming.kind == 'document' and get_extension(incoming) in DOCUMENT_EXTENSIONS
+
+def _read_pdf(path: str) -> str:
+    reader = PdfReader(path)
+    return '\n\n'.join(page.extract_text() or '' for page in reader.pages)
+
+def _read_text(path: str) -> str:
+    with open(path, 'rb') as f:
+        raw = f.read()
+    try:
+        return raw.decode('utf-8-sig')
+    except UnicodeDecodeError:
+        return raw.decode('cp1251', errors='replace')
+
+async def _extract(incoming: IncomingFile) -> str:
+    try:
+        if get_extension(incoming) == '.pdf':
+            return await asyncio.to_thread(_read_pdf, incoming.path)
+        return await asyncio.to_thread(_read_text, incoming.path)
+    except PyPdfError as e:
+        logger.warning(f"Failed to read PDF {incoming.file_name}: {e}")
+        raise FileIntakeError("Не удалось прочитать PDF: файл поврежден или защищен паролем.") from e
+
+async def extract_document_text(incoming: IncomingFile, db: Database) -> str:
+    """Извлекает текст документа (с кэшем). Пустой или слишком длинный документ - FileIntakeError."""
+    text = (await process_with_cache(incoming, 'extract_text', db, _extract)).strip()
+    if not text:
+        raise FileIntakeError("В документе не найден текст (возможно, это скан без текстового слоя).")
+    max_chars = DOCUMENT_CHUNK_CHARS * DOCUMENT_MAX_CHUNKS
+    if len(text) > max_chars:
+        raise FileIntakeError(
+            f"Документ слишком длинный: {format_number(len(text))} символов. "
+            f"Можно изложить документ до {format_number(max_chars)} символов."
+        )
+    return text
+
+async def summarize_document(
+    ai_client, model: str, file_name: str, text: str, user_id: int, db: Database, cache: dict
+) -> tuple[str, float, TokenUsage]:
+    """
+    Возвращает (резюме, время, токены всех запросов). Документ из нескольких частей излагается по частям,
+    затем изложения частей сводятся в одно резюме.
+    """
+    start_time = time.time()
+    chunks = split_text(text, DOCUMENT_CHUNK_CHARS)
+    if len(chunks) == 1:
+        prompt = f"Кратко изложи документ «{file_name}».\n{SUMMARY_FORMAT}\n\nДокумент:\n{text}"
+        return await get_simple_response(ai_client, model, [{"role": "user", "content": prompt}], user_id, db, cache)
+
+    async def summarize_chunk(index: int, chunk: str) -> tuple[str, TokenUsage]:
+        prompt = (
+            f"Это часть {index} из {len(chunks)} документа «{file_name}». "
+            f"Перечисли ее основные мысли и факты сжато, списком.\n\n{chunk}"
+        )
+        response, _, usage = await get_simple_response(
+            ai_client, model, [{"role": "user", "content": prompt}], user_id, db, cache, final_answer=False
+        )
+        return response, usage
+
+    results = await asyncio.gather(*(summarize_chunk(i, chunk) for i, chunk in enumerate(chunks, 1)))
+    partial_summaries = [summary for summary, _ in results]
+    logger.info(f"Summarized {len(chunks)} chunks of document '{file_name}' for user {user_id}")
+    combined = '\n\n'.join(f"Часть {i}:\n{summary}" for i, summary in enumerate(partial_summaries, 1))
+    prompt = (
+        f"Ниже изложения частей документа «{file_name}». Составь по ним единое резюме всего документа.\n"
+        f"{SUMMARY_FORMAT}\n\n{combined}"
+    )
+    summary, _, usage = await get_simple_response(ai_client, model, [{"role": "user", "content": prompt}], user_id, db, cache)
+    return summary, time.time() - start_time, TokenUsage.total([usage for _, usage in results] + [usage])
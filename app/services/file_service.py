@@ -0,0 +1,159 @@
+# app/services/file_service.py
+
+import asyncio
+import base64
+import hashlib
+import logging
+import os
+import time
+import uuid
+from contextlib import asynccontextmanager
+from dataclasses import dataclass
+from typing import Awaitable, Callable
+
+from aiogram import Bot
+from aiogram.types import Message
+
+from app.config import FILE_SIZE_LIMITS, MAX_DOWNLOAD_SIZE, FILES_TMP_DIR, FILES_TMP_MAX_AGE_HOURS, FILE_CACHE_DAYS
+from app.database import Database
+from app.services.user_service import get_user_level
+
+logger = logging.getLogger(__name__)
+
+HASH_CHUNK_SIZE = 1024 * 1024
+
+
+class FileIntakeError(Exception):
+    """Файл не может быть принят. Текст исключения можно показывать пользователю."""
+
+
+@dataclass
+class IncomingFile:
+    kind: str # 'photo', 'document' или 'audio'
+    file_id: str
+    file_unique_id: str
+    file_name: str | None
+    mime_type: str | None
+    size: int | None
+    path: str | None = None # Путь к скачанному файлу во временной папке
+    sha256: str | None = None
+
+
+def extract_file(message: Message) -> IncomingFile | None:
+    """Достает из сообщения файл (фото, документ, аудио или голосовое). None — файла нет."""
+    if message.photo:
+        photo = message.photo[-1] # Самое большое разрешение
+        return IncomingFile('photo', photo.file_id, photo.file_unique_id, None, 'image/jpeg', photo.file_size)
+    if message.document:
+        doc = message.document
+        return IncomingFile('document', doc.file_id, doc.file_unique_id, doc.file_name, doc.mime_type, doc.file_size)
+    audio = message.audio or message.voice
+    if audio:
+        return IncomingFile('audio', audio.file_id, audio.file_unique_id, getattr(audio, 'file_name', None), audio.mime_type, audio.file_size)
+    return None
+
+def get_file_size_limit(user_level: int) -> int:
+    """Максимальный размер файла для уровня подписки с учетом ограничений Bot API."""
+    return min(FILE_SIZE_LIMITS.get(user_level, FILE_SIZE_LIMITS[0]), MAX_DOWNLOAD_SIZE)
+
+async def check_file_allowed(incoming: IncomingFile, user_id: int, db: Database):
+    """Проверяет размер файла по тарифу пользователя. При превышении выбрасывает FileIntakeError."""
+    user_level = await get_user_level(user_id, db)
+    limit = get_file_size_limit(user_level)
+    if incoming.size and incoming.size > limit:
+        raise FileIntakeError(
+            f"Файл слишком большой: {incoming.size / 1024 / 1024:.1f} МБ. "
+            f"Для вашего тарифа доступно до {limit // 1024 // 1024} МБ."
+        )
+
+def _hash_file(path: str) -> str:
+    sha = hashlib.sha256()
+    with open(path, 'rb') as f:
+        while chunk := f.read(HASH_CHUNK_SIZE):
+            sha.update(chunk)
+    return sha.hexdigest()
+
+async def download_file(bot: Bot, incoming: IncomingFile) -> IncomingFile:
+    """Потоково скачивает файл во временную папку и считает его хэш."""
+    os.makedirs(FILES_TMP_DIR, exist_ok=True)
+    extension = os.path.splitext(incoming.file_name or '')[1]
+    path = os.path.join(FILES_TMP_DIR, f"{uuid.uuid4().hex}{extension}")
+    try:
+        await bot.download(incoming.file_id, destination=path)
+        incoming.path = path
+        incoming.sha256 = await asyncio.to_thread(_hash_file, path)
+    except Exception:
+        remove_file(path)
+        raise
+    return incoming
+
+def _read_base64(path: str) -> str:
+    with open(path, 'rb') as f:
+        return base64.b64encode(f.read()).decode('ascii')
+
+async def read_as_data_url(incoming: IncomingFile) -> str:
+    """Содержимое скачанного файла в виде data URL (для передачи изображения модели)."""
+    encoded = await asyncio.to_thread(_read_base64, incoming.path)
+    return f"data:{incoming.mime_type or 'application/octet-stream'};base64,{encoded}"
+
+def remove_file(path: str | None):
+    if path and os.path.exists(path):
+        try:
+            os.remove(path)
+        except OSError as e:
+            logger.warning(f"Failed to remove temp file {path}: {e}")
+
+@asynccontextmanager
+async def receive_file(message: Message, user_id: int, bot: Bot, db: Database):
+    """
+    Единая точка приема файлов для фич (vision, распознавание, вопросы по документам):
+    проверяет лимит, скачивает файл и гарантированно удаляет его после обработки.
+
+        async with receive_file(message, user_id, bot, db) as incoming:
+            ... incoming.path, incoming.sha256 ...
+    """
+    incoming = extract_file(message)
+    if incoming is None:
+        raise FileIntakeError("В сообщении нет файла.")
+    await check_file_allowed(incoming, user_id, db)
+    await download_file(bot, incoming)
+    logger.info(f"Received {incoming.kind} from user {user_id}: {incoming.size} bytes, sha256={incoming.sha256[:12]}")
+    try:
+        yield incoming
+    finally:
+        remove_file(incoming.path)
+
+async def process_with_cache(
+    incoming: IncomingFile,
+    operation: str,
+    db: Database,
+    processor: Callable[[IncomingFile], Awaitable[str]]
+) -> str:
+    """
+    Возвращает результат обработки файла (извлечение текста, распознавание и т.п.).
+    Если такой же файл уже обрабатывался этой операцией, берет результат из file_cache.
+    """
+    cached = await db.get_file_cache(incoming.sha256, operation)
+    if cached is not None:
+        logger.info(f"File cache hit for {operation}: sha256={incoming.sha256[:12]}")
+        return cached
+    result = await processor(incoming)
+    await db.set_file_cache(incoming.sha256, operation, result)
+    return result
+
+async def cleanup_file_cache(db: Database):
+    await db.delete_old_file_cache(FILE_CACHE_DAYS)
+
+def cleanup_stale_files():
+    """Удаляет временные файлы, оставшиеся после перезапуска или сбоя."""
+    if not os.path.isdir(FILES_TMP_DIR):
+        return
+    threshold = time.time() - FILES_TMP_MAX_AGE_HOURS * 3600
+    removed = 0
+    for name in os.listdir(FILES_TMP_DIR):
+        path = os.path.join(FILES_TMP_DIR, name)
+        if os.path.isfile(path) and os.path.getmtime(path) < threshold:
+            remove_file(path)
+            removed += 1
+    if removed:
+        logger.info(f"Removed {removed} stale temp files from {FILES_TMP_DIR}.")
@@ -0,0 +1,59 @@
+# app/services/format_service.py
+# Единое форматирование чисел, цен, дат и длительностей в сообщениях: «1 234», «1 234 ₽»,
+# «05.07.2025», «3 ч 12 мин». Дробная часть отделяется запятой, как принято в русском тексте.
+# Даты показываются в часовом поясе пользователя (utc_offset в часах), без него - по МСК.
+
+from datetime import datetime, timedelta, timezone
+
+from app.config import MSK_TZ
+
+CURRENCY_SIGNS = {'RUB': '₽', 'XTR': '⭐'}
+
+
+def format_number(value, decimals: int = 0) -> str:
+    """Число с пробелами между разрядами: 1234567 -> «1 234 567», 1234.5 (decimals=2) -> «1 234,50»."""
+    return f"{value:,.{decimals}f}".replace(',', ' ').replace('.', ',')
+
+def plural(value, forms: tuple[str, str, str]) -> str:
+    """Форма слова для числа: plural(n, ('запрос', 'запроса', 'запросов')) - «запрос» для 1, «запроса» для 2, «запросов» для 5."""
+    value = abs(int(value))
+    if value % 10 == 1 and value % 100 != 11:
+        return forms[0]
+    if 2 <= value % 10 <= 4 and not 12 <= value % 100 <= 14:
+        return forms[1]
+    return forms[2]
+
+def format_price(amount, currency: str = 'RUB') -> str:
+    """Цена в основных единицах валюты: «1 234 ₽», «99,50 ₽», «500 ⭐». Копейки показываются, только если они есть."""
+    decimals = 0 if float(amount).is_integer() else 2
+    return f"{format_number(amount, decimals)} {CURRENCY_SIGNS.get(currency, currency)}"
+
+def to_local_time(value: datetime | str, utc_offset: int | None = None) -> datetime:
+    """Время (datetime или строка ISO из БД) в часовом поясе пользователя. Время без пояса считается UTC."""
+    if isinstance(value, str):
+        value = datetime.fromisoformat(value)
+    if value.tzinfo is None:
+        value = value.replace(tzinfo=timezone.utc)
+    return value.astimezone(timezone(timedelta(hours=utc_offset)) if utc_offset is not None else MSK_TZ)
+
+def format_date(value: datetime | str, utc_offset: int | None = None) -> str:
+    """«05.07.2025»"""
+    return to_local_time(value, utc_offset).strftime('%d.%m.%Y')
+
+def format_datetime(value: datetime | str, utc_offset: int | None = None, with_year: bool = True) -> str:
+    """«05.07.2025 14:30» или без года: «05.07 14:30»."""
+    return to_local_time(value, utc_offset).strftime('%d.%m.%Y %H:%M' if with_year else '%d.%m %H:%M')
+
+def format_duration(value: timedelta | float) -> str:
+    """Длительность (timedelta или секунды) двумя старшими единицами: «2 д 5 ч», «3 ч 12 мин», «7 мин», «40 сек»."""
+    seconds = int(value.total_seconds() if isinstance(value, timedelta) else value)
+    days, remainder = divmod(max(seconds, 0), 86400)
+    hours, remainder = divmod(remainder, 3600)
+    minutes, seconds = divmod(remainder, 60)
+    if days:
+        return f"{days} д {hours} ч"
+    if hours:
+        return f"{hours} ч {minutes} мин"
+    if minutes:
+        return f"{minutes} мин"
+    return f"{seconds} сек"
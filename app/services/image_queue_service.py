@@ -0,0 +1,67 @@
+# app/services/image_queue_service.py
+# Очередь генераций изображений. Одновременно выполняется не больше IMAGE_GENERATION_CONCURRENCY генераций
+# на весь бот; в каждой группе запросы выполняются по очереди (по GROUP_IMAGE_CONCURRENCY), чтобы одна
+# активная группа не занимала все слоты. Состояние очереди хранится в cache["image_queue"].
+
+import asyncio
+from contextlib import asynccontextmanager
+from typing import Dict
+
+from app.config import IMAGE_GENERATION_CONCURRENCY, GROUP_IMAGE_CONCURRENCY, GROUP_IMAGE_QUEUE_LIMIT
+
+
+class ImageQueueFull(Exception):
+    """В очереди группы уже GROUP_IMAGE_QUEUE_LIMIT запросов."""
+
+
+def _global_semaphore(cache: Dict) -> asyncio.Semaphore:
+    return cache["image_queue"].setdefault('global', asyncio.Semaphore(IMAGE_GENERATION_CONCURRENCY))
+
+@asynccontextmanager
+async def image_generation_slot(cache: Dict):
+    """Ждет свободный слот генерации (общий для всего бота)."""
+    async with _global_semaphore(cache):
+        yield
+
+
+class GroupImageTicket:
+    """
+    Место запроса в очереди группы. position - номер в очереди (0 - запрос выполнится сразу).
+    wait_turn ждет очереди группы и общего слота; слоты освобождаются при выходе из group_image_queue.
+    """
+    def __init__(self, group: dict, global_semaphore: asyncio.Semaphore):
+        self.group = group
+        self.global_semaphore = global_semaphore
+        self.position = max(0, group['pending'] - GROUP_IMAGE_CONCURRENCY + 1)
+        self.acquired = []
+
+    async def wait_turn(self):
+        for semaphore in (self.group['semaphore'], self.global_semaphore):
+            await semaphore.acquire()
+            self.acquired.append(semaphore)
+
+    def release(self):
+        for semaphore in reversed(self.acquired):
+            semaphore.release()
+        self.acquired.clear()
+
+
+@asynccontextmanager
+async def group_image_queue(cache: Dict, chat_id: int):
+    """
+    Ставит запрос в очередь группы (FIFO) и возвращает GroupImageTicket.
+    Если очередь заполнена, вызывает ImageQueueFull.
+    """
+    groups = cache["image_queue"].setdefault('groups', {})
+    group = groups.setdefault(chat_id, {'semaphore': asyncio.Semaphore(GROUP_IMAGE_CONCURRENCY), 'pending': 0})
+    if group['pending'] >= GROUP_IMAGE_CONCURRENCY + GROUP_IMAGE_QUEUE_LIMIT:
+        raise ImageQueueFull()
+    ticket = GroupImageTicket(group, _global_semaphore(cache))
+    group['pending'] += 1
+    try:
+        yield ticket
+    finally:
+        ticket.release()
+        group['pending'] -= 1
+        if not group['pending']:
+            groups.pop(chat_id, None)
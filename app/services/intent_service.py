@@ -0,0 +1,38 @@
+# app/services/intent_service.py
+# Распознавание намерения в сообщении вне меню, чтобы ботом можно было пользоваться без кнопок:
+# «нарисуй …» - генерация изображения, «сколько осталось?» - остаток дневного лимита,
+# все остальное - вопрос модели в чате. Классификатор на правилах: он мгновенный и не тратит лимит.
+
+import re
+
+INTENT_CHAT, INTENT_IMAGE, INTENT_LIMITS = 'chat', 'image', 'limits'
+
+_IMAGE_RE = re.compile(
+    r'^\s*(?:пожалуйста[\s,]+)?'
+    r'(?:нарисуй(?:те)?|изобрази(?:те)?'
+    r'|(?:сгенерируй|создай|сделай)(?:те)?\s+(?:мне\s+)?(?:картинку|изображение|рисунок|арт)'
+    # Английское draw - только вместе с тем, что рисовать: «draw conclusions» - вопрос, а не картинка
+    r'|draw\s+me(?:\s+an?\s+(?:picture|image|drawing|sketch)(?:\s+of)?)?'
+    r'|draw\s+an?\s+(?:picture|image|drawing|sketch)(?:\s+of)?'
+    r'|generate\s+(?:an?\s+)?(?:image|picture)(?:\s+of)?)'
+    r'(?!\w)[\s,:-]*(?:мне\s+)?(?P<prompt>.*)$',
+    re.IGNORECASE | re.DOTALL
+)
+_LIMITS_RE = re.compile(
+    r'сколько\s+(?:у\s+меня\s+)?(?:(?:еще|ещё)\s+)?(?:осталось|остается|запросов|токенов)'
+    r'|(?:мой|мои|какой\s+(?:у\s+меня\s+)?)\s*лимит'
+    r'|остат(?:ок|ки)\s+(?:запросов|токенов|лимит)',
+    re.IGNORECASE
+)
+# Вопрос о лимите короткий; в длинном сообщении такие слова скорее часть вопроса модели
+LIMITS_QUESTION_MAX_LENGTH = 80
+
+
+def detect_intent(text: str) -> tuple[str, str]:
+    """Возвращает (намерение, текст запроса). Для генерации изображения текст запроса - промпт без «нарисуй»."""
+    match = _IMAGE_RE.match(text)
+    if match:
+        return INTENT_IMAGE, match['prompt'].strip()
+    if len(text) <= LIMITS_QUESTION_MAX_LENGTH and _LIMITS_RE.search(text):
+        return INTENT_LIMITS, text
+    return INTENT_CHAT, text
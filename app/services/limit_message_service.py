@@ -0,0 +1,58 @@
+# app/services/limit_message_service.py
+# Сообщение об исчерпанном дневном лимите, свое для каждого тарифа:
+# Free видит цены подписок (и бонус за подписку на каналы), Standard - преимущества Premium,
+# Premium - преимущества Max, Max - время до обновления лимита.
+
+from datetime import datetime, timedelta
+
+from aiogram.types import InlineKeyboardMarkup
+
+from app.config import QUOTA_LIMITS, PRICES, MODELS, BETA_MODELS, REWARD_CHANNELS, QUOTA_REWARD_LIMIT, MSK_TZ, PLAN_NAMES
+from app.keyboards.inline import get_limit_upsell_menu
+from app.services.text_service import format_quota
+from app.services.format_service import format_price, format_duration
+
+
+def format_reset_countdown(now: datetime | None = None) -> str:
+    """Сколько осталось до обновления лимитов (полночь по МСК, как в учете запросов)."""
+    now = (now or datetime.now(MSK_TZ)).astimezone(MSK_TZ)
+    midnight = (now + timedelta(days=1)).replace(hour=0, minute=0, second=0, microsecond=0)
+    return f"Лимит обновится через {format_duration(midnight - now)} (в 00:00 МСК)."
+
+def _plan_line(level: int) -> str:
+    line = f" • <b>{PLAN_NAMES[level]}</b> - {format_quota(QUOTA_LIMITS[level]['daily'])} в день"
+    if QUOTA_LIMITS[level]['max_mode']:
+        line += f" и {format_quota(QUOTA_LIMITS[level]['max_mode'])} в Max Mode"
+    return line + f", {format_price(PRICES[level])}/мес"
+
+def build_limit_message(user_level: int, daily_limit: int, has_bonus: bool) -> tuple[str, InlineKeyboardMarkup | None]:
+    """Возвращает текст и клавиатуру сообщения об исчерпанном лимите для тарифа пользователя."""
+    reset_line = format_reset_countdown()
+    if user_level == 0:
+        offer_reward = bool(REWARD_CHANNELS) and not has_bonus
+        text = (
+            f"<b>Бесплатные запросы на сегодня закончились</b> ({format_quota(daily_limit)} в день).\n\n"
+            "С подпиской запросов больше:\n" + "\n".join(_plan_line(level) for level in (1, 2, 3)) + "\n\n"
+        )
+        if offer_reward:
+            text += f"Или подпишитесь на наши каналы и получайте <b>{format_quota(QUOTA_REWARD_LIMIT)} в день</b> бесплатно.\n\n"
+        return text + reset_line, get_limit_upsell_menu(None, REWARD_CHANNELS if offer_reward else None)
+
+    if user_level == 1:
+        extra_models = len(set(MODELS['premium']) - set(MODELS['standard']) - BETA_MODELS)
+        text = (
+            f"<b>Дневной лимит исчерпан</b> ({format_quota(daily_limit)}).\n\n"
+            f"На тарифе <b>Premium</b> - {format_quota(QUOTA_LIMITS[2]['daily'])} в день и еще {extra_models} моделей, "
+            f"включая самые мощные, за {format_price(PRICES[2])}/мес.\n\n"
+        )
+        return text + reset_line, get_limit_upsell_menu(2)
+
+    if user_level == 2:
+        text = (
+            f"<b>Дневной лимит исчерпан</b> ({format_quota(daily_limit)}).\n\n"
+            f"На тарифе <b>Max</b> доступен Max Mode: {format_quota(QUOTA_LIMITS[3]['max_mode'])} в день, "
+            f"на которые отвечают сразу несколько ведущих моделей, за {format_price(PRICES[3])}/мес.\n\n"
+        )
+        return text + reset_line, get_limit_upsell_menu(3)
+
+    return f"<b>Дневной лимит исчерпан</b> ({format_quota(daily_limit)}).\n\n{reset_line}", None
@@ -0,0 +1,157 @@
+# app/services/markdown_service.py
+# Перевод Markdown из ответов моделей в HTML, который понимает Telegram (parse_mode=HTML).
+# Модели отвечают в Markdown: жирный текст, код, таблицы, заголовки. Без перевода разметка видна как есть,
+# а символы <, > и & ломают разбор HTML. Теги Telegram, которые уже есть в ответе (например, список
+# источников или подпись), сохраняются. Если результат получился некорректным, ответ показывается простым текстом.
+
+import html
+import logging
+import re
+
+logger = logging.getLogger(__name__)
+
+# Теги, которые поддерживает Telegram; они пропускаются без экранирования
+_ALLOWED_TAG = r'</?(?:b|strong|i|em|u|ins|s|strike|del|code|pre|a|tg-spoiler|blockquote|span)(?:\s+[^<>]*)?>'
+_PROTECTED_RE = re.compile(
+    r'`([^`\n]+)`'                                        # Код в строке
+    r'|\[([^\]\n]+)\]\((https?://[^\s)]+)\)'              # Ссылка
+    rf'|({_ALLOWED_TAG})',                                 # Готовый тег Telegram
+    re.IGNORECASE
+)
+_INLINE_RULES = [
+    (re.compile(r'\*\*\*(?!\s)(.+?)(?<!\s)\*\*\*'), r'<b><i>\1</i></b>'),
+    (re.compile(r'\*\*(?!\s)(.+?)(?<!\s)\*\*'), r'<b>\1</b>'),
+    (re.compile(r'(?<!\w)__(?!\s)(.+?)(?<!\s)__(?!\w)'), r'<b>\1</b>'),
+    (re.compile(r'~~(?!\s)(.+?)(?<!\s)~~'), r'<s>\1</s>'),
+    (re.compile(r'(?<![\w*])\*(?![\s*])([^*\n]+?)(?<!\s)\*(?![\w*])'), r'<i>\1</i>'),
+    (re.compile(r'(?<![\w_])_(?![\s_])([^_\n]+?)(?<!\s)_(?![\w_])'), r'<i>\1</i>'),
+    (re.compile(r'\|\|(?!\s)(.+?)(?<!\s)\|\|'), r'<tg-spoiler>\1</tg-spoiler>'),
+]
+_FENCE_RE = re.compile(r'^\s*```\s*([\w+#-]*)')
+_HEADING_RE = re.compile(r'^\s*#{1,6}\s+(.*?)\s*#*\s*$')
+_LIST_ITEM_RE = re.compile(r'^(\s*)[-*+]\s+(.*)')
+_RULE_RE = re.compile(r'^\s*([-*_])(\s*\1){2,}\s*$')
+_TABLE_SEPARATOR_RE = re.compile(r'^\s*\|?\s*:?-+:?\s*(\|\s*:?-+:?\s*)*\|?\s*$')
+_PLACEHOLDER_RE = re.compile('\x00(\\d+)\x00')
+# Амперсанд, который не начинает HTML-сущность (сущности уже есть, например, в списке источников)
+_BARE_AMPERSAND_RE = re.compile(r'&(?!(?:[a-zA-Z]+|#\d+|#x[0-9a-fA-F]+);)')
+
+
+def _escape(text: str) -> str:
+    return _BARE_AMPERSAND_RE.sub('&amp;', text).replace('<', '&lt;').replace('>', '&gt;')
+
+
+def _render_inline(text: str) -> str:
+    """Экранирует строку и переводит в HTML жирный, курсив, зачеркнутый текст, код и ссылки."""
+    protected = []
+
+    def protect(match: re.Match) -> str:
+        code, link_text, link_url, tag = match.groups()
+        if code is not None:
+            protected.append(f"<code>{html.escape(code, quote=False)}</code>")
+        elif link_url is not None:
+            protected.append(f'<a href="{html.escape(link_url)}">{html.escape(link_text, quote=False)}</a>')
+        else:
+            protected.append(tag)
+        return f"\x00{len(protected) - 1}\x00"
+
+    text = _escape(_PROTECTED_RE.sub(protect, text))
+    for pattern, replacement in _INLINE_RULES:
+        text = pattern.sub(replacement, text)
+    return _PLACEHOLDER_RE.sub(lambda match: protected[int(match[1])], text)
+
+def _plain_cell(cell: str) -> str:
+    return re.sub(r'\*\*|__|`', '', cell.strip())
+
+def _render_table(rows: list[str]) -> str:
+    """Таблиц в Telegram нет: таблица показывается моноширинным текстом с выровненными столбцами."""
+    cells = [[_plain_cell(cell) for cell in row.strip().strip('|').split('|')] for row in rows
+             if not _TABLE_SEPARATOR_RE.match(row)]
+    columns = max(len(row) for row in cells)
+    widths = [max((len(row[i]) for row in cells if i < len(row)), default=0) for i in range(columns)]
+    lines = [" | ".join(cell.ljust(widths[i]) for i, cell in enumerate(row)).rstrip() for row in cells]
+    return f"<pre>{html.escape(chr(10).join(lines), quote=False)}</pre>"
+
+def _render_code(lines: list[str], language: str) -> str:
+    code = html.escape("\n".join(lines), quote=False)
+    if language:
+        return f'<pre><code class="language-{html.escape(language)}">{code}</code></pre>'
+    return f"<pre>{code}</pre>"
+
+def _render_blocks(text: str) -> str:
+    output, code_lines, code_language = [], None, ''
+    table_rows, quote_lines = [], []
+
+    def flush():
+        nonlocal table_rows, quote_lines
+        if table_rows:
+            output.append(_render_table(table_rows))
+            table_rows = []
+        if quote_lines:
+            output.append("<blockquote>" + "\n".join(quote_lines) + "</blockquote>")
+            quote_lines = []
+
+    for line in text.split('\n'):
+        fence = _FENCE_RE.match(line)
+        if code_lines is not None:
+            if fence and not fence.group(1):
+                output.append(_render_code(code_lines, code_language))
+                code_lines = None
+            else:
+                code_lines.append(line)
+            continue
+        if fence:
+            flush()
+            code_lines, code_language = [], fence.group(1)
+            continue
+
+        stripped = line.strip()
+        if stripped.startswith('|') and stripped.count('|') >= 2:
+            table_rows.append(stripped)
+            continue
+        if stripped.startswith('>'):
+            quote_lines.append(_render_inline(stripped.lstrip('>').strip()))
+            continue
+        flush()
+
+        heading = _HEADING_RE.match(line)
+        item = _LIST_ITEM_RE.match(line)
+        if heading:
+            output.append(f"<b>{_render_inline(heading.group(1))}</b>")
+        elif _RULE_RE.match(line):
+            output.append("———")
+        elif item:
+            output.append(f"{item.group(1)}• {_render_inline(item.group(2))}")
+        else:
+            output.append(_render_inline(line))
+
+    if code_lines is not None: # Незакрытый блок кода
+        output.append(_render_code(code_lines, code_language))
+    flush()
+    return "\n".join(output)
+
+def is_balanced(text: str) -> bool:
+    """Каждый тег закрыт в правильном порядке; иначе Telegram не примет сообщение."""
+    stack = []
+    for match in re.finditer(r'<(/?)([a-zA-Z][\w-]*)[^>]*>', text):
+        name = match[2].lower()
+        if not match[1]:
+            stack.append(name)
+        elif not stack or stack.pop() != name:
+            return False
+    return not stack
+
+def markdown_to_html(text: str) -> str:
+    """Переводит Markdown ответа модели в HTML Telegram. При ошибке возвращает экранированный простой текст."""
+    try:
+        result = _render_blocks(text)
+        if is_balanced(result):
+            return result
+        logger.debug("Markdown rendering produced unbalanced HTML, falling back to plain text")
+    except Exception as e:
+        logger.warning(f"Markdown rendering failed, falling back to plain text: {e}")
+    return html.escape(text, quote=False)
+
+def html_to_plain(text: str) -> str:
+    """Убирает теги и HTML-сущности: для отправки без разметки, если Telegram не принял HTML."""
+    return html.unescape(re.sub(r'<[^>]+>', '', text))
@@ -0,0 +1,64 @@
+# app/services/message_buffer_service.py
+# Сообщения, которые пользователь отправляет подряд, но которые нужно обработать одним запросом.
+# Альбом (несколько фото в одном сообщении) Telegram присылает отдельными сообщениями с общим media_group_id,
+# а подпись - только у одного из них. Длинный текст пользователи отправляют частями (а Telegram сам делит
+# текст длиннее 4096 символов). Такие сообщения собираются в буфере в кэше, и обработчик первого из них
+# получает все сообщения, когда новые перестают приходить.
+
+import asyncio
+import time
+from typing import Callable, Dict, List
+
+from aiogram.types import Message
+
+from app.config import MEDIA_GROUP_WAIT_SECONDS, PROMPT_PARTS_WAIT_SECONDS, PROMPT_PARTS_MAX_COUNT, PROMPT_PARTS_MAX_CHARS
+
+
+async def _collect(
+    buffers: Dict, key, message: Message, wait_seconds: float,
+    is_full: Callable[[List[Message]], bool] | None = None
+) -> List[Message] | None:
+    """
+    Добавляет сообщение в буфер key. Обработчик первого сообщения ждет, пока wait_seconds
+    не придет новых, и возвращает все сообщения буфера по порядку; для остальных возвращает None.
+    Заполненный буфер (is_full) закрывается сразу: следующее сообщение начнет новый.
+    """
+    buffer = buffers.get(key)
+    if buffer is not None:
+        buffer['messages'].append(message)
+        buffer['updated_at'] = time.monotonic()
+        if is_full and is_full(buffer['messages']):
+            buffer['closed'] = True
+            buffers.pop(key, None)
+        return None
+
+    buffer = buffers[key] = {'messages': [message], 'updated_at': time.monotonic(), 'closed': False}
+    try:
+        while not buffer['closed'] and (remaining := buffer['updated_at'] + wait_seconds - time.monotonic()) > 0:
+            await asyncio.sleep(remaining)
+    finally:
+        if buffers.get(key) is buffer:
+            buffers.pop(key)
+    return sorted(buffer['messages'], key=lambda part: part.message_id)
+
+async def collect_media_group(message: Message, cache: Dict) -> List[Message] | None:
+    """Части альбома: все сообщения с тем же media_group_id (для первой части) или None."""
+    return await _collect(cache["media_groups"], message.media_group_id, message, MEDIA_GROUP_WAIT_SECONDS)
+
+def get_media_group_caption(messages: List[Message]) -> str:
+    """Подпись альбома: Telegram сохраняет ее у одной из частей."""
+    return next(((part.caption or '').strip() for part in messages if (part.caption or '').strip()), '')
+
+async def collect_prompt_parts(message: Message, cache: Dict) -> List[Message] | None:
+    """
+    Текст, отправленный частями: сообщения пользователя, пришедшие подряд с паузами меньше PROMPT_PARTS_WAIT_SECONDS,
+    но не больше PROMPT_PARTS_MAX_COUNT частей и PROMPT_PARTS_MAX_CHARS символов.
+    """
+    if PROMPT_PARTS_WAIT_SECONDS <= 0:
+        return [message]
+    return await _collect(
+        cache["prompt_parts"], message.from_user.id, message, PROMPT_PARTS_WAIT_SECONDS, is_prompt_buffer_full
+    )
+
+def is_prompt_buffer_full(parts: List[Message]) -> bool:
+    return len(parts) >= PROMPT_PARTS_MAX_COUNT or sum(len(part.text or '') for part in parts) >= PROMPT_PARTS_MAX_CHARS
@@ -0,0 +1,66 @@
+# app/services/model_service.py
+# Подбор модели под предпочтения пользователя.
+
+import logging
+from typing import Dict
+
+from app.config import MODEL_INFO, MODEL_CATEGORIES, MODEL_FALLBACKS, ModelInfo, model_supports
+from app.services.system_service import is_model_available
+
+logger = logging.getLogger(__name__)
+
+def score_model(info: ModelInfo, priority: str, task: str, budget: str) -> float:
+    """Оценивает, насколько модель подходит под ответы пользователя в мастере подбора."""
+    if priority == 'speed':
+        score = info.speed * 2 + info.quality
+    elif priority == 'quality':
+        score = info.quality * 2 + info.speed * 0.5
+    else:
+        score = info.speed + info.quality
+
+    if task == 'coding':
+        score += info.coding * 2
+    elif task == 'writing':
+        score += info.writing * 2
+    else:
+        score += (info.coding + info.writing) / 2
+
+    if budget == 'economy':
+        score -= info.cost * 1.5
+    return score
+
+def recommend_model(accessible_models: set, priority: str, task: str, budget: str, cache: Dict) -> str | None:
+    """Возвращает лучшую доступную пользователю текстовую модель или None."""
+    text_models = {m for models in MODEL_CATEGORIES.values() for m in models}
+    candidates = [
+        MODEL_INFO[m] for m in accessible_models
+        if m in MODEL_INFO and m in text_models and is_model_available(m, cache)
+    ]
+    if not candidates:
+        return None
+    best = max(candidates, key=lambda info: (score_model(info, priority, task, budget), info.id))
+    logger.debug(f"Model wizard ({priority}, {task}, {budget}) recommends {best.id}")
+    return best.id
+
+def get_fallback_chain(model: str, accessible_models: set, cache: Dict, capability: str | None = None) -> list:
+    """
+    Запасные модели по порядку: подходящие из MODEL_FALLBACKS, а если таких нет - одна работающая модель
+    из той же категории или, если и таких нет, из любой (с наибольшим качеством).
+    Замена должна быть доступна пользователю, работать и поддерживать capability (например, 'vision'), если он задан.
+    """
+    text_models = {m for models in MODEL_CATEGORIES.values() for m in models}
+    candidates = [
+        m for m in accessible_models
+        if m != model and m in text_models and is_model_available(m, cache)
+        and (capability is None or model_supports(m, capability))
+    ]
+    chain = [m for m in MODEL_FALLBACKS.get(model, []) if m in candidates]
+    if chain or not candidates:
+        return chain
+    category_models = next((models for models in MODEL_CATEGORIES.values() if model in models), [])
+    pool = [m for m in candidates if m in category_models] or candidates
+    return [max(sorted(pool), key=lambda m: MODEL_INFO[m].quality if m in MODEL_INFO else 0)]
+
+def can_answer(model: str, accessible_models: set, cache: Dict) -> bool:
+    """Модель работает или у нее есть работающая запасная модель."""
+    return is_model_available(model, cache) or bool(get_fallback_chain(model, accessible_models, cache))
@@ -0,0 +1,57 @@
+# app/services/network_service.py
+
+import logging
+from urllib.parse import urlparse
+
+import aiohttp
+from aiohttp_socks import ProxyConnector
+from aiogram.client.session.aiohttp import AiohttpSession
+from aiogram.client.telegram import TelegramAPIServer
+from openai import AsyncOpenAI, DefaultAsyncHttpxClient
+
+from app.config import TELEGRAM_PROXY, TELEGRAM_API_SERVER, TELEGRAM_API_LOCAL, AI_PROXY, AI_PROVIDER_PROXIES
+
+logger = logging.getLogger(__name__)
+
+
+def get_proxy_for(url: str) -> str | None:
+    """Возвращает прокси для провайдера по URL его API: сначала персональный, затем общий AI_PROXY."""
+    host = urlparse(url or '').hostname or ''
+    return AI_PROVIDER_PROXIES.get(host, AI_PROXY)
+
+def create_telegram_session() -> AiohttpSession | None:
+    """
+    Создает сессию для Bot API с учетом прокси и собственного сервера Bot API.
+    None — использовать сессию по умолчанию.
+    """
+    if not TELEGRAM_PROXY and not TELEGRAM_API_SERVER:
+        return None
+    kwargs = {}
+    if TELEGRAM_PROXY:
+        logger.info("Telegram Bot API traffic is routed through a proxy.")
+        kwargs['proxy'] = TELEGRAM_PROXY
+    if TELEGRAM_API_SERVER:
+        logger.info(f"Using custom Bot API server {TELEGRAM_API_SERVER} (local mode: {TELEGRAM_API_LOCAL}).")
+        kwargs['api'] = TelegramAPIServer.from_base(TELEGRAM_API_SERVER, is_local=TELEGRAM_API_LOCAL)
+    return AiohttpSession(**kwargs)
+
+def create_ai_client(base_url: str, api_key: str) -> AsyncOpenAI:
+    """Создает клиент AI API с учетом прокси провайдера."""
+    proxy = get_proxy_for(base_url)
+    if not proxy:
+        return AsyncOpenAI(base_url=base_url, api_key=api_key)
+    logger.info(f"AI API traffic for {urlparse(base_url).hostname} is routed through a proxy.")
+    return AsyncOpenAI(base_url=base_url, api_key=api_key, http_client=DefaultAsyncHttpxClient(proxy=proxy))
+
+def create_telegram_http_session() -> aiohttp.ClientSession:
+    """Создает aiohttp-сессию для сервисов Telegram вне Bot API (например, Telegraph) через TELEGRAM_PROXY."""
+    if not TELEGRAM_PROXY:
+        return aiohttp.ClientSession()
+    return aiohttp.ClientSession(connector=ProxyConnector.from_url(TELEGRAM_PROXY))
+
+def create_http_session(url: str) -> aiohttp.ClientSession:
+    """Создает aiohttp-сессию для прямых запросов к провайдеру (в т.ч. через SOCKS-прокси)."""
+    proxy = get_proxy_for(url)
+    if not proxy:
+        return aiohttp.ClientSession()
+    return aiohttp.ClientSession(connector=ProxyConnector.from_url(proxy))
@@ -0,0 +1,102 @@
+# app/services/notification_service.py
+# Доставка уведомлений пользователям с учетом тихих часов.
+# Несрочные уведомления, попавшие в тихие часы, откладываются в очередь pending_notifications
+# и отправляются планировщиком, когда у пользователя наступит утро.
+
+import asyncio
+import logging
+from datetime import datetime, timedelta, timezone
+
+from aiogram import Bot
+from aiogram.exceptions import TelegramRetryAfter
+from aiogram.types import InlineKeyboardMarkup
+
+from app.database import Database
+
+logger = logging.getLogger(__name__)
+
+
+async def send_content(bot: Bot, chat_id: int, text: str, reply_markup: InlineKeyboardMarkup | None = None, media: tuple[str, str] | None = None):
+    """Отправляет текст или вложение (media - (тип, file_id): photo, video, animation, document) с подписью text."""
+    if not media:
+        return await bot.send_message(chat_id, text, reply_markup=reply_markup)
+    media_type, file_id = media
+    send_method = getattr(bot, f"send_{media_type}")
+    return await send_method(chat_id, file_id, caption=text or None, reply_markup=reply_markup)
+
+async def send_with_retry(
+    bot: Bot, user_id: int, text: str, reply_markup: InlineKeyboardMarkup | None = None, media: tuple[str, str] | None = None
+) -> bool:
+    """Отправляет сообщение, выжидая паузу, если Telegram просит замедлиться."""
+    for _ in range(3):
+        try:
+            await send_content(bot, user_id, text, reply_markup, media)
+            return True
+        except TelegramRetryAfter as e:
+            logger.warning(f"Sending throttled by Telegram, sleeping {e.retry_after}s")
+            await asyncio.sleep(e.retry_after)
+        except Exception as e:
+            logger.debug(f"Message to {user_id} failed: {e}")
+            return False
+    return False
+
+def is_quiet_hour(hour: int, quiet_start: int | None, quiet_end: int | None) -> bool:
+    """Попадает ли час (местное время) в тихие часы. Промежуток может переходить через полночь."""
+    if quiet_start is None or quiet_end is None or quiet_start == quiet_end:
+        return False
+    if quiet_start < quiet_end:
+        return quiet_start <= hour < quiet_end
+    return hour >= quiet_start or hour < quiet_end
+
+async def get_quiet_until(db: Database, user_id: int) -> datetime | None:
+    """Если у пользователя сейчас тихие часы, возвращает момент их окончания (UTC), иначе None."""
+    settings = await db.get_notification_settings(user_id)
+    if not settings:
+        return None
+    quiet_start, quiet_end, utc_offset = settings
+    user_tz = timezone(timedelta(hours=utc_offset or 0))
+    local_now = datetime.now(user_tz)
+    if not is_quiet_hour(local_now.hour, quiet_start, quiet_end):
+        return None
+
+    allowed_from = local_now.replace(hour=quiet_end, minute=0, second=0, microsecond=0)
+    if allowed_from <= local_now:
+        allowed_from += timedelta(days=1)
+    return allowed_from.astimezone(timezone.utc)
+
+async def send_notification(
+    bot: Bot,
+    db: Database,
+    user_id: int,
+    text: str,
+    reply_markup: InlineKeyboardMarkup | None = None,
+    urgent: bool = False,
+    media: tuple[str, str] | None = None
+) -> bool:
+    """
+    Отправляет уведомление пользователю. Несрочные уведомления в тихие часы ставятся в очередь.
+    Возвращает True, если уведомление отправлено или отложено.
+    """
+    if not urgent:
+        quiet_until = await get_quiet_until(db, user_id)
+        if quiet_until:
+            markup_json = reply_markup.model_dump_json(exclude_none=True) if reply_markup else None
+            await db.add_pending_notification(user_id, text, markup_json, quiet_until, media)
+            logger.debug(f"Notification for user {user_id} deferred until {quiet_until}")
+            return True
+    return await send_with_retry(bot, user_id, text, reply_markup, media)
+
+async def flush_pending_notifications(bot: Bot, db: Database):
+    """Отправляет отложенные уведомления, у которых закончились тихие часы. Запускается планировщиком."""
+    due = await db.get_due_notifications()
+    if not due:
+        return
+    sent = 0
+    for notification_id, user_id, text, markup_json, media_type, media_file_id in due:
+        reply_markup = InlineKeyboardMarkup.model_validate_json(markup_json) if markup_json else None
+        media = (media_type, media_file_id) if media_type else None
+        if await send_with_retry(bot, user_id, text, reply_markup, media):
+            sent += 1
+        await db.delete_pending_notification(notification_id)
+        await asyncio.sleep(0.1)
+    logger.info(f"Delivered {sent} of {len(due)} deferred notifications.")
@@ -0,0 +1,152 @@
+# app/services/payment_service.py
+# Оплата подписок. Telegram может доставить SuccessfulPayment повторно (например, после
+# перезапуска бота или при обработке журнала пропущенных обновлений), поэтому платеж
+# записывается по уникальному charge id, а подписка продлевается в той же транзакции
+# и только для новой записи. Метаданные платежа хранятся зашифрованными (см. crypto_service).
+# Подписку можно оплатить и в Telegram Stars (XTR); такие платежи администратор может вернуть.
+# Персональный промокод пользователя (например, скидка win-back) применяется к счету автоматически:
+# код передается в payload счета, проверяется перед списанием и отмечается использованным вместе с записью платежа.
+
+import json
+import logging
+
+from aiogram import Bot
+from aiogram.exceptions import TelegramBadRequest
+from aiogram.types import SuccessfulPayment, PreCheckoutQuery
+from aiogram.utils.markdown import hcode
+
+from app.config import ADMIN_IDS, PRICES, PAYMENT_CURRENCY, STAR_PRICES, STARS_PAYMENTS_ENABLED
+from app.database import Database
+from app.services.crypto_service import encrypt_field
+from app.services.user_service import invalidate_user_cache
+from app.services.analytics_service import track
+from app.services.format_service import format_price
+
+logger = logging.getLogger(__name__)
+
+SUBSCRIPTION_PAYLOAD_PREFIX = 'sub'
+# Результат обработки SuccessfulPayment
+PAYMENT_NEW, PAYMENT_DUPLICATE, PAYMENT_UNKNOWN = 'new', 'duplicate', 'unknown'
+
+
+def format_amount(amount: int, currency: str) -> str:
+    """Сумма из минимальных единиц валюты: копейки для рублей, звезды как есть."""
+    return format_price(amount if currency == 'XTR' else amount / 100, currency)
+
+def build_invoice_payload(level: int, days: int, promocode: str | None = None) -> str:
+    payload = f"{SUBSCRIPTION_PAYLOAD_PREFIX}:{level}:{days}"
+    return f"{payload}:{promocode}" if promocode else payload
+
+def parse_invoice_payload(payload: str) -> tuple[int, int, str | None] | None:
+    """Возвращает (уровень, дни, промокод или None) из payload счета или None, если это не счет за подписку."""
+    parts = payload.split(':')
+    if len(parts) not in (3, 4) or parts[0] != SUBSCRIPTION_PAYLOAD_PREFIX:
+        return None
+    try:
+        level, days = int(parts[1]), int(parts[2])
+    except ValueError:
+        return None
+    promocode = parts[3] if len(parts) == 4 else None
+    return (level, days, promocode) if level in (1, 2, 3) and days > 0 else None
+
+def get_expected_amount(level: int, currency: str, discount_percent: int = 0) -> int | None:
+    """Цена подписки (со скидкой) в минимальных единицах валюты или None для неподдерживаемой валюты."""
+    if currency == PAYMENT_CURRENCY:
+        amount = PRICES[level] * 100
+    elif currency == 'XTR' and STARS_PAYMENTS_ENABLED:
+        amount = STAR_PRICES[level]
+    else:
+        return None
+    # Бесплатный счет Telegram не выставит, поэтому даже со скидкой 100% списывается минимальная сумма
+    return max(amount * (100 - discount_percent) // 100, 1) if discount_percent else amount
+
+async def get_user_discount(db: Database, user_id: int) -> tuple[str, int] | None:
+    """Персональный промокод пользователя, который применится к счету: (код, скидка в процентах) или None."""
+    return await db.get_user_promocode(user_id)
+
+async def validate_pre_checkout(query: PreCheckoutQuery, db: Database) -> str | None:
+    """Проверяет счет перед списанием. Возвращает текст ошибки для пользователя или None."""
+    parsed = parse_invoice_payload(query.invoice_payload)
+    if not parsed:
+        return "Счет устарел. Откройте меню подписки и оформите покупку заново."
+    level, _, promocode = parsed
+    discount_percent = 0
+    if promocode:
+        discount = await db.get_user_promocode(query.from_user.id, promocode)
+        if not discount:
+            return "Промокод из этого счета уже использован или истек. Откройте меню подписки и оформите покупку заново."
+        discount_percent = discount[1]
+    if query.total_amount != get_expected_amount(level, query.currency, discount_percent):
+        return "Цена подписки изменилась. Откройте меню подписки и оформите покупку заново."
+    return None
+
+async def _notify_admins_unknown_payment(bot: Bot, user_id: int, payment: SuccessfulPayment):
+    text = (
+        "⚠️ <b>Платеж с нераспознанным счетом</b>\n\n"
+        f"Пользователь: {hcode(user_id)}\n"
+        f"Сумма: {format_amount(payment.total_amount, payment.currency)}\n"
+        f"Charge id: {hcode(payment.telegram_payment_charge_id)}\n"
+        f"Payload: {hcode(payment.invoice_payload)}\n\n"
+        "Деньги списаны, но подписка не выдана и платеж не записан. Выдайте подписку вручную или верните оплату."
+    )
+    for admin_id in ADMIN_IDS:
+        try:
+            await bot.send_message(admin_id, text)
+        except Exception as e:
+            logger.warning(f"Failed to send unknown payment alert to admin {admin_id}: {e}")
+
+async def process_successful_payment(bot: Bot, db: Database, cache: dict, user_id: int, payment: SuccessfulPayment) -> tuple[str, str | None]:
+    """
+    Записывает платеж и продлевает подписку. Возвращает (PAYMENT_NEW, PAYMENT_DUPLICATE или PAYMENT_UNKNOWN,
+    дата окончания подписки). Повторная доставка того же платежа ничего не меняет.
+    Платеж с нераспознанным payload не записывается: о нем сообщается администраторам.
+    """
+    parsed = parse_invoice_payload(payment.invoice_payload)
+    if not parsed:
+        logger.error(f"Payment {payment.telegram_payment_charge_id} from user {user_id} has unknown payload '{payment.invoice_payload}'")
+        await _notify_admins_unknown_payment(bot, user_id, payment)
+        return PAYMENT_UNKNOWN, None
+    level, days, promocode = parsed
+
+    order_info = payment.order_info.model_dump(exclude_none=True) if payment.order_info else None
+    metadata = encrypt_field(json.dumps({"invoice_payload": payment.invoice_payload, "order_info": order_info}, ensure_ascii=False))
+    is_new, subscription_end = await db.record_payment(
+        user_id, level, days, payment.total_amount, payment.currency,
+        payment.telegram_payment_charge_id, payment.provider_payment_charge_id or None, metadata, promocode
+    )
+    if not is_new:
+        logger.warning(f"Duplicate payment {payment.telegram_payment_charge_id} from user {user_id} ignored")
+        return PAYMENT_DUPLICATE, subscription_end
+
+    invalidate_user_cache(user_id, cache)
+    track(cache, 'purchase', user_id, {
+        'level': level, 'days': days, 'amount': payment.total_amount, 'currency': payment.currency, 'promocode': bool(promocode)
+    })
+    logger.info(f"User {user_id} paid {payment.total_amount} {payment.currency} for level {level} ({days} days)"
+                + (f" with promocode {promocode}" if promocode else ""))
+    return PAYMENT_NEW, subscription_end
+
+async def refund_star_payment(bot: Bot, db: Database, cache: dict, telegram_charge_id: str) -> tuple[tuple | None, str | None]:
+    """
+    Возвращает звезды за платеж и отменяет оплаченный им срок подписки.
+    Возвращает (платеж, текст ошибки или None); платеж - (id, user_id, level, days, amount, currency, status).
+    """
+    payment = await db.get_payment(telegram_charge_id)
+    if not payment:
+        return None, "Платеж не найден."
+    _, user_id, _, _, amount, currency, status = payment
+    if currency != 'XTR':
+        return payment, "Вернуть можно только платеж в Telegram Stars, остальные возвращаются через провайдера."
+    if status != 'paid':
+        return payment, "Платеж уже возвращен."
+
+    try:
+        await bot.refund_star_payment(user_id=user_id, telegram_payment_charge_id=telegram_charge_id)
+    except TelegramBadRequest as e:
+        logger.error(f"Failed to refund star payment {telegram_charge_id} for user {user_id}: {e}")
+        return payment, f"Telegram отклонил возврат: {e.message}"
+
+    await db.refund_payment(telegram_charge_id)
+    invalidate_user_cache(user_id, cache)
+    logger.info(f"Refunded {amount} XTR to user {user_id} for payment {telegram_charge_id}")
+    return payment, None
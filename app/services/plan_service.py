@@ -0,0 +1,68 @@
+# app/services/plan_service.py
+# Сравнение тарифов. Таблица строится из тех же настроек, по которым бот проверяет доступ
+# (лимиты, доступные модели, минимальные уровни функций), поэтому описание тарифов не расходится с работой бота.
+
+import html
+
+from app.config import (
+    PLAN_NAMES, PRICES, STAR_PRICES, STARS_PAYMENTS_ENABLED, SUBSCRIPTION_DAYS, QUOTA_LIMITS, QUOTA_MODE,
+    SCHEDULED_PROMPTS_MIN_LEVEL, API_SERVER_ENABLED, API_TOKEN_MIN_LEVEL
+)
+from app.services.file_service import get_file_size_limit
+from app.services.format_service import format_price
+from app.services.builtin_tool_service import WebSearch, get_builtin_tool
+from app.services.user_service import get_accessible_models, get_stream_interval
+
+YES, NO = 'да', '—'
+
+
+def _compact(value) -> str:
+    """Короткая запись лимита для узкой таблицы: 100, 15K, ∞; нулевой лимит - прочерк."""
+    if value == float('inf'):
+        return '∞'
+    if not value:
+        return NO
+    return f"{value // 1000}K" if value >= 10_000 else str(value)
+
+def get_plan_rows() -> list[tuple[str, dict]]:
+    """Строки сравнения: (показатель, {уровень: значение}). Функции, недоступные ни на одном тарифе (например, выключенные в настройках), не показываются."""
+    levels = list(PLAN_NAMES)
+    rows = [
+        ("Цена", {level: format_price(PRICES[level]) if level in PRICES else "0" for level in levels}),
+        ("В день", {level: _compact(QUOTA_LIMITS[level]['daily']) for level in levels}),
+        ("Max Mode", {level: _compact(QUOTA_LIMITS[level]['max_mode']) for level in levels}),
+        ("Моделей", {level: str(len(get_accessible_models(level))) for level in levels}),
+        ("Файлы, МБ", {level: str(get_file_size_limit(level) // 1024 // 1024) for level in levels}),
+    ]
+    # Доступность проверяется теми же функциями, что и при обработке запросов
+    features = [
+        ("Потоковый", lambda level: get_stream_interval(level) is not None),
+        ("Поиск", lambda level: get_builtin_tool(WebSearch.name, level) is not None),
+        ("Расписание", lambda level: level >= SCHEDULED_PROMPTS_MIN_LEVEL),
+        ("API", lambda level: API_SERVER_ENABLED and level >= API_TOKEN_MIN_LEVEL),
+    ]
+    for name, available in features:
+        values = {level: YES if available(level) else NO for level in levels}
+        if YES in values.values():
+            rows.append((name, values))
+    return rows
+
+def format_plans_table(current_level: int) -> str:
+    """Таблица тарифов (HTML, моноширинный текст) с пояснениями под ней."""
+    rows = [("", dict(PLAN_NAMES))] + get_plan_rows()
+    label_width = max(len(label) for label, _ in rows)
+    widths = {level: max(len(values[level]) for _, values in rows) for level in PLAN_NAMES}
+    lines = [
+        " ".join([label.ljust(label_width)] + [values[level].rjust(widths[level]) for level in PLAN_NAMES]).rstrip()
+        for label, values in rows
+    ]
+    unit = "токенов (K - тысяча)" if QUOTA_MODE == 'tokens' else "запросов"
+    notes = [
+        f"Цена - за {SUBSCRIPTION_DAYS} дней" + (
+            ", в Telegram Stars: " + ", ".join(f"{PLAN_NAMES[level]} {format_price(price, 'XTR')}" for level, price in STAR_PRICES.items())
+            if STARS_PAYMENTS_ENABLED else ""
+        ) + ".",
+        f"«В день» и «Max Mode» - дневные лимиты ({unit}).",
+        f"Ваш тариф: <b>{PLAN_NAMES[current_level]}</b>.",
+    ]
+    return f"<b>📊 Сравнение тарифов</b>\n\n<pre>{html.escape(chr(10).join(lines))}</pre>\n\n" + "\n".join(notes)
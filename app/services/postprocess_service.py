@@ -0,0 +1,143 @@
+# app/services/postprocess_service.py
+# Цепочка постобработки ответов моделей. Каждый фильтр - отдельный шаг (убрать рассуждения,
+# поправить форматирование, скрыть секреты, модерация, подпись), который включается
+# в RESPONSE_FILTERS и настраивается своими параметрами в config. Фильтр получает текст
+# и контекст ответа и возвращает новый текст, поэтому его легко проверить отдельно.
+# Та же цепочка применяется к промежуточному тексту при потоковом ответе (context.partial): фильтры
+# скрывают еще незаконченные рассуждения и секреты, а подпись добавляется только к полному ответу.
+
+import logging
+import re
+from dataclasses import dataclass
+
+from app.config import RESPONSE_FILTERS, RESPONSE_BLOCKLIST, RESPONSE_FOOTER
+
+logger = logging.getLogger(__name__)
+
+
+@dataclass(frozen=True)
+class ResponseContext:
+    model: str
+    user_id: int
+    # Ответ показывается пользователю (а не передается дальше, как ответы участников Max Mode)
+    final_answer: bool = True
+    # Промежуточный текст потокового ответа: ответ еще не закончен
+    partial: bool = False
+
+
+class ResponseFilter:
+    """Шаг постобработки ответа."""
+    name = ''
+
+    def apply(self, text: str, context: ResponseContext) -> str:
+        raise NotImplementedError
+
+
+class StripReasoning(ResponseFilter):
+    """
+    Убирает рассуждения reasoning-моделей (<think>...</think>), в том числе без открывающего тега.
+    В промежуточном тексте скрывается и незакрытый блок: рассуждение еще идет.
+    """
+    name = 'strip_reasoning'
+    _BLOCK_RE = re.compile(r'<think>.*?</think>\s*', re.DOTALL)
+    _LEADING_RE = re.compile(r'^.*?</think>\s*', re.DOTALL)
+
+    def apply(self, text, context):
+        text = self._BLOCK_RE.sub('', text)
+        text = self._LEADING_RE.sub('', text) if '</think>' in text else text
+        if context.partial and '<think>' in text:
+            text = text[:text.index('<think>')]
+        return text
+
+
+class NormalizeWhitespace(ResponseFilter):
+    """Убирает пробелы в конце строк, лишние пустые строки и пустое начало ответа."""
+    name = 'normalize_whitespace'
+    _TRAILING_RE = re.compile(r'[ \t]+$', re.MULTILINE)
+    _BLANK_LINES_RE = re.compile(r'\n{3,}')
+
+    def apply(self, text, context):
+        text = self._TRAILING_RE.sub('', text)
+        return self._BLANK_LINES_RE.sub('\n\n', text).strip()
+
+
+class RedactSecrets(ResponseFilter):
+    """
+    Скрывает похожие на секреты строки: ключи API, токены ботов, приватные ключи.
+    В промежуточном тексте приватный ключ скрывается уже с первой строки, не дожидаясь конца блока.
+    """
+    name = 'redact_secrets'
+    PLACEHOLDER = '[секрет скрыт]'
+    _PATTERNS = [
+        re.compile(r'-----BEGIN [A-Z ]*PRIVATE KEY-----.*?-----END [A-Z ]*PRIVATE KEY-----', re.DOTALL),
+        re.compile(r'\bsk-[A-Za-z0-9_-]{20,}'),
+        re.compile(r'\b\d{8,10}:[A-Za-z0-9_-]{35}\b'), # Токен Telegram-бота
+        re.compile(r'\bAKIA[0-9A-Z]{16}\b'),
+        re.compile(r'\bgh[pousr]_[A-Za-z0-9]{36,}\b'),
+    ]
+    _UNFINISHED_KEY_RE = re.compile(r'-----BEGIN [A-Z ]*PRIVATE KEY-----.*$', re.DOTALL)
+
+    def apply(self, text, context):
+        for pattern in self._PATTERNS:
+            text, count = pattern.subn(self.PLACEHOLDER, text)
+            if count and not context.partial:
+                logger.warning(f"Redacted {count} secret(s) in a response of {context.model} for user {context.user_id}")
+        if context.partial:
+            text = self._UNFINISHED_KEY_RE.sub(self.PLACEHOLDER, text)
+        return text
+
+
+class Moderation(ResponseFilter):
+    """Скрывает ответ целиком, если в нем есть фраза из RESPONSE_BLOCKLIST."""
+    name = 'moderation'
+    BLOCKED_TEXT = '⚠️ Ответ скрыт: он нарушает правила сервиса.'
+
+    def __init__(self, blocklist: list[str]):
+        self.blocklist = [phrase.lower() for phrase in blocklist]
+
+    def apply(self, text, context):
+        lowered = text.lower()
+        if any(phrase in lowered for phrase in self.blocklist):
+            if not context.partial:
+                logger.warning(f"Response of {context.model} for user {context.user_id} blocked by moderation")
+            return self.BLOCKED_TEXT
+        return text
+
+
+class AppendFooter(ResponseFilter):
+    """Добавляет подпись RESPONSE_FOOTER к ответам, которые видит пользователь."""
+    name = 'footer'
+
+    def __init__(self, footer: str):
+        self.footer = footer
+
+    def apply(self, text, context):
+        if not self.footer or not context.final_answer or context.partial or not text:
+            return text
+        return f"{text}\n\n{self.footer}"
+
+
+def build_filters(names: list[str]) -> list[ResponseFilter]:
+    """Собирает цепочку в порядке names; неизвестные имена пропускаются с предупреждением."""
+    factories = {
+        StripReasoning.name: StripReasoning,
+        NormalizeWhitespace.name: NormalizeWhitespace,
+        RedactSecrets.name: RedactSecrets,
+        Moderation.name: lambda: Moderation(RESPONSE_BLOCKLIST),
+        AppendFooter.name: lambda: AppendFooter(RESPONSE_FOOTER),
+    }
+    filters = []
+    for name in names:
+        if name not in factories:
+            logger.warning(f"Unknown response filter '{name}' in RESPONSE_FILTERS, skipping")
+            continue
+        filters.append(factories[name]())
+    return filters
+
+FILTERS = build_filters(RESPONSE_FILTERS)
+
+def postprocess_response(text: str, context: ResponseContext, filters: list[ResponseFilter] | None = None) -> str:
+    """Пропускает ответ через цепочку фильтров (по умолчанию - настроенную в RESPONSE_FILTERS)."""
+    for response_filter in FILTERS if filters is None else filters:
+        text = response_filter.apply(text, context)
+    return text
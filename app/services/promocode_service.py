@@ -0,0 +1,93 @@
+# app/services/promocode_service.py
+# Массовая загрузка и выгрузка промокодов в CSV (например, коды, сгенерированные для партнера).
+# Формат загрузки: строка заголовков code,discount_percent,expires_at[,user_id], дата - ГГГГ-ММ-ДД или ДД.ММ.ГГГГ.
+# Выгрузка пишет даты как ДД.ММ.ГГГГ, так что ее можно загрузить обратно.
+
+import csv
+import io
+import re
+from datetime import datetime, time, timezone
+
+from app.config import MSK_TZ
+from app.database import Database
+from app.services.format_service import format_date
+
+CODE_RE = re.compile(r'^[A-Z0-9_-]{3,32}$')
+REQUIRED_COLUMNS = ('code', 'discount_percent', 'expires_at')
+EXPORT_COLUMNS = ('code', 'discount_percent', 'expires_at', 'user_id', 'source')
+MAX_IMPORT_ROWS = 10_000
+
+
+def _parse_expires_at(value: str) -> datetime:
+    """Дата без времени означает конец дня по Москве."""
+    value = value.strip()
+    for pattern, date_format in ((r'\d{4}-\d{2}-\d{2}', '%Y-%m-%d'), (r'\d{2}\.\d{2}\.\d{4}', '%d.%m.%Y')):
+        if re.fullmatch(pattern, value):
+            return datetime.combine(datetime.strptime(value, date_format).date(), time(23, 59, 59), MSK_TZ).astimezone(timezone.utc)
+    expires_at = datetime.fromisoformat(value)
+    return (expires_at if expires_at.tzinfo else expires_at.replace(tzinfo=MSK_TZ)).astimezone(timezone.utc)
+
+def parse_promocodes_csv(text: str) -> tuple[list[tuple], list[str]]:
+    """
+    Разбирает CSV с промокодами. Возвращает (коды (code, user_id, discount_percent, expires_at), ошибки).
+    Повторы внутри файла отбрасываются с ошибкой, строка с ошибкой пропускается целиком.
+    """
+    try:
+        dialect = csv.Sniffer().sniff(text[:2048], delimiters=',;\t')
+    except csv.Error:
+        dialect = csv.excel
+    reader = csv.DictReader(io.StringIO(text), dialect=dialect)
+    columns = [name.strip().lower() for name in reader.fieldnames or []]
+    missing = [name for name in REQUIRED_COLUMNS if name not in columns]
+    if missing:
+        return [], [f"нет столбцов: {', '.join(missing)}"]
+    reader.fieldnames = columns
+
+    now_utc = datetime.now(timezone.utc)
+    codes, errors, seen = [], [], set()
+    for line_no, row in enumerate(reader, start=2):
+        if line_no - 1 > MAX_IMPORT_ROWS:
+            errors.append(f"файл обрезан: больше {MAX_IMPORT_ROWS} строк")
+            break
+        code = (row.get('code') or '').strip().upper()
+        if not code:
+            continue
+        if not CODE_RE.match(code):
+            errors.append(f"строка {line_no}: некорректный код {code[:40]}")
+            continue
+        if code in seen:
+            errors.append(f"строка {line_no}: код {code} повторяется в файле")
+            continue
+        try:
+            discount = int((row.get('discount_percent') or '').strip().rstrip('%'))
+            expires_at = _parse_expires_at(row.get('expires_at') or '')
+            user_id = int(row['user_id']) if (row.get('user_id') or '').strip() else None
+        except (ValueError, TypeError):
+            errors.append(f"строка {line_no}: некорректная скидка, дата или ID пользователя")
+            continue
+        if not 1 <= discount <= 100:
+            errors.append(f"строка {line_no}: скидка должна быть от 1 до 100%")
+            continue
+        if expires_at <= now_utc:
+            errors.append(f"строка {line_no}: срок действия уже истек")
+            continue
+        seen.add(code)
+        codes.append((code, user_id, discount, expires_at))
+    return codes, errors
+
+async def import_promocodes(db: Database, text: str, source: str) -> tuple[int, int, list[str]]:
+    """Загружает коды из CSV. Возвращает (добавлено, уже были в базе, ошибки)."""
+    codes, errors = parse_promocodes_csv(text)
+    added = await db.add_promocodes(codes, source) if codes else 0
+    return added, len(codes) - added, errors
+
+async def export_promocodes_csv(db: Database, source: str | None = None) -> tuple[bytes, int]:
+    """Выгружает неиспользованные действующие коды. Возвращает (содержимое CSV, число кодов)."""
+    rows = await db.get_unused_promocodes(source)
+    output = io.StringIO()
+    writer = csv.writer(output)
+    writer.writerow(EXPORT_COLUMNS)
+    for code, discount, expires_at, user_id, code_source in rows:
+        writer.writerow((code, discount, format_date(expires_at), user_id or '', code_source))
+    # BOM, чтобы Excel правильно определил кодировку
+    return output.getvalue().encode('utf-8-sig'), len(rows)
@@ -0,0 +1,164 @@
+# app/services/prompt_service.py
+# Предобработка входящих запросов перед отправкой модели - зеркало postprocess_service.
+# Шаги (обрезка, нормализация пробелов, подстановка плейсхолдеров, контекст, модерация)
+# включаются в PROMPT_STEPS. Шаг получает подготавливаемый запрос и контекст пользователя;
+# отклонить запрос можно исключением PromptRejected.
+
+import logging
+import re
+from dataclasses import dataclass, field
+from datetime import datetime, timedelta, timezone
+
+from aiogram.types import Message
+
+from app.config import PROMPT_STEPS, PROMPT_BLOCKLIST
+from app.database import Database
+from app.services.scheduled_prompt_service import WEEKDAY_NAMES
+from app.services.user_service import get_utc_offset
+
+logger = logging.getLogger(__name__)
+
+
+class PromptRejected(Exception):
+    """Запрос не может быть отправлен модели. Текст исключения можно показывать пользователю."""
+
+
+@dataclass(frozen=True)
+class PromptContext:
+    user_id: int
+    utc_offset: int
+    language_code: str | None = None
+    first_name: str | None = None
+
+    def now(self) -> datetime:
+        return datetime.now(timezone(timedelta(hours=self.utc_offset)))
+
+
+@dataclass
+class PreparedPrompt:
+    text: str
+    # Сведения для модели, которые не должны попадать в историю беседы (передаются системными сообщениями)
+    notes: list[str] = field(default_factory=list)
+
+    def system_messages(self) -> list:
+        return [{"role": "system", "content": note} for note in self.notes]
+
+    def with_notes(self) -> str:
+        """Текст запроса вместе со сведениями - для мест, где нельзя передать отдельные системные сообщения."""
+        return '\n\n'.join([*(f"[{note}]" for note in self.notes), self.text])
+
+
+class PromptStep:
+    """Шаг предобработки запроса."""
+    name = ''
+
+    def apply(self, prompt: PreparedPrompt, context: PromptContext):
+        raise NotImplementedError
+
+
+class Trim(PromptStep):
+    """Обрезает пробелы по краям. Пустой запрос (например, стикер вместо текста) отклоняется."""
+    name = 'trim'
+
+    def apply(self, prompt, context):
+        prompt.text = prompt.text.strip()
+        if not prompt.text:
+            raise PromptRejected("Отправьте запрос текстом.")
+
+
+class NormalizeWhitespace(PromptStep):
+    """Приводит переводы строк и неразрывные пробелы к обычным, убирает лишние пустые строки."""
+    name = 'normalize_whitespace'
+    _BLANK_LINES_RE = re.compile(r'\n{3,}')
+
+    def apply(self, prompt, context):
+        text = prompt.text.replace('\r\n', '\n').replace('\u00a0', ' ')
+        prompt.text = self._BLANK_LINES_RE.sub('\n\n', text)
+
+
+class ExpandPlaceholders(PromptStep):
+    """Подставляет значения вместо {дата}, {время}, {день} и {имя}; остальные фигурные скобки не трогает."""
+    name = 'placeholders'
+    _PLACEHOLDER_RE = re.compile(r'\{(дата|время|день|имя)\}')
+
+    def apply(self, prompt, context):
+        now = context.now()
+        values = {
+            'дата': now.strftime('%d.%m.%Y'),
+            'время': now.strftime('%H:%M'),
+            'день': WEEKDAY_NAMES[now.weekday()],
+            'имя': context.first_name or '',
+        }
+        prompt.text = self._PLACEHOLDER_RE.sub(lambda match: values[match[1]], prompt.text)
+
+
+class AttachContext(PromptStep):
+    """Сообщает модели текущие дату и время пользователя и язык его интерфейса Telegram."""
+    name = 'context'
+
+    def apply(self, prompt, context):
+        note = f"Сейчас у пользователя {context.now().strftime('%d.%m.%Y %H:%M')} (UTC{context.utc_offset:+d})"
+        if context.language_code:
+            note += f", язык интерфейса: {context.language_code}"
+        prompt.notes.append(note + '.')
+
+
+class Moderation(PromptStep):
+    """Отклоняет запрос с фразой из PROMPT_BLOCKLIST."""
+    name = 'moderation'
+
+    def __init__(self, blocklist: list[str]):
+        self.blocklist = [phrase.lower() for phrase in blocklist]
+
+    def apply(self, prompt, context):
+        lowered = prompt.text.lower()
+        if any(phrase in lowered for phrase in self.blocklist):
+            logger.warning(f"Prompt of user {context.user_id} blocked by moderation")
+            raise PromptRejected("⚠️ Запрос отклонен: он нарушает правила сервиса.")
+
+
+class PromptPipeline:
+    def __init__(self, steps: list[PromptStep]):
+        self.steps = steps
+
+    @classmethod
+    def from_names(cls, names: list[str]) -> 'PromptPipeline':
+        """Собирает цепочку в порядке names; неизвестные имена пропускаются с предупреждением."""
+        factories = {
+            Trim.name: Trim,
+            NormalizeWhitespace.name: NormalizeWhitespace,
+            ExpandPlaceholders.name: ExpandPlaceholders,
+            AttachContext.name: AttachContext,
+            Moderation.name: lambda: Moderation(PROMPT_BLOCKLIST),
+        }
+        steps = []
+        for name in names:
+            if name not in factories:
+                logger.warning(f"Unknown prompt step '{name}' in PROMPT_STEPS, skipping")
+                continue
+            steps.append(factories[name]())
+        return cls(steps)
+
+    def run(self, text: str | None, context: PromptContext) -> PreparedPrompt:
+        """Готовит запрос к отправке. Если запрос отклонен - PromptRejected."""
+        prompt = PreparedPrompt(text or '')
+        for step in self.steps:
+            step.apply(prompt, context)
+        return prompt
+
+PIPELINE = PromptPipeline.from_names(PROMPT_STEPS)
+
+async def prepare_prompt(text: str | None, message: Message, user_id: int, db: Database) -> PreparedPrompt:
+    """
+    Пропускает запрос пользователя через настроенную в PROMPT_STEPS цепочку.
+    Язык и имя берутся у отправителя message, если это сам пользователь
+    (при повторе прерванного запроса message - сообщение бота).
+    """
+    user = message.from_user if message.from_user and message.from_user.id == user_id else None
+    context = PromptContext(
+        user_id=user_id,
+        utc_offset=await get_utc_offset(db, user_id),
+        language_code=user.language_code if user else None,
+        first_name=user.first_name if user else None,
+    )
+    return PIPELINE.run(text, context)
@@ -0,0 +1,539 @@
+# app/services/provider_service.py
+# Провайдеры моделей: бот обращается к моделям не напрямую к одному API, а через провайдера,
+# которого реестр выбирает по имени модели: MODEL_PROVIDERS, затем ModelInfo.provider, остальные модели - DEFAULT_PROVIDER.
+# Каждый провайдер - подкласс Provider; новые типы регистрируются в PROVIDER_TYPES.
+# Ответы всех провайдеров приводятся к формату chat.completions (как у клиента OpenAI).
+
+import asyncio
+import base64
+import json
+import logging
+import time
+
+import aiohttp
+from openai import APIError, APITimeoutError
+from openai.types.chat import ChatCompletion, ChatCompletionChunk
+
+from app.config import (
+    AI_PROVIDERS, MODEL_PROVIDERS, DEFAULT_PROVIDER, MODEL_INFO, ANTHROPIC_API_VERSION, ANTHROPIC_MAX_TOKENS,
+    GEMINI_SAFETY_THRESHOLD, MODEL_CHECK_PROMPT, MODEL_CHECK_MAX_TOKENS
+)
+from app.services.network_service import create_ai_client, create_http_session
+
+logger = logging.getLogger(__name__)
+
+HEALTH_CHECK_CHAT_TIMEOUT = 20.0
+HEALTH_CHECK_IMAGE_TIMEOUT = 45
+
+
+class ProviderHTTPError(RuntimeError):
+    """Провайдер ответил ошибкой на прямой HTTP-запрос (не через клиент OpenAI)."""
+    def __init__(self, status: int, text: str):
+        super().__init__(f"Статус {status}: {text[:500]}")
+        self.status = status
+
+
+class Provider:
+    """
+    Провайдер моделей. chat возвращает ответ в формате chat.completions (или поток, если stream=True),
+    generate_image - байты изображения. Повторы при сбоях выполняет вызывающий код (with_retries).
+    """
+    def __init__(self, name: str):
+        self.name = name
+
+    async def chat(self, model: str, messages: list, max_retries: int | None = None, **kwargs):
+        raise NotImplementedError
+
+    async def generate_image(self, model: str, prompt: str, width: int, height: int) -> bytes:
+        # Генерация изображений есть не у всех провайдеров
+        raise RuntimeError("Провайдер не поддерживает генерацию изображений.")
+
+    async def list_models(self) -> set[str]:
+        raise NotImplementedError
+
+    async def health_check(self, model: str, kind: str = 'chat') -> str:
+        """Короткий тестовый запрос к модели ('chat' или 'image'). Возвращает статус: 'OK' или описание ошибки."""
+        try:
+            if kind == 'image':
+                await asyncio.wait_for(self.generate_image(model, MODEL_CHECK_PROMPT, 512, 512), HEALTH_CHECK_IMAGE_TIMEOUT)
+            else:
+                await self.chat(
+                    model, [{'role': 'user', 'content': MODEL_CHECK_PROMPT}],
+                    temperature=0.7, max_tokens=MODEL_CHECK_MAX_TOKENS, timeout=HEALTH_CHECK_CHAT_TIMEOUT
+                )
+            return 'OK'
+        except (asyncio.TimeoutError, APITimeoutError):
+            logger.warning(f"Model {model} test timed out ({self.name}).")
+            return 'Timeout'
+        except APIError as e:
+            logger.warning(f"Model {model} test failed with APIError: {e.status_code} ({self.name})")
+            return f'API Error {e.status_code}'
+        except ProviderHTTPError as e:
+            logger.warning(f"Model {model} test failed with status {e.status} ({self.name})")
+            return f'Error {e.status}'
+        except Exception as e:
+            logger.error(f"Model {model} test failed with unexpected error: {e}", exc_info=True)
+            return f'Error: {type(e).__name__}'
+
+
+class OpenAICompatibleProvider(Provider):
+    """API в формате OpenAI: /chat/completions, /images/generations и /models."""
+    def __init__(self, name: str, api_url: str, api_key: str):
+        super().__init__(name)
+        self.api_url = api_url
+        self.api_key = api_key
+        self.client = create_ai_client(api_url, api_key)
+
+    async def chat(self, model: str, messages: list, max_retries: int | None = None, **kwargs):
+        client = self.client if max_retries is None else self.client.with_options(max_retries=max_retries)
+        return await client.chat.completions.create(model=model, messages=messages, **kwargs)
+
+    async def generate_image(self, model: str, prompt: str, width: int, height: int) -> bytes:
+        """Провайдер может вернуть ссылку (url) или само изображение (b64_json) - в обоих случаях возвращаются байты."""
+        headers = {"Authorization": f"Bearer {self.api_key}", "Content-Type": "application/json"}
+        payload = {"model": model, "prompt": prompt, "height": height, "width": width, "response_format": "url"}
+        async with create_http_session(self.api_url) as session:
+            async with session.post(f"{self.api_url}/images/generations", headers=headers, json=payload, timeout=180) as response:
+                if response.status != 200:
+                    raise ProviderHTTPError(response.status, await response.text())
+                data = await response.json()
+
+            try:
+                item = data['data'][0]
+            except (KeyError, IndexError, TypeError):
+                raise RuntimeError("Провайдер не вернул изображение.")
+
+            if item.get('b64_json'):
+                return base64.b64decode(item['b64_json'])
+            if item.get('url'):
+                async with session.get(item['url'], timeout=60) as image_response:
+                    if image_response.status != 200:
+                        raise RuntimeError(f"Не удалось скачать изображение (статус {image_response.status}).")
+                    return await image_response.read()
+            raise RuntimeError("Провайдер не вернул изображение.")
+
+    async def list_models(self) -> set[str]:
+        return {model.id async for model in self.client.models.list()}
+
+
+# --- Общее для провайдеров с прямыми HTTP-запросами ---
+
+async def _post_json(url: str, headers: dict, payload: dict, timeout: float, stream: bool):
+    """
+    Отправляет запрос провайдеру и возвращает (сессия, ответ). Поток читается позже,
+    поэтому сессию закрывает вызывающий код. Ответ с ошибкой вызывает ProviderHTTPError.
+    """
+    # У потока ограничивается ожидание каждого фрагмента, а не время всего ответа
+    if stream:
+        client_timeout = aiohttp.ClientTimeout(sock_connect=timeout, sock_read=timeout)
+    else:
+        client_timeout = aiohttp.ClientTimeout(total=timeout)
+    session = create_http_session(url)
+    try:
+        response = await session.post(url, headers=headers, json=payload, timeout=client_timeout)
+        if response.status != 200:
+            raise ProviderHTTPError(response.status, await response.text())
+    except BaseException:
+        await session.close()
+        raise
+    return session, response
+
+async def _get_json(url: str, headers: dict, params: dict | None = None) -> dict:
+    async with create_http_session(url) as session:
+        async with session.get(url, headers=headers, params=params, timeout=30) as response:
+            if response.status != 200:
+                raise ProviderHTTPError(response.status, await response.text())
+            return await response.json()
+
+async def _iter_sse_events(response: aiohttp.ClientResponse):
+    """События SSE-потока (поле data каждого события, разобранное как JSON)."""
+    async for line in response.content:
+        line = line.decode('utf-8').strip()
+        if line.startswith('data:'):
+            yield json.loads(line[5:])
+
+def _split_data_url(url: str) -> tuple[str, str]:
+    """data:image/png;base64,... -> (image/png, данные в base64)."""
+    media_type, _, data = url[5:].partition(';base64,')
+    return media_type, data
+
+def _to_openai_usage(input_tokens: int, output_tokens: int) -> dict:
+    return {"prompt_tokens": input_tokens, "completion_tokens": output_tokens, "total_tokens": input_tokens + output_tokens}
+
+def _to_chat_completion(
+    message_id: str, model: str, text: str, tool_calls: list, finish_reason: str, usage: dict
+) -> ChatCompletion:
+    return ChatCompletion.model_validate({
+        "id": message_id, "object": "chat.completion", "created": int(time.time()), "model": model,
+        "choices": [{
+            "index": 0,
+            "finish_reason": finish_reason,
+            "message": {"role": "assistant", "content": text if text or not tool_calls else None, "tool_calls": tool_calls or None},
+        }],
+        "usage": usage,
+    })
+
+def _to_chunk(message_id: str, model: str, text: str | None = None, usage: dict | None = None) -> ChatCompletionChunk:
+    return ChatCompletionChunk.model_validate({
+        "id": message_id, "object": "chat.completion.chunk", "created": int(time.time()), "model": model,
+        "choices": [{"index": 0, "delta": {"content": text}, "finish_reason": None}] if text else [],
+        "usage": usage,
+    })
+
+def _to_tool_call(call_id: str, name: str, arguments: dict | None) -> dict:
+    return {"id": call_id, "type": "function", "function": {"name": name, "arguments": json.dumps(arguments or {}, ensure_ascii=False)}}
+
+
+# --- Anthropic ---
+
+# Причины остановки Messages API и соответствующие им finish_reason chat.completions
+_ANTHROPIC_STOP_REASONS = {
+    'end_turn': 'stop', 'stop_sequence': 'stop', 'max_tokens': 'length', 'tool_use': 'tool_calls', 'refusal': 'content_filter',
+}
+
+def _to_anthropic_content(content) -> str | list:
+    """Содержимое сообщения: текст или части (текст и изображения, в том числе data URL)."""
+    if isinstance(content, str):
+        return content
+    blocks = []
+    for part in content:
+        if part.get('type') == 'image_url':
+            url = part['image_url']['url']
+            if url.startswith('data:'):
+                media_type, data = _split_data_url(url)
+                blocks.append({"type": "image", "source": {"type": "base64", "media_type": media_type, "data": data}})
+            else:
+                blocks.append({"type": "image", "source": {"type": "url", "url": url}})
+        else:
+            blocks.append({"type": "text", "text": part.get('text', '')})
+    return blocks
+
+def _to_anthropic_messages(messages: list) -> list:
+    """Переводит историю chat.completions (без системных сообщений) в сообщения Messages API, включая вызовы инструментов."""
+    result = []
+    previous_role = None
+    for message in messages:
+        if message['role'] == 'tool':
+            block = {"type": "tool_result", "tool_use_id": message['tool_call_id'], "content": message['content']}
+            # Результаты нескольких вызовов подряд передаются одним сообщением пользователя
+            if previous_role == 'tool':
+                result[-1]['content'].append(block)
+            else:
+                result.append({"role": "user", "content": [block]})
+        elif message.get('tool_calls'):
+            blocks = [{"type": "text", "text": message['content']}] if message.get('content') else []
+            blocks += [
+                {"type": "tool_use", "id": call['id'], "name": call['function']['name'],
+                 "input": json.loads(call['function']['arguments'] or '{}')}
+                for call in message['tool_calls']
+            ]
+            result.append({"role": "assistant", "content": blocks})
+        else:
+            result.append({"role": message['role'], "content": _to_anthropic_content(message['content'])})
+        previous_role = message['role']
+    return result
+
+def _to_anthropic_request(model: str, messages: list, options: dict) -> dict:
+    """
+    Тело запроса /messages из параметров chat.completions. Системные сообщения передаются полем system.
+    response_format не поддерживается: формат JSON задается системным промптом.
+    """
+    payload = {
+        "model": model,
+        "messages": _to_anthropic_messages([message for message in messages if message['role'] != 'system']),
+        "max_tokens": options.get('max_tokens') or ANTHROPIC_MAX_TOKENS,
+    }
+    system = [message['content'] for message in messages if message['role'] == 'system']
+    if system:
+        payload['system'] = '\n\n'.join(system)
+    if options.get('temperature') is not None:
+        # У Anthropic температура от 0 до 1, а в настройках пользователя - до 2
+        payload['temperature'] = min(options['temperature'], 1.0)
+    if options.get('tools'):
+        payload['tools'] = [
+            {"name": tool['function']['name'], "description": tool['function'].get('description', ''),
+             "input_schema": tool['function'].get('parameters') or {"type": "object", "properties": {}}}
+            for tool in options['tools']
+        ]
+        if options.get('tool_choice') == 'none':
+            payload['tool_choice'] = {"type": "none"}
+    if options.get('stream'):
+        payload['stream'] = True
+    return payload
+
+def _from_anthropic_response(data: dict, model: str) -> ChatCompletion:
+    blocks = data.get('content') or []
+    text = ''.join(block['text'] for block in blocks if block.get('type') == 'text')
+    tool_calls = [_to_tool_call(block['id'], block['name'], block.get('input')) for block in blocks if block.get('type') == 'tool_use']
+    usage = data.get('usage') or {}
+    return _to_chat_completion(
+        data.get('id', ''), data.get('model', model), text, tool_calls,
+        _ANTHROPIC_STOP_REASONS.get(data.get('stop_reason'), 'stop'),
+        _to_openai_usage(usage.get('input_tokens', 0), usage.get('output_tokens', 0))
+    )
+
+async def _read_anthropic_stream(session: aiohttp.ClientSession, response: aiohttp.ClientResponse, model: str):
+    """
+    Переводит SSE-события Messages API во фрагменты chat.completions: текст - по мере поступления,
+    расход токенов - последним фрагментом (как при stream_options.include_usage). Закрывает сессию.
+    """
+    message_id, input_tokens, output_tokens = '', 0, 0
+    async with session:
+        async for event in _iter_sse_events(response):
+            if event['type'] == 'message_start':
+                message_id = event['message'].get('id', '')
+                input_tokens = event['message'].get('usage', {}).get('input_tokens', 0)
+            elif event['type'] == 'content_block_delta' and event['delta'].get('type') == 'text_delta':
+                yield _to_chunk(message_id, model, event['delta']['text'])
+            elif event['type'] == 'message_delta':
+                output_tokens = event.get('usage', {}).get('output_tokens', output_tokens)
+            elif event['type'] == 'error':
+                raise RuntimeError(f"Anthropic stream error: {event['error'].get('message')}")
+    yield _to_chunk(message_id, model, usage=_to_openai_usage(input_tokens, output_tokens))
+
+
+class AnthropicProvider(Provider):
+    """Anthropic Messages API: /messages и /models."""
+    def __init__(self, name: str, api_url: str, api_key: str):
+        super().__init__(name)
+        self.api_url = api_url.rstrip('/')
+        self.api_key = api_key
+
+    def _headers(self) -> dict:
+        return {"x-api-key": self.api_key, "anthropic-version": ANTHROPIC_API_VERSION, "Content-Type": "application/json"}
+
+    async def chat(self, model: str, messages: list, max_retries: int | None = None, **kwargs):
+        """Повторов у прямых HTTP-запросов нет, поэтому max_retries не используется."""
+        payload = _to_anthropic_request(model, messages, kwargs)
+        stream = bool(payload.get('stream'))
+        session, response = await _post_json(
+            f"{self.api_url}/messages", self._headers(), payload, kwargs.get('timeout') or 120.0, stream
+        )
+        if stream:
+            return _read_anthropic_stream(session, response, model)
+        async with session:
+            return _from_anthropic_response(await response.json(), model)
+
+    async def list_models(self) -> set[str]:
+        data = await _get_json(f"{self.api_url}/models", self._headers(), {"limit": 1000})
+        return {model['id'] for model in data.get('data', [])}
+
+
+# --- Google Gemini ---
+
+# Категории фильтров безопасности Gemini; для всех задается порог GEMINI_SAFETY_THRESHOLD
+_GEMINI_SAFETY_CATEGORIES = [
+    'HARM_CATEGORY_HARASSMENT', 'HARM_CATEGORY_HATE_SPEECH', 'HARM_CATEGORY_SEXUALLY_EXPLICIT', 'HARM_CATEGORY_DANGEROUS_CONTENT',
+]
+# Причины остановки generateContent и соответствующие им finish_reason chat.completions
+_GEMINI_FINISH_REASONS = {
+    'STOP': 'stop', 'MAX_TOKENS': 'length', 'SAFETY': 'content_filter', 'RECITATION': 'content_filter',
+    'BLOCKLIST': 'content_filter', 'PROHIBITED_CONTENT': 'content_filter', 'SPII': 'content_filter',
+}
+# Ключи JSON Schema, которых нет в подмножестве OpenAPI, принимаемом Gemini
+_GEMINI_UNSUPPORTED_SCHEMA_KEYS = {'$schema', 'additionalProperties'}
+
+def _to_gemini_schema(schema):
+    if isinstance(schema, dict):
+        return {key: _to_gemini_schema(value) for key, value in schema.items() if key not in _GEMINI_UNSUPPORTED_SCHEMA_KEYS}
+    if isinstance(schema, list):
+        return [_to_gemini_schema(item) for item in schema]
+    return schema
+
+def _to_gemini_parts(content) -> list:
+    if isinstance(content, str):
+        return [{"text": content}]
+    parts = []
+    for part in content:
+        if part.get('type') == 'image_url':
+            url = part['image_url']['url']
+            if url.startswith('data:'):
+                media_type, data = _split_data_url(url)
+                parts.append({"inline_data": {"mime_type": media_type, "data": data}})
+            else:
+                # Gemini принимает по ссылке только загруженные в него файлы, поэтому ссылка передается текстом
+                parts.append({"text": url})
+        else:
+            parts.append({"text": part.get('text', '')})
+    return parts
+
+def _to_gemini_contents(messages: list) -> list:
+    """
+    Переводит историю chat.completions (без системных сообщений) в contents: assistant -> model,
+    вызовы инструментов -> functionCall, их результаты -> functionResponse (по имени функции из вызова).
+    """
+    contents = []
+    call_names = {}
+    previous_role = None
+    for message in messages:
+        if message['role'] == 'tool':
+            part = {"functionResponse": {"name": call_names.get(message['tool_call_id'], ''), "response": {"result": message['content']}}}
+            # Результаты нескольких вызовов подряд передаются одним сообщением
+            if previous_role == 'tool':
+                contents[-1]['parts'].append(part)
+            else:
+                contents.append({"role": "user", "parts": [part]})
+        elif message.get('tool_calls'):
+            parts = [{"text": message['content']}] if message.get('content') else []
+            for call in message['tool_calls']:
+                call_names[call['id']] = call['function']['name']
+                parts.append({"functionCall": {"name": call['function']['name'], "args": json.loads(call['function']['arguments'] or '{}')}})
+            contents.append({"role": "model", "parts": parts})
+        else:
+            role = 'model' if message['role'] == 'assistant' else 'user'
+            contents.append({"role": role, "parts": _to_gemini_parts(message['content'])})
+        previous_role = message['role']
+    return contents
+
+def _to_gemini_request(messages: list, options: dict) -> dict:
+    """Тело запроса generateContent из параметров chat.completions. Системные сообщения передаются в systemInstruction."""
+    payload = {
+        "contents": _to_gemini_contents([message for message in messages if message['role'] != 'system']),
+        "safetySettings": [{"category": category, "threshold": GEMINI_SAFETY_THRESHOLD} for category in _GEMINI_SAFETY_CATEGORIES],
+    }
+    system = [message['content'] for message in messages if message['role'] == 'system']
+    if system:
+        payload['systemInstruction'] = {"parts": [{"text": '\n\n'.join(system)}]}
+    generation_config = {}
+    if options.get('temperature') is not None:
+        generation_config['temperature'] = options['temperature']
+    if options.get('max_tokens'):
+        generation_config['maxOutputTokens'] = options['max_tokens']
+    if (options.get('response_format') or {}).get('type') == 'json_object':
+        generation_config['responseMimeType'] = 'application/json'
+    if generation_config:
+        payload['generationConfig'] = generation_config
+    if options.get('tools'):
+        payload['tools'] = [{"functionDeclarations": [
+            {"name": tool['function']['name'], "description": tool['function'].get('description', ''),
+             "parameters": _to_gemini_schema(tool['function'].get('parameters') or {"type": "object", "properties": {}})}
+            for tool in options['tools']
+        ]}]
+        if options.get('tool_choice') == 'none':
+            payload['toolConfig'] = {"functionCallingConfig": {"mode": "NONE"}}
+    return payload
+
+def _gemini_usage(data: dict) -> dict:
+    usage = data.get('usageMetadata') or {}
+    return _to_openai_usage(usage.get('promptTokenCount', 0), usage.get('candidatesTokenCount', 0))
+
+def _from_gemini_response(data: dict, model: str) -> ChatCompletion:
+    """Ответ generateContent. Если запрос заблокирован фильтрами безопасности, текст ответа пустой (finish_reason content_filter)."""
+    candidate = (data.get('candidates') or [{}])[0]
+    parts = (candidate.get('content') or {}).get('parts') or []
+    text = ''.join(part['text'] for part in parts if 'text' in part)
+    tool_calls = [
+        _to_tool_call(f"call_{index}", part['functionCall']['name'], part['functionCall'].get('args'))
+        for index, part in enumerate(parts) if 'functionCall' in part
+    ]
+    if tool_calls:
+        finish_reason = 'tool_calls'
+    elif (data.get('promptFeedback') or {}).get('blockReason'):
+        finish_reason = 'content_filter'
+    else:
+        finish_reason = _GEMINI_FINISH_REASONS.get(candidate.get('finishReason'), 'stop')
+    return _to_chat_completion(data.get('responseId', ''), model, text, tool_calls, finish_reason, _gemini_usage(data))
+
+async def _read_gemini_stream(session: aiohttp.ClientSession, response: aiohttp.ClientResponse, model: str):
+    """
+    Переводит SSE-поток streamGenerateContent во фрагменты chat.completions. Каждое событие - частичный ответ
+    с новым текстом; расход токенов передается последним фрагментом. Закрывает сессию.
+    """
+    message_id, usage = '', _to_openai_usage(0, 0)
+    async with session:
+        async for event in _iter_sse_events(response):
+            message_id = event.get('responseId', message_id)
+            if event.get('usageMetadata'):
+                usage = _gemini_usage(event)
+            candidate = (event.get('candidates') or [{}])[0]
+            text = ''.join(part.get('text', '') for part in (candidate.get('content') or {}).get('parts') or [])
+            if text:
+                yield _to_chunk(message_id, model, text)
+    yield _to_chunk(message_id, model, usage=usage)
+
+
+class GeminiProvider(Provider):
+    """Google Gemini API: generateContent / streamGenerateContent и список моделей (адрес вида .../v1beta)."""
+    def __init__(self, name: str, api_url: str, api_key: str):
+        super().__init__(name)
+        self.api_url = api_url.rstrip('/')
+        self.api_key = api_key
+
+    def _headers(self) -> dict:
+        return {"x-goog-api-key": self.api_key, "Content-Type": "application/json"}
+
+    async def chat(self, model: str, messages: list, max_retries: int | None = None, **kwargs):
+        """Повторов у прямых HTTP-запросов нет, поэтому max_retries не используется."""
+        stream = bool(kwargs.get('stream'))
+        url = f"{self.api_url}/models/{model}:" + ('streamGenerateContent?alt=sse' if stream else 'generateContent')
+        session, response = await _post_json(
+            url, self._headers(), _to_gemini_request(messages, kwargs), kwargs.get('timeout') or 120.0, stream
+        )
+        if stream:
+            return _read_gemini_stream(session, response, model)
+        async with session:
+            return _from_gemini_response(await response.json(), model)
+
+    async def list_models(self) -> set[str]:
+        data = await _get_json(f"{self.api_url}/models", self._headers(), {"pageSize": 1000})
+        return {model['name'].removeprefix('models/') for model in data.get('models', [])}
+
+
+PROVIDER_TYPES = {
+    'openai': OpenAICompatibleProvider,
+    'anthropic': AnthropicProvider,
+    'gemini': GeminiProvider,
+}
+
+
+class ProviderRegistry:
+    """Провайдеры по имени и выбор провайдера для модели."""
+    def __init__(self, providers: dict[str, Provider], model_providers: dict[str, str], default: str):
+        self.providers = providers
+        self.model_providers = model_providers
+        self.default = default
+
+    def for_model(self, model: str) -> Provider:
+        return self.providers[self.model_providers.get(model, self.default)]
+
+    async def list_models(self) -> set[str]:
+        """Модели всех провайдеров. Недоступный провайдер пропускается; если недоступны все - исключение."""
+        results = await asyncio.gather(*(provider.list_models() for provider in self.providers.values()), return_exceptions=True)
+        models, errors = set(), []
+        for provider, result in zip(self.providers.values(), results):
+            if isinstance(result, Exception):
+                logger.warning(f"Failed to list models of provider {provider.name}: {result}")
+                errors.append(result)
+            else:
+                models |= result
+        if errors and len(errors) == len(results):
+            raise errors[0]
+        return models
+
+
+def create_providers() -> ProviderRegistry:
+    """Создает провайдеров из AI_PROVIDERS. При ошибке в конфигурации вызывает ValueError."""
+    providers = {}
+    for name, settings in AI_PROVIDERS.items():
+        provider_type = PROVIDER_TYPES.get(settings['type'])
+        if provider_type is None:
+            raise ValueError(f"Unknown type '{settings['type']}' of AI provider '{name}'")
+        if not settings['api_url']:
+            raise ValueError(f"AI provider '{name}' has no API URL")
+        providers[name] = provider_type(name, settings['api_url'], settings['api_key'])
+
+    unknown = {provider for provider in MODEL_PROVIDERS.values() if provider not in providers}
+    if unknown:
+        raise ValueError(f"MODEL_PROVIDERS refers to unknown AI providers: {', '.join(sorted(unknown))}")
+
+    # Провайдер из описания модели используется, только если он настроен; MODEL_PROVIDERS важнее
+    model_providers = {}
+    for model, info in MODEL_INFO.items():
+        if info.provider in providers:
+            model_providers[model] = info.provider
+        elif info.provider:
+            logger.warning(f"AI provider '{info.provider}' of model {model} is not configured, using {DEFAULT_PROVIDER}")
+    model_providers.update(MODEL_PROVIDERS)
+    if len(providers) > 1:
+        logger.info(f"AI providers: {', '.join(providers)} (default: {DEFAULT_PROVIDER}).")
+    return ProviderRegistry(providers, model_providers, DEFAULT_PROVIDER)
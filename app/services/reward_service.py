@@ -0,0 +1,84 @@
+# app/services/reward_service.py
+# Бонус за подписку на каналы. Пользователь должен оставаться подписанным: периодическая проверка
+# при отписке предупреждает его и дает REWARD_REJOIN_GRACE_HOURS часов, чтобы вернуться,
+# после чего бонус отзывается.
+
+import asyncio
+import logging
+from datetime import datetime, timedelta, timezone
+
+from aiogram import Bot
+
+from app.config import REWARD_CHANNELS, QUOTA_REWARD_LIMIT, REWARD_REJOIN_GRACE_HOURS
+from app.database import Database
+from app.keyboards.inline import get_reward_menu
+from app.services.notification_service import send_notification
+from app.services.text_service import format_quota
+from app.services.user_service import invalidate_user_cache
+
+logger = logging.getLogger(__name__)
+
+MEMBER_STATUSES = ('member', 'administrator', 'creator')
+
+
+async def get_missing_channels(bot: Bot, user_id: int) -> list[dict]:
+    """Каналы, на которые пользователь не подписан. Ошибки Telegram API пробрасываются."""
+    missing = []
+    for channel in REWARD_CHANNELS:
+        member = await bot.get_chat_member(chat_id=channel['id'], user_id=user_id)
+        if member.status not in MEMBER_STATUSES:
+            missing.append(channel)
+    return missing
+
+async def _check_user(bot: Bot, db: Database, cache: dict, user_id: int, revoke_at: str | None, now_utc: datetime) -> str | None:
+    """Проверяет одного пользователя. Возвращает выполненное действие: warned, revoked, restored или None."""
+    missing = await get_missing_channels(bot, user_id)
+    if not missing:
+        if revoke_at:
+            await db.set_reward_revoke_at(user_id, None)
+            return 'restored'
+        return None
+
+    if not revoke_at:
+        await db.set_reward_revoke_at(user_id, now_utc + timedelta(hours=REWARD_REJOIN_GRACE_HOURS))
+        names = ", ".join(channel['name'] for channel in missing)
+        await send_notification(
+            bot, db, user_id,
+            f"⚠️ Вы отписались от канала {names}.\n\n"
+            f"Бонусный лимит ({format_quota(QUOTA_REWARD_LIMIT)} в день) действует, пока вы подписаны на каналы. "
+            f"Подпишитесь снова в течение {REWARD_REJOIN_GRACE_HOURS} ч. и нажмите кнопку проверки, "
+            "иначе бонус будет отключен.",
+            reply_markup=get_reward_menu(REWARD_CHANNELS)
+        )
+        return 'warned'
+
+    if datetime.fromisoformat(revoke_at) > now_utc:
+        return None
+    await db.revoke_reward_bonus(user_id)
+    invalidate_user_cache(user_id, cache)
+    await send_notification(
+        bot, db, user_id,
+        "Бонусный лимит отключен: вы не вернулись в каналы программы. "
+        "Подпишитесь снова, чтобы получить его заново.",
+        reply_markup=get_reward_menu(REWARD_CHANNELS)
+    )
+    return 'revoked'
+
+async def verify_reward_memberships(bot: Bot, db: Database, cache: dict):
+    """Перепроверяет подписки всех получивших бонус. Запускается планировщиком."""
+    if not REWARD_CHANNELS:
+        return
+    now_utc = datetime.now(timezone.utc)
+    results = {'warned': 0, 'revoked': 0, 'restored': 0}
+    users = await db.get_rewarded_users()
+    for user_id, revoke_at in users:
+        try:
+            action = await _check_user(bot, db, cache, user_id, revoke_at, now_utc)
+        except Exception as e:
+            # Проверка не удалась (например, бот потерял доступ к каналу) - пользователя не трогаем
+            logger.warning(f"Reward membership check failed for user {user_id}: {e}")
+            continue
+        if action:
+            results[action] += 1
+        await asyncio.sleep(0.1) # Не упираемся в лимиты Bot API при большом числе пользователей
+    logger.info(f"Reward membership check finished for {len(users)} users: {results}")
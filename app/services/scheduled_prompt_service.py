@@ -0,0 +1,136 @@
+# app/services/scheduled_prompt_service.py
+# Запланированные запросы: пользователь задает промпт и расписание ("пн 9:00"),
+# а планировщик раз в минуту выполняет наступившие запросы и присылает ответы.
+# Время хранится в часовом поясе пользователя, next_run_at - в UTC.
+
+import logging
+import re
+from datetime import datetime, timedelta, timezone
+
+from aiogram import Bot
+
+from app.config import SCHEDULED_PROMPTS_MIN_LEVEL
+from app.database import Database
+from app.services.ai_service import get_simple_response
+from app.services.notification_service import send_with_retry
+from app.services.model_service import can_answer
+from app.services.text_service import split_message
+from app.services.markdown_service import markdown_to_html
+from app.services.trace_service import new_request_id, format_error_code
+from app.services.user_service import (
+    get_user_level, get_user_limits, get_usage_today, get_user_details_cached, get_accessible_models, get_utc_offset
+)
+
+logger = logging.getLogger(__name__)
+
+WEEKDAY_NAMES = ['пн', 'вт', 'ср', 'чт', 'пт', 'сб', 'вс']
+WEEKDAY_ALIASES = {
+    'ежедневно': None, 'каждый день': None,
+    'будни': [0, 1, 2, 3, 4],
+    'выходные': [5, 6],
+}
+_SCHEDULE_RE = re.compile(r'^(?:(?P<days>.+?)\s+)?(?P<hour>\d{1,2})[:.](?P<minute>\d{2})$')
+
+
+def parse_schedule(text: str) -> tuple[list[int] | None, int, int]:
+    """
+    Разбирает расписание вида "пн,ср 9:00", "будни 18:30" или "ежедневно 8:00" (просто "8:00" - тоже ежедневно).
+    Возвращает (дни недели или None для каждого дня, час, минута). При ошибке - ValueError.
+    """
+    match = _SCHEDULE_RE.match(text.strip().lower())
+    if not match:
+        raise ValueError("invalid schedule format")
+    hour, minute = int(match['hour']), int(match['minute'])
+    if not (0 <= hour <= 23 and 0 <= minute <= 59):
+        raise ValueError("invalid time")
+
+    days = (match['days'] or 'ежедневно').strip()
+    if days in WEEKDAY_ALIASES:
+        return WEEKDAY_ALIASES[days], hour, minute
+    weekdays = sorted({WEEKDAY_NAMES.index(day.strip()) for day in days.split(',') if day.strip() in WEEKDAY_NAMES})
+    if not weekdays or len(weekdays) != len([d for d in days.split(',') if d.strip()]):
+        raise ValueError("unknown weekday")
+    return (None if len(weekdays) == 7 else weekdays), hour, minute
+
+def weekdays_to_str(weekdays: list[int] | None) -> str | None:
+    return ','.join(map(str, weekdays)) if weekdays is not None else None
+
+def weekdays_from_str(value: str | None) -> list[int] | None:
+    return [int(day) for day in value.split(',')] if value else None
+
+def format_schedule(weekdays: str | None, hour: int, minute: int) -> str:
+    days = weekdays_from_str(weekdays)
+    if days is None:
+        days_str = "ежедневно"
+    elif days == WEEKDAY_ALIASES['будни']:
+        days_str = "по будням"
+    elif days == WEEKDAY_ALIASES['выходные']:
+        days_str = "по выходным"
+    else:
+        days_str = ", ".join(WEEKDAY_NAMES[day] for day in days)
+    return f"{days_str} в {hour:02d}:{minute:02d}"
+
+def compute_next_run(weekdays: str | None, hour: int, minute: int, utc_offset: int, after: datetime | None = None) -> datetime:
+    """Ближайший момент выполнения (в UTC) строго после after."""
+    local_tz = timezone(timedelta(hours=utc_offset))
+    local_now = (after or datetime.now(timezone.utc)).astimezone(local_tz)
+    days = weekdays_from_str(weekdays)
+    candidate = local_now.replace(hour=hour, minute=minute, second=0, microsecond=0)
+    for shift in range(8):
+        run_at = candidate + timedelta(days=shift)
+        if run_at > local_now and (days is None or run_at.weekday() in days):
+            return run_at.astimezone(timezone.utc)
+    raise ValueError("schedule has no weekdays")
+
+async def _send_chunks(bot: Bot, user_id: int, text: str) -> bool:
+    delivered = True
+    for chunk in split_message(text):
+        delivered = await send_with_retry(bot, user_id, chunk) and delivered
+    return delivered
+
+async def execute_scheduled_prompt(bot: Bot, db: Database, ai_client, cache: dict, scheduled: tuple):
+    """Выполняет один запрос. Расписание сдвигается заранее, чтобы сбой не приводил к повторам."""
+    prompt_id, user_id, prompt, model, weekdays, hour, minute = scheduled
+    new_request_id()
+    next_run_at = compute_next_run(weekdays, hour, minute, await get_utc_offset(db, user_id))
+    await db.set_scheduled_prompt_run(prompt_id, next_run_at)
+    header = f"⏰ <b>Запланированный запрос</b> ({format_schedule(weekdays, hour, minute)})\n"
+
+    details = await get_user_details_cached(user_id, db, cache)
+    if not details or details[4]:
+        return
+    user_level = await get_user_level(user_id, db)
+    if user_level < SCHEDULED_PROMPTS_MIN_LEVEL:
+        # Подписка закончилась: отключаем запрос, чтобы не напоминать о нем каждый раз
+        await db.set_scheduled_prompt_enabled(prompt_id, False, None)
+        await send_with_retry(bot, user_id, header + "Запланированные запросы доступны с тарифа Premium, запрос отключен.")
+        return
+
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        await send_with_retry(bot, user_id, header + "Не выполнен: достигнут дневной лимит запросов.")
+        return
+    if not can_answer(model, get_accessible_models(user_level, await db.is_beta_tester(user_id)), cache):
+        await send_with_retry(bot, user_id, header + f"Не выполнен: модель <b>{model}</b> сейчас недоступна.")
+        return
+
+    try:
+        response_text, _, usage = await get_simple_response(ai_client, model, [{"role": "user", "content": prompt}], user_id, db, cache)
+    except Exception as e:
+        logger.error(f"Scheduled prompt #{prompt_id} failed for user {user_id}: {e}")
+        await send_with_retry(bot, user_id, header + f"Не выполнен: модель <b>{model}</b> вернула ошибку." + format_error_code())
+        return
+
+    answered_model = usage.model or model
+    await db.add_request(user_id, answered_model, is_max_mode=False, tokens=usage.tokens)
+    model_line = answered_model if answered_model == model else f"{answered_model} (вместо {model}, которая не ответила)"
+    await _send_chunks(bot, user_id, f"{header}Модель: {model_line}\n\n{markdown_to_html(response_text)}")
+    logger.info(f"Scheduled prompt #{prompt_id} executed for user {user_id}")
+
+async def run_scheduled_prompts(bot: Bot, db: Database, ai_client, cache: dict):
+    """Выполняет наступившие запланированные запросы. Запускается планировщиком раз в минуту."""
+    for scheduled in await db.get_due_scheduled_prompts():
+        try:
+            await execute_scheduled_prompt(bot, db, ai_client, cache, scheduled)
+        except Exception as e:
+            logger.error(f"Unexpected error in scheduled prompt #{scheduled[0]}: {e}", exc_info=True)
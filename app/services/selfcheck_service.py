@@ -0,0 +1,110 @@
+# app/services/selfcheck_service.py
+# Самопроверка перед запуском (python bot.py --check): конфигурация, БД, миграции
+# (на копии БД), хранилище состояний, Bot API и провайдеры моделей.
+# Используется в пайплайнах деплоя и health-проверках контейнера: код выхода 0 - все в порядке.
+
+import asyncio
+import os
+import shutil
+import tempfile
+
+import aiosqlite
+from aiogram import Bot
+
+from app.config import BOT_TOKEN, API_KEY, API_URL, DATABASE_PATH, FSM_STORAGE
+from app.database import Database
+from app.services.network_service import create_telegram_session
+from app.services.provider_service import create_providers
+from app.storage import create_fsm_storage
+
+CHECK_TIMEOUT = 20 # Таймаут каждой сетевой проверки, сек.
+
+
+def _report(name: str, ok: bool, details: str = '') -> bool:
+    print(f"[{'OK' if ok else 'FAIL'}] {name}{': ' + details if details else ''}")
+    return ok
+
+async def _get_schema(db_path: str) -> dict:
+    """Возвращает {таблица: множество столбцов}."""
+    async with aiosqlite.connect(db_path) as db:
+        tables = [row[0] for row in await (await db.execute("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'")).fetchall()]
+        schema = {}
+        for table in tables:
+            schema[table] = {row[1] for row in await (await db.execute(f'PRAGMA table_info({table})')).fetchall()}
+        return schema
+
+async def check_config() -> bool:
+    missing = [name for name, value in (('BOT_TOKEN', BOT_TOKEN), ('API_KEY', API_KEY), ('API_URL', API_URL)) if not value]
+    return _report("Config", not missing, f"missing {', '.join(missing)}" if missing else '')
+
+async def check_database() -> bool:
+    """Открывает БД и прогоняет создание таблиц и миграции на ее копии, не меняя рабочий файл."""
+    if not os.path.exists(DATABASE_PATH):
+        return _report("Database", True, f"{DATABASE_PATH} does not exist yet and will be created on start")
+    try:
+        async with aiosqlite.connect(DATABASE_PATH) as db:
+            await db.execute('SELECT 1')
+        current = await _get_schema(DATABASE_PATH)
+        with tempfile.TemporaryDirectory() as tmp_dir:
+            copy_path = os.path.join(tmp_dir, 'check.db')
+            shutil.copyfile(DATABASE_PATH, copy_path)
+            await Database(copy_path).init_db()
+            migrated = await _get_schema(copy_path)
+    except Exception as e:
+        return _report("Database", False, str(e))
+
+    _report("Database", True, DATABASE_PATH)
+    changes = [f"table {table}" for table in migrated if table not in current]
+    changes += [
+        f"column {table}.{column}"
+        for table, columns in migrated.items() if table in current
+        for column in sorted(columns - current[table])
+    ]
+    return _report("Migrations (dry run)", True, f"pending: {', '.join(changes)}" if changes else "schema is up to date")
+
+async def check_fsm_storage() -> bool:
+    try:
+        storage = create_fsm_storage(Database(DATABASE_PATH))
+        if FSM_STORAGE == 'redis':
+            await asyncio.wait_for(storage.redis.ping(), CHECK_TIMEOUT)
+        await storage.close()
+    except Exception as e:
+        return _report("FSM storage", False, str(e))
+    return _report("FSM storage", True, FSM_STORAGE)
+
+async def check_telegram() -> bool:
+    bot = Bot(token=BOT_TOKEN, session=create_telegram_session())
+    try:
+        me = await asyncio.wait_for(bot.get_me(), CHECK_TIMEOUT)
+    except Exception as e:
+        return _report("Telegram Bot API", False, str(e))
+    finally:
+        await bot.session.close()
+    return _report("Telegram Bot API", True, f"@{me.username}")
+
+async def check_ai_providers() -> bool:
+    try:
+        registry = create_providers()
+    except ValueError as e:
+        return _report("AI providers", False, str(e))
+    ok = True
+    for name, provider in registry.providers.items():
+        try:
+            models = await asyncio.wait_for(provider.list_models(), CHECK_TIMEOUT)
+        except Exception as e:
+            ok = _report(f"AI provider {name}", False, str(e)) and ok
+            continue
+        _report(f"AI provider {name}", True, f"{len(models)} models available")
+    return ok
+
+async def run_self_check() -> int:
+    """Выполняет все проверки и возвращает код выхода."""
+    if not await check_config():
+        return 1
+    results = [
+        await check_database(),
+        await check_fsm_storage(),
+        await check_telegram(),
+        await check_ai_providers(),
+    ]
+    return 0 if all(results) else 1
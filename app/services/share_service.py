@@ -0,0 +1,49 @@
+# app/services/share_service.py
+# Ссылки на беседы только для чтения. При публикации сохраняется снимок беседы,
+# поэтому дальнейшая переписка владельца по ссылке не видна. Владелец может отозвать ссылку.
+
+import html
+import json
+import secrets
+
+from aiogram import Bot
+from aiogram.utils.deep_linking import create_start_link
+
+from app.config import get_model_display_name
+from app.database import Database
+from app.services.conversation_service import to_api_messages
+from app.services.text_service import split_text
+from app.services.format_service import format_datetime
+
+# Префикс параметра /start для ссылок на беседы
+SHARE_PREFIX = 'share_'
+# Запас до лимита Telegram в 4096 символов на сообщение (с учетом экранирования и подписи)
+MESSAGE_CHUNK_SIZE = 3500
+
+
+async def create_share_link(bot: Bot, db: Database, user_id: int, model: str, history: list) -> tuple[str, str]:
+    """Сохраняет снимок беседы и возвращает (token, ссылка для открытия в боте)."""
+    token = secrets.token_urlsafe(12)
+    await db.add_shared_conversation(token, user_id, model, json.dumps(to_api_messages(history), ensure_ascii=False))
+    return token, await create_start_link(bot, SHARE_PREFIX + token)
+
+async def get_share_link(bot: Bot, token: str) -> str:
+    return await create_start_link(bot, SHARE_PREFIX + token)
+
+async def render_shared_conversation(db: Database, token: str) -> list[str] | None:
+    """Возвращает беседу по ссылке в виде сообщений для отправки или None, если ссылка недействительна."""
+    shared = await db.get_shared_conversation(token)
+    if not shared:
+        return None
+
+    _, model, messages_json, created_at = shared
+    created_str = format_datetime(created_at)
+    parts = [
+        f"🔗 <b>Беседа с моделью {get_model_display_name(model)}</b>\n"
+        f"<i>Только для чтения. Опубликована {created_str} МСК.</i>"
+    ]
+    for item in json.loads(messages_json):
+        author = "👤 <b>Пользователь</b>" if item['role'] == 'user' else "🤖 <b>Модель</b>"
+        for chunk in split_text(item['content'], MESSAGE_CHUNK_SIZE):
+            parts.append(f"{author}\n{html.escape(chunk)}")
+    return parts
@@ -0,0 +1,76 @@
+# app/services/survey_service.py
+# Опросы пользователей: рассылка по сегментам и сводка результатов.
+
+import asyncio
+import logging
+
+from aiogram import Bot
+from aiogram.utils.markdown import hcode
+
+from app.database import Database
+from app.keyboards.inline import get_survey_menu
+from app.services.notification_service import send_notification
+
+logger = logging.getLogger(__name__)
+
+SEGMENTS = {
+    'all': "все пользователи",
+    'free': "пользователи без подписки",
+    'paid': "подписчики",
+    'active': "активные за неделю",
+}
+SEND_DELAY = 0.1
+
+
+async def send_survey(bot: Bot, db: Database, survey_id: int, segment: str, admin_id: int):
+    """Рассылает опрос пользователям сегмента. Запускается через планировщик."""
+    _, question, kind, options = await db.get_survey(survey_id)
+    text = f"📋 <b>Опрос</b>\n\n{question}"
+    if kind == 'nps':
+        text += "\n\n<i>0 - точно нет, 10 - обязательно порекомендую</i>"
+    reply_markup = get_survey_menu(survey_id, kind, options)
+
+    user_ids = await db.get_segment_user_ids(segment)
+    sent = 0
+    for user_id in user_ids:
+        if await send_notification(bot, db, user_id, text, reply_markup=reply_markup):
+            sent += 1
+        await asyncio.sleep(SEND_DELAY)
+
+    logger.info(f"Survey #{survey_id} sent to {sent} of {len(user_ids)} users in segment '{segment}'")
+    try:
+        await bot.send_message(admin_id, f"✅ Опрос #{survey_id} отправлен: {sent} из {len(user_ids)} ({SEGMENTS[segment]}).")
+    except Exception as e:
+        logger.warning(f"Failed to send survey report to admin {admin_id}: {e}")
+
+def calculate_nps(counts: dict) -> float | None:
+    """NPS = % промоутеров (9-10) - % критиков (0-6)."""
+    total = sum(counts.values())
+    if not total:
+        return None
+    promoters = sum(count for score, count in counts.items() if score >= 9)
+    detractors = sum(count for score, count in counts.items() if score <= 6)
+    return (promoters - detractors) * 100 / total
+
+async def format_survey_results(db: Database, survey_id: int) -> str:
+    survey = await db.get_survey(survey_id)
+    if not survey:
+        return f"Опрос #{survey_id} не найден."
+    _, question, kind, options = survey
+    rows = await db.get_survey_answer_counts(survey_id)
+    total = sum(count for _, count in rows)
+    lines = [f"<b>#{survey_id}</b> {question}", f"Ответов: {total}"]
+
+    if kind == 'nps':
+        nps = calculate_nps({int(answer): count for answer, count in rows})
+        lines.append(f"NPS: <b>{nps:+.0f}</b>" if nps is not None else "NPS: —")
+    elif kind == 'choice':
+        counts = dict(rows)
+        for option in options:
+            count = counts.get(option, 0)
+            percent = count * 100 / total if total else 0
+            lines.append(f" • {option}: {count} ({percent:.0f}%)")
+    else:
+        for answer in await db.get_survey_text_answers(survey_id, limit=5):
+            lines.append(f" • {hcode(answer[:200])}")
+    return "\n".join(lines)
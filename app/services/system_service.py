@@ -2,74 +2,118 @@
 # Логика, связанная с состоянием системы, например, проверка моделей.
 
 import asyncio
+import html
 import json
 import logging
+import random
+import time
 from datetime import datetime, timezone, timedelta
 from typing import Dict, List
 
-import aiohttp
-from openai import AsyncOpenAI, APIError
 from aiogram.utils.markdown import hcode
 
+from app.keyboards.inline import get_retry_request_menu
+from app.services.provider_service import ProviderRegistry
+
 # --- ИСПРАВЛЕНИЕ ЗДЕСЬ ---
 # Убираем локальное создание MSK_TZ и импортируем его из config
 from app.config import (
     MODEL_CATEGORIES, IMAGE_MODELS, MAX_MODE_PARTICIPANTS, MAX_MODE_ARBITER,
-    API_URL, API_KEY, MSK_TZ
+    MSK_TZ, ADMIN_IDS, BOT_VERSION, BOT_COMMIT,
+    NOTIFY_USERS_ON_UPDATE, UPDATE_BANNER_DAYS, MODEL_ALERT_CONFIRM_RUNS, MODEL_ALERT_COOLDOWN_MINUTES,
+    MODEL_STATUS_HISTORY_DAYS, CIRCUIT_FAILURE_THRESHOLD, CIRCUIT_OPEN_MINUTES, MODEL_CHECK_INTERVAL_MINUTES,
+    MODEL_CHECK_SPREAD_SECONDS
 )
 
 logger = logging.getLogger(__name__)
 
+# --- Управление уровнем логирования ---
+
+# В стандартном logging нет уровня TRACE, регистрируем его ниже DEBUG
+TRACE_LEVEL = 5
+logging.addLevelName(TRACE_LEVEL, "TRACE")
+
+LOG_LEVELS = {
+    'error': logging.ERROR,
+    'warn': logging.WARNING,
+    'info': logging.INFO,
+    'debug': logging.DEBUG,
+    'trace': TRACE_LEVEL,
+}
+
+def set_log_level(level_name: str, target: str | None = None) -> bool:
+    """
+    Меняет уровень логирования во время работы бота.
+    Если target не указан, меняется уровень корневого логгера.
+    Возвращает False, если уровень неизвестен.
+    """
+    level = LOG_LEVELS.get(level_name.lower())
+    if level is None:
+        return False
+    logging.getLogger(target).setLevel(level)
+    logger.warning(f"Log level for '{target or 'root'}' changed to {level_name.upper()}")
+    return True
+
+def get_log_levels() -> Dict[str, str]:
+    """Возвращает текущие уровни корневого логгера и всех явно настроенных логгеров."""
+    levels = {'root': logging.getLevelName(logging.getLogger().level)}
+    for name, item in sorted(logging.Logger.manager.loggerDict.items()):
+        if isinstance(item, logging.Logger) and item.level != logging.NOTSET:
+            levels[name] = logging.getLevelName(item.level)
+    return levels
+
 # --- Функции проверки моделей ---
 
-async def test_chat_model(ai_client: AsyncOpenAI, model: str) -> dict:
-    """Тестирует доступность текстовой модели."""
-    try:
-        await ai_client.chat.completions.create(
-            model=model, messages=[{'role': 'user', 'content': 'Test'}],
-            temperature=0.7, max_tokens=10, timeout=20.0
-        )
-        return {'model': model, 'status': 'OK'}
-    except APIError as e:
-        logger.warning(f"Model {model} test failed with APIError: {e.status_code}")
-        return {'model': model, 'status': f'API Error {e.status_code}'}
-    except asyncio.TimeoutError:
-        logger.warning(f"Model {model} test timed out.")
-        return {'model': model, 'status': 'Timeout'}
-    except Exception as e:
-        logger.error(f"Model {model} test failed with unexpected error: {e}", exc_info=True)
-        return {'model': model, 'status': f'Error: {type(e).__name__}'}
-
-async def test_image_model(model: str) -> dict:
-    """Тестирует доступность модели для генерации изображений."""
-    async with aiohttp.ClientSession() as session:
-        url = f"{API_URL}/images/generations"
-        headers = {"Authorization": f"Bearer {API_KEY}", "Content-Type": "application/json"}
-        payload = {"model": model, "prompt": "Test", "height": 512, "width": 512, "n": 1, "response_format": "url"}
-        try:
-            async with session.post(url, headers=headers, json=payload, timeout=45) as response:
-                if response.status == 200:
-                    return {'model': model, 'status': 'OK'}
-                else:
-                    logger.warning(f"Image model {model} test failed with status {response.status}")
-                    return {'model': model, 'status': f'Error {response.status}'}
-        except asyncio.TimeoutError:
-            logger.warning(f"Image model {model} test timed out.")
-            return {'model': model, 'status': 'Timeout'}
-        except Exception as e:
-            logger.error(f"Image model {model} test failed with unexpected error: {e}", exc_info=True)
-            return {'model': model, 'status': f'Error: {type(e).__name__}'}
+async def test_chat_model(ai_client: ProviderRegistry, model: str) -> dict:
+    """Тестирует доступность текстовой модели у ее провайдера."""
+    return {'model': model, 'status': await ai_client.for_model(model).health_check(model, 'chat')}
+
+async def test_image_model(ai_client: ProviderRegistry, model: str) -> dict:
+    """Тестирует доступность модели для генерации изображений у ее провайдера."""
+    return {'model': model, 'status': await ai_client.for_model(model).health_check(model, 'image')}
+
+async def _delayed(delay: float, probe):
+    await asyncio.sleep(delay)
+    return await probe
+
+def get_probe_delays(count: int, spread_seconds: float) -> List[float]:
+    """
+    Задержки перед тестовыми запросами: окно spread_seconds делится на count равных частей,
+    и каждый запрос отправляется в случайный момент своей части. Так запросы не совпадают по времени,
+    но и не уходят в одну и ту же секунду каждой проверки.
+    """
+    if count == 0 or spread_seconds <= 0:
+        return [0.0] * count
+    # Проверка должна закончиться до начала следующей, с запасом на таймауты запросов
+    spread_seconds = min(spread_seconds, MODEL_CHECK_INTERVAL_MINUTES * 60 / 2)
+    slot = spread_seconds / count
+    return [(i + random.random()) * slot for i in range(count)]
 
 # --- Основные функции управления состоянием ---
 
+def is_circuit_open(model_name: str, cache: Dict) -> bool:
+    """Модель отключена после CIRCUIT_FAILURE_THRESHOLD сбоев подряд и еще не истекли CIRCUIT_OPEN_MINUTES."""
+    breaker = cache.get("circuit_breaker", {}).get(model_name)
+    return bool(breaker) and breaker['open_until'] > time.monotonic()
+
 def is_model_available(model_name: str, cache: Dict) -> bool:
-    """Проверяет, доступна ли модель, по данным из кэша."""
+    """Проверяет, доступна ли модель: по результатам последней проверки и по числу недавних сбоев подряд."""
+    if is_circuit_open(model_name, cache):
+        return False
     model_status_cache = cache.get("model_status")
     if model_status_cache is None: return True # Если кэша нет, считаем доступной
 
     statuses = model_status_cache.get("statuses", {})
     return statuses.get(model_name, 'OK') == 'OK'
 
+def get_model_statuses(cache: Dict) -> Dict[str, str]:
+    """Статусы моделей для меню: результаты последней проверки, у отключенных после сбоев - 'FAILED'."""
+    statuses = dict(cache.get("model_status", {}).get("statuses", {}))
+    for model_name in cache.get("circuit_breaker", {}):
+        if is_circuit_open(model_name, cache):
+            statuses[model_name] = 'FAILED'
+    return statuses
+
 def are_max_mode_models_available(cache: Dict) -> bool:
     """Проверяет, доступны ли ВСЕ модели, необходимые для Max Mode."""
     required_models = MAX_MODE_PARTICIPANTS + [MAX_MODE_ARBITER]
@@ -79,29 +123,90 @@ def are_max_mode_models_available(cache: Dict) -> bool:
             return False
     return True
 
-def set_model_failed_in_cache(model_name: str, cache: Dict):
-    """Помечает модель как недоступную в кэше до следующей проверки."""
-    model_status_cache = cache.get("model_status")
-    if model_status_cache is not None:
-        statuses = model_status_cache.get("statuses", {})
-        if statuses.get(model_name) != 'FAILED':
-            statuses[model_name] = 'FAILED'
-            model_status_cache["statuses"] = statuses
-            logger.warning(f"Circuit Breaker: Model {model_name} marked as FAILED in cache due to runtime error.")
+def record_model_failure(model_name: str, cache: Dict):
+    """
+    Учитывает сбой модели. После CIRCUIT_FAILURE_THRESHOLD сбоев подряд модель отключается
+    на CIRCUIT_OPEN_MINUTES минут; если пробный запрос после этого тоже неудачен, отключается снова.
+    """
+    breakers = cache.get("circuit_breaker")
+    if breakers is None:
+        return
+    breaker = breakers.setdefault(model_name, {'failures': 0, 'open_until': 0.0})
+    breaker['failures'] += 1
+    if breaker['failures'] >= CIRCUIT_FAILURE_THRESHOLD:
+        breaker['open_until'] = time.monotonic() + CIRCUIT_OPEN_MINUTES * 60
+        logger.warning(
+            f"Circuit breaker: model {model_name} disabled for {CIRCUIT_OPEN_MINUTES} min "
+            f"after {breaker['failures']} consecutive failures."
+        )
 
-async def scheduled_model_test(ai_client: AsyncOpenAI, db, cache: Dict):
+def record_model_success(model_name: str, cache: Dict):
+    """Успешный ответ сбрасывает счетчик сбоев модели."""
+    breaker = cache.get("circuit_breaker", {}).pop(model_name, None)
+    if breaker and breaker['failures'] >= CIRCUIT_FAILURE_THRESHOLD:
+        logger.info(f"Circuit breaker: model {model_name} is available again.")
+
+async def notify_model_status_changes(bot, db, current_statuses: Dict[str, str]):
     """
-    Запланированная задача для проверки всех моделей и обновления их статуса.
+    Уведомляет администраторов только о смене статуса модели (работала -> перестала и обратно).
+    Чтобы «мигающая» модель не засыпала админов сообщениями, новый статус должен продержаться
+    MODEL_ALERT_CONFIRM_RUNS проверок подряд, а уведомления об одной модели отправляются
+    не чаще раза в MODEL_ALERT_COOLDOWN_MINUTES.
     """
-    logger.info("Running scheduled model health check...")
+    recent: Dict[str, List[str]] = {}
+    for model, status in await db.get_recent_model_statuses(MODEL_ALERT_CONFIRM_RUNS):
+        recent.setdefault(model, []).append(status)
 
+    # Последний статус, о котором знают администраторы: {модель: {"ok": bool, "alerted_at": iso|None}}
+    alert_state_row = await db.get_system_state('model_alert_state')
+    alert_state = json.loads(alert_state_row[0]) if alert_state_row else {}
+    now = datetime.now(timezone.utc)
+    changes = []
+
+    for model, status in sorted(current_statuses.items()):
+        is_ok = status == 'OK'
+        known = alert_state.get(model, {"ok": True, "alerted_at": None})
+        if known["ok"] == is_ok:
+            continue
+        history = recent.get(model, [])
+        if len(history) < MODEL_ALERT_CONFIRM_RUNS or any((s == 'OK') != is_ok for s in history):
+            continue
+        if known["alerted_at"] and now - datetime.fromisoformat(known["alerted_at"]) < timedelta(minutes=MODEL_ALERT_COOLDOWN_MINUTES):
+            continue
+        alert_state[model] = {"ok": is_ok, "alerted_at": now.isoformat()}
+        changes.append(f"✅ {hcode(model)} снова работает" if is_ok else f"❌ {hcode(model)} перестала отвечать: {status}")
+
+    if not changes:
+        return
+    await db.set_system_state('model_alert_state', json.dumps(alert_state))
+
+    text = "<b>Изменился статус моделей</b>\n\n" + "\n".join(changes)
+    for admin_id in ADMIN_IDS:
+        try:
+            await bot.send_message(admin_id, text)
+        except Exception as e:
+            logger.warning(f"Failed to send model status alert to admin {admin_id}: {e}")
+    logger.info(f"Sent model status alert: {len(changes)} change(s).")
+
+async def scheduled_model_test(ai_client: ProviderRegistry, db, cache: Dict, bot=None,
+                               spread_seconds: float = MODEL_CHECK_SPREAD_SECONDS):
+    """
+    Запланированная задача для проверки всех моделей и обновления их статуса.
+    Тестовые запросы распределяются по spread_seconds секундам (см. get_probe_delays); статусы обновляются,
+    когда ответят все модели. Результаты сохраняются в историю; если передан bot,
+    администраторы получают уведомления о смене статусов.
+    """
     all_text_models = list(set(model for models in MODEL_CATEGORIES.values() for model in models))
     all_image_models = list(set(IMAGE_MODELS))
 
-    tasks = [test_chat_model(ai_client, m) for m in all_text_models]
-    tasks.extend([test_image_model(m) for m in all_image_models])
+    probes = [test_chat_model(ai_client, m) for m in all_text_models]
+    probes.extend([test_image_model(ai_client, m) for m in all_image_models])
+    # Порядок случайный, чтобы модели одного провайдера не шли подряд
+    random.shuffle(probes)
+    delays = get_probe_delays(len(probes), spread_seconds)
+    logger.info(f"Running scheduled model health check: {len(probes)} probes over {delays[-1] if delays else 0:.0f}s...")
 
-    results = await asyncio.gather(*tasks)
+    results = await asyncio.gather(*(_delayed(delay, probe) for delay, probe in zip(delays, probes)))
 
     current_statuses = {r['model']: r['status'] for r in results}
 
@@ -125,10 +230,15 @@ async def scheduled_model_test(ai_client: AsyncOpenAI, db, cache: Dict):
     await db.set_system_state('model_status', json.dumps(current_statuses))
     await db.set_system_state('last_report', report_text)
 
+    await db.add_model_status_history(current_statuses)
+    await db.delete_old_model_status_history(MODEL_STATUS_HISTORY_DAYS)
+    if bot is not None:
+        await notify_model_status_changes(bot, db, current_statuses)
+
     logger.info("Scheduled model health check finished. State saved to cache and DB.")
 
 
-async def startup_model_check(ai_client: AsyncOpenAI, db, cache: Dict):
+async def startup_model_check(ai_client: ProviderRegistry, db, cache: Dict):
     """
     Проверка статуса моделей при запуске бота.
     Сначала пытается загрузить свежие данные из БД, если их нет - запускает полную проверку.
@@ -144,7 +254,7 @@ async def startup_model_check(ai_client: AsyncOpenAI, db, cache: Dict):
         try:
             status_timestamp = datetime.fromisoformat(status_timestamp_str)
             # Если данные в БД "свежие" (меньше 10 минут)
-            if datetime.now(timezone.utc) - status_timestamp < timedelta(minutes=10):
+            if datetime.now(timezone.utc) - status_timestamp < timedelta(minutes=MODEL_CHECK_INTERVAL_MINUTES):
                 if model_status_cache is not None:
                     model_status_cache["statuses"] = json.loads(status_json)
                     model_status_cache["last_report"] = report_state[0]
@@ -153,6 +263,154 @@ async def startup_model_check(ai_client: AsyncOpenAI, db, cache: Dict):
         except (ValueError, TypeError, json.JSONDecodeError) as e:
             logger.warning(f"Could not parse state from DB ({e}), running full check.")
 
-    # Если свежих данных в БД нет, запускаем полную проверку
+    # Если свежих данных в БД нет, запускаем полную проверку. Статусы нужны сразу, поэтому без разброса по времени
     logger.info("No fresh model status in DB. Running full health check...")
-    await scheduled_model_test(ai_client, db, cache)
\ No newline at end of file
+    await scheduled_model_test(ai_client, db, cache, spread_seconds=0)
+
+
+# --- Прогрев после запуска ---
+
+async def warmup_user_cache(db, cache: Dict):
+    """Загружает в кэш данные недавно активных пользователей, чтобы первые запросы после деплоя не ждали БД."""
+    user_cache = cache.get("user_details")
+    if user_cache is None:
+        return
+    user_ids = await db.get_recently_active_user_ids(days=1, limit=user_cache.maxsize)
+    for user_id in user_ids:
+        details = await db.get_user_details(user_id)
+        if details:
+            user_cache[user_id] = details
+    logger.info(f"Warmed up user cache with {len(user_cache)} recently active users.")
+
+async def prefetch_model_catalog(ai_client: ProviderRegistry, cache: Dict):
+    """Загружает списки моделей провайдеров и предупреждает о моделях из конфига, которых в них нет."""
+    try:
+        catalog = await ai_client.list_models()
+    except Exception as e:
+        logger.warning(f"Failed to prefetch provider model catalog: {e}")
+        return
+
+    catalog_cache = cache.get("model_catalog")
+    if catalog_cache is not None:
+        catalog_cache["models"] = catalog
+
+    configured = set(model for models in MODEL_CATEGORIES.values() for model in models) | set(IMAGE_MODELS)
+    missing = sorted(configured - catalog)
+    if missing:
+        logger.warning(f"Models from config are missing in provider catalog: {', '.join(missing)}")
+    logger.info(f"Provider model catalog prefetched: {len(catalog)} models.")
+
+async def startup_warmup(ai_client: ProviderRegistry, db, cache: Dict):
+    """
+    Прогрев после запуска: статусы моделей, кэш активных пользователей и каталог моделей провайдера.
+    Запускается в фоне, чтобы не задерживать старт polling.
+    """
+    await startup_model_check(ai_client, db, cache)
+    results = await asyncio.gather(
+        warmup_user_cache(db, cache),
+        prefetch_model_catalog(ai_client, cache),
+        return_exceptions=True
+    )
+    for result in results:
+        if isinstance(result, Exception):
+            logger.error(f"Warmup step failed: {result}", exc_info=result)
+    logger.info("Startup warmup finished.")
+
+
+# --- Уведомления о новой версии ---
+
+def get_full_version() -> str:
+    """Возвращает строку версии вместе с коммитом, если он известен."""
+    return f"{BOT_VERSION} ({BOT_COMMIT})" if BOT_COMMIT else BOT_VERSION
+
+async def announce_new_version(bot, db):
+    """
+    Сравнивает текущую версию с последней объявленной в system_state.
+    Если версия изменилась, рассылает администраторам записи списка изменений, добавленные после прошлого
+    объявления, и, при включенной настройке, включает с ними баннер «Что нового» для пользователей.
+    """
+    full_version = get_full_version()
+    last_announced = await db.get_system_state('announced_version')
+    if last_announced and last_announced[0] == full_version:
+        logger.info(f"Version {full_version} was already announced.")
+        return
+
+    announced_entry = await db.get_system_state('announced_changelog_id')
+    entries = await db.get_changelog_since(int(announced_entry[0]) if announced_entry else 0)
+    release_notes = "\n".join(f"• {entry_text}" for _, entry_text in entries)
+
+    text = f"🆕 <b>Бот обновлен до версии {full_version}</b>"
+    if release_notes:
+        text += f"\n\n<b>Что нового:</b>\n{release_notes}"
+    for admin_id in ADMIN_IDS:
+        try:
+            await bot.send_message(admin_id, text)
+        except Exception as e:
+            logger.warning(f"Failed to send version announcement to admin {admin_id}: {e}")
+
+    # При первом запуске пользователям показывать нечего
+    if NOTIFY_USERS_ON_UPDATE and last_announced and release_notes:
+        banner = {'version': BOT_VERSION, 'notes': release_notes}
+        await db.set_system_state('update_banner', json.dumps(banner, ensure_ascii=False))
+
+    if entries:
+        await db.set_system_state('announced_changelog_id', str(entries[-1][0]))
+    await db.set_system_state('announced_version', full_version)
+    logger.info(f"Announced new version {full_version} (previous: {last_announced[0] if last_announced else 'none'}).")
+
+async def get_update_banner(db) -> str:
+    """Возвращает текст баннера об обновлении, если он включен и еще не устарел."""
+    banner_state = await db.get_system_state('update_banner')
+    if not banner_state:
+        return ""
+    value, updated_at = banner_state
+    try:
+        if datetime.now(timezone.utc) - datetime.fromisoformat(updated_at) > timedelta(days=UPDATE_BANNER_DAYS):
+            return ""
+        banner = json.loads(value)
+        return f"🆕 <b>Что нового в версии {banner['version']}:</b>\n{banner['notes']}\n\n"
+    except (ValueError, TypeError, KeyError):
+        return ""
+
+
+# --- Объявление в главном меню ---
+
+async def set_announcement_banner(db, text: str | None):
+    """Задает текст объявления над главным меню; None убирает объявление."""
+    await db.set_system_state('announcement_banner', text or '')
+
+async def get_announcement_text(db) -> str | None:
+    banner_state = await db.get_system_state('announcement_banner')
+    return banner_state[0] if banner_state and banner_state[0] else None
+
+async def get_announcement_banner(db) -> str:
+    """Возвращает объявление администраторов для главного меню или пустую строку."""
+    text = await get_announcement_text(db)
+    return f"📢 {html.escape(text)}\n\n" if text else ""
+
+
+# --- Прерванные запросы ---
+
+async def notify_interrupted_requests(bot, db):
+    """
+    Уведомляет пользователей, чьи запросы к моделям были прерваны перезапуском бота,
+    и предлагает повторить запрос одной кнопкой.
+    """
+    await db.delete_stale_inflight_requests()
+    interrupted = await db.get_interrupted_requests()
+    if not interrupted:
+        return
+
+    logger.info(f"Found {len(interrupted)} interrupted requests, notifying users.")
+    for request_id, user_id, chat_id, kind, model in interrupted:
+        mode_text = "в Max Mode" if kind == 'max_mode' else f"к модели {hcode(model)}"
+        try:
+            await bot.send_message(
+                chat_id,
+                f"⚠️ Ваш запрос {mode_text} был прерван из-за перезапуска бота.\n"
+                "Лимит за него не списан. Нажмите кнопку ниже, чтобы отправить его повторно.",
+                reply_markup=get_retry_request_menu(request_id)
+            )
+        except Exception as e:
+            logger.warning(f"Failed to notify user {user_id} about interrupted request {request_id}: {e}")
+        await db.mark_inflight_request_notified(request_id)
@@ -0,0 +1,129 @@
+# app/services/telegraph_service.py
+# Публикация длинных ответов на Telegra.ph. Markdown из ответа модели
+# переводится в узлы Telegraph (абзацы, заголовки, списки, код, ссылки).
+
+import json
+import logging
+import re
+
+from app.config import TELEGRAPH_ACCESS_TOKEN, TELEGRAPH_AUTHOR_NAME
+from app.database import Database
+from app.services.network_service import create_telegram_http_session
+
+logger = logging.getLogger(__name__)
+
+TELEGRAPH_API_URL = "https://api.telegra.ph"
+_HEADING_RE = re.compile(r'^(#{1,6})\s+(.*)')
+_LIST_ITEM_RE = re.compile(r'^([-*•]|\d+[.)])\s+(.*)')
+_INLINE_RE = re.compile(r'\*\*(.+?)\*\*|`([^`]+)`|\[([^\]]+)\]\((https?://[^)\s]+)\)')
+
+
+class TelegraphError(Exception):
+    """Ошибка при обращении к API Telegraph."""
+
+
+def _inline_nodes(text: str) -> list:
+    """Разбирает жирный текст, код и ссылки внутри строки."""
+    nodes, position = [], 0
+    for match in _INLINE_RE.finditer(text):
+        if match.start() > position:
+            nodes.append(text[position:match.start()])
+        bold, code, link_text, link_url = match.groups()
+        if bold is not None:
+            nodes.append({'tag': 'b', 'children': [bold]})
+        elif code is not None:
+            nodes.append({'tag': 'code', 'children': [code]})
+        else:
+            nodes.append({'tag': 'a', 'attrs': {'href': link_url}, 'children': [link_text]})
+        position = match.end()
+    if position < len(text):
+        nodes.append(text[position:])
+    return nodes
+
+def markdown_to_nodes(text: str) -> list:
+    """Переводит markdown ответа модели в список узлов Telegraph."""
+    nodes, paragraph, list_items, code_lines = [], [], [], None
+    list_tag = 'ul'
+
+    def flush():
+        nonlocal paragraph, list_items
+        if paragraph:
+            children = []
+            for line in paragraph:
+                if children:
+                    children.append({'tag': 'br'})
+                children.extend(_inline_nodes(line))
+            nodes.append({'tag': 'p', 'children': children})
+            paragraph = []
+        if list_items:
+            nodes.append({'tag': list_tag, 'children': [{'tag': 'li', 'children': _inline_nodes(i)} for i in list_items]})
+            list_items = []
+
+    for line in text.split('\n'):
+        stripped = line.strip()
+        if stripped.startswith('```'):
+            if code_lines is None:
+                flush()
+                code_lines = []
+            else:
+                nodes.append({'tag': 'pre', 'children': ['\n'.join(code_lines)]})
+                code_lines = None
+            continue
+        if code_lines is not None:
+            code_lines.append(line)
+            continue
+        if not stripped:
+            flush()
+            continue
+
+        heading = _HEADING_RE.match(stripped)
+        item = _LIST_ITEM_RE.match(stripped)
+        if heading:
+            flush()
+            tag = 'h3' if len(heading.group(1)) <= 2 else 'h4'
+            nodes.append({'tag': tag, 'children': _inline_nodes(heading.group(2))})
+        elif item:
+            item_tag = 'ol' if item.group(1)[0].isdigit() else 'ul'
+            if paragraph or (list_items and item_tag != list_tag):
+                flush()
+            list_tag = item_tag
+            list_items.append(item.group(2))
+        else:
+            if list_items:
+                flush()
+            paragraph.append(stripped)
+
+    if code_lines is not None: # Незакрытый блок кода
+        nodes.append({'tag': 'pre', 'children': ['\n'.join(code_lines)]})
+    flush()
+    return nodes
+
+async def _call(method: str, params: dict) -> dict:
+    async with create_telegram_http_session() as session:
+        async with session.post(f"{TELEGRAPH_API_URL}/{method}", data=params, timeout=30) as response:
+            result = await response.json(content_type=None)
+    if not result.get('ok'):
+        raise TelegraphError(f"Telegraph {method} failed: {result.get('error')}")
+    return result['result']
+
+async def get_access_token(db: Database) -> str:
+    """Возвращает токен из конфига, сохраненный в БД или создает новый аккаунт Telegraph."""
+    if TELEGRAPH_ACCESS_TOKEN:
+        return TELEGRAPH_ACCESS_TOKEN
+    saved = await db.get_system_state('telegraph_token')
+    if saved:
+        return saved[0]
+    account = await _call('createAccount', {'short_name': TELEGRAPH_AUTHOR_NAME[:32], 'author_name': TELEGRAPH_AUTHOR_NAME})
+    await db.set_system_state('telegraph_token', account['access_token'])
+    logger.info("Created a new Telegraph account for publishing long answers")
+    return account['access_token']
+
+async def publish_page(db: Database, title: str, text: str) -> str:
+    """Публикует текст как страницу Telegraph и возвращает ее адрес."""
+    page = await _call('createPage', {
+        'access_token': await get_access_token(db),
+        'title': (title.strip() or "Ответ")[:256],
+        'author_name': TELEGRAPH_AUTHOR_NAME,
+        'content': json.dumps(markdown_to_nodes(text), ensure_ascii=False),
+    })
+    return page['url']
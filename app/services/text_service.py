@@ -0,0 +1,143 @@
+# app/services/text_service.py
+# Вспомогательные функции для длинных текстов.
+
+import re
+
+from app.config import QUOTA_MODE
+from app.services.format_service import format_number, plural
+
+# Лимит Telegram на длину одного сообщения
+TELEGRAM_MESSAGE_LIMIT = 4096
+
+
+def estimate_tokens(text: str) -> int:
+    """Грубая оценка числа токенов без токенизатора (для русского текста - около 3 символов на токен)."""
+    return len(text) // 3 + 1
+
+def format_quota(value) -> str:
+    """Размер лимита в действующих единицах учета (QUOTA_MODE): «1 запрос», «40 запросов» или «200 000 токенов»."""
+    if value == float('inf'):
+        return '∞'
+    if QUOTA_MODE == 'tokens':
+        return f"{format_number(int(value))} {plural(value, ('токен', 'токена', 'токенов'))}"
+    return f"{value} {plural(value, ('запрос', 'запроса', 'запросов'))}"
+
+def format_token_usage(prompt_tokens: int, completion_tokens: int) -> str:
+    """Расход токенов вида «1 200 (запросы) + 340 (ответы)»."""
+    return f"{format_number(prompt_tokens)} (запросы) + {format_number(completion_tokens)} (ответы)"
+
+def format_cost(cost: float) -> str:
+    """Стоимость в долларах; копеечные суммы показываются точнее."""
+    return f"${format_number(cost, 4 if cost < 1 else 2)}"
+
+def shorten_text(text: str, size: int) -> str:
+    """Сокращает текст до size символов: по концу предложения, иначе по границе слова."""
+    text = text.strip()
+    if len(text) <= size:
+        return text
+    cut = max(text.rfind(mark, 0, size) for mark in ('. ', '! ', '? ', '\n'))
+    if cut > size // 3:
+        return text[:cut + 1].strip()
+    cut = text.rfind(' ', 0, size - 1)
+    return text[:cut if cut > 0 else size - 1].rstrip(' ,;:') + '…'
+
+def split_text(text: str, size: int) -> list[str]:
+    """Делит текст на части не длиннее size, по возможности по переводам строк."""
+    chunks = []
+    while len(text) > size:
+        cut = text.rfind('\n', 0, size)
+        if cut <= 0:
+            cut = size
+        chunks.append(text[:cut])
+        text = text[cut:].lstrip('\n')
+    if text:
+        chunks.append(text)
+    return chunks
+
+# --- Деление ответов моделей на сообщения ---
+
+_TAG_RE = re.compile(r'<(/?)([a-zA-Z][\w-]*)[^>]*>')
+_FENCE_RE = re.compile(r'^[ \t]*```.*$', re.MULTILINE)
+# Теги без закрывающей пары: открытыми не считаются
+_VOID_TAGS = {'br', 'hr', 'img', 'wbr', 'input', 'meta', 'link', 'area', 'base', 'col', 'embed', 'source', 'track'}
+
+def _open_tags_after(text: str, open_tags: list[tuple[str, str]]) -> list[tuple[str, str]]:
+    """HTML-теги, оставшиеся открытыми после text: [(имя, открывающий тег целиком)]."""
+    tags = list(open_tags)
+    for match in _TAG_RE.finditer(text):
+        name = match[2].lower()
+        if name in _VOID_TAGS or match[0].endswith('/>'):
+            continue
+        if not match[1]:
+            tags.append((name, match[0]))
+            continue
+        for index in range(len(tags) - 1, -1, -1):
+            if tags[index][0] == name:
+                del tags[index]
+                break
+    return tags
+
+def _fence_after(text: str, fence: str | None) -> str | None:
+    """Строка, открывшая блок кода ```, если после text он остался незакрытым."""
+    for match in _FENCE_RE.finditer(text):
+        fence = None if fence else match[0].strip()
+    return fence
+
+def _find_cut(text: str, size: int, in_code: bool) -> int:
+    """
+    Место разреза не дальше size: перед блоком кода, между абзацами, по переводу строки или пробелу.
+    Слишком короткие части (меньше четверти size) не допускаются. Разрез не попадает внутрь тега или HTML-сущности.
+    in_code - text начинается внутри блока кода.
+    """
+    min_cut = size // 4
+    fences = [match.start() for match in _FENCE_RE.finditer(text, 0, size)]
+    # Блок кода, который начинается в этой части и в нее не помещается, целиком переносится в следующую
+    if fences and (len(fences) + in_code) % 2 == 1 and fences[-1] > min_cut:
+        cut = fences[-1]
+    else:
+        for separator in ('\n\n', '\n', ' '):
+            cut = text.rfind(separator, 0, size)
+            if cut > min_cut:
+                break
+        else:
+            cut = size
+    tag_start = text.rfind('<', 0, cut)
+    if tag_start > text.rfind('>', 0, cut):
+        cut = tag_start
+    entity_start = text.rfind('&', max(0, cut - 10), cut)
+    if entity_start != -1 and ';' not in text[entity_start:cut]:
+        cut = entity_start
+    return cut if cut > 0 else size
+
+def split_message(text: str, size: int = TELEGRAM_MESSAGE_LIMIT) -> list[str]:
+    """
+    Делит ответ модели на сообщения не длиннее size. Части разделяются по границам абзацев и блоков кода;
+    незакрытые в конце части HTML-теги и блок кода ``` закрываются и открываются заново в следующей части,
+    чтобы каждое сообщение можно было отправить с разметкой.
+    """
+    chunks = []
+    open_tags, fence = [], None
+    text = text.strip('\n')
+    while text:
+        prefix = (f"{fence}\n" if fence else '') + ''.join(tag for _, tag in open_tags)
+        if len(prefix) + len(text) <= size:
+            chunks.append(prefix + text)
+            break
+        budget = size - len(prefix)
+        while True:
+            cut = _find_cut(text, budget, fence is not None)
+            body = text[:cut].rstrip()
+            tags_after, fence_after = _open_tags_after(body, open_tags), _fence_after(body, fence)
+            # Столько незакрытых тегов бывает только в сломанной разметке: их закрытие и повтор
+            # не оставили бы места для текста, поэтому такие теги не переносятся
+            if sum(len(tag) for _, tag in tags_after) > size // 4:
+                tags_after = []
+            suffix = ''.join(f"</{name}>" for name, _ in reversed(tags_after)) + ("\n```" if fence_after else '')
+            overflow = len(prefix) + len(body) + len(suffix) - size
+            if overflow <= 0:
+                break
+            budget = max(budget - overflow, 1)
+        chunks.append(prefix + body + suffix)
+        open_tags, fence = tags_after, fence_after
+        text = text[cut:].lstrip(' ').lstrip('\n')
+    return chunks
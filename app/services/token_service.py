@@ -0,0 +1,24 @@
+# app/services/token_service.py
+# Персональные токены API. В БД хранится только хэш, сам токен показывается пользователю один раз.
+
+import hashlib
+import secrets
+
+from app.database import Database
+
+TOKEN_PREFIX = 'ma_'
+
+
+def hash_token(token: str) -> str:
+    return hashlib.sha256(token.encode()).hexdigest()
+
+async def issue_api_token(db: Database, user_id: int) -> str:
+    """Выпускает новый токен пользователя; предыдущий перестает работать."""
+    token = TOKEN_PREFIX + secrets.token_urlsafe(32)
+    await db.set_api_token(user_id, hash_token(token))
+    return token
+
+async def get_user_id_by_token(db: Database, token: str) -> int | None:
+    if not token.startswith(TOKEN_PREFIX):
+        return None
+    return await db.get_user_id_by_api_token(hash_token(token))
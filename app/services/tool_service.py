@@ -0,0 +1,171 @@
+# app/services/tool_service.py
+# Внешние серверы инструментов в стиле MCP: JSON-RPC 2.0 поверх HTTP с методами
+# tools/list и tools/call (ответы ожидаются обычным JSON, без SSE).
+# Инструменты подключенных серверов предлагаются моделям с поддержкой tools
+# под именами вида "<сервер>__<инструмент>", а их вызовы проксируются на сервер. Имя, которое не подходит
+# для API (длиннее 64 символов или с недопустимыми символами), сокращается и дополняется хэшем.
+# Встроенные инструменты (builtin_tool_service) предлагаются вместе с ними под своими именами.
+
+import asyncio
+import hashlib
+import json
+import logging
+import re
+
+import aiohttp
+
+from app.config import TOOL_CALL_TIMEOUT, TOOL_RESULT_MAX_CHARS
+from app.database import Database
+from app.services.builtin_tool_service import get_builtin_tool, get_builtin_definitions
+from app.services.crypto_service import encrypt_field, decrypt_field
+from app.services.network_service import create_http_session
+from app.services.user_service import get_user_level
+
+logger = logging.getLogger(__name__)
+
+TOOL_NAME_SEPARATOR = '__'
+SERVER_NAME_RE = re.compile(r'^[a-z0-9-]{1,20}$') # Без '_', чтобы имя не смешивалось с разделителем
+TOOL_NAME_MAX_LENGTH = 64 # Ограничение API на имя функции
+_TOOL_NAME_INVALID_RE = re.compile(r'[^A-Za-z0-9_-]')
+
+
+class ToolServerError(Exception):
+    """Сервер инструментов недоступен или вернул ошибку."""
+
+
+async def _rpc(url: str, auth_token: str | None, method: str, params: dict | None = None):
+    headers = {"Authorization": f"Bearer {auth_token}"} if auth_token else {}
+    payload = {"jsonrpc": "2.0", "id": 1, "method": method, "params": params or {}}
+    try:
+        async with create_http_session(url) as session:
+            async with session.post(url, headers=headers, json=payload, timeout=TOOL_CALL_TIMEOUT) as response:
+                if response.status != 200:
+                    raise ToolServerError(f"HTTP {response.status}")
+                result = await response.json(content_type=None)
+    except (aiohttp.ClientError, asyncio.TimeoutError, ValueError) as e:
+        raise ToolServerError(str(e) or type(e).__name__) from e
+    if not isinstance(result, dict) or 'error' in result:
+        raise ToolServerError(f"RPC error: {result.get('error') if isinstance(result, dict) else result}")
+    return result.get('result')
+
+async def fetch_server_tools(url: str, auth_token: str | None) -> list:
+    """Запрашивает у сервера список инструментов: [{"name", "description", "inputSchema"}]."""
+    result = await _rpc(url, auth_token, 'tools/list')
+    tools = result.get('tools') if isinstance(result, dict) else None
+    if not isinstance(tools, list) or not all(isinstance(t, dict) and t.get('name') for t in tools):
+        raise ToolServerError("tools/list returned no valid tools")
+    return tools
+
+def _invalidate_tools_cache(cache: dict):
+    cache["tool_servers"].clear()
+
+async def register_tool_server(db: Database, cache: dict, name: str, url: str, auth_token: str | None) -> list:
+    """Подключает сервер (или обновляет его) и возвращает список его инструментов. Токен хранится зашифрованным."""
+    tools = await fetch_server_tools(url, auth_token)
+    await db.save_tool_server(name, url, encrypt_field(auth_token), json.dumps(tools, ensure_ascii=False))
+    _invalidate_tools_cache(cache)
+    return tools
+
+async def set_tool_server_enabled(db: Database, cache: dict, name: str, enabled: bool) -> bool:
+    changed = await db.set_tool_server_enabled(name, enabled)
+    _invalidate_tools_cache(cache)
+    return changed
+
+async def delete_tool_server(db: Database, cache: dict, name: str) -> bool:
+    deleted = await db.delete_tool_server(name)
+    _invalidate_tools_cache(cache)
+    return deleted
+
+async def get_tool_definitions(db: Database, cache: dict, user_id: int) -> list:
+    """
+    Описания доступных пользователю встроенных инструментов и инструментов всех включенных серверов
+    в формате tools для chat.completions.
+    """
+    builtin = get_builtin_definitions(await get_user_level(user_id, db))
+    return builtin + await _get_server_tool_definitions(db, cache)
+
+def _function_name(server_name: str, tool_name: str) -> str:
+    """Имя инструмента сервера для модели: "<сервер>__<инструмент>", при необходимости сокращенное, с хэшем имени."""
+    safe_name = _TOOL_NAME_INVALID_RE.sub('_', tool_name)
+    function_name = f"{server_name}{TOOL_NAME_SEPARATOR}{safe_name}"
+    if safe_name == tool_name and len(function_name) <= TOOL_NAME_MAX_LENGTH:
+        return function_name
+    digest = hashlib.sha1(tool_name.encode()).hexdigest()[:8]
+    return f"{function_name[:TOOL_NAME_MAX_LENGTH - len(digest) - 1]}_{digest}"
+
+async def _load_server_tools(db: Database, cache: dict) -> dict:
+    """
+    Описания инструментов включенных серверов ('definitions') и соответствие имени для модели
+    паре (сервер, инструмент) ('names'). Кэшируется до изменения списка серверов.
+    """
+    tools_cache = cache["tool_servers"]
+    if 'definitions' in tools_cache:
+        return tools_cache
+
+    definitions, names = [], {}
+    for server_name, _, _, tools_json, _ in await db.get_tool_servers(enabled_only=True):
+        for tool in json.loads(tools_json or '[]'):
+            function_name = _function_name(server_name, tool['name'])
+            names[function_name] = (server_name, tool['name'])
+            definitions.append({
+                "type": "function",
+                "function": {
+                    "name": function_name,
+                    "description": (tool.get('description') or '')[:1000],
+                    "parameters": tool.get('inputSchema') or {"type": "object", "properties": {}},
+                }
+            })
+    tools_cache['definitions'], tools_cache['names'] = definitions, names
+    return tools_cache
+
+async def _get_server_tool_definitions(db: Database, cache: dict) -> list:
+    return (await _load_server_tools(db, cache))['definitions']
+
+def _format_tool_result(result) -> str:
+    """Собирает текст из content[] ответа tools/call; остальное отдает как JSON."""
+    if isinstance(result, dict) and isinstance(result.get('content'), list):
+        texts = [item.get('text', '') for item in result['content'] if isinstance(item, dict) and item.get('type') == 'text']
+        text = '\n'.join(texts)
+        return f"Ошибка инструмента: {text}" if result.get('isError') else text
+    return json.dumps(result, ensure_ascii=False)
+
+async def call_tool(db: Database, cache: dict, full_name: str, arguments: str, user_id: int, sources: list) -> str:
+    """
+    Вызывает инструмент по имени из ответа модели. Ошибки не пробрасываются,
+    а возвращаются текстом, чтобы модель могла сообщить о них пользователю.
+    Источники, найденные встроенными инструментами, добавляются в sources.
+    """
+    builtin_tool = get_builtin_tool(full_name, await get_user_level(user_id, db))
+    if builtin_tool:
+        return await _call_builtin_tool(db, builtin_tool, arguments, user_id, sources)
+
+    server_name, tool_name = (await _load_server_tools(db, cache))['names'].get(full_name, (None, None))
+    server = await db.get_tool_server(server_name) if server_name else None
+    if not server or not server[4]:
+        return f"Инструмент {full_name} недоступен."
+    try:
+        parsed_arguments = json.loads(arguments or '{}')
+    except ValueError:
+        return "Ошибка: аргументы инструмента должны быть JSON-объектом."
+
+    _, url, auth_token, _, _ = server
+    try:
+        result = await _rpc(url, decrypt_field(auth_token), 'tools/call', {"name": tool_name, "arguments": parsed_arguments})
+    except ToolServerError as e:
+        logger.warning(f"Tool {full_name} failed for user {user_id}: {e}")
+        return f"Ошибка вызова инструмента: {e}"
+    logger.info(f"Tool {full_name} called for user {user_id}")
+    return _format_tool_result(result)[:TOOL_RESULT_MAX_CHARS]
+
+async def _call_builtin_tool(db: Database, tool, arguments: str, user_id: int, sources: list) -> str:
+    try:
+        parsed_arguments = json.loads(arguments or '{}')
+    except ValueError:
+        return "Ошибка: аргументы инструмента должны быть JSON-объектом."
+    try:
+        result = await tool.run(parsed_arguments, user_id, db, sources)
+    except Exception as e:
+        logger.warning(f"Built-in tool {tool.name} failed for user {user_id}: {e}", exc_info=True)
+        return f"Ошибка вызова инструмента: {e}"
+    logger.info(f"Built-in tool {tool.name} called for user {user_id}")
+    return result[:TOOL_RESULT_MAX_CHARS]
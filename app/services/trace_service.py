@@ -0,0 +1,88 @@
+# app/services/trace_service.py
+# Сквозные идентификаторы запросов. Каждому обновлению от Telegram (и запросу к HTTP API, запланированному запросу)
+# присваивается короткий код, который попадает во все строки лога, записанные при его обработке,
+# и показывается пользователю в сообщении об ошибке ("код ошибки: AB12CD").
+# По коду администратор находит весь след запроса командой /trace.
+
+import asyncio
+import logging
+import secrets
+from contextvars import ContextVar
+
+from cachetools import TTLCache
+
+from app.config import LOG_FILE, TRACE_KEEP_REQUESTS, TRACE_KEEP_HOURS, TRACE_MAX_LINES
+
+# Без похожих друг на друга символов (0/O, 1/I), чтобы код было легко переписать со скриншота
+REQUEST_ID_ALPHABET = 'ABCDEFGHJKLMNPQRSTUVWXYZ23456789'
+REQUEST_ID_LENGTH = 6
+
+_request_id: ContextVar[str | None] = ContextVar('request_id', default=None)
+
+
+def new_request_id() -> str:
+    """Присваивает текущей задаче (и задачам, запущенным из нее) новый код запроса."""
+    request_id = ''.join(secrets.choice(REQUEST_ID_ALPHABET) for _ in range(REQUEST_ID_LENGTH))
+    _request_id.set(request_id)
+    return request_id
+
+def get_request_id() -> str | None:
+    return _request_id.get()
+
+def normalize_request_id(value: str) -> str:
+    return value.strip().lstrip('#').upper()
+
+def format_error_code() -> str:
+    """Строка с кодом запроса для сообщения об ошибке (HTML) или пустая строка, если кода нет."""
+    request_id = get_request_id()
+    return f"\n\nКод ошибки: <code>{request_id}</code> (сообщите его в поддержку)" if request_id else ""
+
+
+class RequestIdFilter(logging.Filter):
+    """Добавляет в запись лога поле request_id (для формата '%(request_id)s')."""
+    def filter(self, record: logging.LogRecord) -> bool:
+        record.request_id = get_request_id() or '-'
+        return True
+
+
+class TraceBufferHandler(logging.Handler):
+    """
+    Хранит в памяти строки лога последних TRACE_KEEP_REQUESTS запросов (не дольше TRACE_KEEP_HOURS),
+    чтобы /trace отвечал без чтения файла лога. На запрос сохраняется не больше TRACE_MAX_LINES строк.
+    """
+    def __init__(self):
+        super().__init__()
+        self.traces = TTLCache(maxsize=TRACE_KEEP_REQUESTS, ttl=TRACE_KEEP_HOURS * 3600)
+
+    def emit(self, record: logging.LogRecord):
+        request_id = get_request_id()
+        if not request_id:
+            return
+        try:
+            lines = self.traces.get(request_id)
+            if lines is None:
+                lines = self.traces[request_id] = []
+            if len(lines) < TRACE_MAX_LINES:
+                lines.append(self.format(record))
+        except Exception:
+            self.handleError(record)
+
+trace_buffer = TraceBufferHandler()
+
+
+def _search_log_file(request_id: str) -> list[str]:
+    marker = f"[{request_id}]"
+    try:
+        with open(LOG_FILE, encoding='utf-8', errors='replace') as log_file:
+            lines = [line.rstrip('\n') for line in log_file if marker in line]
+    except OSError:
+        return []
+    return lines[:TRACE_MAX_LINES]
+
+async def get_trace(request_id: str) -> list[str]:
+    """Строки лога запроса: из памяти, а для старых запросов (например, до перезапуска) - из файла лога."""
+    request_id = normalize_request_id(request_id)
+    lines = trace_buffer.traces.get(request_id)
+    if lines:
+        return list(lines)
+    return await asyncio.to_thread(_search_log_file, request_id)
@@ -0,0 +1,72 @@
+# app/services/update_journal_service.py
+# Обновления, пришедшие, пока бот был остановлен. При запуске ожидающие обновления
+# забираются через getUpdates: обычные сообщения отбрасываются (как и раньше), а критичные
+# (успешные платежи) сохраняются в журнал update_journal. Администратор обрабатывает их
+# командой /replay, чтобы платежи не терялись при деплое.
+
+import json
+import logging
+
+from aiogram import Bot, Dispatcher
+from aiogram.types import Update
+
+from app.config import ADMIN_IDS
+from app.database import Database
+
+logger = logging.getLogger(__name__)
+
+UPDATE_KINDS = {
+    'payment': 'успешные платежи',
+}
+
+
+def get_critical_kind(update: Update) -> str | None:
+    """Возвращает тип критичного обновления или None, если его можно отбросить."""
+    if update.message and update.message.successful_payment:
+        return 'payment'
+    return None
+
+async def collect_missed_updates(bot: Bot, db: Database) -> int:
+    """
+    Забирает все ожидающие обновления, сохраняет критичные в журнал и подтверждает остальные,
+    чтобы polling начался с новых. Возвращает число сохраненных обновлений.
+    """
+    offset, total, saved = None, 0, 0
+    while True:
+        updates = await bot.get_updates(offset=offset, limit=100, timeout=0)
+        if not updates:
+            break
+        for update in updates:
+            kind = get_critical_kind(update)
+            if kind:
+                payload = update.model_dump_json(exclude_none=True)
+                saved += await db.add_journal_update(update.update_id, kind, payload)
+        total += len(updates)
+        offset = updates[-1].update_id + 1
+
+    if total:
+        logger.info(f"Skipped {total} pending updates on start, {saved} critical saved to journal.")
+    if saved:
+        text = (f"⚠️ Пока бот был остановлен, пришло критичных обновлений: {saved}.\n"
+                "Посмотреть и обработать: /replay")
+        for admin_id in ADMIN_IDS:
+            try:
+                await bot.send_message(admin_id, text)
+            except Exception as e:
+                logger.warning(f"Failed to send missed updates notice to admin {admin_id}: {e}")
+    return saved
+
+async def replay_journal(bot: Bot, dispatcher: Dispatcher, db: Database) -> tuple[int, int]:
+    """Передает необработанные обновления журнала в диспетчер. Возвращает (обработано, с ошибкой)."""
+    replayed, failed = 0, 0
+    for update_id, kind, payload in await db.get_pending_journal_updates():
+        try:
+            update = Update.model_validate(json.loads(payload), context={"bot": bot})
+            await dispatcher.feed_update(bot, update)
+        except Exception as e:
+            failed += 1
+            logger.error(f"Failed to replay {kind} update {update_id}: {e}", exc_info=True)
+            continue
+        await db.mark_journal_update_replayed(update_id)
+        replayed += 1
+    return replayed, failed
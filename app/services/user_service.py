@@ -11,7 +11,10 @@ from aiogram.fsm.context import FSMContext
 from aiogram.types import User
 
 from app.database import Database
-from app.config import ADMIN_IDS, LIMITS, REWARD_LIMIT, CAPTCHA_VARIANTS
+from app.config import (
+    ADMIN_IDS, CAPTCHA_VARIANTS, MODELS, BETA_MODELS, QUOTA_MODE, QUOTA_LIMITS, QUOTA_REWARD_LIMIT,
+    TOKENS_PER_REQUEST, TOKENS_PER_MAX_MODE_RUN, DEFAULT_UTC_OFFSET, STREAM_RESPONSES, STREAM_EDIT_INTERVALS
+)
 from app.states import Captcha
 
 logger = logging.getLogger(__name__)
@@ -71,23 +74,94 @@ async def get_user_level(user_id: int, db: Database) -> int:
              pass
     return level
 
-async def get_user_limits(user_id: int, db: Database) -> Tuple[int, int]:
-    """Возвращает кортеж (дневной_лимит, лимит_max_mode)."""
+def get_accessible_models(user_level: int, beta_tester: bool = False) -> set:
+    """Возвращает множество текстовых моделей, доступных на указанном уровне подписки. Бета-модели - только тестировщикам."""
+    accessible_models = set(MODELS['free'])
+    if user_level >= 1: accessible_models.update(MODELS['standard'])
+    if user_level >= 2: accessible_models.update(MODELS['premium'])
+    if not beta_tester:
+        accessible_models -= BETA_MODELS
+    return accessible_models
+
+async def get_user_accessible_models(user_id: int, db: Database) -> set:
+    """Текстовые модели, доступные пользователю по его подписке и участию в бета-тесте."""
+    return get_accessible_models(await get_user_level(user_id, db), await db.is_beta_tester(user_id))
+
+def get_stream_interval(user_level: int) -> float | None:
+    """Как часто обновлять сообщение при потоковом ответе на уровне подписки (сек.); None - ответ приходит целиком."""
+    return STREAM_EDIT_INTERVALS.get(user_level) if STREAM_RESPONSES else None
+
+async def get_favorite_models(user_id: int, db: Database, limit: int = 3) -> list:
+    """Текстовые модели, которыми пользователь пользуется чаще всего: [(model, запросов)]."""
+    text_models = {model for models in MODELS.values() for model in models}
+    return [(model, count) for model, count in await db.get_model_usage(user_id, limit=limit + 5) if model in text_models][:limit]
+
+async def get_favorite_model(user_id: int, db: Database, accessible_models: set | None = None) -> str | None:
+    """Самая используемая пользователем текстовая модель (из accessible_models, если они переданы) или None."""
+    for model, _ in await get_favorite_models(user_id, db, limit=5):
+        if accessible_models is None or model in accessible_models:
+            return model
+    return None
+
+async def get_plan_limits(user_id: int, db: Database) -> Tuple[int, int]:
+    """Возвращает кортеж (дневной_лимит, лимит_max_mode) по тарифу, без разовых бонусов, в единицах QUOTA_MODE."""
     level = await get_user_level(user_id, db)
 
-    # Администраторы
-    if user_id in ADMIN_IDS:
-        return float('inf'), float('inf')
-
     # Проверка на бонус за подписку на каналы
     if level == 0:
         details = await db.get_user_details(user_id)
         if details and details[8]: # has_rewarded_bonus
-            return REWARD_LIMIT, 0
+            return QUOTA_REWARD_LIMIT, 0
 
-    plan_limits = LIMITS.get(level, {"daily": 0, "max_mode": 0})
+    plan_limits = QUOTA_LIMITS.get(level, {"daily": 0, "max_mode": 0})
     return plan_limits["daily"], plan_limits["max_mode"]
 
+async def get_user_limits(user_id: int, db: Database) -> Tuple[int, int]:
+    """
+    Возвращает кортеж (дневной_лимит, лимит_max_mode) с учетом ручных корректировок администратора:
+    персонального дневного лимита, дополнительных запросов на сегодня и разовых запусков Max Mode.
+    При учете по токенам корректировки (они задаются в запросах) пересчитываются в токены.
+    """
+    # Администраторы
+    if user_id in ADMIN_IDS:
+        return float('inf'), float('inf')
+
+    daily_limit, max_mode_limit = await get_plan_limits(user_id, db)
+    overrides = await db.get_quota_overrides(user_id)
+    if overrides:
+        custom_daily_limit, bonus_max_runs, extra_today, bonus_used_today = overrides
+        request_size, run_size = (TOKENS_PER_REQUEST, TOKENS_PER_MAX_MODE_RUN) if QUOTA_MODE == 'tokens' else (1, 1)
+        if custom_daily_limit is not None:
+            daily_limit = custom_daily_limit * request_size
+        daily_limit += extra_today * request_size
+        # Использованные сегодня бонусные запуски уже учтены в счетчике запросов
+        max_mode_limit += ((bonus_max_runs or 0) + bonus_used_today) * run_size
+    return daily_limit, max_mode_limit
+
+async def get_usage_today(user_id: int, db: Database, is_max_mode: bool = False) -> int:
+    """Сколько дневного лимита (обычного или Max Mode) израсходовано сегодня: запросов или токенов, по QUOTA_MODE."""
+    if QUOTA_MODE == 'tokens':
+        return await db.get_user_tokens_today(
+            user_id, is_max_mode, TOKENS_PER_MAX_MODE_RUN if is_max_mode else TOKENS_PER_REQUEST
+        )
+    return await db.get_user_requests_today(user_id, is_max_mode=is_max_mode)
+
+async def add_max_mode_request(user_id: int, db: Database, tokens=(0, 0, 0.0)):
+    """
+    Записывает запрос Max Mode. Пока есть разовые запуски (пробные для новых пользователей
+    или выданные администратором), списываются они, а тарифный лимит остается нетронутым.
+    """
+    overrides = await db.get_quota_overrides(user_id)
+    is_bonus = user_id not in ADMIN_IDS and bool(overrides and overrides[1])
+    await db.add_request(user_id, "max_mode_ensemble", is_max_mode=True, is_bonus=is_bonus, tokens=tokens)
+    if is_bonus:
+        await db.consume_bonus_max_run(user_id)
+
+
+async def get_utc_offset(db: Database, user_id: int) -> int:
+    """Часовой пояс пользователя (смещение от UTC в часах) из настроек уведомлений."""
+    settings = await db.get_notification_settings(user_id)
+    return settings[2] if settings and settings[2] is not None else DEFAULT_UTC_OFFSET
 
 async def check_authentication(user: User, db: Database, state: FSMContext, bot: Bot) -> bool:
     """
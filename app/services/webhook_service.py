@@ -0,0 +1,95 @@
+# app/services/webhook_service.py
+# Входящие вебхуки: внешняя система (CI, мониторинг) отправляет POST на персональный адрес,
+# а бот пересылает содержимое пользователю, при желании кратко изложив его с помощью модели.
+
+import html
+import json
+import logging
+import secrets
+import time
+
+from aiogram import Bot
+
+from app.config import PUBLIC_API_URL, WEBHOOK_RATE_LIMIT, WEBHOOK_SUMMARY_MODEL
+from app.database import Database
+from app.services.ai_service import get_simple_response
+from app.services.notification_service import send_notification
+from app.services.token_service import hash_token
+from app.services.user_service import get_user_limits, get_usage_today
+
+logger = logging.getLogger(__name__)
+
+# Сколько символов содержимого пересылается в сообщении
+PAYLOAD_PREVIEW_SIZE = 3000
+
+
+def build_webhook_url(token: str) -> str:
+    return f"{PUBLIC_API_URL}/v1/hooks/{token}"
+
+async def create_webhook(db: Database, user_id: int, name: str) -> str:
+    """Создает вебхук и возвращает его адрес. Адрес показывается один раз: в БД хранится только хэш токена."""
+    token = secrets.token_urlsafe(24)
+    await db.add_webhook(user_id, name, hash_token(token))
+    return build_webhook_url(token)
+
+async def get_webhook(db: Database, token: str):
+    """Возвращает (id, user_id, name, summarize) или None."""
+    return await db.get_webhook_by_token(hash_token(token))
+
+def check_rate_limit(cache: dict, webhook_id: int) -> bool:
+    """Учитывает доставку и возвращает False, если вебхук превысил WEBHOOK_RATE_LIMIT за минуту."""
+    tracker = cache["webhook_rate"]
+    now = time.monotonic()
+    hits = [t for t in tracker.get(webhook_id, []) if now - t < 60]
+    if len(hits) >= WEBHOOK_RATE_LIMIT:
+        return False
+    hits.append(now)
+    tracker[webhook_id] = hits
+    return True
+
+def format_payload(raw: str) -> str:
+    """JSON показывается с отступами, поле text (если есть) - как обычный текст."""
+    try:
+        data = json.loads(raw)
+    except ValueError:
+        return raw.strip()
+    if isinstance(data, dict) and isinstance(data.get('text'), str):
+        return data['text'].strip()
+    return json.dumps(data, ensure_ascii=False, indent=2)
+
+async def _summarize(ai_client, db: Database, cache: dict, user_id: int, payload: str) -> str | None:
+    """Кратко излагает уведомление. Возвращает None, если лимит исчерпан или модель не ответила."""
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        return None
+    messages = [{
+        "role": "user",
+        "content": "Кратко (2-4 предложения) изложи суть этого уведомления из внешней системы: "
+                   f"что произошло и нужно ли что-то делать.\n\n{payload[:8000]}"
+    }]
+    try:
+        summary, _, usage = await get_simple_response(ai_client, WEBHOOK_SUMMARY_MODEL, messages, user_id, db, cache)
+    except Exception as e:
+        logger.warning(f"Webhook summary failed for user {user_id}: {e}")
+        return None
+    await db.add_request(user_id, usage.model or WEBHOOK_SUMMARY_MODEL, is_max_mode=False, tokens=usage.tokens)
+    return summary
+
+async def deliver_webhook(bot: Bot, db: Database, ai_client, cache: dict, webhook: tuple, raw_payload: str):
+    """Пересылает содержимое вебхука владельцу (с учетом его тихих часов)."""
+    webhook_id, user_id, name, summarize = webhook
+    payload = format_payload(raw_payload) or "(пустое уведомление)"
+    text = f"🔔 <b>{html.escape(name)}</b>\n\n"
+
+    summary = await _summarize(ai_client, db, cache, user_id, payload) if summarize else None
+    if summary:
+        text += f"{html.escape(summary)}\n\n<i>Краткое изложение. Исходное уведомление:</i>\n"
+    elif summarize:
+        text += "<i>Краткое изложение недоступно (исчерпан лимит запросов или модель не ответила).</i>\n\n"
+
+    preview = payload if len(payload) <= PAYLOAD_PREVIEW_SIZE else payload[:PAYLOAD_PREVIEW_SIZE] + "\n…"
+    text += f"<pre>{html.escape(preview)}</pre>"
+
+    if await send_notification(bot, db, user_id, text):
+        await db.mark_webhook_delivered(webhook_id)
+        logger.info(f"Webhook #{webhook_id} forwarded to user {user_id}")
@@ -0,0 +1,89 @@
+# app/services/winback_service.py
+# Возврат ушедших подписчиков: после окончания подписки неактивным пользователям
+# отправляется последовательность сообщений - напоминание, затем персональный промокод.
+
+import logging
+import secrets
+from datetime import datetime, timedelta, timezone
+
+from aiogram import Bot
+from aiogram.utils.markdown import hcode
+
+from app.config import (
+    ADMIN_IDS, MSK_TZ, WINBACK_INACTIVE_DAYS, WINBACK_STAGES, WINBACK_DISCOUNT_PERCENT,
+    WINBACK_CODE_VALID_DAYS, WINBACK_MAX_LAPSE_DAYS
+)
+from app.database import Database
+from app.keyboards.inline import get_winback_menu
+from app.services.notification_service import send_notification
+from app.services.format_service import format_date
+
+logger = logging.getLogger(__name__)
+
+STAGE_NAMES = {1: "Напоминание", 2: "Промокод"}
+
+
+def _get_due_stage(days_since_lapse: int) -> int:
+    """Последний этап, срок которого уже наступил. Пропущенные ранние этапы не отправляются."""
+    due = [stage for stage, days in WINBACK_STAGES.items() if days_since_lapse >= days]
+    return max(due, default=0)
+
+async def _create_promocode(db: Database, user_id: int) -> tuple[str, datetime]:
+    code = f"BACK-{secrets.token_hex(3).upper()}"
+    expires_at = datetime.now(timezone.utc) + timedelta(days=WINBACK_CODE_VALID_DAYS)
+    await db.add_promocode(code, user_id, WINBACK_DISCOUNT_PERCENT, 'winback', expires_at)
+    return code, expires_at
+
+async def _build_stage_text(db: Database, user_id: int, stage: int) -> str:
+    if stage == 1:
+        return (
+            "👋 Давно не виделись!\n\n"
+            "Ваша подписка закончилась, а мы тем временем продолжаем добавлять новые модели и возможности. "
+            "Загляните — бесплатный план по-прежнему доступен, а подписка вернет все модели и лимиты."
+        )
+    code, expires_at = await _create_promocode(db, user_id)
+    expires_str = format_date(expires_at)
+    return (
+        f"🎁 Персональная скидка {WINBACK_DISCOUNT_PERCENT}% на подписку!\n\n"
+        f"Ваш промокод: {hcode(code)}\n"
+        f"Действует до {expires_str}. Скидка применится сама при оплате подписки в боте."
+    )
+
+async def run_winback(bot: Bot, db: Database):
+    """Отправляет очередные этапы win-back. Запускается планировщиком раз в день."""
+    now_utc = datetime.now(timezone.utc)
+    inactive_since = datetime.now(MSK_TZ).date() - timedelta(days=WINBACK_INACTIVE_DAYS)
+    candidates = await db.get_winback_candidates(
+        lapsed_after=now_utc - timedelta(days=WINBACK_MAX_LAPSE_DAYS),
+        lapsed_before=now_utc - timedelta(days=min(WINBACK_STAGES.values())),
+        inactive_since=inactive_since
+    )
+
+    sent = 0
+    for user_id, subscription_end, lapsed_at, stage in candidates:
+        if user_id in ADMIN_IDS:
+            continue
+        # Подписка была продлена и снова закончилась - последовательность начинается заново
+        if lapsed_at != subscription_end:
+            stage = 0
+        try:
+            days_since_lapse = (now_utc - datetime.fromisoformat(subscription_end)).days
+        except (ValueError, TypeError):
+            continue
+        due_stage = _get_due_stage(days_since_lapse)
+        if due_stage <= stage:
+            continue
+
+        text = await _build_stage_text(db, user_id, due_stage)
+        if await send_notification(bot, db, user_id, text, reply_markup=get_winback_menu()):
+            await db.set_winback_stage(user_id, subscription_end, due_stage)
+            sent += 1
+    logger.info(f"Win-back run finished: {sent} messages sent out of {len(candidates)} candidates.")
+
+async def mark_winback_conversion(db: Database, user_id: int):
+    """Засчитывает возврат пользователя, если ему отправлялись сообщения win-back."""
+    stage = await db.get_winback_stage(user_id)
+    if stage:
+        await db.add_winback_event(user_id, stage, 'converted')
+        await db.reset_winback_stage(user_id)
+        logger.info(f"User {user_id} converted after win-back stage {stage}")
@@ -5,16 +5,20 @@ from aiogram.fsm.state import State, StatesGroup
 class Admin(StatesGroup):
     """Состояния для админ-панели."""
     waiting_for_broadcast = State()
+    waiting_for_broadcast_confirm = State()
     waiting_for_grant = State()
     waiting_for_revoke = State()
     waiting_for_block = State()
     waiting_for_unblock = State()
     waiting_for_find_user = State()
+    waiting_for_quota_value = State()
 
 class ImageGen(StatesGroup):
     """Состояния для генерации изображений."""
     waiting_for_model = State()
     waiting_for_prompt = State()
+    waiting_for_style = State() # Промпт получен, ждем выбора стиля
+    waiting_for_style_text = State()
     
 class Captcha(StatesGroup):
     """Состояние для прохождения капчи."""
@@ -24,11 +28,26 @@ class Chat(StatesGroup):
     """Состояние для обычного чата."""
     in_progress = State()
 
+class ModelWizard(StatesGroup):
+    """Состояния мастера подбора модели."""
+    waiting_for_priority = State()
+    waiting_for_task = State()
+    waiting_for_budget = State()
+
 class MaxMode(StatesGroup):
     """Состояние для чата в режиме Max Mode."""
     in_progress = State()
 
+class Survey(StatesGroup):
+    """Состояние для ответа на опрос свободным текстом."""
+    waiting_for_answer = State()
+
 class Settings(StatesGroup):
     """Состояния для меню настроек."""
     waiting_for_instruction = State()
     waiting_for_temperature = State()
+    waiting_for_quiet_hours = State()
+    waiting_for_utc_offset = State()
+    waiting_for_webhook_name = State()
+    waiting_for_scheduled_prompt = State()
+    waiting_for_schedule = State()
@@ -0,0 +1,72 @@
+# app/storage.py
+# Хранилище состояний FSM (капча, настройки, активный чат).
+# По умолчанию состояния хранятся в памяти и теряются при перезапуске;
+# с FSM_STORAGE=redis или sqlite они переживают деплой.
+# Пакет redis нужен только для режима redis; sqlite использует основную БД бота.
+
+import json
+import logging
+from typing import Any, Dict
+
+from aiogram.fsm.state import State
+from aiogram.fsm.storage.base import BaseStorage, StateType, StorageKey
+from aiogram.fsm.storage.memory import MemoryStorage
+
+from app.config import FSM_STORAGE, REDIS_URL, FSM_STATE_TTL_DAYS
+from app.database import Database
+
+logger = logging.getLogger(__name__)
+
+STORAGE_BACKENDS = ('memory', 'redis', 'sqlite')
+
+
+class SqliteStorage(BaseStorage):
+    """Состояния и данные FSM в таблице dialogue_states основной БД (данные - в JSON)."""
+
+    def __init__(self, db: Database):
+        self.db = db
+
+    @staticmethod
+    def _key(key: StorageKey) -> str:
+        business_connection_id = getattr(key, 'business_connection_id', None)
+        return f"{key.bot_id}:{key.chat_id}:{key.user_id}:{key.thread_id}:{business_connection_id}:{key.destiny}"
+
+    async def set_state(self, key: StorageKey, state: StateType = None) -> None:
+        await self.db.set_dialogue_state(self._key(key), state.state if isinstance(state, State) else state)
+
+    async def get_state(self, key: StorageKey) -> str | None:
+        row = await self.db.get_dialogue_state(self._key(key))
+        return row[0] if row else None
+
+    async def set_data(self, key: StorageKey, data: Dict[str, Any]) -> None:
+        await self.db.set_dialogue_data(self._key(key), json.dumps(data, ensure_ascii=False))
+
+    async def get_data(self, key: StorageKey) -> Dict[str, Any]:
+        row = await self.db.get_dialogue_state(self._key(key))
+        return json.loads(row[1]) if row and row[1] else {}
+
+    async def close(self) -> None:
+        pass
+
+
+def create_redis_storage() -> BaseStorage:
+    try:
+        from aiogram.fsm.storage.redis import RedisStorage, DefaultKeyBuilder
+    except ImportError as e:
+        raise RuntimeError("FSM_STORAGE=redis requires the 'redis' package: pip install redis") from e
+    ttl = FSM_STATE_TTL_DAYS * 24 * 3600
+    # with_bot_id разделяет состояния нескольких ботов, использующих один Redis
+    return RedisStorage.from_url(REDIS_URL, key_builder=DefaultKeyBuilder(with_bot_id=True), state_ttl=ttl, data_ttl=ttl)
+
+def create_fsm_storage(db: Database) -> BaseStorage:
+    """Создает хранилище состояний, выбранное в FSM_STORAGE."""
+    if FSM_STORAGE not in STORAGE_BACKENDS:
+        raise ValueError(f"Unknown FSM_STORAGE '{FSM_STORAGE}', expected one of: {', '.join(STORAGE_BACKENDS)}")
+    if FSM_STORAGE == 'redis':
+        storage = create_redis_storage()
+    elif FSM_STORAGE == 'sqlite':
+        storage = SqliteStorage(db)
+    else:
+        storage = MemoryStorage()
+    logger.info(f"Using {FSM_STORAGE} FSM storage.")
+    return storage
@@ -0,0 +1,9 @@
+# app/web/keys.py
+# Ключи зависимостей, которые HTTP-сервер получает от бота (аналог dp["db"] и т.п.).
+
+from aiohttp import web
+
+DB_KEY = web.AppKey('db')
+AI_CLIENT_KEY = web.AppKey('ai_client')
+CACHE_KEY = web.AppKey('cache')
+BOT_KEY = web.AppKey('bot')
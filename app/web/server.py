@@ -0,0 +1,33 @@
+# app/web/server.py
+# HTTP-сервер для интеграций. Работает в том же процессе, что и бот, и использует те же сервисы и БД.
+
+import logging
+
+from aiogram import Bot
+from aiohttp import web
+
+from app.config import API_SERVER_HOST, API_SERVER_PORT
+from app.database import Database
+from app.web import user_api, webhooks
+from app.web.keys import DB_KEY, AI_CLIENT_KEY, CACHE_KEY, BOT_KEY
+
+logger = logging.getLogger(__name__)
+
+
+def create_app(bot: Bot, db: Database, ai_client, cache: dict) -> web.Application:
+    app = web.Application()
+    app[BOT_KEY] = bot
+    app[DB_KEY] = db
+    app[AI_CLIENT_KEY] = ai_client
+    app[CACHE_KEY] = cache
+    app.add_routes(user_api.routes)
+    app.add_routes(webhooks.routes)
+    return app
+
+async def start_web_server(bot: Bot, db: Database, ai_client, cache: dict) -> web.AppRunner:
+    """Запускает HTTP-сервер и возвращает runner для остановки при завершении бота."""
+    runner = web.AppRunner(create_app(bot, db, ai_client, cache))
+    await runner.setup()
+    await web.TCPSite(runner, API_SERVER_HOST, API_SERVER_PORT).start()
+    logger.info(f"HTTP API server started on {API_SERVER_HOST}:{API_SERVER_PORT}")
+    return runner
@@ -0,0 +1,111 @@
+# app/web/user_api.py
+# POST /v1/user/chat — запрос к модели по персональному токену.
+# Запрос проходит те же проверки, что и сообщение в боте: блокировка, антиспам, дневной лимит;
+# запрос списывается с дневного лимита пользователя.
+
+import logging
+
+from aiohttp import web
+from openai import APIError
+
+from app.config import API_TOKEN_MIN_LEVEL, API_MAX_MESSAGES, QUOTA_MODE
+from app.services.ai_service import get_simple_response
+from app.services.abuse_service import check_prompt_abuse
+from app.services.model_service import can_answer
+from app.services.token_service import get_user_id_by_token
+from app.services.trace_service import new_request_id, get_request_id
+from app.services.user_service import (
+    get_user_details_cached, get_user_level, get_user_limits, get_usage_today, get_accessible_models
+)
+from app.web.keys import DB_KEY, AI_CLIENT_KEY, CACHE_KEY
+
+logger = logging.getLogger(__name__)
+routes = web.RouteTableDef()
+
+
+def _error(status: int, message: str, **extra) -> web.Response:
+    return web.json_response({"error": message, "request_id": get_request_id(), **extra}, status=status)
+
+def _parse_messages(body: dict) -> list | None:
+    """Принимает {"prompt": "..."} или {"messages": [{"role": "user", "content": "..."}]}."""
+    if isinstance(body.get('prompt'), str) and body['prompt'].strip():
+        return [{"role": "user", "content": body['prompt']}]
+    messages = body.get('messages')
+    if not isinstance(messages, list) or not 0 < len(messages) <= API_MAX_MESSAGES:
+        return None
+    parsed = []
+    for item in messages:
+        if not isinstance(item, dict) or item.get('role') not in ('user', 'assistant') or not isinstance(item.get('content'), str):
+            return None
+        parsed.append({"role": item['role'], "content": item['content']})
+    return parsed if parsed[-1]['role'] == 'user' else None
+
+@routes.post('/v1/user/chat')
+async def user_chat(request: web.Request) -> web.Response:
+    db, ai_client, cache = request.app[DB_KEY], request.app[AI_CLIENT_KEY], request.app[CACHE_KEY]
+    new_request_id()
+
+    token = request.headers.get('Authorization', '').removeprefix('Bearer ').strip()
+    user_id = await get_user_id_by_token(db, token) if token else None
+    if not user_id:
+        return _error(401, "Invalid or missing API token")
+
+    try:
+        body = await request.json()
+    except ValueError:
+        return _error(400, "Request body must be valid JSON")
+    messages = _parse_messages(body) if isinstance(body, dict) else None
+    if not messages:
+        return _error(400, f"Pass a non-empty 'prompt' or up to {API_MAX_MESSAGES} 'messages' ending with a user message")
+
+    details = await get_user_details_cached(user_id, db, cache)
+    if not details or details[4]:
+        return _error(403, "Access to models is blocked for this account")
+    user_level = await get_user_level(user_id, db)
+    if user_level < API_TOKEN_MIN_LEVEL:
+        return _error(403, "API access requires the Max plan")
+
+    model = body.get('model') or details[5]
+    if not model:
+        return _error(400, "Pass 'model': no model has been selected in the bot yet")
+    accessible_models = get_accessible_models(user_level, await db.is_beta_tester(user_id))
+    if model not in accessible_models:
+        return _error(400, "Unknown model or model is not available on your plan", model=model)
+    if not can_answer(model, accessible_models, cache):
+        return _error(503, "Model is temporarily unavailable", model=model)
+
+    spam_reason = await check_prompt_abuse(user_id, messages[-1]['content'], cache, ai_client)
+    if spam_reason:
+        return _error(429, "Request blocked by anti-spam rules", reason=spam_reason)
+
+    daily_limit, _ = await get_user_limits(user_id, db)
+    if await get_usage_today(user_id, db) >= daily_limit:
+        return _error(429, "Daily request limit reached", daily_limit=int(daily_limit))
+
+    try:
+        response_text, duration, usage = await get_simple_response(ai_client, model, messages, user_id, db, cache)
+    except (APIError, RuntimeError) as e:
+        logger.error(f"API chat error for user {user_id} with model {model}: {e}")
+        return _error(502, "Model provider error", model=model)
+
+    # Если модель не ответила, могла ответить запасная; в ответе указывается фактическая модель
+    requested_model, model = model, usage.model or model
+    await db.add_request(user_id, model, is_max_mode=False, tokens=usage.tokens)
+    logger.info(f"API chat request from user {user_id} with model {model} took {duration:.2f}s")
+    return web.json_response({
+        "request_id": get_request_id(),
+        "model": model,
+        "requested_model": requested_model,
+        "content": response_text,
+        "duration": round(duration, 2),
+        # Безлимитный тариф передается как null: бесконечность не сериализуется в JSON.
+        # used_today и daily_limit - в единицах quota_unit (requests или tokens)
+        "usage": {
+            "requests_today": await db.get_user_requests_today(user_id),
+            "used_today": await get_usage_today(user_id, db),
+            "daily_limit": None if daily_limit == float('inf') else daily_limit,
+            "quota_unit": QUOTA_MODE,
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+        },
+    })
@@ -0,0 +1,40 @@
+# app/web/webhooks.py
+# POST /v1/hooks/{token} — входящее уведомление для пользователя.
+# Запрос принимается сразу, а доставка (и краткое изложение моделью) выполняется в фоне.
+
+import asyncio
+import logging
+
+from aiohttp import web
+
+from app.config import WEBHOOK_MAX_PAYLOAD, WEBHOOK_RATE_LIMIT
+from app.services.webhook_service import get_webhook, check_rate_limit, deliver_webhook
+from app.web.keys import DB_KEY, AI_CLIENT_KEY, CACHE_KEY, BOT_KEY
+
+logger = logging.getLogger(__name__)
+routes = web.RouteTableDef()
+# Доставки в фоне: event loop хранит на задачи только слабые ссылки, и без этого набора задача может быть удалена до завершения
+_delivery_tasks: set[asyncio.Task] = set()
+
+
+@routes.post('/v1/hooks/{token}')
+async def incoming_webhook(request: web.Request) -> web.Response:
+    db, cache = request.app[DB_KEY], request.app[CACHE_KEY]
+    webhook = await get_webhook(db, request.match_info['token'])
+    if not webhook:
+        return web.json_response({"error": "Unknown webhook"}, status=404)
+
+    if request.content_length and request.content_length > WEBHOOK_MAX_PAYLOAD:
+        return web.json_response({"error": f"Payload is larger than {WEBHOOK_MAX_PAYLOAD} bytes"}, status=413)
+    raw_payload = (await request.content.read(WEBHOOK_MAX_PAYLOAD)).decode('utf-8', errors='replace')
+
+    if not check_rate_limit(cache, webhook[0]):
+        logger.warning(f"Webhook #{webhook[0]} is rate limited")
+        return web.json_response({"error": f"Rate limit of {WEBHOOK_RATE_LIMIT} messages per minute exceeded"}, status=429)
+
+    task = asyncio.create_task(deliver_webhook(
+        request.app[BOT_KEY], db, request.app[AI_CLIENT_KEY], cache, webhook, raw_payload
+    ))
+    _delivery_tasks.add(task)
+    task.add_done_callback(_delivery_tasks.discard)
+    return web.json_response({"status": "accepted"}, status=202)
@@ -1,26 +1,48 @@
 # bot.py (в корне проекта)
 
+import argparse
 import asyncio
 import logging
 import json
+import sys
 from datetime import datetime, timezone, timedelta
 from typing import Any, Awaitable, Callable, Dict
 
 from aiogram import Bot, Dispatcher, BaseMiddleware
 from aiogram.client.default import DefaultBotProperties
-from aiogram.fsm.storage.memory import MemoryStorage
 from aiogram.types import BotCommand, TelegramObject, CallbackQuery
 from apscheduler.schedulers.asyncio import AsyncIOScheduler
-from openai import AsyncOpenAI
 from cachetools import TTLCache
 
 # Импорты из нашей новой структуры
-from app.config import BOT_TOKEN, API_KEY, API_URL, DATABASE_PATH
+from app.config import (
+    BOT_TOKEN, DATABASE_PATH, SPAM_WINDOW_SECONDS, SPAM_BLOCK_MINUTES,
+    PENDING_NOTIFICATIONS_INTERVAL_MINUTES, API_SERVER_ENABLED, FSM_STORAGE, FSM_STATE_TTL_DAYS,
+    REWARD_RECHECK_INTERVAL_HOURS, CHAOS_MAX_MINUTES, MAX_MODE_RUNS_KEEP_DAYS, ANALYTICS_FLUSH_SECONDS,
+    ANALYTICS_KEEP_DAYS, LOG_FILE, ADMIN_REPORT_ENABLED, ADMIN_REPORT_HOUR, MODEL_CHECK_INTERVAL_MINUTES
+)
 from app.database import Database
-from app.middlewares import ThrottlingMiddleware
+from app.storage import create_fsm_storage
+from app.middlewares import ThrottlingMiddleware, ChatModeMiddleware, AnalyticsMiddleware, RequestIdMiddleware
 # --- ИЗМЕНЕНИЕ: добавляем group ---
-from app.handlers import admin, chat, common, image_gen, settings, subscription, group
-from app.services.system_service import scheduled_model_test, startup_model_check
+from app.handlers import admin, chat, common, intent, image_gen, settings, subscription, group, model_wizard, survey, routing
+from app.services.system_service import (
+    scheduled_model_test, startup_warmup, announce_new_version, get_full_version, notify_interrupted_requests
+)
+from app.services.broadcast_service import resume_unfinished_broadcasts
+from app.services.network_service import create_telegram_session
+from app.services.provider_service import create_providers
+from app.services.file_service import cleanup_stale_files, cleanup_file_cache
+from app.services.notification_service import flush_pending_notifications
+from app.services.winback_service import run_winback
+from app.services.reward_service import verify_reward_memberships
+from app.services.scheduled_prompt_service import run_scheduled_prompts
+from app.services.selfcheck_service import run_self_check
+from app.services.update_journal_service import collect_missed_updates
+from app.services.analytics_service import flush_events
+from app.services.admin_report_service import send_admin_report
+from app.services.trace_service import RequestIdFilter, trace_buffer
+from app.web.server import start_web_server
 
 # Глобальные переменные и объекты
 logger = logging.getLogger(__name__)
@@ -28,7 +50,22 @@ logger = logging.getLogger(__name__)
 # Глобальный кэш для хранения данных, например, статуса моделей
 GLOBAL_CACHE = {
     "model_status": TTLCache(maxsize=1, ttl=600),
-    "user_details": TTLCache(maxsize=1000, ttl=300) # Кэш для данных пользователей
+    "circuit_breaker": {}, # Сбои моделей подряд и время, до которого модель отключена
+    "media_groups": {}, # Части альбомов, которые еще приходят (message_buffer_service)
+    "prompt_parts": {}, # Запросы, которые пользователи отправляют частями (message_buffer_service)
+    "image_queue": {}, # Слоты генерации изображений и очереди групп (image_queue_service)
+    "analytics_events": [], # События аналитики, ожидающие записи в БД
+    "model_catalog": TTLCache(maxsize=1, ttl=3600), # Список моделей, которые отдает провайдер
+    "user_details": TTLCache(maxsize=1000, ttl=300), # Кэш для данных пользователей
+    "group_cooldowns": TTLCache(maxsize=10_000, ttl=3600), # Время последнего запроса участника группы
+    "spam_tracker": TTLCache(maxsize=10_000, ttl=SPAM_WINDOW_SECONDS), # Последние запросы пользователей
+    "spam_blocks": TTLCache(maxsize=10_000, ttl=SPAM_BLOCK_MINUTES * 60), # Временные ограничения за спам
+    "spam_stats": {}, # Счетчики сработавших правил антиспама
+    "webhook_rate": TTLCache(maxsize=10_000, ttl=60), # Время последних доставок входящих вебхуков
+    "tool_servers": TTLCache(maxsize=1, ttl=300), # Описания инструментов подключенных серверов
+    "context_memory": TTLCache(maxsize=1000, ttl=6 * 3600), # Пересказы старых сообщений бесед (context_service)
+    "public_stats": TTLCache(maxsize=1, ttl=300), # Общая статистика для /stats, чтобы не считать ее на каждый вызов
+    "chaos": TTLCache(maxsize=1, ttl=CHAOS_MAX_MINUTES * 60) # Имитация сбоев провайдера (/chaos), выключается сама
 }
 
 # --- MIDDLEWARE ДЛЯ ЛОГИРОВАНИЯ ---
@@ -53,28 +90,37 @@ async def set_bot_commands(bot_instance: Bot):
     commands = [
         BotCommand(command="start", description="Перезапустить бота / Главное меню"),
         BotCommand(command="menu", description="Показать меню"),
+        BotCommand(command="whatsnew", description="Что нового в боте"),
+        BotCommand(command="stats", description="Статистика бота"),
+        BotCommand(command="profile", description="Ваш профиль и любимые модели"),
     ]
     await bot_instance.set_my_commands(commands)
 
 async def main():
     """Основная функция для запуска бота."""
-    # Настраиваем логирование в файл и в консоль для отладки
+    # Настраиваем логирование в файл и в консоль для отладки.
+    # В каждой строке - код запроса, по которому /trace находит все строки лога одного запроса
+    log_handlers = [
+        logging.FileHandler(LOG_FILE, mode='a'), # Запись в файл (дозапись)
+        logging.StreamHandler(),                 # Вывод в консоль
+        trace_buffer                             # Последние запросы в памяти для /trace
+    ]
+    for handler in log_handlers:
+        handler.addFilter(RequestIdFilter())
     logging.basicConfig(
         level=logging.INFO,
-        format='%(asctime)s - %(levelname)s - %(name)s - %(message)s',
-        handlers=[
-            logging.FileHandler("bot.log", mode='a'), # Запись в файл (дозапись)
-            logging.StreamHandler()                    # Вывод в консоль
-        ]
+        format='%(asctime)s - %(levelname)s - [%(request_id)s] - %(name)s - %(message)s',
+        handlers=log_handlers
     )
-    logger.info("Starting bot...")
+    logger.info(f"Starting bot version {get_full_version()}...")
 
     # Инициализация основных объектов
-    storage = MemoryStorage()
-    bot = Bot(token=BOT_TOKEN, default=DefaultBotProperties(parse_mode="HTML"))
-    dp = Dispatcher(storage=storage)
     db = Database(DATABASE_PATH)
-    ai_client = AsyncOpenAI(base_url=API_URL, api_key=API_KEY)
+    storage = create_fsm_storage(db)
+    bot = Bot(token=BOT_TOKEN, session=create_telegram_session(), default=DefaultBotProperties(parse_mode="HTML"))
+    dp = Dispatcher(storage=storage)
+    # Реестр провайдеров моделей; имя ai_client сохранено, под ним зависимость получают обработчики и сервисы
+    ai_client = create_providers()
     
     # Инициализация планировщика
     scheduler = AsyncIOScheduler(timezone="Europe/Moscow")
@@ -86,49 +132,102 @@ async def main():
     dp["cache"] = GLOBAL_CACHE
 
     # Настройка middleware
+    dp.update.middleware(RequestIdMiddleware())
     dp.update.middleware(LoggingMiddleware())
     dp.update.middleware(ThrottlingMiddleware(rate_limit=1.0))
+    dp.update.middleware(ChatModeMiddleware())
+    dp.update.middleware(AnalyticsMiddleware())
 
     # Регистрация роутеров из модулей handlers
     logger.info("Registering routers...")
-    dp.include_router(common.router)
-    dp.include_router(subscription.router)
-    dp.include_router(settings.router)
-    dp.include_router(image_gen.router)
-    dp.include_router(admin.router)
-    # --- ИЗМЕНЕНИЕ: добавляем роутер для групп ---
-    dp.include_router(group.router)
-    dp.include_router(chat.router) # Роутер для личных сообщений должен идти последним
+    # Посты каналов и сообщения от имени чатов разбираются до остальных роутеров
+    dp.include_router(routing.router)
+    # Личные сообщения. Админский роутер и роутер текста вне меню идут первыми, чтобы их сообщения не перехватывал
+    # обработчик нераспознанных сообщений, роутер чата - последним
+    dp.include_router(routing.build_chat_type_router(
+        "private", routing.PRIVATE_CHAT_TYPES,
+        admin.router, intent.router, common.router, subscription.router, settings.router, image_gen.router,
+        model_wizard.router, survey.router, chat.router
+    ))
+    dp.include_router(routing.build_chat_type_router("groups", routing.GROUP_CHAT_TYPES, group.router))
 
     # Инициализация базы данных
     await db.init_db()
+    cleanup_stale_files()
     
-    # Запускаем проверку моделей как фоновую задачу
-    logger.info("Scheduling startup model check to run in the background.")
-    asyncio.create_task(startup_model_check(ai_client, db, GLOBAL_CACHE))
+    # Запускаем прогрев (статусы моделей, кэши, каталог моделей) как фоновую задачу
+    logger.info("Scheduling startup warmup to run in the background.")
+    asyncio.create_task(startup_warmup(ai_client, db, GLOBAL_CACHE))
 
     # Настройка и запуск фоновой задачи для регулярной проверки моделей
     scheduler.add_job(
         scheduled_model_test, 
         'interval', 
-        minutes=10, 
-        args=(ai_client, db, GLOBAL_CACHE)
+        minutes=MODEL_CHECK_INTERVAL_MINUTES, 
+        args=(ai_client, db, GLOBAL_CACHE, bot)
+    )
+    # Ежедневная очистка устаревших результатов обработки файлов
+    scheduler.add_job(cleanup_file_cache, 'cron', hour=4, args=(db,))
+    scheduler.add_job(db.delete_old_max_mode_runs, 'cron', hour=4, args=(MAX_MODE_RUNS_KEEP_DAYS,))
+    scheduler.add_job(db.delete_old_events, 'cron', hour=4, args=(ANALYTICS_KEEP_DAYS,))
+    # События аналитики записываются пачками
+    scheduler.add_job(flush_events, 'interval', seconds=ANALYTICS_FLUSH_SECONDS, args=(db, GLOBAL_CACHE))
+    if FSM_STORAGE == 'sqlite':
+        # Состояния неактивных пользователей удаляются, как и по TTL в Redis
+        scheduler.add_job(db.delete_stale_dialogue_states, 'cron', hour=4, args=(FSM_STATE_TTL_DAYS,))
+    # Отправка уведомлений, отложенных из-за тихих часов
+    scheduler.add_job(
+        flush_pending_notifications, 'interval',
+        minutes=PENDING_NOTIFICATIONS_INTERVAL_MINUTES, args=(bot, db)
+    )
+    # Сообщения ушедшим подписчикам отправляются раз в день, днем
+    scheduler.add_job(run_winback, 'cron', hour=12, args=(bot, db))
+    if ADMIN_REPORT_ENABLED:
+        scheduler.add_job(send_admin_report, 'cron', hour=ADMIN_REPORT_HOUR, args=(bot, db, GLOBAL_CACHE))
+    # Запланированные запросы пользователей
+    scheduler.add_job(run_scheduled_prompts, 'interval', minutes=1, args=(bot, db, ai_client, GLOBAL_CACHE))
+    # Получившие бонус за каналы должны оставаться в них
+    scheduler.add_job(
+        verify_reward_memberships, 'interval',
+        hours=REWARD_RECHECK_INTERVAL_HOURS, args=(bot, db, GLOBAL_CACHE)
     )
     scheduler.start()
 
+    # Возобновляем рассылки, прерванные предыдущим запуском
+    await resume_unfinished_broadcasts(scheduler, bot, db)
+    # Сообщаем пользователям о запросах, прерванных перезапуском
+    await notify_interrupted_requests(bot, db)
+
     # Установка команд бота
     await set_bot_commands(bot)
 
+    # Оповещаем администраторов, если версия бота изменилась
+    await announce_new_version(bot, db)
+
+    # HTTP API для интеграций по персональным токенам
+    web_runner = await start_web_server(bot, db, ai_client, GLOBAL_CACHE) if API_SERVER_ENABLED else None
+
     # Запуск polling
     try:
-        await bot.delete_webhook(drop_pending_updates=True)
+        await bot.delete_webhook()
+        # Обновления за время простоя: критичные сохраняются в журнал, остальные пропускаются
+        await collect_missed_updates(bot, db)
         await dp.start_polling(bot)
     finally:
+        if web_runner:
+            await web_runner.cleanup()
+        await flush_events(db, GLOBAL_CACHE)
         await bot.session.close()
+        await storage.close()
         scheduler.shutdown()
         logger.info("Bot stopped.")
 
 if __name__ == '__main__':
+    parser = argparse.ArgumentParser(description="MiniArima Telegram bot")
+    parser.add_argument('--check', action='store_true', help="проверить конфигурацию и подключения и выйти")
+    if parser.parse_args().check:
+        sys.exit(asyncio.run(run_self_check()))
+
     # Создаем логгер для этого блока, чтобы точно записать критическую ошибку
     main_logger = logging.getLogger(__name__)
     try:
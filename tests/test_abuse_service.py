@@ -0,0 +1,56 @@
+# tests/test_abuse_service.py
+# Табличные тесты классификатора спама. Запуск: python -m unittest discover -s tests -t .
+
+import asyncio
+import os
+import unittest
+from unittest.mock import AsyncMock, MagicMock, patch
+
+os.environ.setdefault('ADMIN_IDS', '1')
+
+from app.services import abuse_service
+
+_PROMPT = "qwrtpsdfgh zxcvbnmlkj bcdfghjklm"
+
+
+def _classifier(result: dict | None = None, error: Exception | None = None) -> AsyncMock:
+    return AsyncMock(return_value=result, side_effect=error)
+
+
+class ConfirmWithModelTest(unittest.TestCase):
+    CASES = [
+        # (описание, ответ классификатора, ожидаемый результат)
+        ("модель подтвердила", _classifier({"spam": True}), True),
+        ("модель не подтвердила", _classifier({"spam": False}), False),
+        ("строка вместо булева значения", _classifier({"spam": "false"}), False),
+        ("ответ без поля spam", _classifier({}), False),
+        ("ошибка запроса", _classifier(error=RuntimeError("provider down")), False),
+        ("таймаут", _classifier(error=asyncio.TimeoutError()), False),
+    ]
+
+    def test_cases(self):
+        for name, classifier, expected in self.CASES:
+            with self.subTest(name), patch.object(abuse_service, 'get_structured_response', classifier):
+                self.assertIs(asyncio.run(abuse_service._confirm_with_model(MagicMock(), _PROMPT)), expected)
+
+
+class GibberishRuleTest(unittest.TestCase):
+    CASES = [
+        # (описание, ответ классификатора, модель-классификатор, клиент, ожидаемая причина)
+        ("без модели-классификатора", _classifier({"spam": True}), '', MagicMock(), None),
+        ("без клиента", _classifier({"spam": True}), 'classifier', None, None),
+        ("ошибка модели", _classifier(error=RuntimeError("provider down")), 'classifier', MagicMock(), None),
+        ("модель не подтвердила", _classifier({"spam": False}), 'classifier', MagicMock(), None),
+        ("модель подтвердила", _classifier({"spam": True}), 'classifier', MagicMock(), 'gibberish'),
+    ]
+
+    def test_cases(self):
+        for name, classifier, model, client, expected in self.CASES:
+            cache = {"spam_blocks": {}, "spam_tracker": {}}
+            with self.subTest(name), patch.object(abuse_service, 'get_structured_response', classifier), \
+                    patch.object(abuse_service, 'SPAM_CLASSIFIER_MODEL', model):
+                self.assertEqual(asyncio.run(abuse_service.check_prompt_abuse(1, _PROMPT, cache, client)), expected)
+
+
+if __name__ == '__main__':
+    unittest.main()
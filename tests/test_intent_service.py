@@ -0,0 +1,49 @@
+# tests/test_intent_service.py
+# Табличные тесты распознавания намерения в тексте вне меню. Запуск: python -m unittest discover -s tests -t .
+
+import os
+import unittest
+
+os.environ.setdefault('ADMIN_IDS', '1')
+
+from app.services.intent_service import INTENT_CHAT, INTENT_IMAGE, INTENT_LIMITS, detect_intent
+
+_LONG_QUESTION = "Объясни, пожалуйста, подробно, как работает лимит запросов в API и сколько осталось до сброса у OpenAI"
+
+
+class DetectIntentTest(unittest.TestCase):
+    CASES = [
+        # (описание, текст, ожидаемое намерение, ожидаемый текст запроса)
+        ("нарисуй", "нарисуй кота в шляпе", INTENT_IMAGE, "кота в шляпе"),
+        ("нарисуйте с пожалуйста", "Пожалуйста, нарисуйте закат", INTENT_IMAGE, "закат"),
+        ("нарисуй мне", "нарисуй мне дом", INTENT_IMAGE, "дом"),
+        ("изобрази с двоеточием", "Изобрази: море", INTENT_IMAGE, "море"),
+        ("сгенерируй картинку", "сгенерируй картинку робота", INTENT_IMAGE, "робота"),
+        ("сделай мне рисунок", "сделай мне рисунок леса", INTENT_IMAGE, "леса"),
+        ("нарисуй без промпта", "нарисуй", INTENT_IMAGE, ""),
+        ("draw me", "draw me a cat", INTENT_IMAGE, "a cat"),
+        ("draw a picture of", "Draw a picture of a sunset", INTENT_IMAGE, "a sunset"),
+        ("draw me an image of", "draw me an image of mountains", INTENT_IMAGE, "mountains"),
+        ("generate an image", "generate an image of a robot", INTENT_IMAGE, "a robot"),
+        ("draw в переносном смысле", "draw conclusions from this text", INTENT_CHAT, "draw conclusions from this text"),
+        ("draw как часть слова", "drawing tips for beginners", INTENT_CHAT, "drawing tips for beginners"),
+        ("draw без объекта", "draw a cat", INTENT_CHAT, "draw a cat"),
+        ("нарисуй не в начале", "как нарисовать кота?", INTENT_CHAT, "как нарисовать кота?"),
+        ("сделай без картинки", "сделай вывод по тексту", INTENT_CHAT, "сделай вывод по тексту"),
+        ("сколько осталось", "Сколько осталось?", INTENT_LIMITS, "Сколько осталось?"),
+        ("сколько у меня запросов", "сколько у меня запросов", INTENT_LIMITS, "сколько у меня запросов"),
+        ("сколько еще", "сколько ещё осталось", INTENT_LIMITS, "сколько ещё осталось"),
+        ("мой лимит", "какой у меня лимит?", INTENT_LIMITS, "какой у меня лимит?"),
+        ("остаток токенов", "остаток токенов", INTENT_LIMITS, "остаток токенов"),
+        ("длинный вопрос про лимиты", _LONG_QUESTION, INTENT_CHAT, _LONG_QUESTION),
+        ("обычный вопрос", "Что такое черная дыра?", INTENT_CHAT, "Что такое черная дыра?"),
+    ]
+
+    def test_cases(self):
+        for name, text, intent, prompt in self.CASES:
+            with self.subTest(name):
+                self.assertEqual(detect_intent(text), (intent, prompt))
+
+
+if __name__ == '__main__':
+    unittest.main()
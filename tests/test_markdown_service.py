@@ -0,0 +1,90 @@
+# tests/test_markdown_service.py
+# Табличные тесты перевода Markdown в HTML Telegram. Запуск: python -m unittest discover -s tests -t .
+
+import os
+import unittest
+
+os.environ.setdefault('ADMIN_IDS', '1')
+
+from app.services.markdown_service import _render_inline, _render_table, is_balanced, markdown_to_html
+
+
+class MarkdownToHtmlTest(unittest.TestCase):
+    CASES = [
+        # (описание, Markdown, ожидаемый HTML)
+        ("жирный", "**bold**", "<b>bold</b>"),
+        ("курсив внутри жирного", "**bold *italic* text**", "<b>bold <i>italic</i> text</b>"),
+        ("жирный внутри курсива", "_a **b** c_", "<i>a <b>b</b> c</i>"),
+        ("жирный курсив", "***both***", "<b><i>both</i></b>"),
+        ("snake_case не курсив", "use snake_case_name here", "use snake_case_name here"),
+        ("умножение не курсив", "2*3*4 = 24", "2*3*4 = 24"),
+        ("звездочки с пробелами", "a * b * c", "a * b * c"),
+        ("незакрытый жирный", "**unclosed bold", "**unclosed bold"),
+        ("зачеркнутый и спойлер", "x ~~del~~ ||sp||", "x <s>del</s> <tg-spoiler>sp</tg-spoiler>"),
+        ("инлайн-код экранируется", "`a < b` and <b>x</b>", "<code>a &lt; b</code> and <b>x</b>"),
+        ("ссылка с амперсандом", "[link](https://ex.com/a?b=1&c=2)",
+         '<a href="https://ex.com/a?b=1&amp;c=2">link</a>'),
+        ("амперсанд и готовые сущности", "Tom & Jerry &amp; &lt;x&gt; &#39; &copy;",
+         "Tom &amp; Jerry &amp; &lt;x&gt; &#39; &copy;"),
+        ("заголовок", "# Head", "<b>Head</b>"),
+        ("список", "- item\n* item2", "• item\n• item2"),
+        ("цитата", "> quote\n> more", "<blockquote>quote\nmore</blockquote>"),
+        ("блок кода", "```\ncode <b>\n```\nafter", "<pre>code &lt;b&gt;</pre>\nafter"),
+        ("незакрытый блок кода", "```python\nprint(1)",
+         '<pre><code class="language-python">print(1)</code></pre>'),
+        ("таблица", "| a | b |\n|---|---|\n| 1 | **2** |", "<pre>a | b\n1 | 2</pre>"),
+    ]
+
+    def test_cases(self):
+        for name, source, expected in self.CASES:
+            with self.subTest(name):
+                result = markdown_to_html(source)
+                self.assertEqual(result, expected)
+                self.assertTrue(is_balanced(result))
+
+
+class RenderInlineTest(unittest.TestCase):
+    CASES = [
+        ("простой текст", "plain & text", "plain &amp; text"),
+        ("разрешенный тег сохраняется", "<i>x</i> **y**", "<i>x</i> <b>y</b>"),
+        ("чужой тег экранируется", "<script>", "&lt;script&gt;"),
+        ("разметка внутри кода не действует", "`**x**`", "<code>**x**</code>"),
+    ]
+
+    def test_cases(self):
+        for name, source, expected in self.CASES:
+            with self.subTest(name):
+                self.assertEqual(_render_inline(source), expected)
+
+
+class RenderTableTest(unittest.TestCase):
+    CASES = [
+        ("выравнивание столбцов", ["| name | n |", "|---|:-:|", "| x | 10 |"], "<pre>name | n\nx    | 10</pre>"),
+        ("строки разной длины", ["| a | b | c |", "| 1 |"], "<pre>a | b | c\n1</pre>"),
+        ("амперсанд в ячейке", ["| a & b |"], "<pre>a &amp; b</pre>"),
+    ]
+
+    def test_cases(self):
+        for name, rows, expected in self.CASES:
+            with self.subTest(name):
+                self.assertEqual(_render_table(rows), expected)
+
+
+class IsBalancedTest(unittest.TestCase):
+    CASES = [
+        ("пустая строка", "", True),
+        ("вложенные теги", "<b>a <i>b</i></b>", True),
+        ("тег с атрибутами", '<a href="x">y</a>', True),
+        ("незакрытый тег", "<b>a", False),
+        ("лишний закрывающий тег", "a</b>", False),
+        ("перекрестные теги", "<b><i>x</b></i>", False),
+    ]
+
+    def test_cases(self):
+        for name, text, expected in self.CASES:
+            with self.subTest(name):
+                self.assertEqual(is_balanced(text), expected)
+
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,134 @@
+# tests/test_text_service.py
+# Табличные тесты деления длинного ответа на сообщения Telegram. Запуск: python -m unittest discover -s tests -t .
+
+import os
+import unittest
+
+os.environ.setdefault('ADMIN_IDS', '1')
+
+import re
+
+from unittest.mock import patch
+
+from app.services import text_service
+from app.services.text_service import TELEGRAM_MESSAGE_LIMIT, _find_cut, format_quota, split_message
+
+_CODE_BLOCK = ('<pre><code class="language-python">'
+               + "\n".join(f"x{i} = {i} &amp; {i}" for i in range(800)) + "</code></pre>")
+_MIXED = "\n\n".join([
+    "<b>Жирный абзац</b> с текстом. " * 40,
+    "```python\n" + "value = a & b  # &lt;tag&gt;\n" * 300 + "```",
+    "<blockquote>" + "Цитата &amp; <i>курсив</i>. " * 200 + "</blockquote>",
+    "<i>курсив</i> и <code>код</code> " * 200,
+])
+
+
+def is_balanced(text: str) -> bool:
+    stack = []
+    for closing, name in re.findall(r'<(/?)([a-zA-Z][\w-]*)[^>]*>', text):
+        if name == 'br':
+            continue
+        if not closing:
+            stack.append(name)
+        elif not stack or stack.pop() != name:
+            return False
+    return not stack
+
+
+class SplitMessageTest(unittest.TestCase):
+    CASES = [
+        # (описание, текст, размер части)
+        ("короткий текст", "Привет", TELEGRAM_MESSAGE_LIMIT),
+        ("длинный текст без разметки", "слово " * 2000, TELEGRAM_MESSAGE_LIMIT),
+        ("разрез внутри жирного", "<b>" + "word " * 2000 + "</b>", TELEGRAM_MESSAGE_LIMIT),
+        ("разрез внутри <pre><code>", _CODE_BLOCK, TELEGRAM_MESSAGE_LIMIT),
+        ("разрез рядом с сущностью", "a" * 4090 + " &amp; хвост", TELEGRAM_MESSAGE_LIMIT),
+        ("абзацы, код и цитата", _MIXED, TELEGRAM_MESSAGE_LIMIT),
+        ("теги без пары", "line<br>\n" * 3000, TELEGRAM_MESSAGE_LIMIT),
+        ("блок кода в Markdown", "текст\n```\n" + "line\n" * 100 + "```", 120),
+        ("текст без пробелов", "x" * 5000, 100),
+    ]
+
+    def test_chunks_fit_and_are_balanced(self):
+        for name, text, size in self.CASES:
+            with self.subTest(name):
+                chunks = split_message(text, size)
+                self.assertTrue(chunks)
+                for chunk in chunks:
+                    self.assertLessEqual(len(chunk), size)
+                    self.assertTrue(is_balanced(chunk), chunk[-80:])
+                    self.assertEqual(chunk.count('```') % 2, 0)
+
+    def test_short_text_is_single_chunk(self):
+        self.assertEqual(split_message("\n<b>Привет</b>\n"), ["<b>Привет</b>"])
+
+    def test_code_block_is_reopened(self):
+        chunks = split_message(_CODE_BLOCK)
+        self.assertGreater(len(chunks), 1)
+        for chunk in chunks[1:]:
+            self.assertTrue(chunk.startswith('<pre><code class="language-python">'))
+        for chunk in chunks[:-1]:
+            self.assertTrue(chunk.endswith('</code></pre>'))
+
+    def test_fence_is_reopened(self):
+        chunks = split_message("```\n" + "line\n" * 100 + "```", 120)
+        self.assertGreater(len(chunks), 1)
+        for chunk in chunks:
+            self.assertTrue(chunk.startswith('```'))
+            self.assertTrue(chunk.endswith('```'))
+
+    def test_unclosed_tags_do_not_hang(self):
+        for text in ("<b>" * 3000 + "text " * 2000, "<i>x " * 5000):
+            with self.subTest(text[:10]):
+                chunks = split_message(text)
+                self.assertTrue(all(len(chunk) <= TELEGRAM_MESSAGE_LIMIT for chunk in chunks))
+                self.assertIn("text" if "text" in text else "x", chunks[-1])
+
+    def test_entity_is_not_cut(self):
+        chunks = split_message("a" * 4090 + " &amp; хвост")
+        self.assertEqual(chunks, ["a" * 4090, "&amp; хвост"])
+
+
+class FindCutTest(unittest.TestCase):
+    CASES = [
+        # (описание, текст, размер, внутри блока кода, ожидаемое место разреза)
+        ("между абзацами", "a" * 30 + "\n\n" + "b" * 30 + "\nc" * 10, 50, False, 30),
+        ("по переводу строки", "a" * 30 + "\n" + "b" * 30, 50, False, 30),
+        ("по пробелу", "a" * 30 + " " + "b" * 30, 50, False, 30),
+        ("слишком короткая часть", "a" * 5 + " " + "b" * 60, 50, False, 50),
+        ("не внутри тега", "a" * 45 + '<a href="x">y</a>', 50, False, 45),
+        ("не внутри сущности", "a" * 46 + "&amp;b", 50, False, 46),
+        ("перед блоком кода", "a" * 30 + "\n```\n" + "b" * 30, 50, False, 31),
+        ("конец блока кода не переносится", "a" * 30 + "\n```\n" + "b" * 30, 50, True, 34),
+    ]
+
+    def test_cases(self):
+        for name, text, size, in_code, expected in self.CASES:
+            with self.subTest(name):
+                self.assertEqual(_find_cut(text, size, in_code), expected)
+
+
+class FormatQuotaTest(unittest.TestCase):
+    CASES = [
+        # (описание, единицы учета, лимит, ожидаемый текст)
+        ("1 запрос", 'requests', 1, "1 запрос"),
+        ("2 запроса", 'requests', 2, "2 запроса"),
+        ("5 запросов", 'requests', 5, "5 запросов"),
+        ("11 запросов", 'requests', 11, "11 запросов"),
+        ("21 запрос", 'requests', 21, "21 запрос"),
+        ("114 запросов", 'requests', 114, "114 запросов"),
+        ("0 запросов", 'requests', 0, "0 запросов"),
+        ("безлимит", 'requests', float('inf'), "∞"),
+        ("токены с разрядами", 'tokens', 200_000, "200 000 токенов"),
+        ("1 001 токен", 'tokens', 1001, "1 001 токен"),
+        ("3 токена", 'tokens', 3, "3 токена"),
+    ]
+
+    def test_cases(self):
+        for name, mode, value, expected in self.CASES:
+            with self.subTest(name), patch.object(text_service, 'QUOTA_MODE', mode):
+                self.assertEqual(format_quota(value), expected)
+
+
+if __name__ == '__main__':
+    unittest.main()